@@ -2,17 +2,57 @@
 extern crate log;
 extern crate mio;
 extern crate byteorder;
+extern crate lz4_flex;
+extern crate rustls;
 
 #[path="../shared/frame.rs"]
 pub mod frame;
-use frame::{MessageFrame, ToFrame, Message};
+pub use frame::ProtocolError;
+use frame::{MessageFrame, ToFrame, Message, CompressionStats, ProtocolResult, CAPABILITY_COMPRESSION, CAPABILITY_INPUT_COMMANDS, SUPPORTED_CAPABILITIES, DEFAULT_COMPRESSION_THRESHOLD};
+
+#[path="../shared/math.rs"]
+pub mod math;
 
 #[path="../shared/state.rs"]
 pub mod state;
-use state::{ClientState, Position, Rotation, Transform};
+use state::{ClientState, Position, Rotation, Transform, Velocity};
+
+#[path="../shared/entity.rs"]
+pub mod entity;
+
+#[path="../shared/input.rs"]
+pub mod input;
+use input::{InputCommand, MAX_INPUT_DURATION_MS};
+
+#[path="../shared/clock.rs"]
+pub mod clock;
+use clock::{ClockSample, ClockSync};
+
+#[path="../shared/logging.rs"]
+pub mod logging;
+use logging::LogContext;
+
+#[path="../shared/udp.rs"]
+pub mod udp;
+use udp::{Delivery, UdpConnection};
+
+#[path="../shared/transport.rs"]
+pub mod transport;
+use transport::{Connection, TcpConnection};
+
+#[path="../shared/tls.rs"]
+pub mod tls;
+use tls::{ServerTrust, TlsConnection};
+
+#[path="../shared/fragment.rs"]
+pub mod fragment;
+use fragment::{Fragmenter, Reassembler};
+
+#[path="../shared/priority.rs"]
+pub mod priority;
+use priority::{Priority, PriorityQueue, MESSAGES_PER_WRITE};
 
 use mio::tcp::*;
-use mio::TryWrite;
 use mio::util::Slab;
 use std::net::SocketAddr;
 use std::io::{Result, Read, Error, ErrorKind};
@@ -22,8 +62,7 @@ use std::thread::JoinHandle;
 use std::sync::{Arc, RwLock};
 //use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::collections::VecDeque;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const CLIENT_TOKEN: mio::Token = mio::Token(1);
 
@@ -31,6 +70,15 @@ const CLIENT_TOKEN: mio::Token = mio::Token(1);
 /// Used as the capacity value in Vec::with_capacity(capacity: usize);
 const RECEIVED_MESSAGES_PER_TICK: usize = 2;
 
+/// How often the client pings the server to keep its estimate of the server clock current
+const CLOCK_SYNC_INTERVAL_MS: u64 = 1000;
+
+/// Until this many clock samples have been collected, the server is pinged more often
+const CLOCK_SYNC_STARTUP_SAMPLES: usize = 4;
+
+/// How often the server is pinged while the first clock samples are collected
+const CLOCK_SYNC_STARTUP_INTERVAL_MS: u64 = 100;
+
 
 /// Contains data related to the client
 pub struct ClientData{
@@ -40,7 +88,8 @@ pub struct ClientData{
 
     interest: EventSet,
 
-    send_queue: VecDeque<MessageFrame>,
+    /// Outgoing messages, and how each should be delivered over unreliable transports
+    send_queue: PriorityQueue<(MessageFrame, Delivery)>,
 
     // Buffer of received messages
     receive_queue: Vec<Message>,
@@ -50,23 +99,91 @@ pub struct ClientData{
     state_updated: bool,
 
     /// Set to true once the client has received initial ClientState from the server
-    is_authenticated_client: bool
+    is_authenticated_client: bool,
+
+    /// Payloads larger than this are compressed, once the server has agreed to compression
+    compression_threshold: Option<usize>,
+
+    /// How well outgoing payloads have compressed
+    compression: CompressionStats,
+
+    /// Whether outgoing frames carry a CRC32 trailer
+    frame_checksums: bool,
+
+    /// Set once the server has agreed to move this client by its input commands
+    input_commands: bool,
+
+    /// Sequence number of the next input command sent
+    next_input_sequence: u32,
+
+    /// When the last input command was sent
+    last_input: Option<Instant>,
+
+    /// When the client connected, which input command timestamps and the client clock count from
+    started: Instant,
+
+    /// Estimates the server clock from the replies to clock sync pings
+    clock: ClockSync,
+
+    /// When the last clock sync ping was sent
+    last_clock_sync: Option<Instant>
 }
 
 impl ClientData{
     fn new() -> ClientData{
         ClientData {
             id: None,
-            send_queue: VecDeque::new(),
+            send_queue: PriorityQueue::new(),
             token: CLIENT_TOKEN,
             interest: EventSet::readable(),
             receive_queue: Vec::with_capacity(RECEIVED_MESSAGES_PER_TICK),
             client_state: ClientState::new(CLIENT_TOKEN.as_usize() as u32),
             state_updated: false,
-            is_authenticated_client: false
+            is_authenticated_client: false,
+            compression_threshold: None,
+            compression: CompressionStats::new(),
+            frame_checksums: false,
+            input_commands: false,
+            next_input_sequence: 0,
+            last_input: None,
+            started: Instant::now(),
+            clock: ClockSync::new(),
+            last_clock_sync: None
         }
     }
 
+    /// Microseconds on the client's clock, which counts from when the client connected
+    fn clock_time(&self) -> u64{
+        let elapsed = Instant::now().duration_since(self.started);
+        elapsed.as_secs() * 1000000 + elapsed.subsec_nanos() as u64 / 1000
+    }
+
+    /// Queue a clock sync ping if one is due
+    fn sync_clock(&mut self){
+        let interval = match self.clock.sample_count() < CLOCK_SYNC_STARTUP_SAMPLES{
+            true  => Duration::from_millis(CLOCK_SYNC_STARTUP_INTERVAL_MS),
+            false => Duration::from_millis(CLOCK_SYNC_INTERVAL_MS)
+        };
+        let now = Instant::now();
+        if self.last_clock_sync.map_or(false, |last| now.duration_since(last) < interval){
+            return;
+        }
+        self.last_clock_sync = Some(now);
+
+        let message = Message::Ping(Some(ClockSample::new(self.clock_time())));
+        let delivery = Delivery::for_message(&message);
+        self.send_queue.push_back(Priority::Control, (message.to_frame(), delivery));
+    }
+
+    /// Answer the server's round trip ping @sample, which arrived at @received
+    fn answer_ping(&mut self, mut sample: ClockSample, received: u64){
+        sample.answer(received, self.clock_time());
+        let message = Message::Ping(Some(sample));
+        let delivery = Delivery::for_message(&message);
+        self.send_queue.push_back(Priority::Control, (message.to_frame(), delivery));
+        self.set_writable();
+    }
+
     fn set_writable(&mut self){
         self.interest.insert(EventSet::writable());
     }
@@ -84,22 +201,29 @@ impl ClientData{
 
 /// Maintains a reference to the client, the socket, and the thread join handle
 struct ClientInterface{
-    socket: TcpStream,
+    socket: Box<Connection>,
 
     thread_handle: Option<JoinHandle<()>>,
 
     client: Arc<RwLock<ClientData>>,
 
     is_connected: bool,
+
+    fragmenter: Fragmenter,
+
+    /// Collects fragments of large messages sent by the server
+    reassembler: Reassembler,
 }
 
 impl ClientInterface{
-    fn new(event_loop: Arc<RwLock<EventLoop<ClientInterface>>>, socket: TcpStream, client: Arc<RwLock<ClientData>>) -> Arc<RwLock<ClientInterface>>{
+    fn new(event_loop: Arc<RwLock<EventLoop<ClientInterface>>>, socket: Box<Connection>, client: Arc<RwLock<ClientData>>) -> Arc<RwLock<ClientInterface>>{
         let interface = Arc::new(RwLock::new(ClientInterface{
             socket: socket,
             thread_handle: None,
             client: client,
-            is_connected: true
+            is_connected: true,
+            fragmenter: Fragmenter::new(),
+            reassembler: Reassembler::new()
         }));
 
         let thread_interface = interface.clone();
@@ -114,15 +238,15 @@ impl ClientInterface{
                     }
                 }
 
-                if let Ok(client_interface) = thread_interface.try_write(){
+                if let Ok(mut client_interface) = thread_interface.try_write(){
                     if !client_interface.is_connected{
-                        info!("The client has disconnected from the server!");
+                        info!("Disconnected from the server");
 
                         if let Ok(mut event_loop) = event_loop.write(){
                             event_loop.shutdown();
                         }
 
-                        client_interface.socket.shutdown(Shutdown::Both);
+                        client_interface.socket.shutdown();
                         break;
                     }
                 }
@@ -140,60 +264,225 @@ impl ClientInterface{
 
     fn register(&mut self, event_loop: &mut EventLoop<ClientInterface>){
         if let Ok(client) = self.client.read(){
-            event_loop.register(
-                &self.socket,
-                client.token,
-                client.interest,
-                PollOpt::edge() | PollOpt::oneshot()
-            ).or_else(|e| {
-                info!("Failed to register server! {:?}", e);
-                Err(e)
-            }).ok();
+            if let Some(socket) = self.socket.evented(){
+                event_loop.register(
+                    socket,
+                    client.token,
+                    client.interest,
+                    PollOpt::edge() | PollOpt::oneshot()
+                ).or_else(|e| {
+                    error!("Failed to register connection: {:?}", e);
+                    Err(e)
+                }).ok();
+            }
         }
     }
 
     fn reregister(&mut self, event_loop: &mut EventLoop<ClientInterface>){
         if let Ok(client) = self.client.read(){
-            event_loop.reregister(&self.socket, client.token, client.interest, PollOpt::edge())
-                .or_else(|e|{
-                    info!("I am a sad panda, {:?}", e);
-                    Err(e)
-            }).ok();
+            if let Some(socket) = self.socket.evented(){
+                event_loop.reregister(socket, client.token, client.interest, PollOpt::edge())
+                    .or_else(|e|{
+                        warn!("Failed to reregister connection: {:?}", e);
+                        Err(e)
+                }).ok();
+            }
         }
     }
 
-    pub fn read(&mut self) -> Result<Message>{
-        let read_socket = <TcpStream as Read>::by_ref(&mut self.socket);
+    /// Read the next message from the server.
+    /// Anything else received along with it is passed on to the receive queue.
+    pub fn read(&mut self) -> ProtocolResult<Message>{
+        trace!("Reading message");
 
-        info!("Begin client read message");
-
-        // Read the message from the socket
-        let message = Message::read(read_socket);
+        let mut received = self.socket.receive().into_iter();
+        let message = received.next().unwrap_or(Err(ProtocolError::Io(Error::new(ErrorKind::WouldBlock, String::from("No message available")))));
 
         match message{
-            Ok(message) => {
-                match message{
-                    Message::Text{message: ref message_text} => {
-                        info!("Received message: {}", &message_text);
-                    },
-                    Message::Ping => {
-                        info!("Received Ping!");
-                    },
-                    Message::ClientUpdate(_) =>{
-                        info!("Received client update packet!");
-                    },
-                    Message::GameStateUpdate( _ ) => {
-                        info!("Received game state update!");
+            Ok((message, _)) => {
+                // Only the first message of a batch or reassembled fragments is returned,
+                // the rest are queued like anything else
+                let mut messages = match self.unpack(message){
+                    Ok(messages) => messages.into_iter(),
+                    Err(e) => {
+                        self.on_bad_frame(&e);
+                        return Err(e);
                     }
+                };
+                let first = messages.next();
+                for message in messages{
+                    Self::log_received(&message);
+                    self.on_message_received(message);
+                }
+                self.on_read(received.collect());
+
+                if let Some(ref message) = first{
+                    Self::log_received(message);
                 }
-                return Ok(message);
+                return first.ok_or(ProtocolError::Io(Error::new(ErrorKind::WouldBlock, String::from("No complete message available"))));
             },
             Err(e) => {
+                self.on_read(received.collect());
                 return Err(e);
             }
         }
     }
 
+    /// Hand every queued message to the connection, and write as much as it will take
+    fn flush(&mut self){
+        if let Ok(mut client) = self.client.try_write(){
+            for _ in 0..MESSAGES_PER_WRITE{
+                let (mut message_frame, delivery) = match client.send_queue.pop_front(){
+                    Some(queued) => queued,
+                    None => { break; }
+                };
+                if let Some(threshold) = client.compression_threshold{
+                    if let Some((uncompressed_length, compressed_length)) = message_frame.compress(threshold){
+                        client.compression.record(message_frame.code(), uncompressed_length, compressed_length);
+                    }
+                }
+                if client.frame_checksums{
+                    message_frame.add_checksum();
+                }
+
+                let output_bytes = message_frame.to_bytes();
+                if self.fragmenter.needs_split(output_bytes.len()){
+                    // Every fragment is needed to rebuild the message, so they're always sent reliably
+                    for fragment in self.fragmenter.split(&output_bytes){
+                        let mut fragment_frame = fragment.to_frame();
+                        if client.frame_checksums{
+                            fragment_frame.add_checksum();
+                        }
+                        if let Err(e) = self.socket.send(fragment_frame.to_bytes(), Delivery::ReliableOrdered){
+                            warn!("Dropping fragment of {:?} message: {:?}", message_frame.code(), e);
+                        }
+                    }
+                }
+                else if let Err(e) = self.socket.send(output_bytes, delivery){
+                    warn!("Dropping {:?} message: {:?}", message_frame.code(), e);
+                }
+            }
+        }
+        else{
+            trace!("Nothing to write");
+        }
+
+        if let Err(e) = self.socket.flush(){
+            warn!("Failed to write message: {:?}", e);
+        }
+    }
+
+    /// Act on everything read from the server
+    fn on_read(&mut self, received: Vec<ProtocolResult<(Message, usize)>>){
+        for received_message in received{
+            match received_message{
+                Ok((message, _)) => {
+                    match self.unpack(message){
+                        Ok(messages) => {
+                            for message in messages{
+                                Self::log_received(&message);
+                                self.on_message_received(message);
+                            }
+                        },
+                        Err(e) => {
+                            self.on_bad_frame(&e);
+                            return;
+                        }
+                    }
+                },
+                Err(ProtocolError::Io(e)) => {
+                    if e.kind() == ErrorKind::UnexpectedEof || e.kind() == ErrorKind::TimedOut{
+                        info!("The server closed the connection: {}", e);
+                        self.set_socket_disconnected();
+                    }
+                    else if e.kind() != ErrorKind::WouldBlock{
+                        warn!("Failed to read message: {:?}", e);
+                    }
+                    if let Some(error_number) = e.raw_os_error(){
+                        if error_number == 10057{
+                            info!("Socket is not connected");
+                            self.set_socket_disconnected();
+                        }
+                    }
+                },
+                Err(e) => {
+                    self.on_bad_frame(&e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Expand a Batch into the messages it carries,
+    /// and hold on to Fragments until the message they're part of is complete
+    fn unpack(&mut self, message: Message) -> ProtocolResult<Vec<Message>>{
+        match message{
+            Message::Batch(messages) => {
+                let mut unpacked = Vec::with_capacity(messages.len());
+                for message in messages{
+                    unpacked.extend(try!(self.unpack(message)));
+                }
+                return Ok(unpacked);
+            },
+            Message::Fragment{ message_id, index, count, data } => {
+                match try!(self.reassembler.insert(message_id, index, count, data, Instant::now())){
+                    Some(message) => {
+                        trace!("Reassembled message {}", message_id);
+                        return self.unpack(message);
+                    },
+                    None => { return Ok(Vec::new()); }
+                }
+            },
+            message => { return Ok(vec![message]); }
+        }
+    }
+
+    /// Close the connection after a frame which couldn't be read, as the rest of the input can't be trusted
+    fn on_bad_frame(&mut self, e: &ProtocolError){
+        error!("Closing connection after bad frame: {}", e);
+        self.socket.shutdown();
+        self.set_socket_disconnected();
+    }
+
+    /// Log a message received from the server
+    fn log_received(message: &Message){
+        match *message{
+            Message::Text{message: ref message_text} => {
+                debug!("Received text message: {}", &message_text);
+            },
+            Message::Ping(_) => {
+                trace!("Received ping");
+            },
+            Message::ClientUpdate(_) =>{
+                trace!("Received client update");
+            },
+            Message::GameStateUpdate( _ ) => {
+                trace!("Received game state update");
+            },
+            Message::Login{ account_id: _ } => {
+                warn!("Received a login message from the server");
+            },
+            Message::Disconnect{ reason, message: ref message_text } => {
+                info!("Disconnected by the server: {:?}, {}", reason, message_text);
+            },
+            Message::Hello{ capabilities } => {
+                debug!("Server supports capabilities {:#04x}", capabilities);
+            },
+            Message::Batch(ref messages) => {
+                trace!("Received a batch of {} messages", messages.len());
+            },
+            Message::Fragment{ message_id, index, count, data: _ } => {
+                trace!("Received fragment {} of {} of message {}", index, count, message_id);
+            },
+            Message::EntityUpdate(ref deltas) => {
+                trace!("Received changes to {} entities", deltas.len());
+            },
+            Message::Input(_) => {
+                warn!("Received an input command from the server");
+            }
+        }
+    }
+
     fn set_writable(&mut self){
         if let Ok(mut client) = self.client.write(){
             client.set_writable();
@@ -207,12 +496,78 @@ impl ClientInterface{
     }
 
     fn has_messages_to_send(&self) -> bool{
+        if self.socket.has_pending_output(){
+            return true;
+        }
         if let Ok(client) = self.client.try_read(){
             return client.has_messages_to_send();
         }
         return false;
     }
 
+    /// Fields attached to log events about this connection
+    fn log_context(&self) -> LogContext{
+        let entity = self.client.try_read().ok().and_then(|client| client.id);
+        LogContext::for_connection(CLIENT_TOKEN.as_usize(), entity, self.socket.peer_addr())
+    }
+
+    /// Hand a message received from the server to the game
+    fn on_message_received(&mut self, message: Message){
+        // The server closes the connection after a Disconnect,
+        // but the message is still passed along so the game can show the reason
+        if let Message::Disconnect{ reason: _, message: _ } = message{
+            self.set_socket_disconnected();
+        }
+
+        if let Ok(mut data) = self.client.write(){
+            if let Message::ClientUpdate(client_state) = message{
+                if data.id == Some(client_state.id){
+                    // The server moved us out of something we walked into
+                    debug!("Position corrected to {:?}", client_state.position);
+                    data.client_state.position = client_state.position;
+                    data.receive_queue.push(message);
+                }
+                else{
+                    info!("Assigned entity ID {}", client_state.id);
+                    data.client_state = client_state;
+                    data.id = Some(client_state.id);
+                    data.is_authenticated_client = true;
+                }
+            }
+            else if let Message::Ping(Some(sample)) = message{
+                let received = data.clock_time();
+                if sample.is_answered(){
+                    data.clock.add_sample(sample, received);
+                    trace!("Server clock is {:?}us ahead", data.clock.offset());
+                }
+                else{
+                    data.answer_ping(sample, received);
+                }
+            }
+            else if let Message::Hello{ capabilities } = message{
+                if capabilities & CAPABILITY_COMPRESSION != 0{
+                    data.compression_threshold = Some(DEFAULT_COMPRESSION_THRESHOLD);
+                }
+                data.input_commands = capabilities & CAPABILITY_INPUT_COMMANDS != 0;
+            }
+            else{
+                if let Message::EntityUpdate(ref deltas) = message{
+                    // When moved by input commands, the server decides where this client is
+                    if data.input_commands{
+                        let own_state = deltas.iter()
+                            .filter(|delta| Some(delta.id) == data.id)
+                            .filter_map(|delta| delta.state)
+                            .last();
+                        if let Some(state) = own_state{
+                            data.client_state = state;
+                        }
+                    }
+                }
+                data.receive_queue.push(message);
+            }
+        }
+    }
+
     fn set_socket_disconnected(&mut self){
         self.is_connected = false;
     }
@@ -223,13 +578,23 @@ impl Handler for ClientInterface{
     type Message = ();
 
     fn tick(&mut self, event_loop: &mut EventLoop<ClientInterface>) {
-        //info!("Begin client tick");
+        let _context = logging::enter(self.log_context());
 
         if let Ok(mut data) = self.client.try_write(){
+            if self.is_connected{
+                data.sync_clock();
+            }
+
+            // Servers moving the client by input commands ignore client updates
+            if data.state_updated && data.input_commands{
+                data.state_updated = false;
+            }
             if data.state_updated{
                 // @TODO: Check if there's already a ClientState message in the output queue
                 let client_state = data.client_state;
-                data.send_queue.push_back(Message::new_client_update_message(&client_state).to_frame());
+                let message = Message::new_client_update_message(&client_state);
+                let delivery = Delivery::for_message(&message);
+                data.send_queue.push_back(Priority::State, (message.to_frame(), delivery));
                 data.state_updated = false;
             }
         }
@@ -241,6 +606,17 @@ impl Handler for ClientInterface{
         }
 
         if self.is_connected{
+            self.reassembler.expire(Instant::now());
+
+            // Unreliable transports also send acks and heartbeats here, so flush every tick
+            self.flush();
+
+            // Connections with no socket to wait on are read every tick
+            if self.socket.evented().is_none(){
+                let received = self.socket.receive();
+                self.on_read(received);
+            }
+
             self.reregister(event_loop);
         }
 
@@ -248,97 +624,26 @@ impl Handler for ClientInterface{
     }
 
     fn ready(&mut self, event_loop: &mut EventLoop<ClientInterface>, token: Token, events: EventSet) {
-        assert!(token != Token(0), "Token 0, y?????");
+        assert!(token != Token(0), "We're not supposed to get a Token(0)!");
+        let _context = logging::enter(self.log_context());
 
         if events.is_error(){
-            info!("Client received error for token {:?}", token);
+            warn!("Error event on connection");
             return;
         }
 
         if events.is_hup(){
-            info!("Oh shit, did the server ({:?}) crash or some shit?!", token);
+            info!("The server hung up");
             return;
         }
 
         if events.is_writable(){
-            info!("TIME TO TALK MOTHERFUCKER");
-
-            if let Ok(mut client) = self.client.try_write(){
-                if !client.send_queue.is_empty(){
-                //     let output_buffer = client.send_queue.iter()
-                //                     .map(|mes| mes.to_bytes() ).
-                //                     fold(Vec::new(), |mut buf, mut mes|{ buf.append(&mut mes); buf });
-                   //
-                   //
-                //     match self.socket.try_write(output_buffer.as_slice()){
-                //        Ok(Some(n)) => {
-                //            info!("Wrote {} bytes", n);
-                //            client.send_queue.clear();
-                //        },
-                //        Ok(None) => {
-                //            info!("Nothing happened but it's okay I guess?");
-                //            //client.send_queue.push_back(message_frame);
-                //        },
-                //        Err(e) => {
-                //            info!("Oh fuck me god fucking damn it fucking shit fuck: {:?}", e);
-                //            //client.send_queue.push_back(message_frame);
-                //        }
-                //    };
-                    if let Some(message_frame) = client.send_queue.pop_front(){
-                        match self.socket.try_write(message_frame.to_bytes().as_slice()){
-                            Ok(Some(n)) => {
-                                info!("Wrote {} bytes", n);
-                            },
-                            Ok(None) => {
-                                info!("Nothing happened but it's okay I guess?");
-                                client.send_queue.push_back(message_frame);
-                            },
-                            Err(e) => {
-                                info!("Oh fuck me god fucking damn it fucking shit fuck: {:?}", e);
-                                client.send_queue.push_back(message_frame);
-                            }
-                        };
-                    }
-                    else{
-                        info!("Failed to pop message from queue!");
-                    }
-                }
-            }
-            else{
-                info!("Nothing to write...");
-            }
+            self.flush();
         }
 
         if events.is_readable(){
-            info!("OH shit, what've you got to say?");
-
-            let received_message = self.read();
-
-
-            match received_message{
-                Ok(message) => {
-                    if let Ok(mut data) = self.client.write(){
-                        if let Message::ClientUpdate(client_state) = message{
-                            info!("Received client ID: {}", client_state.id);
-                            data.client_state.id = client_state.id;
-                            data.id = Some(client_state.id);
-                            data.is_authenticated_client = true;
-                        }
-                        else{
-                            data.receive_queue.push(message);
-                        }
-                    }
-                },
-                Err(e) => {
-                    info!("Error trying to read! {:?}", e);
-                    if let Some(error_number) = e.raw_os_error(){
-                        if error_number == 10057{
-                            info!("Socket is not connected!");
-                            self.set_socket_disconnected();
-                        }
-                    }
-                }
-            }
+            let received = self.socket.receive();
+            self.on_read(received);
         }
 
         //let client_rereg = self.client.clone();
@@ -347,14 +652,14 @@ impl Handler for ClientInterface{
     }
 
     fn notify(&mut self, _: &mut EventLoop<Self>, _: Self::Message) {
-        info!("Received notify!");
+        debug!("Received notify");
     }
 
     fn timeout(&mut self, _: &mut EventLoop<Self>, _: Self::Timeout) {
-        info!("Received timeout");
+        trace!("Received timeout");
     }
     fn interrupted(&mut self, _: &mut EventLoop<Self>) {
-        info!("Interrupted! :O");
+        debug!("Interrupted");
     }
 }
 
@@ -375,31 +680,73 @@ impl Client{
         let socket = TcpStream::connect(address);
         match socket{
             Ok(socket) => {
-                let event_loop = Arc::new(RwLock::new(EventLoop::new().ok().expect("Failed to create event loop!")));
-                let client_data = Arc::new(RwLock::new(ClientData::new()));
-
-                let interface_event_loop = event_loop.clone();
-                let client_interface = ClientInterface::new(interface_event_loop, socket, client_data.clone());
-
-                let mut client = Client{
-                    data: client_data,
-                    interface: client_interface,
-                    event_loop: event_loop,
-                    is_authenticated_client: false,
-                    id: None
-                };
+                return Ok(Self::start(Box::new(TcpConnection::new(socket))));
+            },
+            Err(e) => {
+                error!("Failed to connect to {}: {:?}", address, e);
+                return Err(e);
+            }
+        }
+    }
 
-                client.register();
+    /// Connect to a TLS-enabled server at @address, verifying it as @server_name according to @trust
+    pub fn connect_tls(address: &SocketAddr, server_name: &str, trust: ServerTrust) -> Result<Client>{
+        let config = try!(tls::client_config(&trust));
+        let socket = TcpStream::connect(address);
+        match socket{
+            Ok(socket) => {
+                let connection = try!(TlsConnection::connect(socket, server_name, config));
+                return Ok(Self::start(Box::new(connection)));
+            },
+            Err(e) => {
+                error!("Failed to connect to {}: {:?}", address, e);
+                return Err(e);
+            }
+        }
+    }
 
-                return Ok(client);
+    /// Connect to the server's UDP transport at @address.
+    /// Messages are sent with their default delivery; see `send_message_with_delivery`.
+    pub fn connect_udp(address: &SocketAddr) -> Result<Client>{
+        match UdpConnection::connect(address){
+            Ok(connection) => {
+                return Ok(Self::start(Box::new(connection)));
             },
             Err(e) => {
-                info!("Failed to connect! {:?}", e);
+                error!("Failed to open a UDP socket for {}: {:?}", address, e);
                 return Err(e);
             }
         }
     }
 
+    /// Run the client over an already open @connection, such as an in-memory one
+    pub fn from_connection(connection: Box<Connection>) -> Client{
+        Self::start(connection)
+    }
+
+    /// Start the event loop thread for @connection
+    fn start(connection: Box<Connection>) -> Client{
+        let event_loop = Arc::new(RwLock::new(EventLoop::new().ok().expect("Failed to create event loop!")));
+        let client_data = Arc::new(RwLock::new(ClientData::new()));
+
+        let interface_event_loop = event_loop.clone();
+        let client_interface = ClientInterface::new(interface_event_loop, connection, client_data.clone());
+
+        let mut client = Client{
+            data: client_data,
+            interface: client_interface,
+            event_loop: event_loop,
+            is_authenticated_client: false,
+            id: None
+        };
+
+        // Tell the server what this client supports before anything else is sent
+        client.send_message(&Message::new_hello_message(SUPPORTED_CAPABILITIES));
+        client.register();
+
+        return client;
+    }
+
     /// Register with the event loop
     fn register(&mut self){
         if let Ok(mut interface) = self.interface.write(){
@@ -420,21 +767,76 @@ impl Client{
     }
 
     pub fn send_message<T: ToFrame>(&mut self, message: &T){
+        let message_frame = message.to_frame();
+        let delivery = Delivery::for_code(&message_frame.code());
+        let priority = Priority::for_frame(&message_frame.code(), message_frame.to_bytes().len());
+        self.send_frame(message_frame, delivery, priority);
+    }
+
+    /// Send @message with the given @delivery, rather than the default for its type.
+    /// The delivery only applies to UDP connections; TCP is always reliable and ordered.
+    pub fn send_message_with_delivery<T: ToFrame>(&mut self, message: &T, delivery: Delivery){
+        let message_frame = message.to_frame();
+        let priority = Priority::for_frame(&message_frame.code(), message_frame.to_bytes().len());
+        self.send_frame(message_frame, delivery, priority);
+    }
+
+    /// Send @message with the given @priority, rather than the default for its type.
+    /// Bulk messages are dropped while the send queue is behind.
+    pub fn send_message_with_priority<T: ToFrame>(&mut self, message: &T, priority: Priority){
+        let message_frame = message.to_frame();
+        let delivery = Delivery::for_code(&message_frame.code());
+        self.send_frame(message_frame, delivery, priority);
+    }
+
+    fn send_frame(&mut self, message_frame: MessageFrame, delivery: Delivery, priority: Priority){
         if let Ok(mut data) = self.data.write(){
-            data.send_queue.push_back(message.to_frame());
+            let code = message_frame.code();
+            if !data.send_queue.push_back(priority, (message_frame, delivery)){
+                debug!("Send queue is behind, dropping {:?} message", code);
+            }
             data.set_writable();
         } else { return; }
 
         self.reregister();
     }
 
-    pub fn read(&mut self) -> Result<Message>{
+    /// How well the payloads this client has sent have compressed, by message type
+    pub fn compression_stats(&self) -> CompressionStats{
+        match self.data.read(){
+            Ok(data) => data.compression.clone(),
+            Err(_) => CompressionStats::new()
+        }
+    }
+
+    /// Follow each frame sent with a CRC32 trailer, so the server can check its integrity
+    pub fn set_frame_checksums(&mut self, enabled: bool){
+        if let Ok(mut data) = self.data.write(){
+            data.frame_checksums = enabled;
+        }
+    }
+
+    /// Reject frames from the server with payloads longer than @max_payload_length,
+    /// rather than the default `frame::DEFAULT_MAX_PAYLOAD_LENGTH`
+    pub fn set_max_payload_length(&mut self, max_payload_length: u32){
+        if let Ok(mut interface) = self.interface.write(){
+            interface.socket.set_max_payload_length(max_payload_length);
+        }
+    }
+
+    /// Identify this client as the account @account_id.
+    /// The server disconnects the client if the account is banned.
+    pub fn login(&mut self, account_id: u32){
+        self.send_message(&Message::new_login_message(account_id));
+    }
+
+    pub fn read(&mut self) -> ProtocolResult<Message>{
         if let Ok(mut interface) = self.interface.write(){
             // Suddenly realizing that I just wrote some really confusing code here.
             return interface.read();
         }
         else{
-            return Err(Error::new(ErrorKind::Other, String::from("Failed to read from client interface!")));
+            return Err(ProtocolError::Io(Error::new(ErrorKind::Other, String::from("Failed to read from client interface!"))));
         }
     }
 
@@ -519,6 +921,22 @@ impl Client{
         return None;
     }
 
+    /// Update the @velocity of the client, which other clients use to extrapolate its movement
+    pub fn set_velocity(&mut self, velocity: Velocity){
+        if let Ok(mut data) = self.data.try_write(){
+            data.client_state.velocity = velocity;
+            data.state_updated = true;
+        }
+    }
+
+    /// Get the client's current velocity
+    pub fn get_velocity(&self) -> Option<Velocity> {
+        if let Ok(data) = self.data.read(){
+            return Some(data.client_state.velocity);
+        }
+        return None;
+    }
+
     /// Get the client's position and rotation
     pub fn get_transform(&self) -> Option<Transform>{
         if let Ok(data) = self.data.read(){
@@ -527,6 +945,62 @@ impl Client{
         return None;
     }
 
+    /// Returns TRUE if the server moves this client by its input commands,
+    /// in which case `send_input` is used in place of `set_transform` and `set_velocity`
+    pub fn uses_input_commands(&self) -> bool{
+        match self.data.read(){
+            Ok(data) => data.input_commands,
+            Err(_) => false
+        }
+    }
+
+    /// Send what the player is doing: @movement sideways and forward, each between -1 and 1,
+    /// facing @yaw degrees with the `input::BUTTON_*` bits in @buttons held.
+    /// The input is taken to have been held since the last call, up to `MAX_INPUT_DURATION_MS`.
+    pub fn send_input(&mut self, movement: (f32, f32), yaw: f32, buttons: u8){
+        let command = if let Ok(mut data) = self.data.write(){
+            let now = Instant::now();
+            let held = data.last_input.map_or(Duration::from_millis(0), |last| now.duration_since(last));
+            let duration = (held.as_secs() * 1000 + held.subsec_nanos() as u64 / 1000000).min(MAX_INPUT_DURATION_MS as u64) as u16;
+            let since_start = now.duration_since(data.started);
+
+            let mut command = InputCommand::new(data.next_input_sequence, (since_start.as_secs() * 1000 + since_start.subsec_nanos() as u64 / 1000000) as u32, duration);
+            command.movement = movement;
+            command.yaw = yaw;
+            command.buttons = buttons;
+
+            data.next_input_sequence = data.next_input_sequence.wrapping_add(1);
+            data.last_input = Some(now);
+            command
+        } else { return; };
+
+        self.send_message(&Message::Input(command));
+    }
+
+    /// The server's clock, counting from when it started, once the client has heard back from a clock sync ping
+    pub fn server_time(&self) -> Option<Duration>{
+        if let Ok(data) = self.data.read(){
+            return data.clock.server_time(data.clock_time()).map(|micros| Duration::new(micros / 1000000, (micros % 1000000) as u32 * 1000));
+        }
+        return None;
+    }
+
+    /// The simulation tick the server is running now, estimated from its clock
+    pub fn estimated_server_tick(&self) -> Option<u64>{
+        if let Ok(data) = self.data.read(){
+            return data.clock.server_tick(data.clock_time());
+        }
+        return None;
+    }
+
+    /// The shortest recent round trip to the server, measured by clock sync pings
+    pub fn round_trip_time(&self) -> Option<Duration>{
+        if let Ok(data) = self.data.read(){
+            return data.clock.round_trip();
+        }
+        return None;
+    }
+
     pub fn is_authenticated(&mut self) -> bool{
         // If the cached value is `false` then either we're not authenticated,
         // or we haven't checked the actual ClientData value yet
@@ -1,10 +1,25 @@
 extern crate mio;
 extern crate log;
 
-use client::GameClient;
+use client::{GameClient, WrittenMessage};
+use config::{ServerConfig, MovementMode};
+use bans::{BanList, BanEntry, BanTarget};
+use throttle::ConnectionThrottle;
+use metrics;
+use metrics::Metrics;
+use logging;
+use logging::LogContext;
+use transport::{Connection, Transport, TcpTransport};
+use tls;
+use tls::TlsTransport;
+use udp::{Delivery, UdpTransport};
+use websocket::WebSocketTransport;
+use zone::{Zone, ZoneInput, ZoneTemplate, DEFAULT_ZONE, FIRST_NON_PLAYER_ENTITY_ID, SIMULATION_STEP_MS};
+use scheduler::{Simulated, ZoneScheduler};
+use clock::ClockSample;
+use input::InputCommand;
 
 //use mio::{TryRead, TryWrite};
-use mio::tcp::*;
 use mio::util::Slab;
 use mio::Token;
 use mio::{EventLoop, EventSet, PollOpt, Handler};
@@ -14,36 +29,88 @@ use std::net::SocketAddr;
 //use std::io::Cursor;
 //use std::thread;
 //use std::sync::mpsc;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, RwLock, Mutex};
 //use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::atomic::AtomicUsize;
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
 
-#[path="../shared/frame.rs"]
-mod frame;
-use frame::{Message};
+use frame::{Message, ToFrame, DisconnectReason, ProtocolError, ProtocolResult, CAPABILITY_COMPRESSION, CAPABILITY_INPUT_COMMANDS, SUPPORTED_CAPABILITIES};
+use state::Position;
+use entity::{Component, EntityKind};
 
-#[path="../shared/state.rs"]
-mod state;
-use state::{ClientState, GameState};
-
-
-const SERVER_TOKEN: mio::Token = mio::Token(1);
+/// Listeners are given tokens counting down from here, while clients count up from 2
+const LISTENER_TOKEN_BASE: usize = ::std::usize::MAX - 1;
 
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
 enum Destination{
-    Client(Token),
-    Broadcast
+    Client(Token)
+}
+
+/// Commands sent to a running server through its event loop channel
+pub enum ServerCommand{
+    /// Add a ban, and disconnect any connected clients it covers
+    Ban(BanEntry),
+
+    /// Lift the ban on the given target
+    Unban(BanTarget),
+
+    /// Re-read the ban list from disk
+    ReloadBans,
+
+    /// Log every active ban
+    ListBans,
+
+    /// Add a non-player entity to the default zone
+    Spawn{ kind: EntityKind, position: Position, components: Vec<Component> },
+
+    /// Remove the non-player entity with the given ID
+    Despawn(u32),
+
+    /// Move a player to the named zone, or a new instance of the named template
+    Transfer{ id: u32, zone: String },
+
+    /// Log every zone running and who is in it
+    ListZones
+}
+
+/// What the event loop keeps of a zone, which stays at hand while the zone is away being simulated
+struct ZoneHandle{
+    /// Where to send the zone input
+    inbox: Sender<ZoneInput>,
+
+    /// The zone's latest simulation step when it was last seen, and when that step was due
+    tick: u64,
+    last_step: Instant
 }
 
-#[derive(Clone)]
 pub struct AuthoritativeServerState{
     clients: Arc<RwLock<Slab<GameClient>>>,
     token_counter: Arc<AtomicUsize>,
     message_queue: HashMap<Destination, Vec<Message>>,
-    game_state: GameState,
 
-    game_state_updated: bool
+    /// Every zone which isn't being simulated right now, by name
+    zones: HashMap<String, Zone>,
+
+    /// Every zone running, by name, whether or not it's being simulated
+    zone_handles: HashMap<String, ZoneHandle>,
+
+    /// The zone each player is in, by entity ID
+    player_zones: HashMap<u32, String>,
+
+    /// The zones started when a player was first sent to them, which are torn down once empty
+    on_demand_zones: HashSet<String>,
+
+    /// Hands out non-player entity IDs to every zone
+    entity_ids: Arc<AtomicUsize>,
+
+    /// The number given to the next instance created
+    next_instance: usize,
+
+    /// When the server started, which the clock sent to clients counts from
+    started: Instant
 }
 
 impl AuthoritativeServerState{
@@ -53,99 +120,210 @@ impl AuthoritativeServerState{
             // Max 128 connections
             clients: Arc::new(RwLock::new(Slab::new_starting_at(Token(2), 128))),
             message_queue: HashMap::new(),
-            game_state: GameState::new(),
-            game_state_updated: false
+            zones: HashMap::new(),
+            zone_handles: HashMap::new(),
+            player_zones: HashMap::new(),
+            on_demand_zones: HashSet::new(),
+            entity_ids: Arc::new(AtomicUsize::new(FIRST_NON_PLAYER_ENTITY_ID as usize)),
+            next_instance: 1,
+            started: Instant::now()
         }
     }
 }
 
 pub struct AuthoritativeServer{
-    // The socket on which this server is listening
-    socket: TcpListener,
-
-    // The token assigned to this server's own connection
-    token: Token,
+    // The transports on which this server accepts clients; each is registered as `listener_token(index)`
+    transports: Vec<Box<Transport>>,
 
     // Current state for the server
-    state: AuthoritativeServerState
+    state: AuthoritativeServerState,
+
+    config: ServerConfig,
+
+    // IP ranges and accounts which may not connect
+    bans: BanList,
+
+    // Per-IP connection rate limiting
+    throttle: ConnectionThrottle,
+
+    // Counters and gauges served by the metrics endpoint
+    metrics: Arc<Mutex<Metrics>>,
+
+    // What each kind of zone is built from, by name
+    templates: HashMap<String, ZoneTemplate>,
+
+    // Simulates zones on worker threads, so game logic doesn't hold up network I/O
+    scheduler: ZoneScheduler
+}
+
+/// Microseconds from @start until @time
+fn micros_since(start: Instant, time: Instant) -> u64{
+    let elapsed = time.duration_since(start);
+    elapsed.as_secs() * 1000000 + elapsed.subsec_nanos() as u64 / 1000
+}
+
+/// The event loop token of the transport at @index
+fn listener_token(index: usize) -> Token{
+    Token(LISTENER_TOKEN_BASE - index)
 }
 
 impl AuthoritativeServer{
-    pub fn new(address: SocketAddr) -> AuthoritativeServer{
-        let server_state = AuthoritativeServerState::new();
-        let server_state_clone = server_state.clone();
+    pub fn new(config: ServerConfig) -> AuthoritativeServer{
+        let mut server_state = AuthoritativeServerState::new();
 
         info!("Starting authoritative server");
-        let server_socket = TcpListener::bind(&address).expect("Failed to start socket listener!");
+        let mut transports: Vec<Box<Transport>> = Vec::new();
+        match (&config.tls_certificate, &config.tls_key){
+            (&Some(ref certificate_path), &Some(ref key_path)) => {
+                info!("Accepting TLS clients on {}", config.address);
+                let tls_config = tls::server_config(certificate_path, key_path).expect("Failed to load TLS certificate!");
+                transports.push(Box::new(TlsTransport::bind(&config.address, tls_config).expect("Failed to start socket listener!")));
+            },
+            _ => {
+                transports.push(Box::new(TcpTransport::bind(&config.address).expect("Failed to start socket listener!")));
+            }
+        }
+
+        if let Some(address) = config.websocket_address{
+            info!("Accepting WebSocket clients on {}", address);
+            transports.push(Box::new(WebSocketTransport::bind(&address).expect("Failed to start WebSocket listener!")));
+        }
+
+        if let Some(address) = config.udp_address{
+            info!("Accepting UDP clients on {}", address);
+            transports.push(Box::new(UdpTransport::bind(&address).expect("Failed to bind UDP socket!")));
+        }
+
+        let bans = BanList::load(&config.ban_list_path).expect("Failed to load ban list!");
+        let throttle = ConnectionThrottle::new(config.connection_rate_limit, config.connection_rate_window);
+
+        let metrics = Arc::new(Mutex::new(Metrics::new()));
+        if let Some(port) = config.metrics_port{
+            metrics::serve(metrics.clone(), port);
+        }
+
+        let mut templates = HashMap::new();
+        templates.insert(String::from(DEFAULT_ZONE), ZoneTemplate::default_zone(&config));
+        if let Some(ref path) = config.zone_path{
+            for template in ZoneTemplate::load_all(path).expect("Failed to load zone data file!"){
+                templates.insert(template.name.clone(), template);
+            }
+        }
+
+        let world = Zone::new(DEFAULT_ZONE, &templates[DEFAULT_ZONE], &config, server_state.entity_ids.clone()).expect("Failed to load the default zone!");
+        let scheduler = ZoneScheduler::new(config.simulation_threads);
 
         let mut server = AuthoritativeServer{
-            socket: server_socket,
-            token: SERVER_TOKEN,
-            state: server_state_clone
+            transports: transports,
+            state: server_state,
+            config: config,
+            bans: bans,
+            throttle: throttle,
+            metrics: metrics,
+            templates: templates,
+            scheduler: scheduler
         };
+        server.add_zone(world);
+        return server;
+    }
 
-        let mut event_loop = EventLoop::new().expect("Failed to create server event loop!");
+    /// Also accept clients from @transport. Must be called before `run`.
+    pub fn listen(&mut self, transport: Box<Transport>){
+        self.transports.push(transport);
+    }
 
-        event_loop.register(&server.socket,
-                            SERVER_TOKEN,
-                            EventSet::readable(),
-                            PollOpt::edge()).expect("Failed to register server with event loop!");
+    /// Register the listeners with @event_loop and process events forever
+    pub fn run(&mut self, event_loop: &mut EventLoop<AuthoritativeServer>){
+        for (index, transport) in self.transports.iter().enumerate(){
+            if let Some(listener) = transport.evented(){
+                event_loop.register(listener,
+                                    listener_token(index),
+                                    EventSet::readable(),
+                                    PollOpt::edge()).expect("Failed to register server with event loop!");
+            }
+        }
 
-        info!("Running event loop...");
+        debug!("Running event loop");
 
         loop{
-            let timeout = event_loop.timeout_ms(123, 300).unwrap();
-            event_loop.run_once(&mut server, None);
+            let timeout = event_loop.timeout_ms(123, SIMULATION_STEP_MS).unwrap();
+            event_loop.run_once(self, None).ok();
             let _ = event_loop.clear_timeout(timeout);
         }
+    }
 
+    /// The index of the transport registered as @token, if it belongs to one
+    fn transport_index(&self, token: Token) -> Option<usize>{
+        if token.as_usize() > LISTENER_TOKEN_BASE{
+            return None;
+        }
 
-        return server;
+        let index = LISTENER_TOKEN_BASE - token.as_usize();
+        match index < self.transports.len(){
+            true  => Some(index),
+            false => None
+        }
     }
 
-    fn start_accept_loop(&mut self, event_loop: &mut EventLoop<AuthoritativeServer>){
-        info!("Beginning server accept loop");
+    /// Accept every pending connection on the transport at @index
+    fn start_accept_loop(&mut self, event_loop: &mut EventLoop<AuthoritativeServer>, index: usize){
+        trace!("Beginning server accept loop");
 
         loop{
-            let socket = match self.socket.accept(){
-                Ok(s) => {
-                    match s{
-                        Some((socket,_)) => socket,
-                        None => {
-                            info!("Accept loop encountered WouldBlock");
-                            return;
-                        }
-                    }
+            let (connection, address) = match self.transports[index].accept(){
+                Ok(Some(accepted)) => accepted,
+                Ok(None) => {
+                    trace!("Accept loop encountered WouldBlock");
+                    return;
                 },
                 Err(e) => {
-                    info!("Failed to accept new socket. Error: {:?}", e);
+                    error!("Failed to accept new socket: {:?}", e);
                     return;
                 }
             };
 
+            let _context = logging::enter(LogContext{ peer: Some(address), ..LogContext::new() });
+
+            if let Some((reason, message)) = self.check_new_connection(&address){
+                self.reject_connection(connection, &address, reason, message);
+                continue;
+            }
+
             // If a client is successfully registered, this will be set to that client's token.
             let mut registered_token : Option<Token> = None;
 
             if let Ok(ref mut clients) = self.state.clients.write(){
+                if !clients.has_remaining(){
+                    self.reject_connection(connection, &address, DisconnectReason::ServerFull, String::from("The server is full"));
+                    continue;
+                }
+
+                let max_payload_length = self.config.max_payload_length;
+                let checksums = self.config.frame_checksums;
                 match &clients.insert_with(|token| {
-                    info!("Inserting new connection from {:?}", token);
-                    GameClient::new(socket, token)
+                    let mut client = GameClient::new(connection, token, address);
+                    client.set_max_payload_length(max_payload_length);
+                    client.checksums = checksums;
+                    client
                 }) {
                     &Some(token) => {
-                        info!("Insertion successful!");
                         let ref mut client: GameClient = clients[token];
+                        let _context = logging::enter(client.log_context());
+                        info!("Accepted connection");
                         match client.register(event_loop){
                             Ok(_) => {
                                 registered_token = Some(token);
+                                let connected_clients = clients.count();
+                                self.record(|metrics| metrics.connected_clients = connected_clients);
                             },
                             Err(e) => {
-                                info!("Failed to register connection {:?} with event loop, error: {:?}", token, e);
+                                error!("Failed to register connection {:?} with event loop: {:?}", token, e);
                                 //clients.remove(token);
                             }
                         }
                     },
                     &None => {
-                        info!("Failed to insert!");
+                        error!("Failed to insert new connection into the client slab");
                     }
                 }
             };
@@ -156,6 +334,187 @@ impl AuthoritativeServer{
         }
     }
 
+    /// Decide whether a connection from @address may be accepted.
+    /// Returns the reason to give the client if it should be turned away.
+    fn check_new_connection(&mut self, address: &SocketAddr) -> Option<(DisconnectReason, String)>{
+        if let Some(ban) = self.bans.find_ip(&address.ip()){
+            return Some((DisconnectReason::Banned, ban.describe()));
+        }
+
+        if !self.throttle.allow(address.ip()){
+            return Some((DisconnectReason::RateLimited, String::from("Too many connection attempts, try again later")));
+        }
+
+        return None;
+    }
+
+    /// Tell a freshly accepted connection why it is being turned away, then close it.
+    /// A WebSocket client which hasn't completed its handshake is closed without being told.
+    fn reject_connection(&self, mut connection: Box<Connection>, address: &SocketAddr, reason: DisconnectReason, message: String){
+        info!("Rejecting connection from {}: {:?}, {}", address, reason, message);
+        self.record(|metrics| metrics.disconnect(&format!("{:?}", reason)));
+
+        let output_bytes = Message::new_disconnect_message(reason, message).to_frame().to_bytes();
+        connection.send(output_bytes, Delivery::ReliableOrdered).ok();
+        connection.shutdown();
+    }
+
+    /// Send the client a Disconnect message, and close the connection once it has been written.
+    /// Anything else still queued for the client is dropped.
+    fn disconnect_client(&mut self, token: Token, reason: DisconnectReason, message: String){
+        info!("Disconnecting: {:?}, {}", reason, message);
+        self.record(|metrics| metrics.disconnect(&format!("{:?}", reason)));
+
+        self.get_client_mut(token, |client|{
+            client.clear_queues();
+            client.queue(Message::new_disconnect_message(reason, message.clone()));
+            client.closing = true;
+        }).ok();
+    }
+
+    /// Close the connection given by @token and remove its player from the game state
+    fn remove_client(&mut self, token: Token){
+        if let Ok(mut clients) = self.state.clients.write(){
+            if let Some(mut client) = clients.remove(token){
+                client.shutdown();
+            }
+            let connected_clients = clients.count();
+            if let Ok(mut metrics) = self.metrics.lock(){
+                metrics.connected_clients = connected_clients;
+                metrics.client_removed(token.as_usize());
+            }
+        }
+
+        // The zone drops the player when it next takes in its inbox, and is torn down when it comes back empty
+        let id = token.as_usize() as u32;
+        self.send_to_zone(id, ZoneInput::Leave(id));
+        self.state.player_zones.remove(&id);
+    }
+
+    /// Fields attached to log events about the connection given by @token
+    fn log_context(&self, token: Token) -> LogContext{
+        self.get_client(token, |client| client.log_context())
+            .unwrap_or(LogContext::for_connection(token.as_usize(), None, None))
+    }
+
+    /// Update the server metrics
+    fn record<F>(&self, action: F) where F: FnOnce(&mut Metrics){
+        if let Ok(mut metrics) = self.metrics.lock(){
+            action(&mut metrics);
+        }
+    }
+
+    /// Record the messages written to a client in the server metrics
+    fn record_written(&self, written: Vec<WrittenMessage>){
+        self.record(|metrics|{
+            for message in written{
+                if let Some((uncompressed_length, compressed_length)) = message.compression{
+                    metrics.compressed(message.code.clone(), uncompressed_length, compressed_length);
+                }
+                if message.contents.is_empty(){
+                    metrics.message_out(message.code, message.length);
+                }
+                else{
+                    metrics.batch_out(&message.contents, message.length);
+                }
+            }
+        });
+    }
+
+    /// Called when a client lists the capabilities it supports.
+    /// Replies with those the server shares, and enables them for the client.
+    fn on_client_hello(&mut self, token: Token, capabilities: u8){
+        let mut shared_capabilities = capabilities & SUPPORTED_CAPABILITIES;
+        if self.config.movement_mode != MovementMode::Input{
+            shared_capabilities &= !CAPABILITY_INPUT_COMMANDS;
+        }
+        else if shared_capabilities & CAPABILITY_INPUT_COMMANDS == 0{
+            warn!("Client can't send input commands, so can't move");
+        }
+        let compression_threshold = self.config.compression_threshold;
+
+        debug!("Negotiated capabilities {:#04x}", shared_capabilities);
+        self.get_client_mut(token, |client|{
+            if shared_capabilities & CAPABILITY_COMPRESSION != 0{
+                client.compression_threshold = Some(compression_threshold);
+            }
+            client.queue(Message::new_hello_message(shared_capabilities));
+        }).ok();
+    }
+
+    /// Answer a clock sync ping from the client at @token with the server's clock and the tick of its zone
+    fn on_clock_sync(&mut self, token: Token, mut sample: ClockSample){
+        let started = self.state.started;
+        let received = micros_since(started, Instant::now());
+        if let Some(zone) = self.state.player_zones.get(&(token.as_usize() as u32)).and_then(|name| self.state.zone_handles.get(name)){
+            sample.tick = zone.tick;
+            sample.tick_time = micros_since(started, zone.last_step);
+        }
+        sample.step = (SIMULATION_STEP_MS * 1000) as u32;
+        sample.answer(received, micros_since(started, Instant::now()));
+        self.send_message_to_client(token, Message::Ping(Some(sample)));
+    }
+
+    /// Called when the client at @token answers the ping measuring the round trip to it
+    fn on_round_trip(&mut self, token: Token, sample: ClockSample){
+        let received = micros_since(self.state.started, Instant::now());
+        self.get_client_mut(token, |client|{
+            client.add_round_trip(sample, received);
+            trace!("Latency is {:?}", client.latency());
+        }).ok();
+    }
+
+    /// Called when a client sends an input command, which is queued for the next simulation step
+    fn on_client_input(&mut self, token: Token, command: InputCommand){
+        if self.config.movement_mode != MovementMode::Input{
+            warn!("Rejected input command, players are moved by client updates");
+            return;
+        }
+
+        let id = token.as_usize() as u32;
+        let latency = self.latency_of(token);
+        self.send_to_zone(id, ZoneInput::Input(id, command, latency));
+    }
+
+    /// Called when a client identifies itself with an account
+    fn on_client_login(&mut self, token: Token, account_id: u32){
+        let ban_description = self.bans.find_account(account_id).map(|ban| ban.describe());
+        if let Some(description) = ban_description{
+            self.disconnect_client(token, DisconnectReason::Banned, description);
+            return;
+        }
+
+        info!("Logged in as account {}", account_id);
+        self.get_client_mut(token, |client|{
+            client.account_id = Some(account_id);
+        }).ok();
+    }
+
+    /// Add a ban, persist it, and disconnect anyone it applies to
+    fn ban(&mut self, entry: BanEntry){
+        info!("Banning {}", entry.target);
+
+        let mut banned_tokens = Vec::new();
+        if let Ok(clients) = self.state.clients.read(){
+            for client in clients.iter(){
+                let account_banned = client.account_id.map(|id| entry.target.matches_account(id)).unwrap_or(false);
+                if account_banned || entry.target.matches_ip(&client.peer.ip()){
+                    banned_tokens.push(client.token);
+                }
+            }
+        }
+
+        let description = entry.describe();
+        self.bans.add(entry);
+        if let Err(e) = self.bans.save(){
+            error!("Failed to save ban list: {:?}", e);
+        }
+
+        for token in banned_tokens{
+            self.disconnect_client(token, DisconnectReason::Banned, description.clone());
+        }
+    }
+
     fn get_client_mut<'a, F, R>(&'a mut self, token: Token, mut action: F) -> Result<R, String>
         where F: FnMut(&mut GameClient) -> R {
         if let Ok(mut clients) = self.state.clients.write(){
@@ -184,194 +543,1113 @@ impl AuthoritativeServer{
         Err(String::from(format!("No client exists with token {:?}", token)))
     }
 
+    /// Act on a message received from the client given by @token
+    fn handle_message(&mut self, token: Token, message: Message){
+        match message{
+            Message::Text{ message: _} => {
+                debug!("Received text message");
+
+                // Chat is heard by everyone in the same zone
+                if !self.send_to_zone(token.as_usize() as u32, ZoneInput::Broadcast(message)){
+                    warn!("Dropped text message from a client in no zone");
+                }
+            },
+
+            Message::Ping(None) => { },
+            Message::Ping(Some(sample)) => {
+                if sample.is_answered(){
+                    self.on_round_trip(token, sample);
+                }
+                else{
+                    self.on_clock_sync(token, sample);
+                }
+            },
+
+            Message::ClientUpdate(client_state) => {
+                if client_state.id as usize != token.as_usize(){
+                    warn!("Rejected client update for another entity, claimed ID {}", client_state.id);
+                }
+                else{
+                    trace!("Received client update {:?}", client_state);
+                    match self.config.movement_mode{
+                        MovementMode::Client => {
+                            self.send_to_zone(client_state.id, ZoneInput::Move(client_state));
+                        },
+                        MovementMode::Input => { warn!("Rejected client update, players are moved by input commands"); }
+                    }
+                }
+            },
+            Message::GameStateUpdate(_) => {
+                warn!("Received a game state update from a client");
+            },
+            Message::Login{ account_id } => {
+                self.on_client_login(token, account_id);
+            },
+            Message::Disconnect{ reason: _, message: _ } => {
+                warn!("Received a disconnect message from a client");
+            },
+            Message::Hello{ capabilities } => {
+                self.on_client_hello(token, capabilities);
+            },
+            Message::Batch(messages) => {
+                for message in messages{
+                    self.handle_message(token, message);
+                }
+            },
+            Message::Fragment{ message_id, index, count, data } => {
+                self.on_client_fragment(token, message_id, index, count, data);
+            },
+            Message::EntityUpdate(_) => {
+                warn!("Received an entity update from a client");
+            },
+            Message::Input(command) => {
+                self.on_client_input(token, command);
+            }
+        }
+    }
+
+    /// Called when a client sends part of a large message.
+    /// Once every part has arrived, the message is handled like any other.
+    fn on_client_fragment(&mut self, token: Token, message_id: u32, index: u16, count: u16, data: Vec<u8>){
+        let now = Instant::now();
+        let mut data = Some(data);
+        let reassembled = self.get_client_mut(token, |client|{
+            client.reassembler.insert(message_id, index, count, data.take().unwrap_or(Vec::new()), now)
+        });
+
+        match reassembled{
+            Ok(Ok(Some(message))) => {
+                trace!("Reassembled message {}", message_id);
+                self.handle_message(token, message);
+            },
+            Ok(Ok(None)) => {},
+            Ok(Err(e)) => {
+                warn!("Closing connection after bad fragment: {}", e);
+                self.record(|metrics| metrics.decode_errors += 1);
+                self.disconnect_client(token, DisconnectReason::ProtocolError, e.to_string());
+            },
+            Err(e) => { error!("{}", e); }
+        }
+    }
+
+    /// Act on everything read from the client given by @token.
+    /// A client sending a malformed frame is disconnected, as the rest of its input can't be trusted.
+    /// Returns FALSE if the client has been removed.
+    fn on_client_read(&mut self, token: Token, messages: Vec<ProtocolResult<(Message, usize)>>) -> bool{
+        // Messages from a client which is being closed are ignored, though a hangup still removes it
+        let closing = self.get_client(token, |client| client.closing).unwrap_or(false);
+
+        for message in messages{
+            match message{
+                Ok(_) if closing => {},
+                Ok((message, bytes_read)) => {
+                    self.record(|metrics| metrics.message_in(message.get_message_code(), bytes_read));
+                    self.handle_message(token, message);
+                },
+                Err(ProtocolError::Io(e)) => {
+                    match e.kind(){
+                        ErrorKind::WouldBlock => {},
+                        ErrorKind::UnexpectedEof => {
+                            info!("Connection closed by peer");
+                            self.record(|metrics| metrics.disconnect("Hangup"));
+                            self.remove_client(token);
+                            return false;
+                        },
+                        ErrorKind::TimedOut => {
+                            info!("Connection timed out");
+                            self.record(|metrics| metrics.disconnect("Timeout"));
+                            self.remove_client(token);
+                            return false;
+                        },
+                        ErrorKind::ConnectionAborted => {
+                            warn!("Closing connection after protocol error: {}", e);
+                            self.record(|metrics| metrics.disconnect("ProtocolError"));
+                            self.remove_client(token);
+                            return false;
+                        },
+                        _ => {
+                            warn!("Failed to read message: {:?}", e);
+                        }
+                    }
+                },
+                Err(_) if closing => {},
+                Err(e) => {
+                    warn!("Closing connection after bad frame: {}", e);
+                    self.record(|metrics| metrics.decode_errors += 1);
+                    self.disconnect_client(token, DisconnectReason::ProtocolError, e.to_string());
+                    return true;
+                }
+            }
+        }
+        return true;
+    }
+
     /// Called when a new client connects and has been registered with the event loop
     fn on_new_client_registered(&mut self, token: Token){
-        info!("Registration successful!");
         self.construct_state_for_new_client(token);
     }
 
     /// Return TRUE if there are messages bound toward a client given by @token
     fn has_messages_for_client(&self, client: &GameClient) -> bool{
         // If there are any messages in messages bound for all clients, then we're good.
-        !client.send_queue.is_empty()
+        !client.send_queue.is_empty() || client.has_pending_output()
     }
 
-    /// Update the Game State with the given Client State
-    fn update_client_in_game_state(&mut self, client_state: &ClientState){
-        self.state.game_state.clients.insert(client_state.id, *client_state);
-        self.state.game_state_updated = true;
+    /// Send @input to the zone the player @id is in, which applies it in the order it was sent.
+    /// Returns FALSE if the player isn't in any zone.
+    fn send_to_zone(&self, id: u32, input: ZoneInput) -> bool{
+        match self.state.player_zones.get(&id).and_then(|name| self.state.zone_handles.get(name)){
+            Some(zone) => zone.inbox.send(input).is_ok(),
+            None => false
+        }
     }
 
-    fn construct_state_for_new_client(&mut self, token: Token){
-        let state = ClientState::new(token.as_usize() as u32);
-        self.update_client_in_game_state(&state);
-        self.send_message_to_client(token, Message::new_client_update_message(&state));
+    /// Put @zone among the zones running
+    fn add_zone(&mut self, zone: Zone){
+        self.state.zone_handles.insert(zone.name.clone(), ZoneHandle{
+            inbox: zone.inbox(),
+            tick: zone.tick(),
+            last_step: zone.last_step()
+        });
+        self.state.zones.insert(zone.name.clone(), zone);
     }
 
-    fn send_message_to_client(&mut self, token: Token, message: Message){
-        let destination = Destination::Client(token.clone());
+    /// Take back @zones, which have finished their simulation steps.
+    /// Each takes in what was sent to it meanwhile, and those started on demand are torn down once empty.
+    fn return_zones(&mut self, zones: Vec<Simulated>){
+        for zone in zones{
+            match zone{
+                Simulated::Done(mut zone) => {
+                    zone.receive_inputs();
 
-        if self.state.message_queue.contains_key(&destination){
-            if let Some(mailbox) = self.state.message_queue.get_mut(&destination){
-                mailbox.push(message);
+                    // A player who disconnected while being transferred arrives after it was told to leave
+                    let strays: Vec<u32> = zone.players().iter().cloned().filter(|id| self.state.player_zones.get(id) != Some(&zone.name)).collect();
+                    for id in strays{
+                        zone.leave(id);
+                    }
+
+                    if self.is_abandoned(&zone){
+                        info!("Tearing down empty zone `{}`", zone.name);
+                        self.state.zone_handles.remove(&zone.name);
+                        self.state.on_demand_zones.remove(&zone.name);
+                        continue;
+                    }
+                    if let Some(handle) = self.state.zone_handles.get_mut(&zone.name){
+                        handle.tick = zone.tick();
+                        handle.last_step = zone.last_step();
+                    }
+                    self.state.zones.insert(zone.name.clone(), zone);
+                },
+                Simulated::Lost(name) => { self.lose_zone(&name); }
             }
         }
+    }
+
+    /// Forget the zone @name, which panicked while it was being simulated, and disconnect everyone in it.
+    /// The default zone is started again so new players have somewhere to go.
+    fn lose_zone(&mut self, name: &str){
+        error!("Lost zone `{}`", name);
+        self.state.zone_handles.remove(name);
+        self.state.on_demand_zones.remove(name);
+
+        let players: Vec<u32> = self.state.player_zones.iter().filter(|&(_, zone)| zone == name).map(|(id, _)| *id).collect();
+        for id in players{
+            self.state.player_zones.remove(&id);
+            self.disconnect_client(Token(id as usize), DisconnectReason::Unknown, format!("Zone `{}` failed", name));
+        }
+
+        if name == DEFAULT_ZONE{
+            match Zone::new(DEFAULT_ZONE, &self.templates[DEFAULT_ZONE], &self.config, self.state.entity_ids.clone()){
+                Ok(zone) => { self.add_zone(zone); },
+                Err(e) => { error!("Failed to restart the default zone: {}", e); }
+            }
+        }
+    }
+
+    /// The name of the zone the player @id is in
+    pub fn zone_of(&self, id: u32) -> Option<&str>{
+        self.state.player_zones.get(&id).map(|name| name.as_str())
+    }
+
+    /// Start the zone @name, built from the template named @template
+    fn start_zone(&mut self, name: &str, template: &str) -> Result<(), String>{
+        let template = try!(self.templates.get(template).cloned().ok_or(format!("No zone template named `{}`", template)));
+        let zone = try!(Zone::new(name, &template, &self.config, self.state.entity_ids.clone()).map_err(|e| format!("Failed to load zone `{}`: {}", name, e)));
+        self.add_zone(zone);
+        self.state.on_demand_zones.insert(String::from(name));
+        Ok(())
+    }
+
+    /// Start a new instance of the zone template @template, returning its name.
+    /// The instance is torn down once the last player to enter it leaves.
+    pub fn create_instance(&mut self, template: &str) -> Result<String, String>{
+        let name = format!("{}#{}", template, self.state.next_instance);
+        try!(self.start_zone(&name, template));
+        self.state.next_instance += 1;
+        return Ok(name);
+    }
+
+    /// Move the player @id to the zone @zone, starting it from its template if it isn't running.
+    /// Naming an instanced template sends the player to a new instance of it.
+    /// Returns the name of the zone the player is now in.
+    pub fn transfer_player(&mut self, id: u32, zone: &str) -> Result<String, String>{
+        let from = try!(self.state.player_zones.get(&id).cloned().ok_or(format!("Player {} isn't in any zone", id)));
+        let to = if self.state.zone_handles.contains_key(zone){
+            String::from(zone)
+        }
+        else if self.templates.get(zone).map_or(false, |template| template.instanced){
+            try!(self.create_instance(zone))
+        }
         else{
-            self.state.message_queue.insert(destination, vec![message]);
+            try!(self.start_zone(zone, zone));
+            String::from(zone)
+        };
+        if to == from{
+            return Ok(to);
         }
+
+        // The zone the player leaves hands its entity on to the one it's going to,
+        // without waiting for either if they're being simulated
+        let destination = try!(self.state.zone_handles.get(&to).map(|zone| zone.inbox.clone()).ok_or(format!("Zone `{}` has gone", to)));
+        let transfer = ZoneInput::Transfer{ id: id, to: destination };
+        if !self.send_to_zone(id, transfer){
+            return Err(format!("Zone `{}` has gone", from));
+        }
+        self.state.player_zones.insert(id, to.clone());
+        info!("Transferring player {} from zone `{}` to `{}`", id, from, to);
+        return Ok(to);
+    }
+
+    /// Return TRUE if @zone was started on demand and everyone has left it, including those on their way in
+    fn is_abandoned(&self, zone: &Zone) -> bool{
+        // Instances are always started on demand
+        let on_demand = self.state.on_demand_zones.contains(&zone.name) && zone.name != DEFAULT_ZONE;
+        return on_demand && zone.is_empty() && !self.state.player_zones.values().any(|name| *name == zone.name);
     }
 
-    fn broadcast(&mut self, message: Message){
-        let mut message_queue = &mut self.state.message_queue;
-        if message_queue.contains_key(&Destination::Broadcast){
-            let broadcast_queue = message_queue.get_mut(&Destination::Broadcast);
-            if let Some(broadcast_queue) = broadcast_queue{
-                broadcast_queue.push(message);
+    /// Add an entity of @kind at @position to the default zone, returning its ID.
+    /// The entity appears once the zone takes in its inbox, without waiting on the zone if it's being simulated.
+    pub fn spawn_entity(&mut self, kind: EntityKind, owner: Option<u32>, position: Position, components: Vec<Component>) -> u32{
+        let id = self.state.entity_ids.fetch_add(1, Ordering::SeqCst) as u32;
+        let input = ZoneInput::Spawn{ id: id, kind: kind, owner: owner, position: position, components: components };
+        match self.state.zone_handles.get(DEFAULT_ZONE){
+            Some(zone) => { zone.inbox.send(input).ok(); },
+            None => { error!("The default zone is missing, can't spawn entity {}", id); }
+        }
+        return id;
+    }
+
+    /// Remove the non-player entity @id from whichever zone it's in.
+    /// Every zone is told, and those without it ignore it; players are removed by disconnecting them.
+    pub fn despawn_entity(&mut self, id: u32){
+        for zone in self.state.zone_handles.values(){
+            zone.inbox.send(ZoneInput::Despawn(id)).ok();
+        }
+    }
+
+    /// Take back the zones which have finished their simulation steps, without waiting for the others,
+    /// and queue what the zones have to tell single players
+    fn collect_zones(&mut self){
+        let zones = self.scheduler.finished();
+        self.return_zones(zones);
+
+        let mut outgoing = Vec::new();
+        for zone in self.state.zones.values_mut(){
+            outgoing.extend(zone.take_outbox());
+        }
+        for (id, message) in outgoing{
+            self.send_message_to_client(Token(id as usize), message);
+        }
+    }
+
+    /// Hand every zone with a simulation step due by @now to the worker threads.
+    /// Zones still being simulated from an earlier tick are left to catch up when they're back.
+    fn simulate(&mut self, now: Instant){
+        let due: Vec<String> = self.state.zones.values().filter(|zone| zone.step_due(now)).map(|zone| zone.name.clone()).collect();
+        for name in due{
+            if let Some(zone) = self.state.zones.remove(&name){
+                self.scheduler.dispatch(zone, now);
             }
-            else{
-                info!("Error: Failed to get mutable destination vec!");
+        }
+    }
+
+    /// The time messages take to reach the client at @token, zero until it has been measured
+    fn latency_of(&self, token: Token) -> Duration{
+        let latency = match self.state.clients.read(){
+            Ok(clients) => clients.get(token).and_then(|client| client.latency()),
+            Err(_) => None
+        };
+        return latency.unwrap_or(Duration::from_millis(0));
+    }
+
+    /// Put the new player at @token in the default zone, which sends it where it spawned and what it can see.
+    /// This goes through the zone's inbox, so a new client doesn't wait on the zone if it's being simulated.
+    fn construct_state_for_new_client(&mut self, token: Token){
+        let id = token.as_usize() as u32;
+        self.state.player_zones.insert(id, String::from(DEFAULT_ZONE));
+        if !self.send_to_zone(id, ZoneInput::Enter{ id: id, entity: None, forgotten: Vec::new() }){
+            error!("The default zone is missing, player {} has nowhere to go", id);
+        }
+    }
+
+    fn send_message_to_client(&mut self, token: Token, message: Message){
+        let destination = Destination::Client(token.clone());
+
+        if self.state.message_queue.contains_key(&destination){
+            if let Some(mailbox) = self.state.message_queue.get_mut(&destination){
+                mailbox.push(message);
             }
         }
         else{
-            message_queue.insert(Destination::Broadcast, vec![message]);
+            self.state.message_queue.insert(destination, vec![message]);
         }
     }
 }
 
 impl Handler for AuthoritativeServer{
     type Timeout = u32;
-    type Message = ();
+    type Message = ServerCommand;
 
     fn tick(&mut self, event_loop: &mut EventLoop<AuthoritativeServer>) {
         //info!("Begin server tick!");
+        let tick_start = Instant::now();
+        let started = self.state.started;
 
-        if self.state.game_state_updated{
-            let game_state_message = Message::GameStateUpdate(self.state.game_state.clients.values().map(|client| *client).collect::<Vec<ClientState>>());
-            self.broadcast(game_state_message);
-            self.state.game_state_updated = false;
+        // Transports without a socket to wait on are checked for new clients every tick
+        for index in 0..self.transports.len(){
+            if self.transports[index].evented().is_none(){
+                self.start_accept_loop(event_loop, index);
+            }
         }
 
+        self.collect_zones();
+
+        let mut closed_tokens = Vec::new();
+        let mut slow_tokens = Vec::new();
+        let mut polled_reads = Vec::new();
         if let Ok(mut clients) = self.state.clients.write(){
             for client in clients.iter_mut(){
+                let _context = logging::enter(client.log_context());
+                client.reassembler.expire(tick_start);
+                client.probe_round_trip(tick_start, micros_since(started, tick_start));
+
+                let mut dropped = 0;
+
                 // Add any messages to the client which are destined specifically to this client.
                 if let Some(mailbox) = self.state.message_queue.get_mut(&Destination::Client(client.token.clone())){
-                    while let Some(message) = mailbox.pop(){
-                        info!("Added message {:?}", message);
-                        client.send_queue.push_back(message);
+                    for message in mailbox.drain(..){
+                        trace!("Queued {:?}", message);
+                        if !client.queue(message){
+                            dropped += 1;
+                        }
                     }
                 }
 
-                // Add any 'Broadcast' messages that exist to the client's send queue.
-                if let Some(mailbox) = self.state.message_queue.get(&Destination::Broadcast){
-                    for broadcast_message in mailbox{
-                        info!("Added message {:?}", broadcast_message);
-                        client.send_queue.push_back(broadcast_message.clone());
+                // Add the messages for everyone in the client's zone,
+                // and whatever changed there this tick that the client is allowed to see
+                let id = client.token.as_usize() as u32;
+                if let Some(zone) = self.state.player_zones.get(&id).and_then(|name| self.state.zones.get(name)){
+                    for broadcast_message in zone.broadcasts(){
+                        if !client.queue(broadcast_message.clone()){
+                            dropped += 1;
+                        }
+                    }
+
+                    let deltas = zone.deltas_for(id);
+                    if !deltas.is_empty(){
+                        client.queue(Message::EntityUpdate(deltas));
                     }
                 }
 
-                // Reregister the client with the event loop,
-                // so we continue to receive events for this client
-                let client_register_writable = self.has_messages_for_client(client);
-                client.reregister(event_loop, client_register_writable).ok();
+                if dropped > 0{
+                    debug!("Send queue is behind, dropped {} bulk messages", dropped);
+                    self.record(|metrics| metrics.dropped_messages += dropped);
+                }
+
+                if !client.closing && client.is_too_far_behind(){
+                    slow_tokens.push((client.token, client.backlog()));
+                }
+
+                if client.is_polled(){
+                    // Clients with no socket to wait on are written and read every tick
+                    match client.write(){
+                        Ok(written) => { self.record_written(written); },
+                        Err(e) => { warn!("Failed to write: {:?}", e); }
+                    }
+
+                    if client.is_ready_to_close(){
+                        closed_tokens.push(client.token);
+                    }
+                    else{
+                        polled_reads.push((client.token, client.read()));
+                    }
+                }
+                else{
+                    // Reregister the client with the event loop,
+                    // so we continue to receive events for this client
+                    let client_register_writable = self.has_messages_for_client(client);
+                    client.reregister(event_loop, client_register_writable).ok();
+                }
+
+                let queue_depth = client.backlog();
+                self.record(|metrics| metrics.send_queue_depth(client.token.as_usize(), queue_depth));
             }
+            for zone in self.state.zones.values_mut(){
+                zone.end_tick();
+            }
+        }
+
+        for (token, backlog) in slow_tokens{
+            let _context = logging::enter(self.log_context(token));
+            self.disconnect_client(token, DisconnectReason::TooSlow, format!("{} messages waiting to be sent", backlog));
         }
 
-        // Clear out the broadcast queue
-        // Warning: This assumes no failures to send
-        let ref mut message_queue = self.state.message_queue;
-        if let Some(broadcast_queue) = message_queue.get_mut(&Destination::Broadcast){
-            broadcast_queue.clear();
+        for token in closed_tokens{
+            self.remove_client(token);
         }
 
+        for (token, messages) in polled_reads{
+            let _context = logging::enter(self.log_context(token));
+            self.on_client_read(token, messages);
+        }
+
+        // Zones are simulated while the event loop gets on with I/O, and their changes go out next tick
+        self.simulate(tick_start);
+
+        self.record(|metrics| metrics.tick_duration(tick_start.elapsed()));
         //info!("End server tick!");
     }
 
     fn ready(&mut self, event_loop: &mut EventLoop<AuthoritativeServer>, token: Token, events: EventSet) {
         assert!(token != Token(0), "We're not supposed to get a Token(0)!");
 
-        info!("Ready for {:?}", token);
+        let transport_index = self.transport_index(token);
+
+        let _context = match transport_index{
+            Some(_) => None,
+            None => Some(logging::enter(self.log_context(token)))
+        };
+        trace!("Ready for {:?}", events);
 
         if events.is_error(){
-            info!("Error event for token {:?}", token);
+            warn!("Error event on connection");
             //Reset token?
             return;
         }
 
         if events.is_hup(){
-            info!("OH FUCK NO, {:?} DID NOT JUST FUCKING HANG UP ON ME!", token);
-            info!("I'M GOING TO FUCKING MURDER YOU FUCKER");
+            info!("Connection hung up");
+            self.record(|metrics| metrics.disconnect("Hangup"));
 
-            if let Ok(mut clients) = self.state.clients.write(){
-                &clients.remove(token);
-                let _ = self.state.game_state.clients.remove(&(token.as_usize() as u32));
-                self.state.game_state_updated = true;
-            }
+            self.remove_client(token);
             //Reset?
             return;
         }
 
         if events.is_readable(){
-            info!("GOT SHIT TO READ FROM MY BRAH {:?} HELLLL YEAH", token);
 
-            if self.token == token{
-                self.start_accept_loop(event_loop);
+            if let Some(index) = transport_index{
+                self.start_accept_loop(event_loop, index);
             }
             else{
-                    let message = self.get_client_mut(token, |client|{
-                        return client.read();
-                    }).ok();
+                let messages = self.get_client_mut(token, |client|{
+                    return client.read();
+                }).unwrap_or(Vec::new());
 
-                    if let Some(Ok(message)) = message{
-                        match message{
-                            Message::Text{ message: _} => {
-                                info!("--> Received text message");
-                                let mut message_queue = &mut self.state.message_queue;
-                                if message_queue.contains_key(&Destination::Broadcast){
-                                    let broadcast_queue = message_queue.get_mut(&Destination::Broadcast);
-                                    if let Some(broadcast_queue) = broadcast_queue{
-                                        broadcast_queue.push(message);
-                                    }
-                                    else{
-                                        info!("Error: Failed to get mutable destination vec!");
-                                    }
-                                }
-                                else{
-                                    message_queue.insert(Destination::Broadcast, vec![message]);
-                                }
-                            },
+                if !self.on_client_read(token, messages){
+                    return;
+                }
+            }
+        }
 
-                            Message::Ping => {
-                                //self.state.message_queue.insert(Destination::Broadcast, message);
-                            },
+        if events.is_writable(){
+            let (written, ready_to_close) = self.get_client_mut(token, |client|{
+                (client.write(), client.is_ready_to_close())
+            }).unwrap_or((Ok(Vec::new()), false));
 
-                            Message::ClientUpdate(client_state) => {
-                                info!("Received client update: {:?}", client_state);
-                                if client_state.id as usize != token.as_usize(){
-                                    info!("Error: Imposter trying to send client update! Claimed ID: {}, Token ID: {}", client_state.id, token.as_usize());
-                                }
-                                else{
-                                    info!("Received client update packet! {:?}", client_state);
-                                    self.update_client_in_game_state(&client_state);
-                                }
-                            },
-                            Message::GameStateUpdate(_) => {
-                                info!("Error: Received game state update from a client! ");
-                            }
-                        };
+            if let Ok(written) = written{
+                self.record_written(written);
+            }
+
+            if ready_to_close{
+                self.remove_client(token);
+            }
+        }
+    }
+
+    fn notify(&mut self, _: &mut EventLoop<AuthoritativeServer>, command: ServerCommand){
+        match command{
+            ServerCommand::Ban(entry) => {
+                self.ban(entry);
+            },
+            ServerCommand::Unban(target) => {
+                if self.bans.remove(&target){
+                    info!("Unbanned {}", target);
+                    if let Err(e) = self.bans.save(){
+                        error!("Failed to save ban list: {:?}", e);
                     }
-                    else{
-                        info!("Error reading from client!");
+                }
+                else{
+                    warn!("No ban exists for {}", target);
+                }
+            },
+            ServerCommand::ReloadBans => {
+                match self.bans.reload(){
+                    Ok(_) => { info!("Reloaded {} bans from {}", self.bans.entries().len(), self.config.ban_list_path); },
+                    Err(e) => { error!("Failed to reload ban list: {:?}", e); }
+                }
+            },
+            ServerCommand::ListBans => {
+                for entry in self.bans.entries(){
+                    info!("{} -- {}", entry.target, entry.describe());
+                }
+            },
+            ServerCommand::Spawn{ kind, position, components } => {
+                self.spawn_entity(kind, None, position, components);
+            },
+            ServerCommand::Despawn(id) => {
+                self.despawn_entity(id);
+            },
+            ServerCommand::Transfer{ id, zone } => {
+                if let Err(e) = self.transfer_player(id, &zone){
+                    warn!("Failed to transfer player {}: {}", id, e);
+                }
+            },
+            ServerCommand::ListZones => {
+                // Answered from what the event loop knows, so zones being simulated aren't waited on
+                for (name, zone) in self.state.zone_handles.iter(){
+                    let players = self.state.player_zones.values().filter(|player_zone| *player_zone == name).count();
+                    info!("{} -- {} players, tick {}", name, players, zone.tick);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use entity::EntityDelta;
+    use frame::MessageCode;
+    use math::Scalar;
+    use transport::{Connection, MemoryListener};
+    use fragment::{Fragmenter, Reassembler};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Poll @connection until a message matching @predicate arrives
+    fn wait_for<F>(connection: &mut Connection, predicate: F) -> Message where F: Fn(&Message) -> bool{
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline{
+            for received in connection.receive(){
+                let (message, _) = received.expect("Failed to read message");
+                for message in message.into_messages(){
+                    if predicate(&message){
+                        return message;
                     }
+                }
             }
+            thread::sleep(Duration::from_millis(10));
         }
+        panic!("Timed out waiting for a message");
+    }
 
-        if events.is_writable(){
-            info!("Oh shit, motherfucking {:?} is writable! Look at this guy!", token);
+    /// Have every zone take in what's been sent to it, as if it had just come back from being simulated,
+    /// then queue what the zones have to tell players
+    fn settle(server: &mut AuthoritativeServer){
+        // A player transferred twice passes through three zones' inboxes
+        for _ in 0..3{
+            let zones = server.state.zones.drain().map(|(_, zone)| Simulated::Done(zone)).collect();
+            server.return_zones(zones);
+        }
+        server.collect_zones();
+    }
+
+    /// Write @contents to a data file named after @name in the temp directory, returning its path.
+    /// The process ID keeps concurrent test runs from sharing files.
+    fn fixture(name: &str, contents: &str) -> String{
+        use std::io::Write;
+        let path = ::std::env::temp_dir().join(format!("lag-test-{}-{}.txt", ::std::process::id(), name));
+        ::std::fs::File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_loopback_clients(){
+        let mut config = ServerConfig::new();
+        config.address = "127.0.0.1:0".parse().unwrap();
+        config.ban_list_path = String::from("/nonexistent/lag-test-bans.txt");
+
+        let listener = MemoryListener::new();
+        let connector = listener.connector();
 
-            //fucking write some shit
-            self.get_client_mut(token, |client|{
-                client.write()
-            }).ok();
+        thread::spawn(move ||{
+            let mut event_loop = EventLoop::new().unwrap();
+            let mut server = AuthoritativeServer::new(config);
+            server.listen(Box::new(listener));
+            server.run(&mut event_loop);
+        });
+
+        let mut first = connector.connect().unwrap();
+        let mut second = connector.connect().unwrap();
+
+        // Each client is assigned its own entity
+        let first_id = match wait_for(&mut first, |message| message.get_message_code() == MessageCode::ClientUpdate){
+            Message::ClientUpdate(state) => state.id,
+            _ => unreachable!()
+        };
+        let second_id = match wait_for(&mut second, |message| message.get_message_code() == MessageCode::ClientUpdate){
+            Message::ClientUpdate(state) => state.id,
+            _ => unreachable!()
+        };
+        assert!(first_id != second_id);
+
+        // The server only agrees to the capabilities it supports, and only takes input commands when configured to
+        let hello = Message::new_hello_message(0xFF).to_frame().to_bytes();
+        first.send(hello, Delivery::ReliableOrdered).unwrap();
+        match wait_for(&mut first, |message| message.get_message_code() == MessageCode::Hello){
+            Message::Hello{ capabilities } => { assert_eq!(capabilities, SUPPORTED_CAPABILITIES & !CAPABILITY_INPUT_COMMANDS); },
+            _ => unreachable!()
+        }
+
+        // Chat is broadcast to everyone
+        let text = Message::new_text_message(String::from("Hello")).to_frame().to_bytes();
+        first.send(text, Delivery::ReliableOrdered).unwrap();
+        match wait_for(&mut second, |message| message.get_message_code() == MessageCode::Text){
+            Message::Text{ message } => { assert_eq!(message, "Hello"); },
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_oversized_frame_disconnects(){
+        let mut config = ServerConfig::new();
+        config.address = "127.0.0.1:0".parse().unwrap();
+        config.ban_list_path = String::from("/nonexistent/lag-test-bans.txt");
+        config.max_payload_length = 16;
+
+        let listener = MemoryListener::new();
+        let connector = listener.connector();
+
+        thread::spawn(move ||{
+            let mut event_loop = EventLoop::new().unwrap();
+            let mut server = AuthoritativeServer::new(config);
+            server.listen(Box::new(listener));
+            server.run(&mut event_loop);
+        });
+
+        let mut client = connector.connect().unwrap();
+        let text = Message::new_text_message(String::from("Far too long for this server")).to_frame().to_bytes();
+        client.send(text, Delivery::ReliableOrdered).unwrap();
+
+        match wait_for(&mut client, |message| message.get_message_code() == MessageCode::Disconnect){
+            Message::Disconnect{ reason, message: _ } => { assert_eq!(reason, DisconnectReason::ProtocolError); },
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_large_message_fragmented(){
+        let mut config = ServerConfig::new();
+        config.address = "127.0.0.1:0".parse().unwrap();
+        config.ban_list_path = String::from("/nonexistent/lag-test-bans.txt");
+
+        let listener = MemoryListener::new();
+        let connector = listener.connector();
+
+        thread::spawn(move ||{
+            let mut event_loop = EventLoop::new().unwrap();
+            let mut server = AuthoritativeServer::new(config);
+            server.listen(Box::new(listener));
+            server.run(&mut event_loop);
+        });
+
+        let mut sender = connector.connect().unwrap();
+        let mut receiver = connector.connect().unwrap();
+        wait_for(&mut receiver, |message| message.get_message_code() == MessageCode::ClientUpdate);
+
+        // A chat message too large for one frame is sent as fragments, and broadcast the same way
+        let text: String = (0..5000).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        let frame_bytes = Message::new_text_message(text.clone()).to_frame().to_bytes();
+        for fragment in Fragmenter::new().split(&frame_bytes){
+            sender.send(fragment.to_frame().to_bytes(), Delivery::ReliableOrdered).unwrap();
+        }
+
+        // Fragments are collected straight from the connection, as several arrive at once
+        let mut reassembler = Reassembler::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline{
+            for received in receiver.receive(){
+                if let (Message::Fragment{ message_id, index, count, data }, _) = received.expect("Failed to read message"){
+                    if let Some(message) = reassembler.insert(message_id, index, count, data, Instant::now()).unwrap(){
+                        match message{
+                            Message::Text{ message } => { assert_eq!(message, text); },
+                            other => { panic!("Expected the chat message, got {:?}", other); }
+                        }
+                        return;
+                    }
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Timed out waiting for the reassembled message");
+    }
+
+    #[test]
+    fn test_spawned_entities_replicated(){
+        let mut config = ServerConfig::new();
+        config.address = "127.0.0.1:0".parse().unwrap();
+        config.ban_list_path = String::from("/nonexistent/lag-test-bans.txt");
+
+        let listener = MemoryListener::new();
+        let connector = listener.connector();
+
+        let (sender_tx, sender_rx) = ::std::sync::mpsc::channel();
+        thread::spawn(move ||{
+            let mut event_loop = EventLoop::new().unwrap();
+            sender_tx.send(event_loop.channel()).unwrap();
+            let mut server = AuthoritativeServer::new(config);
+            server.listen(Box::new(listener));
+            server.run(&mut event_loop);
+        });
+        let commands = sender_rx.recv().unwrap();
+
+        let mut client = connector.connect().unwrap();
+        let player_id = match wait_for(&mut client, |message| message.get_message_code() == MessageCode::ClientUpdate){
+            Message::ClientUpdate(state) => state.id,
+            _ => unreachable!()
+        };
+
+        // A spawned NPC is replicated, without its server-only components
+        let _ = commands.send(ServerCommand::Spawn{
+            kind: EntityKind::Npc,
+            position: Position::new(5.0, 0.0, 5.0),
+            components: vec![Component::Health{ current: 10, maximum: 10 }, Component::Loot{ table_id: 3 }]
+        });
+        let is_npc = |delta: &EntityDelta| delta.meta.map_or(false, |(kind, _)| kind == EntityKind::Npc);
+        let npc_id = match wait_for(&mut client, |message| match *message{
+            Message::EntityUpdate(ref deltas) => deltas.iter().any(|delta| is_npc(delta)),
+            _ => false
+        }){
+            Message::EntityUpdate(deltas) => {
+                let npc = deltas.into_iter().find(|delta| is_npc(delta)).unwrap();
+                assert_eq!(npc.state.unwrap().position, Position::new(5.0, 0.0, 5.0));
+                assert_eq!(npc.components, vec![Component::Health{ current: 10, maximum: 10 }]);
+                npc.id
+            },
+            _ => unreachable!()
+        };
+        assert!(npc_id >= FIRST_NON_PLAYER_ENTITY_ID);
+
+        // Players can't be despawned, but the NPC can
+        let _ = commands.send(ServerCommand::Despawn(player_id));
+        let _ = commands.send(ServerCommand::Despawn(npc_id));
+        match wait_for(&mut client, |message| match *message{
+            Message::EntityUpdate(ref deltas) => deltas.iter().any(|delta| delta.despawned),
+            _ => false
+        }){
+            Message::EntityUpdate(deltas) => {
+                assert!(deltas.iter().any(|delta| delta.id == npc_id && delta.despawned));
+                assert!(!deltas.iter().any(|delta| delta.id == player_id && delta.despawned));
+            },
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_npcs_simulated(){
+        let npc_path = fixture("npcs", "[guard]\nposition = 0 0 5\nspeed = 4\nbehavior = patrol 10 0 5; 0 0 5\n");
+
+        let mut config = ServerConfig::new();
+        config.address = "127.0.0.1:0".parse().unwrap();
+        config.ban_list_path = String::from("/nonexistent/lag-test-bans.txt");
+        config.npc_path = Some(npc_path);
+
+        let listener = MemoryListener::new();
+        let connector = listener.connector();
+        thread::spawn(move ||{
+            let mut event_loop = EventLoop::new().unwrap();
+            let mut server = AuthoritativeServer::new(config);
+            server.listen(Box::new(listener));
+            server.run(&mut event_loop);
+        });
+
+        // The guard walks its patrol route, and its movement is replicated
+        let mut client = connector.connect().unwrap();
+        let moved = |delta: &EntityDelta| delta.id >= FIRST_NON_PLAYER_ENTITY_ID && delta.state.map_or(false, |state| state.position.0.to_f32() > 0.0);
+        match wait_for(&mut client, |message| match *message{
+            Message::EntityUpdate(ref deltas) => deltas.iter().any(|delta| moved(delta)),
+            _ => false
+        }){
+            Message::EntityUpdate(deltas) => {
+                let guard = deltas.into_iter().find(|delta| moved(delta)).unwrap().state.unwrap();
+                assert!(guard.velocity.0.to_f32() > 3.9);
+            },
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_npcs_navigate(){
+        let npc_path = fixture("navigating-npcs", "[guard]\nposition = 0.5 0 0.5\nspeed = 4\nbehavior = patrol 8.5 0 0.5\n");
+        let navigation_path = fixture("navigation", "..X.......\n..........\n");
+
+        let mut config = ServerConfig::new();
+        config.address = "127.0.0.1:0".parse().unwrap();
+        config.ban_list_path = String::from("/nonexistent/lag-test-bans.txt");
+        config.npc_path = Some(npc_path);
+        config.navigation_path = Some(navigation_path);
+
+        let listener = MemoryListener::new();
+        let connector = listener.connector();
+        thread::spawn(move ||{
+            let mut event_loop = EventLoop::new().unwrap();
+            let mut server = AuthoritativeServer::new(config);
+            server.listen(Box::new(listener));
+            server.run(&mut event_loop);
+        });
+
+        // The wall between the guard and its waypoint is walked around rather than through
+        let mut client = connector.connect().unwrap();
+        let moving = |delta: &EntityDelta| delta.id >= FIRST_NON_PLAYER_ENTITY_ID && delta.state.map_or(false, |state| state.velocity.length().to_f32() > 0.0);
+        match wait_for(&mut client, |message| match *message{
+            Message::EntityUpdate(ref deltas) => deltas.iter().any(|delta| moving(delta)),
+            _ => false
+        }){
+            Message::EntityUpdate(deltas) => {
+                let guard = deltas.into_iter().find(|delta| moving(delta)).unwrap().state.unwrap();
+                assert!(guard.velocity.2.to_f32() > 0.0, "Guard walked straight at the wall: {:?}", guard);
+            },
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_moves_collide(){
+        let collision_path = fixture("collision", "box 2 0 -5 3 3 5\n");
+
+        let mut config = ServerConfig::new();
+        config.address = "127.0.0.1:0".parse().unwrap();
+        config.ban_list_path = String::from("/nonexistent/lag-test-bans.txt");
+        config.collision_path = Some(collision_path);
+
+        let listener = MemoryListener::new();
+        let connector = listener.connector();
+        thread::spawn(move ||{
+            let mut event_loop = EventLoop::new().unwrap();
+            let mut server = AuthoritativeServer::new(config);
+            server.listen(Box::new(listener));
+            server.run(&mut event_loop);
+        });
+
+        let mut client = connector.connect().unwrap();
+        let mut state = match wait_for(&mut client, |message| message.get_message_code() == MessageCode::ClientUpdate){
+            Message::ClientUpdate(state) => state,
+            _ => unreachable!()
+        };
+
+        // Walking into the wall is corrected, and the client told where it ended up
+        state.position = Position::new(2.2, 0.0, 0.0);
+        client.send(Message::new_client_update_message(&state).to_frame().to_bytes(), Delivery::ReliableOrdered).unwrap();
+        match wait_for(&mut client, |message| message.get_message_code() == MessageCode::ClientUpdate){
+            Message::ClientUpdate(corrected) => {
+                assert_eq!(corrected.id, state.id);
+                assert!(corrected.position.distance(Position::new(1.6, 0.0, 0.0)).to_f32() < 0.01, "Corrected to {:?}", corrected.position);
+            },
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_inputs_move_players(){
+        use input::InputCommand;
+
+        let mut config = ServerConfig::new();
+        config.address = "127.0.0.1:0".parse().unwrap();
+        config.ban_list_path = String::from("/nonexistent/lag-test-bans.txt");
+        config.movement_mode = MovementMode::Input;
+        config.player_speed = 4.0;
+
+        let listener = MemoryListener::new();
+        let connector = listener.connector();
+        thread::spawn(move ||{
+            let mut event_loop = EventLoop::new().unwrap();
+            let mut server = AuthoritativeServer::new(config);
+            server.listen(Box::new(listener));
+            server.run(&mut event_loop);
+        });
+
+        let mut client = connector.connect().unwrap();
+        let mut state = match wait_for(&mut client, |message| message.get_message_code() == MessageCode::ClientUpdate){
+            Message::ClientUpdate(state) => state,
+            _ => unreachable!()
+        };
+        client.send(Message::new_hello_message(SUPPORTED_CAPABILITIES).to_frame().to_bytes(), Delivery::ReliableOrdered).unwrap();
+        match wait_for(&mut client, |message| message.get_message_code() == MessageCode::Hello){
+            Message::Hello{ capabilities } => { assert!(capabilities & CAPABILITY_INPUT_COMMANDS != 0); },
+            _ => unreachable!()
+        }
+
+        // Client updates are ignored, and the player walks where its input takes it
+        state.position = Position::new(50.0, 0.0, 0.0);
+        client.send(Message::new_client_update_message(&state).to_frame().to_bytes(), Delivery::ReliableOrdered).unwrap();
+        let mut command = InputCommand::new(1, 0, 125);
+        command.movement = (0.0, 1.0);
+        command.yaw = 90.0;
+        client.send(Message::Input(command).to_frame().to_bytes(), Delivery::ReliableOrdered).unwrap();
+
+        // A replayed command isn't applied twice
+        client.send(Message::Input(command).to_frame().to_bytes(), Delivery::ReliableOrdered).unwrap();
+
+        let id = state.id;
+        match wait_for(&mut client, |message| match *message{
+            Message::EntityUpdate(ref deltas) => deltas.iter().any(|delta| delta.id == id && delta.state.is_some()),
+            _ => false
+        }){
+            Message::EntityUpdate(deltas) => {
+                let moved = deltas.iter().filter(|delta| delta.id == id).filter_map(|delta| delta.state).last().unwrap();
+                assert!(moved.position.distance(Position::new(0.5, 0.0, 0.0)).to_f32() < 0.01, "Moved to {:?}", moved.position);
+            },
+            _ => unreachable!()
+        }
+
+        let mut command = InputCommand::new(2, 125, 125);
+        command.movement = (0.0, -1.0);
+        command.yaw = 90.0;
+        client.send(Message::Input(command).to_frame().to_bytes(), Delivery::ReliableOrdered).unwrap();
+        match wait_for(&mut client, |message| match *message{
+            Message::EntityUpdate(ref deltas) => deltas.iter().any(|delta| delta.id == id && delta.state.is_some()),
+            _ => false
+        }){
+            Message::EntityUpdate(deltas) => {
+                let moved = deltas.iter().filter(|delta| delta.id == id).filter_map(|delta| delta.state).last().unwrap();
+                assert!(moved.position.distance(Position::new(0.0, 0.0, 0.0)).to_f32() < 0.01, "Moved to {:?}", moved.position);
+            },
+            _ => unreachable!()
         }
     }
+
+    #[test]
+    fn test_zone_transfers(){
+        let zone_path = fixture("zones", "[dungeon]\ninstanced = true\nspawn = 20 0 0\n\n[town]\n");
+
+        let mut config = ServerConfig::new();
+        config.address = "127.0.0.1:0".parse().unwrap();
+        config.ban_list_path = String::from("/nonexistent/lag-test-bans.txt");
+        config.zone_path = Some(zone_path);
+        let mut server = AuthoritativeServer::new(config);
+
+        server.construct_state_for_new_client(Token(5));
+        server.construct_state_for_new_client(Token(6));
+        let item = server.spawn_entity(EntityKind::Item, None, Position::new(1.0, 0.0, 1.0), Vec::new());
+        assert_eq!(server.zone_of(5), Some(DEFAULT_ZONE));
+        settle(&mut server);
+        server.state.message_queue.clear();
+
+        // Sending a player to an instanced template starts a new instance, which others can join
+        let instance = server.transfer_player(5, "dungeon").unwrap();
+        assert_eq!(instance, "dungeon#1");
+        assert_eq!(server.zone_of(5), Some("dungeon#1"));
+        settle(&mut server);
+        assert!(server.state.zones["dungeon#1"].players().contains(&5));
+
+        // The player forgets the zone it left, and is told where it arrived
+        let mailbox = server.state.message_queue[&Destination::Client(Token(5))].clone();
+        match mailbox[0]{
+            Message::EntityUpdate(ref deltas) => {
+                assert!(deltas.iter().all(|delta| delta.despawned));
+                assert!(deltas.iter().any(|delta| delta.id == 6) && deltas.iter().any(|delta| delta.id == item));
+            },
+            ref message => { panic!("Expected despawns, got {:?}", message); }
+        }
+        match mailbox[1]{
+            Message::ClientUpdate(state) => { assert_eq!((state.id, state.position), (5, Position::new(20.0, 0.0, 0.0))); },
+            ref message => { panic!("Expected a client update, got {:?}", message); }
+        }
+
+        // Chat stays within a zone
+        server.handle_message(Token(6), Message::new_text_message(String::from("Hello")));
+        for zone in server.state.zones.values_mut(){
+            zone.simulate(Instant::now());
+        }
+        assert_eq!(server.state.zones[DEFAULT_ZONE].broadcasts().len(), 1);
+        assert!(server.state.zones["dungeon#1"].broadcasts().is_empty());
+
+        assert_eq!(server.transfer_player(6, &instance).unwrap(), instance);
+        assert_eq!(server.transfer_player(6, "dungeon").unwrap(), "dungeon#2");
+        assert!(server.transfer_player(6, "nowhere").is_err());
+        assert_eq!(server.zone_of(6), Some("dungeon#2"));
+
+        // A player sent on before it arrived is forwarded, keeping its entity
+        settle(&mut server);
+        assert!(server.state.zones["dungeon#2"].players().contains(&6));
+        assert!(server.state.zones[&instance].players().contains(&5) && !server.state.zones[&instance].players().contains(&6));
+
+        // Zones started on demand are torn down once they come back empty, while the default zone stays up
+        assert_eq!(server.transfer_player(5, "town").unwrap(), "town");
+        settle(&mut server);
+        assert!(!server.state.zones.contains_key("dungeon#1"));
+        server.remove_client(Token(6));
+        settle(&mut server);
+        assert!(!server.state.zones.contains_key("dungeon#2"));
+        assert!(server.state.zones.contains_key("town"));
+        assert_eq!(server.transfer_player(5, DEFAULT_ZONE).unwrap(), DEFAULT_ZONE);
+        settle(&mut server);
+        assert!(!server.state.zones.contains_key("town"));
+        assert!(server.state.zone_handles.keys().all(|name| name == DEFAULT_ZONE));
+        server.remove_client(Token(5));
+        settle(&mut server);
+        assert!(server.state.zones.contains_key(DEFAULT_ZONE));
+
+        // A player who disconnects mid-transfer doesn't linger in the zone it was going to
+        server.construct_state_for_new_client(Token(7));
+        settle(&mut server);
+        server.transfer_player(7, "town").unwrap();
+        server.remove_client(Token(7));
+        settle(&mut server);
+        assert!(server.state.zones.values().all(|zone| zone.is_empty()));
+        assert!(!server.state.zones.contains_key("town"));
+    }
+
+    #[test]
+    fn test_clock_sync(){
+        use clock::{ClockSample, ClockSync};
+
+        let mut config = ServerConfig::new();
+        config.address = "127.0.0.1:0".parse().unwrap();
+        config.ban_list_path = String::from("/nonexistent/lag-test-bans.txt");
+
+        let listener = MemoryListener::new();
+        let connector = listener.connector();
+        thread::spawn(move ||{
+            let mut event_loop = EventLoop::new().unwrap();
+            let mut server = AuthoritativeServer::new(config);
+            server.listen(Box::new(listener));
+            server.run(&mut event_loop);
+        });
+
+        let mut client = connector.connect().unwrap();
+        wait_for(&mut client, |message| message.get_message_code() == MessageCode::ClientUpdate);
+
+        // Let the server run a few steps, then ask for its clock
+        thread::sleep(Duration::from_millis(SIMULATION_STEP_MS * 4));
+        let started = Instant::now();
+        client.send(Message::Ping(Some(ClockSample::new(0))).to_frame().to_bytes(), Delivery::Unreliable).unwrap();
+        let sample = match wait_for(&mut client, |message| match *message{ Message::Ping(Some(_)) => true, _ => false }){
+            Message::Ping(Some(sample)) => sample,
+            _ => unreachable!()
+        };
+        let elapsed = Instant::now().duration_since(started);
+        let received = elapsed.as_secs() * 1000000 + elapsed.subsec_nanos() as u64 / 1000;
+
+        assert_eq!(sample.step, SIMULATION_STEP_MS as u32 * 1000);
+        assert!(sample.tick >= 2, "Only {} ticks", sample.tick);
+        assert!(sample.server_sent >= sample.server_received && sample.server_received >= sample.tick_time);
+
+        // The server started before the client connected, so its clock is ahead
+        let mut clock = ClockSync::new();
+        clock.add_sample(sample, received);
+        assert!(clock.offset().unwrap() > 0);
+        assert!(clock.server_tick(received).unwrap() >= sample.tick);
+    }
 }
@@ -0,0 +1,256 @@
+extern crate log;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write, ErrorKind, Result, Error};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds since the unix epoch, used for ban expiry.
+pub fn unix_now() -> u64{
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Something that can be banned
+#[derive(Clone, Debug, PartialEq)]
+pub enum BanTarget{
+    /// An IP range, given as an address and a prefix length.
+    /// A single address is a range with the full prefix length.
+    Ip(IpAddr, u8),
+
+    /// A player account.
+    /// Clients claim their account ID at login without proving it, so an account ban alone
+    /// won't stop a player who logs in as someone else; ban their IP range as well.
+    Account(u32)
+}
+
+impl BanTarget{
+    /// Parse a target of the form `ip <addr>[/<prefix>]` or `account <id>`.
+    pub fn parse(kind: &str, value: &str) -> ::std::result::Result<BanTarget, String>{
+        match kind{
+            "ip" => {
+                let mut parts = value.splitn(2, '/');
+                let address = try!(parts.next().unwrap_or("").parse::<IpAddr>().map_err(|_| format!("Invalid IP address `{}`", value)));
+                let max_prefix = match address{ IpAddr::V4(_) => 32, IpAddr::V6(_) => 128 };
+                let prefix = match parts.next(){
+                    Some(prefix) => try!(prefix.parse::<u8>().map_err(|_| format!("Invalid prefix length `{}`", prefix))),
+                    None => max_prefix
+                };
+                if prefix > max_prefix{
+                    return Err(format!("Prefix length {} is too long for {}", prefix, address));
+                }
+                Ok(BanTarget::Ip(address, prefix))
+            },
+            "account" => {
+                value.parse::<u32>().map(BanTarget::Account).map_err(|_| format!("Invalid account ID `{}`", value))
+            },
+            _ => { Err(format!("Unknown ban target `{}`, expected `ip` or `account`", kind)) }
+        }
+    }
+
+    /// Return TRUE if @address falls within this target's range
+    pub fn matches_ip(&self, address: &IpAddr) -> bool{
+        match (self, address){
+            (&BanTarget::Ip(IpAddr::V4(ref range), prefix), &IpAddr::V4(ref address)) => {
+                prefix_matches(&range.octets(), &address.octets(), prefix)
+            },
+            (&BanTarget::Ip(IpAddr::V6(ref range), prefix), &IpAddr::V6(ref address)) => {
+                prefix_matches(&range.octets(), &address.octets(), prefix)
+            },
+            _ => false
+        }
+    }
+
+    /// Return TRUE if this target is the account @account_id
+    pub fn matches_account(&self, account_id: u32) -> bool{
+        *self == BanTarget::Account(account_id)
+    }
+}
+
+impl fmt::Display for BanTarget{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        match self{
+            &BanTarget::Ip(address, prefix) => write!(f, "ip {}/{}", address, prefix),
+            &BanTarget::Account(id) => write!(f, "account {}", id)
+        }
+    }
+}
+
+/// Compare the first @prefix bits of two addresses
+fn prefix_matches(range: &[u8], address: &[u8], prefix: u8) -> bool{
+    let full_bytes = (prefix / 8) as usize;
+    if range[..full_bytes] != address[..full_bytes]{
+        return false;
+    }
+
+    let remaining_bits = prefix % 8;
+    if remaining_bits == 0{
+        return true;
+    }
+
+    let mask = 0xFFu8 << (8 - remaining_bits);
+    (range[full_bytes] & mask) == (address[full_bytes] & mask)
+}
+
+#[derive(Clone, Debug)]
+pub struct BanEntry{
+    pub target: BanTarget,
+
+    /// Unix time at which the ban is lifted, or None for a permanent ban
+    pub expires: Option<u64>,
+
+    /// Shown to the banned player when they are disconnected
+    pub reason: String
+}
+
+impl BanEntry{
+    pub fn is_expired(&self, now: u64) -> bool{
+        match self.expires{
+            Some(expires) => expires <= now,
+            None => false
+        }
+    }
+
+    /// Human readable description sent along with the Disconnect message
+    pub fn describe(&self) -> String{
+        match self.expires{
+            Some(expires) => format!("Banned until {} (unix time): {}", expires, self.reason),
+            None => format!("Banned permanently: {}", self.reason)
+        }
+    }
+}
+
+/// The set of banned IP ranges and accounts, persisted to a text file.
+///
+/// Each line of the file is `<ip|account> <target> <expiry|-> [reason]`, e.g.
+/// `ip 10.0.0.0/8 - abuse` or `account 42 1767225600 cheating`.
+///
+/// Account IDs aren't authenticated, so account bans are only as good as clients' honesty;
+/// IP bans are the ones that hold.
+pub struct BanList{
+    path: String,
+    entries: Vec<BanEntry>
+}
+
+impl BanList{
+    pub fn new(path: &str) -> BanList{
+        BanList{
+            path: String::from(path),
+            entries: Vec::new()
+        }
+    }
+
+    /// Load the ban list stored at @path. A missing file is an empty list.
+    pub fn load(path: &str) -> Result<BanList>{
+        let mut bans = BanList::new(path);
+        try!(bans.reload());
+        return Ok(bans);
+    }
+
+    /// Re-read the ban list from disk, replacing the current entries.
+    pub fn reload(&mut self) -> Result<()>{
+        let mut contents = String::new();
+        match File::open(&self.path){
+            Ok(mut file) => { try!(file.read_to_string(&mut contents)); },
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                info!("No ban list found at {}, starting with an empty list", self.path);
+            },
+            Err(e) => { return Err(e); }
+        }
+
+        let mut entries = Vec::new();
+        for (line_number, line) in contents.lines().enumerate(){
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#'){
+                continue;
+            }
+
+            let entry = try!(Self::parse_entry(line).map_err(|e|{
+                Error::new(ErrorKind::InvalidData, format!("{}:{}: {}", self.path, line_number + 1, e))
+            }));
+            entries.push(entry);
+        }
+
+        self.entries = entries;
+        return Ok(());
+    }
+
+    fn parse_entry(line: &str) -> ::std::result::Result<BanEntry, String>{
+        let mut parts = line.splitn(4, ' ');
+        let kind = parts.next().unwrap_or("");
+        let target = try!(BanTarget::parse(kind, parts.next().unwrap_or("")));
+        let expires = match parts.next(){
+            None | Some("-") => None,
+            Some(expires) => Some(try!(expires.parse::<u64>().map_err(|_| format!("Invalid expiry `{}`", expires))))
+        };
+        let reason = String::from(parts.next().unwrap_or("").trim());
+
+        Ok(BanEntry{ target: target, expires: expires, reason: reason })
+    }
+
+    /// Write the ban list back to disk, dropping expired entries.
+    pub fn save(&mut self) -> Result<()>{
+        let now = unix_now();
+        self.entries.retain(|entry| !entry.is_expired(now));
+
+        let mut file = try!(File::create(&self.path));
+        for entry in self.entries.iter(){
+            let expires = entry.expires.map(|e| e.to_string()).unwrap_or(String::from("-"));
+            try!(writeln!(file, "{} {} {}", entry.target, expires, entry.reason));
+        }
+        return Ok(());
+    }
+
+    /// Add a ban, replacing any existing ban on the same target
+    pub fn add(&mut self, entry: BanEntry){
+        self.entries.retain(|existing| existing.target != entry.target);
+        self.entries.push(entry);
+    }
+
+    /// Remove the ban on @target. Returns TRUE if one existed.
+    pub fn remove(&mut self, target: &BanTarget) -> bool{
+        let count = self.entries.len();
+        self.entries.retain(|existing| existing.target != *target);
+        count != self.entries.len()
+    }
+
+    pub fn entries(&self) -> &[BanEntry]{
+        &self.entries
+    }
+
+    /// Find an active ban covering @address
+    pub fn find_ip(&self, address: &IpAddr) -> Option<&BanEntry>{
+        let now = unix_now();
+        self.entries.iter().find(|entry| !entry.is_expired(now) && entry.target.matches_ip(address))
+    }
+
+    /// Find an active ban on @account_id
+    pub fn find_account(&self, account_id: u32) -> Option<&BanEntry>{
+        let now = unix_now();
+        self.entries.iter().find(|entry| !entry.is_expired(now) && entry.target.matches_account(account_id))
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+
+    #[test]
+    fn test_cidr_matches(){
+        let target = BanTarget::parse("ip", "10.1.0.0/16").unwrap();
+
+        assert!(target.matches_ip(&"10.1.200.3".parse().unwrap()));
+        assert!(!target.matches_ip(&"10.2.0.1".parse().unwrap()));
+        assert!(!target.matches_ip(&"::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_entry(){
+        let entry = BanList::parse_entry("account 42 1000 speed hacking").unwrap();
+
+        assert!(entry.target.matches_account(42));
+        assert_eq!(entry.expires, Some(1000));
+        assert_eq!(entry.reason, "speed hacking");
+        assert!(entry.is_expired(1000));
+    }
+}
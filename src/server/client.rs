@@ -3,15 +3,19 @@ extern crate log;
 use authoritative::AuthoritativeServer;
 use std::io::Result;
 //use std::io;
-use std::io::prelude::*;
 use mio::{Token, EventLoop, EventSet, PollOpt};
-use mio::tcp::{TcpStream};
 use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 //use byteorder::{ByteOrder, BigEndian, LittleEndian};
 
-#[path="../shared/frame.rs"]
-mod frame;
-use frame::{Message, ToFrame};
+use clock::{ClockSample, micros_to_duration};
+use frame::{Message, MessageCode, ProtocolResult, ToFrame, BATCH_ENTRY_HEADER_LENGTH, DEFAULT_MAX_PAYLOAD_LENGTH};
+use fragment::{Fragmenter, Reassembler, DEFAULT_MAX_MESSAGE_LENGTH};
+use priority::{Priority, PriorityQueue, DEFAULT_MAX_BACKLOG, MESSAGES_PER_WRITE};
+use logging::LogContext;
+use transport::Connection;
+use udp::Delivery;
 
 /// The state of the client's connection
 // pub enum ClientState{
@@ -20,42 +24,120 @@ use frame::{Message, ToFrame};
 // //    Athenticated        // The client has successfully authenticated
 // }
 
+/// At most this many fragments are written at a time, so small messages aren't held up behind large ones
+const FRAGMENTS_PER_WRITE: usize = 16;
+
+/// Clients are pinged this often to measure the round trip to them
+const ROUND_TRIP_PROBE_INTERVAL_MS: u64 = 1000;
+
+/// A client with more than this many messages and fragments waiting to be written is disconnected
+pub const MAX_BACKLOG: usize = DEFAULT_MAX_BACKLOG * 4;
+
+/// A message handed to a client's connection
+pub struct WrittenMessage{
+    pub code: MessageCode,
+
+    /// Codes of the messages packed in the frame, if it is a Batch
+    pub contents: Vec<MessageCode>,
+
+    /// Size of the frame as sent
+    pub length: usize,
+
+    /// Payload size before and after compression, if it was large enough to try
+    pub compression: Option<(usize, usize)>
+}
+
 pub struct GameClient{
-    socket: TcpStream,
+    connection: Box<Connection>,
     pub token: Token,
 //    state: ClientState,
 
-    pub send_queue: VecDeque<Message>,
+    /// The remote address of this connection
+    pub peer: SocketAddr,
+
+    /// The account this client logged in as, if it has sent a Login message
+    pub account_id: Option<u32>,
+
+    /// Set when the connection should be closed once the send queue has been flushed
+    pub closing: bool,
+
+    /// Payloads larger than this are compressed, once the client has said it supports compression
+    pub compression_threshold: Option<usize>,
+
+    /// Whether frames sent to this client carry a CRC32 trailer
+    pub checksums: bool,
+
+    pub send_queue: PriorityQueue<Message>,
+
+    /// Fragments of large messages, written a few at a time after the send queue
+    fragment_queue: VecDeque<Message>,
+
+    fragmenter: Fragmenter,
+
+    /// Collects fragments of large messages sent by the client
+    pub reassembler: Reassembler,
+
+    /// The smoothed round trip to the client, measured by pinging it
+    round_trip: Option<Duration>,
+
+    /// When the client was last pinged to measure the round trip.
+    /// Starts at connection, so the first ping doesn't hold up the client's setup.
+    last_probe: Instant
 }
 
 impl GameClient{
-    pub fn new(socket: TcpStream, token: Token) -> GameClient{
+    pub fn new(connection: Box<Connection>, token: Token, peer: SocketAddr) -> GameClient{
         GameClient {
-            socket: socket,
+            connection: connection,
             token: token,
+            peer: peer,
+            account_id: None,
+            closing: false,
+            compression_threshold: None,
+            checksums: false,
 //            state: ClientState::Connected,
-            send_queue: VecDeque::with_capacity(15) // 15 is an arbitrary guess at the average max backlog
+            send_queue: PriorityQueue::new(),
+            fragment_queue: VecDeque::new(),
+            fragmenter: Fragmenter::new(),
+            reassembler: Reassembler::new(),
+            round_trip: None,
+            last_probe: Instant::now()
         }
     }
 
+    /// Return TRUE if the client has no socket to register with the event loop,
+    /// and must instead be read and written on every tick.
+    pub fn is_polled(&self) -> bool{
+        self.connection.evented().is_none()
+    }
+
     pub fn register(&mut self, event_loop: &mut EventLoop<AuthoritativeServer>) -> Result<()>{
-        info!("Registering token {:?}", self.token);
+        let socket = match self.connection.evented(){
+            Some(socket) => socket,
+            None => { return Ok(()); }
+        };
+
+        debug!("Registering connection");
 
         event_loop.register(
-            &self.socket,
+            socket,
             self.token,
             EventSet::readable() | EventSet::writable(),
             PollOpt::edge() | PollOpt::oneshot()
         ).and_then(|(),|{
             Ok(())
         }).or_else(|e|{
-            info!("Failed to register {:?}, {:?}", self.token, e);
+            error!("Failed to register connection: {:?}", e);
             Err(e)
         })
     }
 
     pub fn reregister(&mut self, event_loop: &mut EventLoop<AuthoritativeServer>, as_writable: bool) -> Result<()>{
         //info!("Reregistering token {:?}", self.token);
+        let socket = match self.connection.evented(){
+            Some(socket) => socket,
+            None => { return Ok(()); }
+        };
 
         let mut event_set = EventSet::readable();
         if as_writable{
@@ -63,59 +145,284 @@ impl GameClient{
         }
 
         event_loop.reregister(
-            &self.socket,
+            socket,
             self.token,
             event_set,
             PollOpt::edge() | PollOpt::oneshot()
         ).and_then(|(),|{
             Ok(())
         }).or_else(|e|{
-            info!("Failed to reregister {:?}, {:?}", self.token, e);
+            warn!("Failed to reregister connection: {:?}", e);
             Err(e)
         })
     }
 
-    pub fn write(&mut self) -> Result<()>{
-        let write_socket = <TcpStream as Write>::by_ref(&mut self.socket);
+    /// Queue @message to be sent to the client with the default priority for its type.
+    /// Returns FALSE if it was dropped because the client has fallen behind.
+    pub fn queue(&mut self, message: Message) -> bool{
+        let priority = Priority::for_message(&message);
+        return self.queue_with_priority(priority, message);
+    }
+
+    /// Queue @message to be sent to the client with @priority.
+    /// Bulk messages are dropped once the backlog, including unsent fragments, reaches the send queue's max_backlog.
+    /// Nothing more is queued once the client is closing.
+    /// Returns FALSE if it was dropped because the client has fallen behind.
+    pub fn queue_with_priority(&mut self, priority: Priority, message: Message) -> bool{
+        if self.closing{
+            return true;
+        }
+        if priority == Priority::Bulk && self.backlog() >= self.send_queue.max_backlog{
+            return false;
+        }
+        return self.send_queue.push_back(priority, message);
+    }
+
+    /// The number of messages and fragments waiting to be written
+    pub fn backlog(&self) -> usize{
+        self.send_queue.len() + self.fragment_queue.len()
+    }
+
+    /// Return TRUE if the client has fallen so far behind that it should be disconnected
+    pub fn is_too_far_behind(&self) -> bool{
+        self.backlog() > MAX_BACKLOG
+    }
+
+    /// Drop everything waiting to be written
+    pub fn clear_queues(&mut self){
+        self.send_queue.clear();
+        self.fragment_queue.clear();
+    }
+
+    /// Hand queued messages to the connection, most urgent first, and write as much as it will take.
+    /// Consecutive messages with the same delivery are packed into a single Batch frame.
+    /// Returns a description of each frame written.
+    pub fn write(&mut self) -> Result<Vec<WrittenMessage>>{
+        let mut written = Vec::new();
+        let mut budget = MESSAGES_PER_WRITE;
+
+        while budget > 0{
+            let first_message = match self.send_queue.pop_front(){
+                Some(message) => message,
+                None => { break; }
+            };
+            budget -= 1;
+
+            let delivery = Self::delivery_for(&first_message);
+            let mut batch_length = first_message.to_bytes().len() + BATCH_ENTRY_HEADER_LENGTH;
+            let mut messages = vec![first_message];
 
-        info!("Sending message to {:?}", self.token);
-        if let Some(output_message) = self.send_queue.pop_front(){
-            info!("Sending {:?} to client!", output_message);
-            let output_bytes = output_message.to_frame().to_bytes();
-            write_socket.write(&output_bytes).ok();
+            while let Some(next_length) = self.send_queue.front()
+                .filter(|next| Self::delivery_for(next) == delivery)
+                .map(|next| next.to_bytes().len() + BATCH_ENTRY_HEADER_LENGTH){
+                if budget == 0 || batch_length + next_length > DEFAULT_MAX_PAYLOAD_LENGTH as usize{
+                    break;
+                }
+                budget -= 1;
+                batch_length += next_length;
+                messages.push(self.send_queue.pop_front().unwrap());
+            }
+
+            let contents: Vec<MessageCode> = messages.iter().map(|message| message.get_message_code()).collect();
+            let output_message = match messages.len(){
+                1 => messages.pop().unwrap(),
+                _ => Message::new_batch_message(messages)
+            };
+
+            trace!("Sending {:?}", output_message);
+            let mut output_frame = output_message.to_frame();
+            let compression = self.compression_threshold.and_then(|threshold| output_frame.compress(threshold));
+            if self.checksums{
+                output_frame.add_checksum();
+            }
+            let output_bytes = output_frame.to_bytes();
+            let output_length = output_bytes.len();
+
+            if self.fragmenter.needs_split(output_length){
+                if output_length > DEFAULT_MAX_MESSAGE_LENGTH{
+                    warn!("Dropping {:?}, {} bytes is too large to send", output_message.get_message_code(), output_length);
+                }
+                else{
+                    trace!("Fragmenting {:?} of {} bytes", output_message.get_message_code(), output_length);
+                    self.fragment_queue.extend(self.fragmenter.split(&output_bytes));
+                }
+                continue;
+            }
+
+            match self.connection.send(output_bytes, delivery){
+                Ok(_) => {
+                    written.push(WrittenMessage{
+                        code: output_message.get_message_code(),
+                        contents: if contents.len() > 1 { contents } else { Vec::new() },
+                        length: output_length,
+                        compression: compression
+                    });
+                },
+                Err(e) => { warn!("Dropping {:?}: {:?}", output_message.get_message_code(), e); }
+            }
         }
 
-        return Ok(());
-    }
-
-    pub fn read(&mut self) -> Result<Message>{
-        // Create the socket from which we will read
-        let read_socket = <TcpStream as Read>::by_ref(&mut self.socket);
-
-        // Read the message from the socket
-        let message = Message::read(read_socket);
-
-        return message;
-
-        // match message{
-        //     Ok(message) => {
-        //         match message{
-        //             Message::Text{message: ref message_text} => {
-        //                 info!("Received message: {}", &message_text);
-        //             },
-        //             Message::Ping => {
-        //                 info!("Received Ping!");
-        //             },
-        //             Message::ClientUpdate{position: _, rotation: _} => {
-        //                 info!("Received ")
-        //             }
-        //         }
-        //         return Ok(message);
-        //     },
-        //     Err(e) => {
-        //         info!("SHITS FUCKED UP! {:?}", e);
-        //         return Err(e);
-        //     }
-        // }
+        // Every fragment is needed to rebuild the message, so they're always sent reliably
+        for _ in 0..FRAGMENTS_PER_WRITE{
+            let fragment = match self.fragment_queue.pop_front(){
+                Some(fragment) => fragment,
+                None => { break; }
+            };
+
+            let mut output_frame = fragment.to_frame();
+            if self.checksums{
+                output_frame.add_checksum();
+            }
+            let output_bytes = output_frame.to_bytes();
+            let output_length = output_bytes.len();
+
+            match self.connection.send(output_bytes, Delivery::ReliableOrdered){
+                Ok(_) => {
+                    written.push(WrittenMessage{
+                        code: MessageCode::Fragment,
+                        contents: Vec::new(),
+                        length: output_length,
+                        compression: None
+                    });
+                },
+                Err(e) => { warn!("Dropping fragment: {:?}", e); }
+            }
+        }
+
+        try!(self.connection.flush());
+        return Ok(written);
+    }
+
+    fn delivery_for(message: &Message) -> Delivery{
+        // The only ClientUpdate the server sends assigns the client its entity, so it can't be lost
+        return match message{
+            &Message::ClientUpdate(_) => Delivery::ReliableOrdered,
+            _ => Delivery::for_message(message)
+        };
+    }
+
+    /// Read whatever the connection has available, returning each message along with the number
+    /// of bytes it took up, or the error reading it.
+    pub fn read(&mut self) -> Vec<ProtocolResult<(Message, usize)>>{
+        self.connection.receive()
+    }
+
+    /// Fields attached to log events about this connection
+    pub fn log_context(&self) -> LogContext{
+        LogContext::for_connection(self.token.as_usize(), Some(self.token.as_usize() as u32), Some(self.peer))
+    }
+
+    /// Return TRUE if there is output waiting on the socket becoming writable,
+    /// or fragments still to be written
+    pub fn has_pending_output(&self) -> bool{
+        self.connection.has_pending_output() || !self.fragment_queue.is_empty()
+    }
+
+    /// Return TRUE if the client is closing and everything queued for it has been written
+    pub fn is_ready_to_close(&self) -> bool{
+        self.closing && self.send_queue.is_empty() && !self.has_pending_output()
+    }
+
+    /// Reject frames from the client with payloads longer than @max_payload_length
+    pub fn set_max_payload_length(&mut self, max_payload_length: u32){
+        self.connection.set_max_payload_length(max_payload_length);
+    }
+
+    /// Ping the client to measure the round trip, if one is due by @now.
+    /// @clock is the server's clock at @now, in microseconds.
+    pub fn probe_round_trip(&mut self, now: Instant, clock: u64){
+        if now.duration_since(self.last_probe) < Duration::from_millis(ROUND_TRIP_PROBE_INTERVAL_MS){
+            return;
+        }
+        self.last_probe = now;
+        self.queue(Message::Ping(Some(ClockSample::new(clock))));
+    }
+
+    /// Take in the client's answer @sample to a round trip ping, which arrived at @clock on the server's clock
+    pub fn add_round_trip(&mut self, sample: ClockSample, clock: u64){
+        let round_trip = micros_to_duration(sample.round_trip(clock));
+        self.round_trip = Some(match self.round_trip{
+            Some(smoothed) => (smoothed * 7 + round_trip) / 8,
+            None => round_trip
+        });
+    }
+
+    /// How long messages take to reach the client, once the round trip has been measured
+    pub fn latency(&self) -> Option<Duration>{
+        self.round_trip.or_else(|| self.connection.rtt()).map(|rtt| rtt / 2)
+    }
+
+    pub fn shutdown(&mut self){
+        self.connection.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use state::ClientState;
+    use transport::MemoryConnection;
+
+    fn long_text() -> Message{
+        Message::new_text_message((0..32 * 1024).map(|_| 'x').collect())
+    }
+
+    #[test]
+    fn test_bulk_dropped_when_backlogged(){
+        let address = "127.0.0.1:1".parse().unwrap();
+        let peer = "127.0.0.1:2".parse().unwrap();
+        let (local, _remote) = MemoryConnection::pair(address, peer);
+        let mut client = GameClient::new(Box::new(local), Token(1), peer);
+
+        // Only some of the fragments go out on each write, the rest wait in the backlog
+        assert!(client.queue(long_text()));
+        client.write().unwrap();
+        assert!(client.send_queue.is_empty());
+        assert!(client.backlog() > 0);
+
+        client.send_queue.max_backlog = client.backlog();
+        assert!(!client.queue(long_text()));
+        assert!(!client.queue_with_priority(Priority::Bulk, Message::new_text_message(String::from("Hello"))));
+        assert!(client.queue(Message::new_text_message(String::from("Hello"))));
+        assert!(!client.is_too_far_behind());
+
+        while client.backlog() <= MAX_BACKLOG{
+            assert!(client.queue(Message::new_client_update_message(&ClientState::new(7))));
+        }
+        assert!(client.is_too_far_behind());
+
+        client.clear_queues();
+        assert_eq!(client.backlog(), 0);
+        client.closing = true;
+        assert!(client.queue(Message::new_text_message(String::from("Hello"))));
+        assert_eq!(client.backlog(), 0);
+    }
+
+    #[test]
+    fn test_round_trip_measured(){
+        let address = "127.0.0.1:1".parse().unwrap();
+        let peer = "127.0.0.1:2".parse().unwrap();
+        let (local, _remote) = MemoryConnection::pair(address, peer);
+        let mut client = GameClient::new(Box::new(local), Token(1), peer);
+        assert_eq!(client.latency(), None);
+
+        // The first ping waits a while, so it doesn't hold up the client's setup
+        let now = Instant::now();
+        client.probe_round_trip(now, 1000);
+        assert!(client.send_queue.is_empty());
+        let later = now + Duration::from_millis(ROUND_TRIP_PROBE_INTERVAL_MS);
+        client.probe_round_trip(later, 1000);
+        client.probe_round_trip(later, 2000);
+        assert_eq!(client.send_queue.len(), 1);
+        let mut sample = match client.send_queue.pop_front(){
+            Some(Message::Ping(Some(sample))) => sample,
+            other => { panic!("Expected a ping, got {:?}", other); }
+        };
+        assert!(!sample.is_answered());
+
+        // The time the client held the ping doesn't count
+        sample.answer(500, 5500);
+        client.add_round_trip(sample, 46000);
+        assert_eq!(client.latency(), Some(Duration::from_millis(20)));
     }
 }
@@ -0,0 +1,254 @@
+extern crate log;
+
+use std::fs::File;
+use std::io::{Read, ErrorKind, Result, Error};
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use logging::LogFormat;
+use frame::{DEFAULT_COMPRESSION_THRESHOLD, DEFAULT_MAX_PAYLOAD_LENGTH};
+use navigation::DEFAULT_PATH_QUERIES_PER_STEP;
+use history::{DEFAULT_INTERPOLATION_DELAY_MS, DEFAULT_MAX_REWIND_MS};
+use scheduler::DEFAULT_SIMULATION_THREADS;
+
+/// Who decides where players move
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MovementMode{
+    /// Clients send where their player is, and the server accepts it unless it collides with something
+    Client,
+
+    /// Clients send input commands, and the server moves their players itself
+    Input
+}
+
+impl MovementMode{
+    pub fn from_str(mode: &str) -> Option<MovementMode>{
+        match mode{
+            "client" => Some(MovementMode::Client),
+            "input" => Some(MovementMode::Input),
+            _ => None
+        }
+    }
+}
+
+/// Server settings, loaded from a `key = value` file.
+/// Any setting missing from the file keeps its default value.
+#[derive(Clone, Debug)]
+pub struct ServerConfig{
+    /// The address on which the server listens for game clients
+    pub address: SocketAddr,
+
+    /// PEM certificate chain for the game client listener; TLS is enabled when this and `tls_key` are set
+    pub tls_certificate: Option<String>,
+
+    /// PEM private key matching `tls_certificate`
+    pub tls_key: Option<String>,
+
+    /// The address on which the server accepts WebSocket clients, if enabled
+    pub websocket_address: Option<SocketAddr>,
+
+    /// The address on which the server accepts UDP clients, if enabled
+    pub udp_address: Option<SocketAddr>,
+
+    /// Payloads larger than this many bytes are compressed for clients which support it
+    pub compression_threshold: usize,
+
+    /// Frames from clients with longer payloads are rejected, and the client disconnected
+    pub max_payload_length: u32,
+
+    /// Whether frames sent to clients carry a CRC32 trailer
+    pub frame_checksums: bool,
+
+    /// File in which the ban list is persisted
+    pub ban_list_path: String,
+
+    /// Maximum number of connections accepted from a single IP within `connection_rate_window`
+    pub connection_rate_limit: u32,
+
+    /// Length, in seconds, of the connection rate limiting window
+    pub connection_rate_window: u64,
+
+    /// Port on localhost serving metrics over HTTP, if enabled
+    pub metrics_port: Option<u16>,
+
+    /// Data file describing the NPCs spawned when the server starts, if any
+    pub npc_path: Option<String>,
+
+    /// Data file holding the navigation grid; without one, NPCs walk in straight lines
+    pub navigation_path: Option<String>,
+
+    /// Maximum number of path queries answered each simulation step
+    pub path_queries_per_step: usize,
+
+    /// Data file holding the level geometry characters collide with, if any
+    pub collision_path: Option<String>,
+
+    /// Data file describing the zones players can be sent to besides the default one, if any
+    pub zone_path: Option<String>,
+
+    /// Whether players are moved by their clients or by the server
+    pub movement_mode: MovementMode,
+
+    /// How fast the server moves players in `MovementMode::Input`, in world units per second
+    pub player_speed: f32,
+
+    /// The furthest back, in milliseconds, the server rewinds the world to check what a client hit
+    pub max_rewind_ms: u64,
+
+    /// How far behind the latest state they've received, in milliseconds, clients draw other entities
+    pub interpolation_delay_ms: u64,
+
+    /// Number of worker threads zones are simulated on; with none, zones are simulated on the event loop thread
+    pub simulation_threads: usize,
+
+    /// Whether logs are written as plain text or JSON
+    pub log_format: LogFormat
+}
+
+impl ServerConfig{
+    pub fn new() -> ServerConfig{
+        ServerConfig{
+            address: "0.0.0.0:6969".parse().unwrap(),
+            tls_certificate: None,
+            tls_key: None,
+            websocket_address: None,
+            udp_address: None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            max_payload_length: DEFAULT_MAX_PAYLOAD_LENGTH,
+            frame_checksums: false,
+            ban_list_path: String::from("bans.txt"),
+            connection_rate_limit: 5,
+            connection_rate_window: 10,
+            metrics_port: None,
+            npc_path: None,
+            navigation_path: None,
+            path_queries_per_step: DEFAULT_PATH_QUERIES_PER_STEP,
+            collision_path: None,
+            zone_path: None,
+            movement_mode: MovementMode::Client,
+            player_speed: 5.0,
+            max_rewind_ms: DEFAULT_MAX_REWIND_MS,
+            interpolation_delay_ms: DEFAULT_INTERPOLATION_DELAY_MS,
+            simulation_threads: DEFAULT_SIMULATION_THREADS,
+            log_format: LogFormat::Text
+        }
+    }
+
+    /// Load the config file at @path, falling back to defaults for unset keys.
+    pub fn load(path: &str) -> Result<ServerConfig>{
+        load_data(path, ServerConfig::parse)
+    }
+
+    /// Parse the `key = value` settings in @contents, falling back to defaults for unset keys.
+    pub fn parse(contents: &str) -> Result<ServerConfig>{
+        let mut config = ServerConfig::new();
+        try!(parse_lines(contents, |line| match line{
+            Line::Setting(key, value) => config.set(key, value),
+            Line::Section(name) => Err(format!("Unexpected section `[{}]`", name))
+        }));
+
+        if config.tls_certificate.is_some() != config.tls_key.is_some(){
+            return Err(Error::new(ErrorKind::InvalidData, "`tls_certificate` and `tls_key` must be set together"));
+        }
+
+        return Ok(config);
+    }
+
+    /// Apply a single setting.
+    fn set(&mut self, key: &str, value: &str) -> ::std::result::Result<(), String>{
+        match key{
+            "address"                => { self.address = try!(parse_value(key, value)); },
+            "tls_certificate"        => { self.tls_certificate = Some(String::from(value)); },
+            "tls_key"                => { self.tls_key = Some(String::from(value)); },
+            "websocket_address"      => { self.websocket_address = Some(try!(parse_value(key, value))); },
+            "udp_address"            => { self.udp_address = Some(try!(parse_value(key, value))); },
+            "compression_threshold"  => { self.compression_threshold = try!(parse_value(key, value)); },
+            "max_payload_length"     => { self.max_payload_length = try!(parse_value(key, value)); },
+            "frame_checksums"        => { self.frame_checksums = try!(parse_value(key, value)); },
+            "ban_list_path"          => { self.ban_list_path = String::from(value); },
+            "connection_rate_limit"  => { self.connection_rate_limit = try!(parse_value(key, value)); },
+            "connection_rate_window" => { self.connection_rate_window = try!(parse_value(key, value)); },
+            "metrics_port"           => { self.metrics_port = Some(try!(parse_value(key, value))); },
+            "npc_path"               => { self.npc_path = Some(String::from(value)); },
+            "navigation_path"        => { self.navigation_path = Some(String::from(value)); },
+            "path_queries_per_step"  => { self.path_queries_per_step = try!(parse_value(key, value)); },
+            "collision_path"         => { self.collision_path = Some(String::from(value)); },
+            "zone_path"              => { self.zone_path = Some(String::from(value)); },
+            "movement_mode"          => {
+                self.movement_mode = try!(MovementMode::from_str(value).ok_or(format!("Invalid value `{}` for `{}`, expected `client` or `input`", value, key)));
+            },
+            "player_speed"           => { self.player_speed = try!(parse_value(key, value)); },
+            "max_rewind_ms"          => { self.max_rewind_ms = try!(parse_value(key, value)); },
+            "interpolation_delay_ms" => { self.interpolation_delay_ms = try!(parse_value(key, value)); },
+            "simulation_threads"     => { self.simulation_threads = try!(parse_value(key, value)); },
+            "log_format"             => {
+                self.log_format = try!(LogFormat::from_str(value).ok_or(format!("Invalid value `{}` for `{}`, expected `text` or `json`", value, key)));
+            },
+            _ => { return Err(format!("Unknown setting `{}`", key)); }
+        }
+        Ok(())
+    }
+}
+
+/// A line of a settings or data file
+pub enum Line<'a>{
+    /// `[name]`, starting a new section
+    Section(&'a str),
+
+    /// `key = value`
+    Setting(&'a str, &'a str)
+}
+
+/// Hand each line of @contents to @apply, skipping blank lines and `#` comments.
+/// Lines that don't parse, or that @apply rejects, are reported with their line number.
+pub fn parse_lines<F>(contents: &str, mut apply: F) -> Result<()> where F: FnMut(Line) -> ::std::result::Result<(), String>{
+    for (line_number, line) in contents.lines().enumerate(){
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#'){
+            continue;
+        }
+        let invalid = |message: String| Error::new(ErrorKind::InvalidData, format!("line {}: {}", line_number + 1, message));
+
+        let parsed = if line.starts_with('[') && line.ends_with(']'){
+            Line::Section(line[1..line.len() - 1].trim())
+        }
+        else{
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = try!(parts.next().map(|value| value.trim()).ok_or(invalid(String::from("expected `key = value`"))));
+            Line::Setting(key, value)
+        };
+        try!(apply(parsed).map_err(invalid));
+    }
+    return Ok(());
+}
+
+/// Parse @contents made of `[name]` lines, each followed by `key = value` settings.
+/// @section makes a new section from its name, and @set applies a setting to the latest one.
+pub fn parse_sections<T, N, S>(contents: &str, mut section: N, mut set: S) -> Result<Vec<T>>
+    where N: FnMut(&str) -> ::std::result::Result<T, String>, S: FnMut(&mut T, &str, &str) -> ::std::result::Result<(), String>{
+    let mut sections = Vec::new();
+    try!(parse_lines(contents, |line| match line{
+        Line::Section(name) => {
+            sections.push(try!(section(name)));
+            Ok(())
+        },
+        Line::Setting(key, value) => match sections.last_mut(){
+            Some(current) => set(current, key, value),
+            None => Err(String::from("expected `[name]` before any settings"))
+        }
+    }));
+    return Ok(sections);
+}
+
+/// Read the data file at @path and hand its contents to @parse, naming the file in any error it returns
+pub fn load_data<T, F>(path: &str, parse: F) -> Result<T> where F: FnOnce(&str) -> Result<T>{
+    let mut contents = String::new();
+    let mut file = try!(File::open(path));
+    try!(file.read_to_string(&mut contents));
+    return parse(&contents).map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e)));
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> ::std::result::Result<T, String>{
+    value.parse::<T>().map_err(|_| format!("Invalid value `{}` for `{}`", value, key))
+}
@@ -0,0 +1,105 @@
+extern crate log;
+
+use authoritative::ServerCommand;
+use bans::{BanEntry, BanTarget, unix_now};
+use entity::EntityKind;
+use state::Position;
+
+use mio::Sender;
+use std::io;
+use std::io::prelude::*;
+use std::thread;
+
+const HELP: &'static str = "Commands:
+    ban <ip|account> <target> [<seconds>|-] [reason]
+    unban <ip|account> <target>
+    bans
+    reload-bans
+    spawn <npc|item|projectile|door> <x> <y> <z>
+    despawn <id>
+    transfer <player id> <zone>
+    zones";
+
+/// Read admin commands from stdin on a background thread, and forward them to the server
+pub fn spawn(sender: Sender<ServerCommand>){
+    thread::spawn(move ||{
+        let stdin = io::stdin();
+        for line in stdin.lock().lines(){
+            let line = match line{
+                Ok(line) => line,
+                Err(_) => { break; }
+            };
+
+            if line.trim().is_empty(){
+                continue;
+            }
+
+            match parse_command(line.trim()){
+                Ok(command) => {
+                    if sender.send(command).is_err(){
+                        error!("Failed to send console command to the server");
+                    }
+                },
+                Err(e) => {
+                    println!("{}\n{}", e, HELP);
+                }
+            }
+        }
+    });
+}
+
+fn parse_command(line: &str) -> Result<ServerCommand, String>{
+    let mut parts = line.splitn(5, ' ');
+    match parts.next().unwrap_or(""){
+        "ban" => {
+            let target = try!(BanTarget::parse(parts.next().unwrap_or(""), parts.next().unwrap_or("")));
+            let expires = match parts.next(){
+                None | Some("-") => None,
+                Some(duration) => {
+                    let seconds = try!(duration.parse::<u64>().map_err(|_| format!("Invalid ban duration `{}`", duration)));
+                    Some(unix_now() + seconds)
+                }
+            };
+            let reason = String::from(parts.next().unwrap_or("No reason given"));
+
+            Ok(ServerCommand::Ban(BanEntry{ target: target, expires: expires, reason: reason }))
+        },
+        "unban" => {
+            let target = try!(BanTarget::parse(parts.next().unwrap_or(""), parts.next().unwrap_or("")));
+            Ok(ServerCommand::Unban(target))
+        },
+        "bans" => { Ok(ServerCommand::ListBans) },
+        "reload-bans" => { Ok(ServerCommand::ReloadBans) },
+        "spawn" => {
+            let kind = match parts.next().unwrap_or(""){
+                "npc" => EntityKind::Npc,
+                "item" => EntityKind::Item,
+                "projectile" => EntityKind::Projectile,
+                "door" => EntityKind::Door,
+                kind => { return Err(format!("Unknown entity kind `{}`", kind)); }
+            };
+
+            let mut coordinates = [0.0f32; 3];
+            for coordinate in coordinates.iter_mut(){
+                let value = parts.next().unwrap_or("");
+                *coordinate = try!(value.parse::<f32>().map_err(|_| format!("Invalid coordinate `{}`", value)));
+            }
+
+            Ok(ServerCommand::Spawn{ kind: kind, position: Position::new(coordinates[0], coordinates[1], coordinates[2]), components: Vec::new() })
+        },
+        "despawn" => {
+            let id = parts.next().unwrap_or("");
+            Ok(ServerCommand::Despawn(try!(id.parse::<u32>().map_err(|_| format!("Invalid entity ID `{}`", id)))))
+        },
+        "transfer" => {
+            let id = parts.next().unwrap_or("");
+            let id = try!(id.parse::<u32>().map_err(|_| format!("Invalid player ID `{}`", id)));
+            match parts.next(){
+                Some(zone) if !zone.trim().is_empty() => Ok(ServerCommand::Transfer{ id: id, zone: String::from(zone.trim()) }),
+                _ => Err(String::from("Expected a zone to transfer to"))
+            }
+        },
+        "zones" => { Ok(ServerCommand::ListZones) },
+        command => { Err(format!("Unknown command `{}`", command)) }
+    }
+}
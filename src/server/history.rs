@@ -0,0 +1,151 @@
+extern crate log;
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use state::{ClientState, GameState};
+
+/// How far behind the latest state they've received clients draw other entities, so they can interpolate between updates
+pub const DEFAULT_INTERPOLATION_DELAY_MS: u64 = 100;
+
+/// The furthest back the server rewinds the world to check a hit, by default
+pub const DEFAULT_MAX_REWIND_MS: u64 = 500;
+
+/// Every entity's transform at the end of a simulation step
+struct Snapshot{
+    tick: u64,
+    states: HashMap<u32, ClientState>
+}
+
+/// Entity transforms over the last few simulation steps,
+/// so hits can be checked against where things were when a client saw them
+pub struct StateHistory{
+    /// Oldest first
+    snapshots: VecDeque<Snapshot>,
+
+    /// Length of a simulation step
+    step: Duration,
+
+    /// Number of snapshots kept
+    capacity: usize
+}
+
+impl StateHistory{
+    /// A history of @step long simulation steps, going back as far as @max_rewind
+    pub fn new(step: Duration, max_rewind: Duration) -> StateHistory{
+        let capacity = (duration_ms(max_rewind) / ::std::cmp::max(duration_ms(step), 1)) as usize + 1;
+        StateHistory{
+            snapshots: VecDeque::with_capacity(capacity),
+            step: step,
+            capacity: capacity
+        }
+    }
+
+    /// Record the transform of every entity in @game_state at the end of simulation step @tick
+    pub fn record(&mut self, tick: u64, game_state: &GameState){
+        if self.snapshots.len() == self.capacity{
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(Snapshot{
+            tick: tick,
+            states: game_state.entities.iter().map(|(id, entity)| (*id, *entity.state())).collect()
+        });
+    }
+
+    /// The oldest tick the world can be rewound to
+    pub fn oldest_tick(&self) -> Option<u64>{
+        self.snapshots.front().map(|snapshot| snapshot.tick)
+    }
+
+    /// The latest tick recorded
+    pub fn latest_tick(&self) -> Option<u64>{
+        self.snapshots.back().map(|snapshot| snapshot.tick)
+    }
+
+    /// Every entity's transform at @tick, which may fall between steps.
+    /// Ticks outside the history are clamped to the oldest or latest recorded.
+    pub fn at(&self, tick: f64) -> Option<HashMap<u32, ClientState>>{
+        let (oldest, latest) = match (self.oldest_tick(), self.latest_tick()){
+            (Some(oldest), Some(latest)) => (oldest, latest),
+            _ => { return None; }
+        };
+        if tick < oldest as f64{
+            debug!("Can't rewind to tick {:.1}, only as far as {}", tick, oldest);
+        }
+        let tick = tick.max(oldest as f64).min(latest as f64);
+
+        let index = self.snapshots.iter().rposition(|snapshot| snapshot.tick as f64 <= tick).unwrap_or(0);
+        let earlier = &self.snapshots[index];
+        let later = match self.snapshots.get(index + 1){
+            Some(later) if tick > earlier.tick as f64 => later,
+            _ => { return Some(earlier.states.clone()); }
+        };
+
+        // Entities which were removed before the later step stay where they were last seen
+        let t = ((tick - earlier.tick as f64) / (later.tick - earlier.tick) as f64) as f32;
+        return Some(earlier.states.iter().map(|(id, state)|{
+            let state = match later.states.get(id){
+                Some(next) => state.interpolate(next, t),
+                None => *state
+            };
+            (*id, state)
+        }).collect());
+    }
+
+    /// What a client saw when it acted at @tick: the latest state it had received was @latency old,
+    /// and it drew other entities @interpolation_delay behind that
+    pub fn rewind(&self, tick: u64, latency: Duration, interpolation_delay: Duration) -> Option<HashMap<u32, ClientState>>{
+        let behind = duration_ms(latency + interpolation_delay) as f64 / ::std::cmp::max(duration_ms(self.step), 1) as f64;
+        return self.at(tick as f64 - behind);
+    }
+}
+
+fn duration_ms(duration: Duration) -> u64{
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1000000
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use entity::Entity;
+    use math::Scalar;
+    use state::{ClientState, GameState, Position};
+    use std::time::Duration;
+
+    fn world_at(x: f32) -> GameState{
+        let mut game_state = GameState::new();
+        let mut state = ClientState::new(7);
+        state.position = Position::new(x, 0.0, 0.0);
+        game_state.entities.insert(7, Entity::player(state));
+        return game_state;
+    }
+
+    #[test]
+    fn test_rewind(){
+        let mut history = StateHistory::new(Duration::from_millis(50), Duration::from_millis(200));
+        assert!(history.at(0.0).is_none());
+        for tick in 0..10{
+            history.record(tick, &world_at(tick as f32));
+        }
+
+        // Only the last 200ms are kept
+        assert_eq!((history.oldest_tick(), history.latest_tick()), (Some(5), Some(9)));
+
+        // Between steps, entities are interpolated
+        let states = history.at(6.5).unwrap();
+        assert!((states[&7].position.0.to_f32() - 6.5).abs() < 0.01);
+
+        // Acting at tick 9 with 50ms of latency and a 25ms interpolation delay saw 1.5 steps earlier
+        let states = history.rewind(9, Duration::from_millis(50), Duration::from_millis(25)).unwrap();
+        assert!((states[&7].position.0.to_f32() - 7.5).abs() < 0.01);
+
+        // Rewinding further than the window stops at its start
+        let states = history.rewind(9, Duration::from_secs(2), Duration::from_millis(100)).unwrap();
+        assert!((states[&7].position.0.to_f32() - 5.0).abs() < 0.01);
+
+        // Removed entities stay where they were last seen until they're gone
+        history.record(10, &GameState::new());
+        assert!((history.at(9.5).unwrap()[&7].position.0.to_f32() - 9.0).abs() < 0.01);
+        assert!(history.at(10.0).unwrap().is_empty());
+    }
+}
@@ -4,30 +4,112 @@ extern crate byteorder;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate lz4_flex;
+extern crate rustls;
+#[cfg(test)]
+extern crate rcgen;
 
 mod authoritative;
 mod client;
+mod config;
+mod bans;
+mod throttle;
+mod console;
+mod metrics;
+mod websocket;
+mod npc;
+mod navigation;
+mod physics;
+mod history;
+mod zone;
+mod scheduler;
 
 #[path="../shared/frame.rs"]
 mod frame;
 
+#[path="../shared/math.rs"]
+mod math;
+
 #[path="../shared/state.rs"]
 mod state;
+
+#[path="../shared/entity.rs"]
+mod entity;
+
+#[path="../shared/input.rs"]
+mod input;
+
+#[path="../shared/clock.rs"]
+mod clock;
 use state::ClientState;
 
+#[path="../shared/logging.rs"]
+mod logging;
+use logging::LogFormat;
+
+#[path="../shared/udp.rs"]
+mod udp;
+
+#[path="../shared/transport.rs"]
+mod transport;
+
+#[path="../shared/tls.rs"]
+mod tls;
+
+#[path="../shared/fragment.rs"]
+mod fragment;
+
+#[path="../shared/priority.rs"]
+mod priority;
+
 use authoritative::AuthoritativeServer;
+use config::ServerConfig;
+
+use env_logger::LogBuilder;
+use log::LogLevelFilter;
+use mio::EventLoop;
+use std::env;
+use std::io::ErrorKind;
+use std::process;
+
+const DEFAULT_CONFIG_PATH: &'static str = "lag-server.conf";
 
-use std::net::SocketAddr;
+/// Install a logger writing @format, filtered by RUST_LOG (info level by default)
+fn init_logging(format: LogFormat){
+    let mut builder = LogBuilder::new();
+    builder.format(move |record| format.format(record));
+
+    match env::var("RUST_LOG"){
+        Ok(filters) => { builder.parse(&filters); },
+        Err(_) => { builder.filter(None, LogLevelFilter::Info); }
+    }
+
+    builder.init().unwrap();
+}
 
 fn main(){
-    env_logger::init().unwrap();
+    // Use the config file given on the command line, or the default one if it exists
+    let config = match env::args().nth(1){
+        Some(path) => ServerConfig::load(&path).expect("Failed to load config file!"),
+        None => match ServerConfig::load(DEFAULT_CONFIG_PATH){
+            Ok(config) => config,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => ServerConfig::new(),
+            Err(e) => {
+                // Logging isn't set up until the config is known
+                eprintln!("Failed to load config file {}: {}", DEFAULT_CONFIG_PATH, e);
+                process::exit(1);
+            }
+        }
+    };
+
+    init_logging(config.log_format);
 
-    
-    let address_str = "0.0.0.0:6969";
-    let address: SocketAddr = address_str.parse::<SocketAddr>().expect("Failed to parse address!");
+    info!("Starting server on address {}", config.address);
+    let mut event_loop = EventLoop::new().expect("Failed to create server event loop!");
+    let mut server = AuthoritativeServer::new(config);
 
-    info!("Starting server on address {}", address_str);
-    let _ = AuthoritativeServer::new(address);
+    console::spawn(event_loop.channel());
+    server.run(&mut event_loop);
 
     info!("Done!");
 }
@@ -0,0 +1,270 @@
+extern crate log;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as FmtWrite;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use frame::{CompressionStats, MessageCode};
+
+/// Upper bounds, in seconds, of the tick duration histogram buckets
+const TICK_BUCKETS: [f64; 10] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5];
+
+/// A cumulative histogram in the Prometheus style
+pub struct Histogram{
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64
+}
+
+impl Histogram{
+    pub fn new(bounds: &'static [f64]) -> Histogram{
+        Histogram{
+            bounds: bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0
+        }
+    }
+
+    pub fn observe(&mut self, value: f64){
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()){
+            if value <= *bound{
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, name: &str, output: &mut String){
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()){
+            let _ = writeln!(output, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(output, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(output, "{}_sum {}", name, self.sum);
+        let _ = writeln!(output, "{}_count {}", name, self.count);
+    }
+}
+
+/// Counters and gauges describing the running server
+pub struct Metrics{
+    pub connected_clients: usize,
+    messages_in: HashMap<MessageCode, u64>,
+    messages_out: HashMap<MessageCode, u64>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub batches_out: u64,
+    tick_duration: Histogram,
+    send_queue_depth: BTreeMap<usize, usize>,
+    pub decode_errors: u64,
+
+    /// Bulk messages dropped because a client's send queue had fallen behind
+    pub dropped_messages: u64,
+    disconnects: BTreeMap<String, u64>,
+    compression: CompressionStats
+}
+
+impl Metrics{
+    pub fn new() -> Metrics{
+        Metrics{
+            connected_clients: 0,
+            messages_in: HashMap::new(),
+            messages_out: HashMap::new(),
+            bytes_in: 0,
+            bytes_out: 0,
+            batches_out: 0,
+            tick_duration: Histogram::new(&TICK_BUCKETS),
+            send_queue_depth: BTreeMap::new(),
+            decode_errors: 0,
+            dropped_messages: 0,
+            disconnects: BTreeMap::new(),
+            compression: CompressionStats::new()
+        }
+    }
+
+    pub fn message_in(&mut self, code: MessageCode, bytes: usize){
+        *self.messages_in.entry(code).or_insert(0) += 1;
+        self.bytes_in += bytes as u64;
+    }
+
+    pub fn message_out(&mut self, code: MessageCode, bytes: usize){
+        *self.messages_out.entry(code).or_insert(0) += 1;
+        self.bytes_out += bytes as u64;
+    }
+
+    /// Record a Batch frame carrying messages with the given codes
+    pub fn batch_out(&mut self, contents: &[MessageCode], bytes: usize){
+        for code in contents{
+            *self.messages_out.entry(code.clone()).or_insert(0) += 1;
+        }
+        self.bytes_out += bytes as u64;
+        self.batches_out += 1;
+    }
+
+    pub fn tick_duration(&mut self, duration: Duration){
+        let seconds = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0;
+        self.tick_duration.observe(seconds);
+    }
+
+    /// Record the send queue depth of the client with the given token
+    pub fn send_queue_depth(&mut self, token: usize, depth: usize){
+        self.send_queue_depth.insert(token, depth);
+    }
+
+    /// Forget the per-client gauges of a client which has gone away
+    pub fn client_removed(&mut self, token: usize){
+        self.send_queue_depth.remove(&token);
+    }
+
+    /// Count a closed connection, labelled with why it was closed
+    pub fn disconnect(&mut self, reason: &str){
+        *self.disconnects.entry(String::from(reason)).or_insert(0) += 1;
+    }
+
+    /// Record the payload size of an outgoing @code message before and after compression
+    pub fn compressed(&mut self, code: MessageCode, uncompressed_length: usize, compressed_length: usize){
+        self.compression.record(code, uncompressed_length, compressed_length);
+    }
+
+    /// Render every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String{
+        let mut output = String::new();
+
+        let _ = writeln!(output, "# TYPE lag_connected_clients gauge");
+        let _ = writeln!(output, "lag_connected_clients {}", self.connected_clients);
+
+        Self::render_codes(&mut output, "lag_messages_in_total", &self.messages_in);
+        Self::render_codes(&mut output, "lag_messages_out_total", &self.messages_out);
+
+        let _ = writeln!(output, "# TYPE lag_bytes_in_total counter");
+        let _ = writeln!(output, "lag_bytes_in_total {}", self.bytes_in);
+        let _ = writeln!(output, "# TYPE lag_bytes_out_total counter");
+        let _ = writeln!(output, "lag_bytes_out_total {}", self.bytes_out);
+        let _ = writeln!(output, "# TYPE lag_batches_out_total counter");
+        let _ = writeln!(output, "lag_batches_out_total {}", self.batches_out);
+
+        let _ = writeln!(output, "# TYPE lag_tick_duration_seconds histogram");
+        self.tick_duration.render("lag_tick_duration_seconds", &mut output);
+
+        let _ = writeln!(output, "# TYPE lag_send_queue_depth gauge");
+        for (token, depth) in self.send_queue_depth.iter(){
+            let _ = writeln!(output, "lag_send_queue_depth{{token=\"{}\"}} {}", token, depth);
+        }
+
+        let _ = writeln!(output, "# TYPE lag_decode_errors_total counter");
+        let _ = writeln!(output, "lag_decode_errors_total {}", self.decode_errors);
+        let _ = writeln!(output, "# TYPE lag_dropped_messages_total counter");
+        let _ = writeln!(output, "lag_dropped_messages_total {}", self.dropped_messages);
+
+        let _ = writeln!(output, "# TYPE lag_disconnects_total counter");
+        for (reason, count) in self.disconnects.iter(){
+            let _ = writeln!(output, "lag_disconnects_total{{reason=\"{}\"}} {}", reason, count);
+        }
+
+        let totals = self.compression.totals();
+        let _ = writeln!(output, "# TYPE lag_compression_input_bytes_total counter");
+        for &(ref code, uncompressed, _) in totals.iter(){
+            let _ = writeln!(output, "lag_compression_input_bytes_total{{code=\"{:?}\"}} {}", code, uncompressed);
+        }
+        let _ = writeln!(output, "# TYPE lag_compression_output_bytes_total counter");
+        for &(ref code, _, compressed) in totals.iter(){
+            let _ = writeln!(output, "lag_compression_output_bytes_total{{code=\"{:?}\"}} {}", code, compressed);
+        }
+        let _ = writeln!(output, "# TYPE lag_compression_ratio gauge");
+        for &(ref code, _, _) in totals.iter(){
+            if let Some(ratio) = self.compression.ratio(code.clone()){
+                let _ = writeln!(output, "lag_compression_ratio{{code=\"{:?}\"}} {}", code, ratio);
+            }
+        }
+
+        return output;
+    }
+
+    fn render_codes(output: &mut String, name: &str, counts: &HashMap<MessageCode, u64>){
+        // Sort by code so the output is stable between scrapes
+        let mut counts = counts.iter().collect::<Vec<_>>();
+        counts.sort_by_key(|&(code, _)| code.clone() as u8);
+
+        let _ = writeln!(output, "# TYPE {} counter", name);
+        for (code, count) in counts{
+            let _ = writeln!(output, "{}{{code=\"{:?}\"}} {}", name, code, count);
+        }
+    }
+}
+
+/// Serve @metrics over HTTP on localhost:@port from a background thread
+pub fn serve(metrics: Arc<Mutex<Metrics>>, port: u16){
+    let address = SocketAddr::from(([127, 0, 0, 1], port));
+    let listener = match TcpListener::bind(address){
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to start metrics endpoint on {}: {:?}", address, e);
+            return;
+        }
+    };
+
+    info!("Serving metrics on http://{}/metrics", address);
+    thread::spawn(move ||{
+        for stream in listener.incoming(){
+            match stream{
+                Ok(stream) => { respond(stream, &metrics); },
+                Err(e) => { warn!("Failed to accept metrics connection: {:?}", e); }
+            }
+        }
+    });
+}
+
+fn respond(mut stream: TcpStream, metrics: &Arc<Mutex<Metrics>>){
+    stream.set_read_timeout(Some(Duration::from_secs(5))).ok();
+
+    // Only the request line matters, e.g. `GET /metrics HTTP/1.1`
+    let mut request_line = String::new();
+    if BufReader::new(&stream).read_line(&mut request_line).is_err(){
+        return;
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = if path == "/metrics" || path == "/"{
+        match metrics.lock(){
+            Ok(metrics) => ("200 OK", metrics.render()),
+            Err(_) => ("500 Internal Server Error", String::new())
+        }
+    }
+    else{
+        ("404 Not Found", String::new())
+    };
+
+    let response = format!("HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+    stream.write_all(response.as_bytes()).ok();
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use frame::MessageCode;
+
+    #[test]
+    fn test_render(){
+        let mut metrics = Metrics::new();
+        metrics.message_in(MessageCode::Text, 13);
+        metrics.tick_duration(Duration::from_millis(3));
+        metrics.disconnect("Banned");
+        metrics.compressed(MessageCode::GameStateUpdate, 1000, 250);
+
+        let output = metrics.render();
+
+        assert!(output.contains("lag_messages_in_total{code=\"Text\"} 1\n"));
+        assert!(output.contains("lag_bytes_in_total 13\n"));
+        assert!(output.contains("lag_tick_duration_seconds_bucket{le=\"0.0025\"} 0\n"));
+        assert!(output.contains("lag_tick_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(output.contains("lag_disconnects_total{reason=\"Banned\"} 1\n"));
+        assert!(output.contains("lag_compression_input_bytes_total{code=\"GameStateUpdate\"} 1000\n"));
+        assert!(output.contains("lag_compression_ratio{code=\"GameStateUpdate\"} 0.25\n"));
+    }
+}
@@ -0,0 +1,330 @@
+extern crate log;
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::io::{ErrorKind, Result, Error};
+
+use config::load_data;
+use math::{Scalar, Unit};
+use state::Position;
+
+/// Path queries answered each simulation step, unless configured otherwise
+pub const DEFAULT_PATH_QUERIES_PER_STEP: usize = 16;
+
+/// Cost of moving diagonally between cells, relative to moving straight
+const DIAGONAL_COST: f32 = ::std::f32::consts::SQRT_2;
+
+/// A grid of walkable and blocked cells over the ground plane (X and Z)
+#[derive(Clone, Debug)]
+pub struct NavGrid{
+    /// World position of the corner of cell (0, 0)
+    pub origin: Position,
+
+    /// Width and depth of each cell, in world units
+    pub cell_size: f32,
+
+    pub width: usize,
+    pub depth: usize,
+    walkable: Vec<bool>
+}
+
+impl NavGrid{
+    /// A grid of @width by @depth cells, all walkable
+    pub fn new(origin: Position, cell_size: f32, width: usize, depth: usize) -> NavGrid{
+        NavGrid{
+            origin: origin,
+            cell_size: cell_size,
+            width: width,
+            depth: depth,
+            walkable: vec![true; width * depth]
+        }
+    }
+
+    /// Load the grid in the data file at @path
+    pub fn load(path: &str) -> Result<NavGrid>{
+        load_data(path, NavGrid::parse)
+    }
+
+    /// Parse the grid in @contents.
+    /// `key = value` settings are followed by one line per row of cells, `.` walkable and `X` blocked.
+    pub fn parse(contents: &str) -> Result<NavGrid>{
+        let mut origin = Position::zero();
+        let mut cell_size = 1.0;
+        let mut rows: Vec<Vec<bool>> = Vec::new();
+        for (line_number, line) in contents.lines().enumerate(){
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#'){
+                continue;
+            }
+            let invalid = |message: String| Error::new(ErrorKind::InvalidData, format!("line {}: {}", line_number + 1, message));
+
+            if line.contains('='){
+                let mut parts = line.splitn(2, '=');
+                let key = parts.next().unwrap_or("").trim();
+                let value = parts.next().unwrap_or("").trim();
+                match key{
+                    "origin" => {
+                        let coordinates: Vec<f32> = value.split_whitespace().filter_map(|coordinate| coordinate.parse::<f32>().ok()).collect();
+                        if coordinates.len() != 3{
+                            return Err(invalid(format!("Invalid origin `{}`, expected `<x> <y> <z>`", value)));
+                        }
+                        origin = Position::new(coordinates[0], coordinates[1], coordinates[2]);
+                    },
+                    "cell_size" => {
+                        cell_size = try!(value.parse::<f32>().ok().filter(|size| *size > 0.0).ok_or(invalid(format!("Invalid cell size `{}`", value))));
+                    },
+                    _ => { return Err(invalid(format!("Unknown setting `{}`", key))); }
+                }
+                continue;
+            }
+
+            let row = try!(line.chars().map(|cell| match cell{
+                '.' => Ok(true),
+                'X' => Ok(false),
+                other => Err(invalid(format!("Unknown cell `{}`, expected `.` or `X`", other)))
+            }).collect::<Result<Vec<bool>>>());
+            if rows.first().map_or(false, |first| first.len() != row.len()){
+                return Err(invalid(String::from("Every row must be the same width")));
+            }
+            rows.push(row);
+        }
+
+        let width = rows.first().map_or(0, |row| row.len());
+        let mut grid = NavGrid::new(origin, cell_size, width, rows.len());
+        grid.walkable = rows.into_iter().flat_map(|row| row.into_iter()).collect();
+        return Ok(grid);
+    }
+
+    /// Mark the cell at (@x, @z) as walkable or blocked
+    pub fn set_walkable(&mut self, x: usize, z: usize, walkable: bool){
+        if x < self.width && z < self.depth{
+            self.walkable[z * self.width + x] = walkable;
+        }
+    }
+
+    /// The cell containing @position, if it is on the grid
+    pub fn cell_at(&self, position: Position) -> Option<(usize, usize)>{
+        let x = ((position.0 - self.origin.0).to_f32() / self.cell_size).floor();
+        let z = ((position.2 - self.origin.2).to_f32() / self.cell_size).floor();
+        if x < 0.0 || z < 0.0 || x >= self.width as f32 || z >= self.depth as f32{
+            return None;
+        }
+        return Some((x as usize, z as usize));
+    }
+
+    /// The world position of the centre of the cell at (@x, @z), at the height of @y
+    pub fn cell_centre(&self, x: usize, z: usize, y: Unit) -> Position{
+        Position(
+            self.origin.0 + Unit::from_f32((x as f32 + 0.5) * self.cell_size),
+            y,
+            self.origin.2 + Unit::from_f32((z as f32 + 0.5) * self.cell_size)
+        )
+    }
+
+    /// Return TRUE if @position is on the grid, in a walkable cell
+    pub fn is_walkable(&self, position: Position) -> bool{
+        self.cell_at(position).map_or(false, |(x, z)| self.is_walkable_cell(x as isize, z as isize))
+    }
+
+    fn is_walkable_cell(&self, x: isize, z: isize) -> bool{
+        x >= 0 && z >= 0 && (x as usize) < self.width && (z as usize) < self.depth && self.walkable[z as usize * self.width + x as usize]
+    }
+
+    /// The shortest walkable path from @from to @to, as waypoints ending at @to.
+    /// Returns None if either end is blocked or off the grid, or no path exists.
+    pub fn find_path(&self, from: Position, to: Position) -> Option<Vec<Position>>{
+        let start = match self.cell_at(from){ Some(cell) if self.is_walkable(from) => cell, _ => { return None; } };
+        let goal = match self.cell_at(to){ Some(cell) if self.is_walkable(to) => cell, _ => { return None; } };
+
+        let heuristic = |(x, z): (usize, usize)|{
+            let dx = (x as f32 - goal.0 as f32).abs();
+            let dz = (z as f32 - goal.1 as f32).abs();
+            (dx - dz).abs() + dx.min(dz) * DIAGONAL_COST
+        };
+
+        let mut open = BinaryHeap::new();
+        let mut came_from: HashMap<(usize, usize), (usize, usize)> = HashMap::new();
+        let mut cost: HashMap<(usize, usize), f32> = HashMap::new();
+        cost.insert(start, 0.0);
+        open.push(OpenCell{ cell: start, estimate: heuristic(start) });
+
+        while let Some(OpenCell{ cell, .. }) = open.pop(){
+            if cell == goal{
+                let mut cells = vec![cell];
+                while let Some(previous) = came_from.get(cells.last().unwrap()){
+                    cells.push(*previous);
+                }
+                cells.pop();
+                cells.reverse();
+
+                let mut path: Vec<Position> = cells.into_iter().map(|(x, z)| self.cell_centre(x, z, to.1)).collect();
+                path.pop();
+                path.push(to);
+                return Some(path);
+            }
+
+            let cell_cost = cost[&cell];
+            for &(dx, dz) in [(-1, 0), (1, 0), (0, -1), (0, 1), (-1, -1), (-1, 1), (1, -1), (1, 1)].iter(){
+                let (x, z) = (cell.0 as isize + dx, cell.1 as isize + dz);
+                if !self.is_walkable_cell(x, z){
+                    continue;
+                }
+                // Diagonal moves may not cut the corner of a blocked cell
+                if dx != 0 && dz != 0 && !(self.is_walkable_cell(cell.0 as isize + dx, cell.1 as isize) && self.is_walkable_cell(cell.0 as isize, cell.1 as isize + dz)){
+                    continue;
+                }
+
+                let neighbour = (x as usize, z as usize);
+                let neighbour_cost = cell_cost + if dx != 0 && dz != 0 { DIAGONAL_COST } else { 1.0 };
+                if cost.get(&neighbour).map_or(true, |existing| neighbour_cost < *existing){
+                    cost.insert(neighbour, neighbour_cost);
+                    came_from.insert(neighbour, cell);
+                    open.push(OpenCell{ cell: neighbour, estimate: neighbour_cost + heuristic(neighbour) });
+                }
+            }
+        }
+        return None;
+    }
+}
+
+/// A cell waiting to be explored, ordered so the lowest estimate is popped first
+struct OpenCell{
+    cell: (usize, usize),
+    estimate: f32
+}
+
+impl PartialEq for OpenCell{
+    fn eq(&self, other: &OpenCell) -> bool{ self.estimate == other.estimate }
+}
+
+impl Eq for OpenCell{}
+
+impl PartialOrd for OpenCell{
+    fn partial_cmp(&self, other: &OpenCell) -> Option<Ordering>{ Some(self.cmp(other)) }
+}
+
+impl Ord for OpenCell{
+    fn cmp(&self, other: &OpenCell) -> Ordering{
+        other.estimate.partial_cmp(&self.estimate).unwrap_or(Ordering::Equal)
+    }
+}
+
+/// A request for a path, made on behalf of the entity @requester
+struct PathQuery{
+    requester: u32,
+    from: Position,
+    to: Position
+}
+
+/// Answers path queries on a NavGrid, at most `queries_per_step` each simulation step
+pub struct Pathfinder{
+    pub grid: NavGrid,
+    pending: VecDeque<PathQuery>,
+    results: HashMap<u32, (Position, Option<Vec<Position>>)>,
+    pub queries_per_step: usize
+}
+
+impl Pathfinder{
+    pub fn new(grid: NavGrid) -> Pathfinder{
+        Pathfinder{
+            grid: grid,
+            pending: VecDeque::new(),
+            results: HashMap::new(),
+            queries_per_step: DEFAULT_PATH_QUERIES_PER_STEP
+        }
+    }
+
+    /// Queue a query for a path from @from to @to for @requester, replacing any it already has queued
+    pub fn request(&mut self, requester: u32, from: Position, to: Position){
+        self.pending.retain(|query| query.requester != requester);
+        self.pending.push_back(PathQuery{ requester: requester, from: from, to: to });
+    }
+
+    /// Answer queued queries, up to the per-step budget. Returns how many were answered.
+    pub fn run(&mut self) -> usize{
+        let mut answered = 0;
+        while answered < self.queries_per_step{
+            let query = match self.pending.pop_front(){
+                Some(query) => query,
+                None => { break; }
+            };
+            let path = self.grid.find_path(query.from, query.to);
+            if path.is_none(){
+                debug!("No path for {} from {:?} to {:?}", query.requester, query.from, query.to);
+            }
+            self.results.insert(query.requester, (query.to, path));
+            answered += 1;
+        }
+
+        if !self.pending.is_empty(){
+            trace!("Path query budget spent, {} queries waiting", self.pending.len());
+        }
+        return answered;
+    }
+
+    /// The answer to @requester's latest query, as the goal and the path to it, if it has been answered
+    pub fn take_result(&mut self, requester: u32) -> Option<(Position, Option<Vec<Position>>)>{
+        self.results.remove(&requester)
+    }
+
+    /// Forget any queries and answers for @requester
+    pub fn cancel(&mut self, requester: u32){
+        self.pending.retain(|query| query.requester != requester);
+        self.results.remove(&requester);
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use state::Position;
+
+    fn load(rows: &str) -> NavGrid{
+        NavGrid::parse(&format!("origin = -1 0 -1\ncell_size = 2\n{}", rows)).unwrap()
+    }
+
+    #[test]
+    fn test_find_path(){
+        // A room open only on the right
+        let grid = load("# Test room\n.....\n.XXX.\n.X...\n.XXX.\n.....\n");
+        assert_eq!((grid.width, grid.depth), (5, 5));
+        assert!(grid.is_walkable(Position::new(0.0, 0.0, 0.0)));
+        assert!(!grid.is_walkable(Position::new(2.0, 0.0, 2.0)));
+        assert!(!grid.is_walkable(Position::new(-2.0, 0.0, 0.0)));
+
+        let from = Position::new(4.0, 0.0, 4.0);
+        let to = Position::new(0.5, 0.0, 8.5);
+        let path = grid.find_path(from, to).unwrap();
+        assert_eq!(*path.last().unwrap(), to);
+        assert!(path.iter().all(|waypoint| grid.is_walkable(*waypoint)));
+
+        // Out of the room, then around its wall
+        let length = path.iter().fold((0.0, from), |(length, last), waypoint| (length + last.distance(*waypoint).to_f32(), *waypoint)).0;
+        assert_eq!(path.len(), 8);
+        assert!(length > 12.0, "Path of {} went through a wall", length);
+
+        assert!(grid.find_path(from, Position::new(2.0, 0.0, 2.0)).is_none());
+        assert!(grid.find_path(from, Position::new(100.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn test_query_budget(){
+        let mut pathfinder = Pathfinder::new(NavGrid::new(Position::zero(), 1.0, 10, 10));
+        pathfinder.queries_per_step = 2;
+        for requester in 0..3{
+            pathfinder.request(requester, Position::new(0.5, 0.0, 0.5), Position::new(9.5, 0.0, 9.5));
+        }
+        pathfinder.request(0, Position::new(0.5, 0.0, 0.5), Position::new(0.5, 0.0, 5.5));
+
+        assert_eq!(pathfinder.run(), 2);
+        assert!(pathfinder.take_result(0).is_none());
+        assert_eq!(pathfinder.take_result(1).unwrap().1.unwrap().len(), 9);
+        assert_eq!(pathfinder.run(), 1);
+        assert_eq!(pathfinder.run(), 0);
+
+        // Only the latest query from a requester is answered
+        let (goal, path) = pathfinder.take_result(0).unwrap();
+        assert_eq!(goal, Position::new(0.5, 0.0, 5.5));
+        assert_eq!(path.unwrap().len(), 5);
+    }
+}
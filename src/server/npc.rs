@@ -0,0 +1,476 @@
+extern crate log;
+
+use std::collections::VecDeque;
+use std::io::Result;
+
+use config::{load_data, parse_sections};
+use math::{Scalar, Unit};
+use state::{ClientState, Position, Rotation, Velocity};
+use entity::Component;
+
+/// NPCs within this distance of where they're heading have arrived
+const ARRIVAL_DISTANCE: f32 = 0.05;
+
+/// NPCs give up on a player once they're this many times their reaction radius away
+const LEASH_FACTOR: f32 = 1.5;
+
+/// NPCs ask for a new route once where they're heading is this far from the end of their current one
+const REPATH_DISTANCE: f32 = 1.0;
+
+/// Default movement speed, in world units per second
+const DEFAULT_SPEED: f32 = 2.0;
+
+/// What an NPC does when no player has its attention
+#[derive(Clone, Debug, PartialEq)]
+pub enum Behavior{
+    /// Stand still
+    Idle,
+
+    /// Walk to random points within the box between two corners
+    Wander{ min: Position, max: Position },
+
+    /// Walk between waypoints in order, looping back to the first
+    Patrol(Vec<Position>)
+}
+
+/// What an NPC does when a player comes within @radius of it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reaction{
+    Chase{ radius: f32 },
+    Flee{ radius: f32 }
+}
+
+impl Reaction{
+    fn radius(&self) -> f32{
+        match *self{
+            Reaction::Chase{ radius } | Reaction::Flee{ radius } => radius
+        }
+    }
+}
+
+/// An NPC, as described in an NPC data file
+#[derive(Clone, Debug, PartialEq)]
+pub struct NpcDefinition{
+    pub name: String,
+    pub position: Position,
+    pub speed: f32,
+    pub behavior: Behavior,
+    pub reaction: Option<Reaction>,
+    pub components: Vec<Component>
+}
+
+impl NpcDefinition{
+    pub fn new(name: &str) -> NpcDefinition{
+        NpcDefinition{
+            name: String::from(name),
+            position: Position::zero(),
+            speed: DEFAULT_SPEED,
+            behavior: Behavior::Idle,
+            reaction: None,
+            components: Vec::new()
+        }
+    }
+
+    /// Load every NPC in the data file at @path
+    pub fn load_all(path: &str) -> Result<Vec<NpcDefinition>>{
+        load_data(path, NpcDefinition::parse_all)
+    }
+
+    /// Parse every NPC in @contents.
+    /// Each NPC starts with a `[name]` line, followed by `key = value` settings.
+    pub fn parse_all(contents: &str) -> Result<Vec<NpcDefinition>>{
+        parse_sections(contents, |name| Ok(NpcDefinition::new(name)), NpcDefinition::set)
+    }
+
+    /// Apply a single setting.
+    fn set(&mut self, key: &str, value: &str) -> ::std::result::Result<(), String>{
+        match key{
+            "position" => { self.position = try!(parse_position(value)); },
+            "speed"    => { self.speed = try!(value.parse::<f32>().map_err(|_| format!("Invalid speed `{}`", value))); },
+            "behavior" => { self.behavior = try!(parse_behavior(value)); },
+            "reaction" => { self.reaction = try!(parse_reaction(value)); },
+            "health"   => {
+                let health = try!(value.parse::<u16>().map_err(|_| format!("Invalid health `{}`", value)));
+                self.components.push(Component::Health{ current: health, maximum: health });
+            },
+            "damage"   => {
+                let damage = try!(value.parse::<u16>().map_err(|_| format!("Invalid damage `{}`", value)));
+                self.components.push(Component::Damage(damage));
+            },
+            _ => { return Err(format!("Unknown setting `{}`", key)); }
+        }
+        Ok(())
+    }
+}
+
+/// Parse a position of the form `<x> <y> <z>`
+pub fn parse_position(value: &str) -> ::std::result::Result<Position, String>{
+    let coordinates: Vec<f32> = try!(value.split_whitespace()
+        .map(|coordinate| coordinate.parse::<f32>())
+        .collect::<::std::result::Result<Vec<f32>, _>>()
+        .map_err(|_| format!("Invalid position `{}`", value)));
+
+    if coordinates.len() != 3{
+        return Err(format!("Invalid position `{}`, expected `<x> <y> <z>`", value));
+    }
+    Ok(Position::new(coordinates[0], coordinates[1], coordinates[2]))
+}
+
+/// Parse `idle`, `wander <corner>; <corner>` or `patrol <waypoint>; <waypoint>...`
+fn parse_behavior(value: &str) -> ::std::result::Result<Behavior, String>{
+    let mut parts = value.splitn(2, char::is_whitespace);
+    let kind = parts.next().unwrap_or("");
+    let positions = try!(parts.next().unwrap_or("").split(';')
+        .filter(|position| !position.trim().is_empty())
+        .map(parse_position)
+        .collect::<::std::result::Result<Vec<Position>, String>>());
+
+    match (kind, positions.len()){
+        ("idle", 0) => Ok(Behavior::Idle),
+        ("wander", 2) => {
+            let (a, b) = (positions[0], positions[1]);
+            Ok(Behavior::Wander{
+                min: Position(min_unit(a.0, b.0), min_unit(a.1, b.1), min_unit(a.2, b.2)),
+                max: Position(max_unit(a.0, b.0), max_unit(a.1, b.1), max_unit(a.2, b.2))
+            })
+        },
+        ("patrol", count) if count > 0 => Ok(Behavior::Patrol(positions)),
+        _ => Err(format!("Invalid behavior `{}`, expected `idle`, `wander <corner>; <corner>` or `patrol <waypoint>; ...`", value))
+    }
+}
+
+/// Parse `none`, `chase <radius>` or `flee <radius>`
+fn parse_reaction(value: &str) -> ::std::result::Result<Option<Reaction>, String>{
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    let radius = || parts.get(1).and_then(|radius| radius.parse::<f32>().ok()).ok_or(format!("Invalid reaction radius in `{}`", value));
+
+    match parts.get(0).cloned(){
+        Some("none") => Ok(None),
+        Some("chase") => Ok(Some(Reaction::Chase{ radius: try!(radius()) })),
+        Some("flee") => Ok(Some(Reaction::Flee{ radius: try!(radius()) })),
+        _ => Err(format!("Invalid reaction `{}`, expected `none`, `chase <radius>` or `flee <radius>`", value))
+    }
+}
+
+fn min_unit(a: Unit, b: Unit) -> Unit{ if a < b { a } else { b } }
+fn max_unit(a: Unit, b: Unit) -> Unit{ if a > b { a } else { b } }
+
+/// What an NPC is doing right now
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NpcState{
+    /// Following its behavior
+    Default,
+
+    /// Running toward the player entity with this ID
+    Chasing(u32),
+
+    /// Running away from the player entity with this ID
+    Fleeing(u32)
+}
+
+/// The server-side brain of an NPC entity
+#[derive(Clone, Debug)]
+pub struct Npc{
+    pub entity_id: u32,
+    pub state: NpcState,
+    speed: f32,
+    behavior: Behavior,
+    reaction: Option<Reaction>,
+
+    /// Where the NPC is heading while wandering or patrolling
+    destination: Option<Position>,
+
+    /// The waypoint the NPC is patrolling toward
+    waypoint: usize,
+
+    /// Whether the NPC walks routes found on the navigation grid, rather than in straight lines
+    pub navigating: bool,
+
+    /// The rest of the route the NPC is walking, nearest first
+    route: VecDeque<Position>,
+
+    /// Where the route leads
+    route_goal: Option<Position>,
+
+    /// Where the NPC last asked for a route to, until the route arrives
+    requested_goal: Option<Position>,
+
+    /// A route the NPC needs, not yet passed on to the pathfinder
+    path_request: Option<Position>,
+
+    /// State of the random number generator picking where to wander
+    seed: u32
+}
+
+impl Npc{
+    pub fn new(entity_id: u32, definition: &NpcDefinition) -> Npc{
+        Npc{
+            entity_id: entity_id,
+            state: NpcState::Default,
+            speed: definition.speed,
+            behavior: definition.behavior.clone(),
+            reaction: definition.reaction,
+            destination: None,
+            waypoint: 0,
+            navigating: false,
+            route: VecDeque::new(),
+            route_goal: None,
+            requested_goal: None,
+            path_request: None,
+            seed: entity_id.wrapping_mul(2654435761) | 1
+        }
+    }
+
+    /// Advance the NPC at @current by @dt seconds, given the positions of every player.
+    /// Returns the NPC's new state.
+    pub fn update(&mut self, current: &ClientState, players: &[(u32, Position)], dt: f32) -> ClientState{
+        self.react(current.position, players);
+
+        let target = match self.state{
+            NpcState::Chasing(id) => find_player(players, id),
+            NpcState::Fleeing(id) => find_player(players, id).map(|player| current.position + (current.position - player)),
+            NpcState::Default => self.next_destination(current.position)
+        };
+        let target = match self.navigating{
+            true => target.and_then(|goal| self.follow_route(current.position, goal)),
+            false => target
+        };
+
+        let mut next = *current;
+        next.velocity = Velocity::zero();
+        if let Some(target) = target{
+            let offset = target - current.position;
+            let distance = offset.length().to_f32();
+            let step = self.speed * dt;
+
+            if distance > 0.0 && dt > 0.0{
+                let travelled = if step < distance { step } else { distance };
+                next.velocity = offset.normalize() * Unit::from_f32(travelled / dt);
+                next.position = current.position + offset.normalize() * Unit::from_f32(travelled);
+                next.rotation = Rotation::from_degrees(offset.0.to_f32().atan2(offset.2.to_f32()).to_degrees());
+            }
+        }
+        return next;
+    }
+
+    /// Where to head next on the way to @goal from @position, asking for a new route if the current one leads elsewhere
+    fn follow_route(&mut self, position: Position, goal: Position) -> Option<Position>{
+        let is_near = |other: Option<Position>| other.map_or(false, |other| other.distance(goal).to_f32() <= REPATH_DISTANCE);
+        if !is_near(self.route_goal) && !is_near(self.requested_goal){
+            self.requested_goal = Some(goal);
+            self.path_request = Some(goal);
+        }
+
+        while self.route.front().map_or(false, |waypoint| waypoint.distance(position).to_f32() <= ARRIVAL_DISTANCE){
+            self.route.pop_front();
+        }
+        return self.route.front().cloned();
+    }
+
+    /// Take the goal the NPC needs a route to, if it needs a new one
+    pub fn take_path_request(&mut self) -> Option<Position>{
+        self.path_request.take()
+    }
+
+    /// Walk @path to @goal, or find somewhere else to go if there is no path
+    pub fn set_route(&mut self, goal: Position, path: Option<Vec<Position>>){
+        if self.requested_goal == Some(goal){
+            self.requested_goal = None;
+        }
+        self.route_goal = Some(goal);
+
+        match path{
+            Some(path) => { self.route = path.into_iter().collect(); },
+            None => {
+                debug!("NPC {} can't reach {:?}", self.entity_id, goal);
+                self.route.clear();
+                if self.state == NpcState::Default{
+                    if let Behavior::Patrol(ref waypoints) = self.behavior{
+                        self.waypoint = (self.waypoint + 1) % waypoints.len();
+                    }
+                    self.destination = None;
+                }
+            }
+        }
+    }
+
+    /// Notice players coming within the reaction radius, and lose interest in those who get away
+    fn react(&mut self, position: Position, players: &[(u32, Position)]){
+        let reaction = match self.reaction{
+            Some(reaction) => reaction,
+            None => { return; }
+        };
+
+        match self.state{
+            NpcState::Chasing(id) | NpcState::Fleeing(id) => {
+                let escaped = find_player(players, id).map_or(true, |player| player.distance(position).to_f32() > reaction.radius() * LEASH_FACTOR);
+                if !escaped{
+                    return;
+                }
+                debug!("NPC {} lost interest in player {}", self.entity_id, id);
+                self.state = NpcState::Default;
+            },
+            NpcState::Default => {}
+        }
+
+        let nearest = players.iter()
+            .map(|&(id, player)| (id, player.distance(position).to_f32()))
+            .filter(|&(_, distance)| distance <= reaction.radius())
+            .fold(None, |nearest: Option<(u32, f32)>, (id, distance)| match nearest{
+                Some((_, nearest_distance)) if nearest_distance <= distance => nearest,
+                _ => Some((id, distance))
+            });
+
+        if let Some((id, _)) = nearest{
+            debug!("NPC {} noticed player {}", self.entity_id, id);
+            self.state = match reaction{
+                Reaction::Chase{ .. } => NpcState::Chasing(id),
+                Reaction::Flee{ .. } => NpcState::Fleeing(id)
+            };
+        }
+    }
+
+    /// Where the NPC's behavior has it heading from @position
+    fn next_destination(&mut self, position: Position) -> Option<Position>{
+        let arrived = self.destination.map_or(true, |destination| destination.distance(position).to_f32() <= ARRIVAL_DISTANCE);
+        if !arrived{
+            return self.destination;
+        }
+
+        self.destination = match self.behavior{
+            Behavior::Idle => None,
+            Behavior::Wander{ min, max } => {
+                let x = self.random_between(min.0, max.0);
+                let y = self.random_between(min.1, max.1);
+                let z = self.random_between(min.2, max.2);
+                Some(Position(x, y, z))
+            },
+            Behavior::Patrol(ref waypoints) => {
+                if self.destination.is_some(){
+                    self.waypoint = (self.waypoint + 1) % waypoints.len();
+                }
+                Some(waypoints[self.waypoint])
+            }
+        };
+        return self.destination;
+    }
+
+    /// A pseudo-random value between @min and @max
+    fn random_between(&mut self, min: Unit, max: Unit) -> Unit{
+        // xorshift32
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+
+        let t = (self.seed as f32) / (::std::u32::MAX as f32);
+        return Unit::from_f32(min.to_f32() + (max.to_f32() - min.to_f32()) * t);
+    }
+}
+
+fn find_player(players: &[(u32, Position)], id: u32) -> Option<Position>{
+    players.iter().find(|&&(player_id, _)| player_id == id).map(|&(_, position)| position)
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use state::{ClientState, Position};
+
+    fn run(npc: &mut Npc, state: &mut ClientState, players: &[(u32, Position)], steps: usize){
+        for _ in 0..steps{
+            *state = npc.update(state, players, 0.1);
+        }
+    }
+
+    #[test]
+    fn test_load_definitions(){
+        let definitions = NpcDefinition::parse_all("# Guards\n[guard]\nposition = 1 0 2\nspeed = 3\nbehavior = patrol 0 0 0; 10 0 0\nreaction = chase 8\nhealth = 20\n\n\
+                                                    [rabbit]\nbehavior = wander 5 0 5; -5 0 -5\nreaction = flee 4\n").unwrap();
+        assert_eq!(definitions.len(), 2);
+        assert_eq!(definitions[0].name, "guard");
+        assert_eq!(definitions[0].position, Position::new(1.0, 0.0, 2.0));
+        assert_eq!(definitions[0].behavior, Behavior::Patrol(vec![Position::zero(), Position::new(10.0, 0.0, 0.0)]));
+        assert_eq!(definitions[0].reaction, Some(Reaction::Chase{ radius: 8.0 }));
+        assert_eq!(definitions[0].components, vec![Component::Health{ current: 20, maximum: 20 }]);
+        assert_eq!(definitions[1].speed, DEFAULT_SPEED);
+        assert_eq!(definitions[1].behavior, Behavior::Wander{ min: Position::new(-5.0, 0.0, -5.0), max: Position::new(5.0, 0.0, 5.0) });
+
+        assert!(NpcDefinition::parse_all("speed = 3\n").is_err());
+        assert!(parse_behavior("patrol").is_err());
+        assert!(parse_reaction("chase").is_err());
+    }
+
+    #[test]
+    fn test_patrol_and_wander(){
+        let mut definition = NpcDefinition::new("guard");
+        definition.behavior = Behavior::Patrol(vec![Position::new(1.0, 0.0, 0.0), Position::new(1.0, 0.0, 1.0)]);
+        let mut npc = Npc::new(0x01000000, &definition);
+        let mut state = ClientState::new(0x01000000);
+
+        // Moves at its speed toward the first waypoint, then on to the next
+        run(&mut npc, &mut state, &[], 1);
+        assert!(state.position.distance(Position::new(0.2, 0.0, 0.0)).to_f32() < 0.01);
+        assert!((state.velocity.length().to_f32() - DEFAULT_SPEED).abs() < 0.01);
+        run(&mut npc, &mut state, &[], 9);
+        assert!(state.position.distance(Position::new(1.0, 0.0, 1.0)).to_f32() < 0.01);
+
+        definition.behavior = Behavior::Wander{ min: Position::new(-2.0, 0.0, -2.0), max: Position::new(2.0, 0.0, 2.0) };
+        let mut npc = Npc::new(0x01000001, &definition);
+        let mut state = ClientState::new(0x01000001);
+        for _ in 0..100{
+            run(&mut npc, &mut state, &[], 1);
+            assert!(state.position.0.to_f32().abs() <= 2.01 && state.position.2.to_f32().abs() <= 2.01);
+        }
+        assert!(state.position != Position::zero());
+    }
+
+    #[test]
+    fn test_chase_and_flee(){
+        let mut definition = NpcDefinition::new("wolf");
+        definition.reaction = Some(Reaction::Chase{ radius: 5.0 });
+        let mut npc = Npc::new(0x01000000, &definition);
+        let mut state = ClientState::new(0x01000000);
+
+        // Players out of range are ignored
+        run(&mut npc, &mut state, &[(2, Position::new(6.0, 0.0, 0.0))], 1);
+        assert_eq!(npc.state, NpcState::Default);
+        assert_eq!(state.position, Position::zero());
+
+        // The nearest player in range is chased, until they get away
+        run(&mut npc, &mut state, &[(2, Position::new(4.0, 0.0, 0.0)), (3, Position::new(0.0, 0.0, -3.0))], 1);
+        assert_eq!(npc.state, NpcState::Chasing(3));
+        assert!(state.position.2.to_f32() < 0.0);
+        run(&mut npc, &mut state, &[(3, Position::new(0.0, 0.0, -20.0))], 1);
+        assert_eq!(npc.state, NpcState::Default);
+
+        definition.reaction = Some(Reaction::Flee{ radius: 5.0 });
+        let mut npc = Npc::new(0x01000001, &definition);
+        let mut state = ClientState::new(0x01000001);
+        run(&mut npc, &mut state, &[(2, Position::new(1.0, 0.0, 0.0))], 5);
+        assert_eq!(npc.state, NpcState::Fleeing(2));
+        assert!(state.position.0.to_f32() < -0.9);
+    }
+
+    #[test]
+    fn test_follows_route(){
+        let mut definition = NpcDefinition::new("guard");
+        definition.behavior = Behavior::Patrol(vec![Position::new(1.0, 0.0, 1.0)]);
+        let mut npc = Npc::new(0x01000000, &definition);
+        npc.navigating = true;
+        let mut state = ClientState::new(0x01000000);
+
+        // Waits for a route, asking for it only once
+        run(&mut npc, &mut state, &[], 2);
+        assert_eq!(state.position, Position::zero());
+        assert_eq!(npc.take_path_request(), Some(Position::new(1.0, 0.0, 1.0)));
+        assert_eq!(npc.take_path_request(), None);
+
+        // Then walks it a waypoint at a time
+        npc.set_route(Position::new(1.0, 0.0, 1.0), Some(vec![Position::new(1.0, 0.0, 0.0), Position::new(1.0, 0.0, 1.0)]));
+        run(&mut npc, &mut state, &[], 5);
+        assert!(state.position.distance(Position::new(1.0, 0.0, 0.0)).to_f32() < 0.01);
+        run(&mut npc, &mut state, &[], 5);
+        assert!(state.position.distance(Position::new(1.0, 0.0, 1.0)).to_f32() < 0.01);
+        assert_eq!(npc.take_path_request(), None);
+    }
+}
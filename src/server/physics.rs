@@ -0,0 +1,314 @@
+extern crate log;
+
+use std::io::{ErrorKind, Result, Error};
+
+use config::load_data;
+use math::{Scalar, Unit};
+use state::Position;
+
+/// Horizontal distance from a character's centre to the edge of its collision box
+pub const CHARACTER_RADIUS: f32 = 0.4;
+
+/// Height of a character's collision box, from its feet
+pub const CHARACTER_HEIGHT: f32 = 1.8;
+
+/// Penetration is resolved at most this many times per character each step
+const MAX_RESOLVE_ITERATIONS: usize = 4;
+
+/// Positions moved less than this by collision are left as they were sent
+pub const CORRECTION_TOLERANCE: f32 = 0.01;
+
+/// An axis-aligned box of level geometry
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb{
+    pub min: [f32; 3],
+    pub max: [f32; 3]
+}
+
+impl Aabb{
+    pub fn new(a: [f32; 3], b: [f32; 3]) -> Aabb{
+        Aabb{
+            min: [a[0].min(b[0]), a[1].min(b[1]), a[2].min(b[2])],
+            max: [a[0].max(b[0]), a[1].max(b[1]), a[2].max(b[2])]
+        }
+    }
+
+    /// The collision box of a character standing at @position
+    pub fn character(position: [f32; 3]) -> Aabb{
+        Aabb{
+            min: [position[0] - CHARACTER_RADIUS, position[1], position[2] - CHARACTER_RADIUS],
+            max: [position[0] + CHARACTER_RADIUS, position[1] + CHARACTER_HEIGHT, position[2] + CHARACTER_RADIUS]
+        }
+    }
+
+    /// How far along the ray from @origin in the unit @direction it enters this box, if it does at all.
+    /// Rays starting inside the box hit it straight away.
+    pub fn ray_distance(&self, origin: [f32; 3], direction: [f32; 3]) -> Option<f32>{
+        let (mut near, mut far) = (0.0f32, ::std::f32::INFINITY);
+        for axis in 0..3{
+            if direction[axis] == 0.0{
+                if origin[axis] < self.min[axis] || origin[axis] > self.max[axis]{
+                    return None;
+                }
+                continue;
+            }
+            let a = (self.min[axis] - origin[axis]) / direction[axis];
+            let b = (self.max[axis] - origin[axis]) / direction[axis];
+            near = near.max(a.min(b));
+            far = far.min(a.max(b));
+            if near > far{
+                return None;
+            }
+        }
+        return Some(near);
+    }
+
+    /// The shortest move which takes @self out of @other, if they overlap
+    pub fn penetration(&self, other: &Aabb) -> Option<[f32; 3]>{
+        let mut shortest: Option<[f32; 3]> = None;
+        for axis in 0..3{
+            let push_negative = other.min[axis] - self.max[axis];
+            let push_positive = other.max[axis] - self.min[axis];
+            if push_negative >= 0.0 || push_positive <= 0.0{
+                return None;
+            }
+
+            let push = if -push_negative < push_positive { push_negative } else { push_positive };
+            if shortest.map_or(true, |shortest| push.abs() < shortest.iter().map(|d| d.abs()).sum()){
+                let mut correction = [0.0; 3];
+                correction[axis] = push;
+                shortest = Some(correction);
+            }
+        }
+        return shortest;
+    }
+}
+
+/// Ground heights sampled on a grid over the X and Z axes
+#[derive(Clone, Debug, PartialEq)]
+pub struct Heightfield{
+    /// World position of the first sample
+    pub origin: [f32; 3],
+
+    /// Distance between samples, in world units
+    pub spacing: f32,
+
+    pub width: usize,
+    pub depth: usize,
+
+    /// Sample heights above the origin, row by row along X
+    pub heights: Vec<f32>
+}
+
+impl Heightfield{
+    /// The ground height at (@x, @z), interpolated between samples, if it is over the heightfield
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32>{
+        let u = (x - self.origin[0]) / self.spacing;
+        let v = (z - self.origin[2]) / self.spacing;
+        if u < 0.0 || v < 0.0 || u > (self.width - 1) as f32 || v > (self.depth - 1) as f32{
+            return None;
+        }
+
+        let (x0, z0) = (u.floor() as usize, v.floor() as usize);
+        let (x1, z1) = ((x0 + 1).min(self.width - 1), (z0 + 1).min(self.depth - 1));
+        let (tx, tz) = (u - x0 as f32, v - z0 as f32);
+        let sample = |x: usize, z: usize| self.heights[z * self.width + x];
+
+        let near = sample(x0, z0) + (sample(x1, z0) - sample(x0, z0)) * tx;
+        let far = sample(x0, z1) + (sample(x1, z1) - sample(x0, z1)) * tx;
+        return Some(self.origin[1] + near + (far - near) * tz);
+    }
+}
+
+/// The static level geometry characters collide with
+#[derive(Clone, Debug)]
+pub struct CollisionWorld{
+    pub boxes: Vec<Aabb>,
+    pub heightfields: Vec<Heightfield>
+}
+
+impl CollisionWorld{
+    /// A world with no geometry
+    pub fn new() -> CollisionWorld{
+        CollisionWorld{
+            boxes: Vec::new(),
+            heightfields: Vec::new()
+        }
+    }
+
+    /// Load the level geometry in the data file at @path
+    pub fn load(path: &str) -> Result<CollisionWorld>{
+        load_data(path, CollisionWorld::parse)
+    }
+
+    /// Parse the level geometry in @contents, one shape per line:
+    /// `box <x> <y> <z> <x> <y> <z>` giving opposite corners, or
+    /// `heightfield <x> <y> <z> <spacing> <width> <depth> <height>...` giving the heights row by row.
+    pub fn parse(contents: &str) -> Result<CollisionWorld>{
+        let mut world = CollisionWorld::new();
+        for (line_number, line) in contents.lines().enumerate(){
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#'){
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let shape = words.next().unwrap_or("");
+            let values = try!(words.map(|word| word.parse::<f32>()).collect::<::std::result::Result<Vec<f32>, _>>().map_err(|_|{
+                Error::new(ErrorKind::InvalidData, format!("line {}: expected numbers after `{}`", line_number + 1, shape))
+            }));
+            try!(world.add_shape(shape, &values).map_err(|e| Error::new(ErrorKind::InvalidData, format!("line {}: {}", line_number + 1, e))));
+        }
+
+        return Ok(world);
+    }
+
+    fn add_shape(&mut self, shape: &str, values: &[f32]) -> ::std::result::Result<(), String>{
+        match shape{
+            "box" => {
+                if values.len() != 6{
+                    return Err(String::from("expected `box <x> <y> <z> <x> <y> <z>`"));
+                }
+                self.boxes.push(Aabb::new([values[0], values[1], values[2]], [values[3], values[4], values[5]]));
+            },
+            "heightfield" => {
+                if values.len() < 6{
+                    return Err(String::from("expected `heightfield <x> <y> <z> <spacing> <width> <depth> <height>...`"));
+                }
+                let (spacing, width, depth) = (values[3], values[4] as usize, values[5] as usize);
+                if spacing <= 0.0 || width < 2 || depth < 2 || values.len() != 6 + width * depth{
+                    return Err(format!("expected a positive spacing and {} by {} heights", width, depth));
+                }
+                self.heightfields.push(Heightfield{
+                    origin: [values[0], values[1], values[2]],
+                    spacing: spacing,
+                    width: width,
+                    depth: depth,
+                    heights: values[6..].to_vec()
+                });
+            },
+            _ => { return Err(format!("Unknown shape `{}`, expected `box` or `heightfield`", shape)); }
+        }
+        Ok(())
+    }
+
+    /// The highest ground under (@x, @z), if there is a heightfield there
+    pub fn ground_height(&self, x: f32, z: f32) -> Option<f32>{
+        self.heightfields.iter()
+            .filter_map(|heightfield| heightfield.height_at(x, z))
+            .fold(None, |highest: Option<f32>, height| Some(highest.map_or(height, |highest| highest.max(height))))
+    }
+
+    /// How far along the ray from @origin in the unit @direction it first hits a box.
+    /// Heightfields don't block rays.
+    pub fn ray_distance(&self, origin: [f32; 3], direction: [f32; 3]) -> Option<f32>{
+        self.boxes.iter()
+            .filter_map(|aabb| aabb.ray_distance(origin, direction))
+            .fold(None, |nearest: Option<f32>, distance| Some(nearest.map_or(distance, |nearest| nearest.min(distance))))
+    }
+
+    /// Move a character at @position out of the ground, the level geometry and the characters at @others
+    pub fn resolve(&self, position: Position, others: &[Position]) -> Position{
+        let mut resolved = to_array(position);
+        for _ in 0..MAX_RESOLVE_ITERATIONS{
+            let mut moved = false;
+
+            // Push characters apart sideways, so nobody is pushed into the floor
+            for other in others.iter().map(|other| to_array(*other)){
+                let character = Aabb::character(resolved);
+                if character.penetration(&Aabb::character(other)).is_none(){
+                    continue;
+                }
+                let (dx, dz) = (resolved[0] - other[0], resolved[2] - other[2]);
+                let distance = (dx * dx + dz * dz).sqrt();
+                let (nx, nz) = if distance > 0.0 { (dx / distance, dz / distance) } else { (1.0, 0.0) };
+                let push = CHARACTER_RADIUS * 2.0 - distance;
+                if push > 0.0{
+                    resolved[0] += nx * push;
+                    resolved[2] += nz * push;
+                    moved = true;
+                }
+            }
+
+            for aabb in self.boxes.iter(){
+                if let Some(correction) = Aabb::character(resolved).penetration(aabb){
+                    for axis in 0..3{
+                        resolved[axis] += correction[axis];
+                    }
+                    moved = true;
+                }
+            }
+
+            if let Some(ground) = self.ground_height(resolved[0], resolved[2]){
+                if resolved[1] < ground{
+                    resolved[1] = ground;
+                    moved = true;
+                }
+            }
+
+            if !moved{
+                break;
+            }
+        }
+        return Position(Unit::from_f32(resolved[0]), Unit::from_f32(resolved[1]), Unit::from_f32(resolved[2]));
+    }
+}
+
+pub fn to_array(position: Position) -> [f32; 3]{
+    [position.0.to_f32(), position.1.to_f32(), position.2.to_f32()]
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use state::Position;
+
+    fn assert_near(actual: Position, expected: Position){
+        assert!(actual.distance(expected).to_f32() < 0.01, "Expected {:?}, got {:?}", expected, actual);
+    }
+
+    #[test]
+    fn test_load_world(){
+        let world = CollisionWorld::parse("# A wall on a slope\nbox 2 0 -5 3 3 5\nheightfield 0 0 0 10 2 2 0 1 0 1\n").unwrap();
+        assert_eq!(world.boxes, vec![Aabb::new([2.0, 0.0, -5.0], [3.0, 3.0, 5.0])]);
+        assert_eq!(world.ground_height(5.0, 5.0), Some(0.5));
+        assert_eq!(world.ground_height(-1.0, 5.0), None);
+
+        assert!(CollisionWorld::parse("heightfield 0 0 0 1 2 2 0 1\n").is_err());
+        assert!(CollisionWorld::parse("sphere 0 0 0 1\n").is_err());
+    }
+
+    #[test]
+    fn test_resolve(){
+        let mut world = CollisionWorld::new();
+        world.boxes.push(Aabb::new([2.0, 0.0, -5.0], [3.0, 3.0, 5.0]));
+        world.heightfields.push(Heightfield{ origin: [-10.0, 0.0, -10.0], spacing: 20.0, width: 2, depth: 2, heights: vec![0.0, 0.0, 2.0, 2.0] });
+
+        // Walking into the wall stops at its face, standing on the ground
+        assert_near(world.resolve(Position::new(1.9, 1.0, 0.0), &[]), Position::new(1.6, 1.0, 0.0));
+        assert_near(world.resolve(Position::new(3.3, 1.0, 0.0), &[]), Position::new(3.4, 1.0, 0.0));
+
+        // Below ground is lifted onto it, and open space is left alone
+        assert_near(world.resolve(Position::new(0.0, 0.0, 0.0), &[]), Position::new(0.0, 1.0, 0.0));
+        assert_near(world.resolve(Position::new(-5.0, 2.0, 0.0), &[]), Position::new(-5.0, 2.0, 0.0));
+
+        // Characters are pushed apart sideways
+        let resolved = world.resolve(Position::new(-5.0, 2.0, 0.0), &[Position::new(-5.5, 2.0, 0.0)]);
+        assert_near(resolved, Position::new(-4.7, 2.0, 0.0));
+    }
+
+    #[test]
+    fn test_ray_distance(){
+        let wall = Aabb::new([2.0, 0.0, -5.0], [3.0, 3.0, 5.0]);
+        assert_eq!(wall.ray_distance([0.0, 1.0, 0.0], [1.0, 0.0, 0.0]), Some(2.0));
+        assert_eq!(wall.ray_distance([0.0, 1.0, 0.0], [-1.0, 0.0, 0.0]), None);
+        assert_eq!(wall.ray_distance([0.0, 4.0, 0.0], [1.0, 0.0, 0.0]), None);
+        assert_eq!(wall.ray_distance([2.5, 1.0, 0.0], [0.0, 0.0, 1.0]), Some(0.0));
+
+        let mut world = CollisionWorld::new();
+        world.boxes.push(wall);
+        world.boxes.push(Aabb::new([6.0, 0.0, -5.0], [7.0, 3.0, 5.0]));
+        assert_eq!(world.ray_distance([0.0, 1.0, 0.0], [1.0, 0.0, 0.0]), Some(2.0));
+        assert_eq!(world.ray_distance([10.0, 1.0, 0.0], [-1.0, 0.0, 0.0]), Some(3.0));
+    }
+}
@@ -0,0 +1,185 @@
+extern crate log;
+
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use std::time::Instant;
+
+use zone::Zone;
+
+/// Number of worker threads zones are simulated on, by default
+pub const DEFAULT_SIMULATION_THREADS: usize = 2;
+
+/// A zone handed back by the scheduler
+pub enum Simulated{
+    /// The zone, done with its steps
+    Done(Zone),
+
+    /// The name of a zone which panicked during its steps, and is gone
+    Lost(String)
+}
+
+/// Runs zone simulation steps on a pool of worker threads, off the event loop.
+/// A zone is handed to a worker while its steps run, and handed back once they're done;
+/// anything sent to it meanwhile waits in its inbox. A slow zone only holds up itself.
+pub struct ZoneScheduler{
+    /// Zones waiting for a worker, and when to simulate them up to; None if zones are simulated on the calling thread
+    jobs: Option<Sender<(Zone, Instant)>>,
+
+    /// Zones which have run their steps, in the order they finished
+    finished: Receiver<Simulated>,
+    finished_sender: Sender<Simulated>
+}
+
+impl ZoneScheduler{
+    /// Start @threads workers. With none, zones are simulated as they're dispatched.
+    pub fn new(threads: usize) -> ZoneScheduler{
+        let (finished_sender, finished) = channel();
+
+        let jobs = match threads{
+            0 => None,
+            _ => {
+                let (jobs, queue) = channel();
+                let queue = Arc::new(Mutex::new(queue));
+                for index in 0..threads{
+                    let queue = queue.clone();
+                    let finished = finished_sender.clone();
+                    thread::Builder::new()
+                        .name(format!("zone-{}", index))
+                        .spawn(move || work(queue, finished))
+                        .expect("Failed to start zone worker thread!");
+                }
+                info!("Simulating zones on {} worker threads", threads);
+                Some(jobs)
+            }
+        };
+
+        ZoneScheduler{
+            jobs: jobs,
+            finished: finished,
+            finished_sender: finished_sender
+        }
+    }
+
+    /// Run the simulation steps @zone has due by @now. The zone is handed back by `finished` once they're done,
+    /// even if its simulation panicked.
+    pub fn dispatch(&mut self, zone: Zone, now: Instant){
+        match self.jobs{
+            Some(ref jobs) => {
+                jobs.send((zone, now)).expect("Zone worker threads have stopped!");
+            },
+            None => {
+                self.finished_sender.send(simulate(zone, now)).ok();
+            }
+        }
+    }
+
+    /// Take back the zones which have finished their steps, without waiting for any others
+    pub fn finished(&mut self) -> Vec<Simulated>{
+        let mut zones = Vec::new();
+        while let Ok(zone) = self.finished.try_recv(){
+            zones.push(zone);
+        }
+        return zones;
+    }
+}
+
+/// Run the steps @zone has due by @now, catching a panic so the zone is reported lost instead of never coming back
+fn simulate(mut zone: Zone, now: Instant) -> Simulated{
+    let name = zone.name.clone();
+    match panic::catch_unwind(AssertUnwindSafe(move ||{
+        zone.simulate(now);
+        zone
+    })){
+        Ok(zone) => Simulated::Done(zone),
+        Err(_) => {
+            error!("Zone `{}` panicked during its simulation step", name);
+            Simulated::Lost(name)
+        }
+    }
+}
+
+/// Simulate zones taken from @queue until the scheduler is dropped, handing each back through @finished
+fn work(queue: Arc<Mutex<Receiver<(Zone, Instant)>>>, finished: Sender<Simulated>){
+    loop{
+        // The queue is only locked while waiting for a job, so the other workers can take the next one
+        let job = match queue.lock(){
+            Ok(queue) => queue.recv(),
+            Err(_) => { return; }
+        };
+        let (zone, now) = match job{
+            Ok(job) => job,
+            Err(_) => { return; }
+        };
+
+        if finished.send(simulate(zone, now)).is_err(){
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use config::ServerConfig;
+    use math::Scalar;
+    use state::ClientState;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+    use zone::{Zone, ZoneInput, ZoneTemplate, SIMULATION_STEP_MS};
+
+    fn done(zone: Simulated) -> Zone{
+        match zone{
+            Simulated::Done(zone) => zone,
+            Simulated::Lost(name) => { panic!("Lost zone `{}`", name); }
+        }
+    }
+
+    #[test]
+    fn test_zones_simulated_on_workers(){
+        let config = ServerConfig::new();
+        let entity_ids = Arc::new(AtomicUsize::new(1000));
+        let mut scheduler = ZoneScheduler::new(2);
+
+        let mut zones = Vec::new();
+        for name in ["north", "south", "east"].iter(){
+            let mut zone = Zone::new(name, &ZoneTemplate::new(name), &config, entity_ids.clone()).unwrap();
+            zone.enter(1, None);
+            zones.push(zone);
+        }
+
+        // Inputs sent while a zone is away are applied in the order they were sent, once it runs
+        let inbox = zones[0].inbox();
+        let now = zones.iter().map(|zone| zone.last_step()).max().unwrap() + Duration::from_millis(SIMULATION_STEP_MS);
+        for zone in zones.drain(..){
+            scheduler.dispatch(zone, now);
+        }
+        for x in 1..4{
+            let mut state = ClientState::new(1);
+            state.position.0 = Scalar::from_f32(x as f32);
+            inbox.send(ZoneInput::Move(state)).unwrap();
+        }
+
+        let mut returned: Vec<Zone> = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while returned.len() < 3 && Instant::now() < deadline{
+            returned.extend(scheduler.finished().into_iter().map(done));
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(returned.len(), 3);
+        assert!(returned.iter().all(|zone| zone.tick() == 1));
+        assert!(scheduler.finished().is_empty());
+
+        let mut north = returned.into_iter().find(|zone| zone.name == "north").unwrap();
+        north.simulate(now + Duration::from_millis(SIMULATION_STEP_MS));
+        assert!((north.snapshot_for(1).iter().find(|delta| delta.id == 1).unwrap().state.unwrap().position.0.to_f32() - 3.0).abs() < 0.01);
+
+        // Without workers, zones are simulated as they're dispatched
+        let mut scheduler = ZoneScheduler::new(0);
+        scheduler.dispatch(north, now + Duration::from_millis(SIMULATION_STEP_MS * 4));
+        assert_eq!(done(scheduler.finished().remove(0)).tick(), 5);
+    }
+}
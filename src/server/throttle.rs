@@ -0,0 +1,50 @@
+extern crate log;
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Once this many addresses are tracked, idle addresses are pruned on the next check
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Limits how many connections a single IP may open within a sliding window
+pub struct ConnectionThrottle{
+    limit: usize,
+    window: Duration,
+    attempts: HashMap<IpAddr, VecDeque<Instant>>
+}
+
+impl ConnectionThrottle{
+    pub fn new(limit: u32, window_secs: u64) -> ConnectionThrottle{
+        ConnectionThrottle{
+            limit: limit as usize,
+            window: Duration::from_secs(window_secs),
+            attempts: HashMap::new()
+        }
+    }
+
+    /// Record a connection attempt from @address.
+    /// Returns FALSE if the address has exceeded its connection rate.
+    pub fn allow(&mut self, address: IpAddr) -> bool{
+        let now = Instant::now();
+        let window = self.window;
+
+        if self.attempts.len() >= PRUNE_THRESHOLD{
+            self.attempts.retain(|_, attempts|{
+                attempts.back().map(|last| now.duration_since(*last) < window).unwrap_or(false)
+            });
+        }
+
+        let attempts = self.attempts.entry(address).or_insert_with(VecDeque::new);
+        while attempts.front().map(|first| now.duration_since(*first) >= window).unwrap_or(false){
+            attempts.pop_front();
+        }
+
+        if attempts.len() >= self.limit{
+            return false;
+        }
+
+        attempts.push_back(now);
+        return true;
+    }
+}
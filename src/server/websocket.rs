@@ -0,0 +1,667 @@
+extern crate log;
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use byteorder::{ByteOrder, BigEndian};
+use mio::{Evented, TryRead, TryWrite};
+use mio::tcp::{TcpListener, TcpStream, Shutdown};
+
+use frame::{Message, ProtocolResult, DEFAULT_MAX_PAYLOAD_LENGTH};
+use transport::{Connection, Transport};
+use udp::Delivery;
+
+/// Appended to the client's key before hashing, see RFC 6455 section 1.3
+const WEBSOCKET_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest upgrade request we'll buffer before giving up on the client
+const MAX_HANDSHAKE_LENGTH: usize = 8192;
+
+/// Largest message a client may send, after reassembling fragments
+const MAX_MESSAGE_LENGTH: usize = 1 << 20;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Opcode{
+    Continuation = 0x0,
+    Text         = 0x1,
+    Binary       = 0x2,
+    Close        = 0x8,
+    Ping         = 0x9,
+    Pong         = 0xA
+}
+
+impl Opcode{
+    pub fn from_u8(byte: u8) -> Option<Opcode>{
+        match byte{
+            0x0 => { Some(Opcode::Continuation) },
+            0x1 => { Some(Opcode::Text) },
+            0x2 => { Some(Opcode::Binary) },
+            0x8 => { Some(Opcode::Close) },
+            0x9 => { Some(Opcode::Ping) },
+            0xA => { Some(Opcode::Pong) },
+            _   => { None }
+        }
+    }
+
+    fn is_control(&self) -> bool{
+        (*self as u8) & 0x8 != 0
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct WebSocketFrame{
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>
+}
+
+/// A protocol violation by the peer; the connection can't continue after one of these
+fn protocol_error(message: String) -> Error{
+    Error::new(ErrorKind::ConnectionAborted, message)
+}
+
+/// Parse a frame from the start of @input, returning it along with the number of bytes it took up.
+/// Returns None if @input doesn't hold a whole frame yet.
+/// Frames sent by clients must be masked.
+pub fn read_frame(input: &[u8]) -> Result<Option<(WebSocketFrame, usize)>>{
+    if input.len() < 2{
+        return Ok(None);
+    }
+
+    let fin = input[0] & 0x80 != 0;
+    if input[0] & 0x70 != 0{
+        return Err(protocol_error(String::from("Received a frame with reserved bits set")));
+    }
+    let opcode = try!(Opcode::from_u8(input[0] & 0x0F).ok_or(protocol_error(format!("Received unknown opcode {:x}", input[0] & 0x0F))));
+
+    if input[1] & 0x80 == 0{
+        return Err(protocol_error(String::from("Received an unmasked frame from a client")));
+    }
+
+    let (length, mut offset) = match input[1] & 0x7F{
+        126 => {
+            if input.len() < 4 { return Ok(None); }
+            (BigEndian::read_u16(&input[2..4]) as u64, 4)
+        },
+        127 => {
+            if input.len() < 10 { return Ok(None); }
+            (BigEndian::read_u64(&input[2..10]), 10)
+        },
+        length => (length as u64, 2)
+    };
+
+    if opcode.is_control() && (!fin || length > 125){
+        return Err(protocol_error(String::from("Received a fragmented or oversized control frame")));
+    }
+    if length > MAX_MESSAGE_LENGTH as u64{
+        return Err(protocol_error(format!("Received a frame of {} bytes", length)));
+    }
+    let length = length as usize;
+
+    if input.len() < offset + 4 + length{
+        return Ok(None);
+    }
+    let mask = [input[offset], input[offset + 1], input[offset + 2], input[offset + 3]];
+    offset += 4;
+
+    let payload = input[offset..offset + length].iter().enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+
+    return Ok(Some((WebSocketFrame{ fin: fin, opcode: opcode, payload: payload }, offset + length)));
+}
+
+/// Encode a single, unmasked frame as sent by the server
+pub fn encode_frame(opcode: Opcode, payload: &[u8]) -> Vec<u8>{
+    let mut output = Vec::with_capacity(payload.len() + 10);
+    output.push(0x80 | opcode as u8);
+
+    if payload.len() < 126{
+        output.push(payload.len() as u8);
+    }
+    else if payload.len() <= 0xFFFF{
+        let mut length = [0u8; 2];
+        BigEndian::write_u16(&mut length, payload.len() as u16);
+        output.push(126);
+        output.extend_from_slice(&length);
+    }
+    else{
+        let mut length = [0u8; 8];
+        BigEndian::write_u64(&mut length, payload.len() as u64);
+        output.push(127);
+        output.extend_from_slice(&length);
+    }
+
+    output.extend_from_slice(payload);
+    return output;
+}
+
+/// Parse an HTTP upgrade request from the start of @input.
+/// Returns the client's Sec-WebSocket-Key and the length of the request,
+/// or None if the request hasn't been fully received.
+pub fn read_upgrade_request(input: &[u8]) -> Result<Option<(String, usize)>>{
+    let end = match input.windows(4).position(|window| window == b"\r\n\r\n"){
+        Some(end) => end + 4,
+        None => {
+            if input.len() > MAX_HANDSHAKE_LENGTH{
+                return Err(protocol_error(String::from("Upgrade request is too long")));
+            }
+            return Ok(None);
+        }
+    };
+
+    let request = String::from_utf8_lossy(&input[..end]);
+    let mut lines = request.split("\r\n");
+
+    let request_line = lines.next().unwrap_or("");
+    if !request_line.starts_with("GET "){
+        return Err(protocol_error(format!("Expected a GET request, received `{}`", request_line)));
+    }
+
+    let mut key = None;
+    let mut upgrade = false;
+    let mut version = None;
+    for line in lines{
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim();
+
+        match name.as_str(){
+            "upgrade"               => { upgrade = value.eq_ignore_ascii_case("websocket"); },
+            "sec-websocket-key"     => { key = Some(String::from(value)); },
+            "sec-websocket-version" => { version = Some(String::from(value)); },
+            _ => {}
+        }
+    }
+
+    if !upgrade{
+        return Err(protocol_error(String::from("Request is not a WebSocket upgrade")));
+    }
+    if version.as_ref().map(|version| version.as_str()) != Some("13"){
+        return Err(protocol_error(format!("Unsupported WebSocket version {:?}", version)));
+    }
+
+    match key{
+        Some(key) => Ok(Some((key, end))),
+        None => Err(protocol_error(String::from("Upgrade request is missing Sec-WebSocket-Key")))
+    }
+}
+
+/// The Sec-WebSocket-Accept value answering the client's @key
+pub fn accept_key(key: &str) -> String{
+    let mut input = String::from(key);
+    input.push_str(WEBSOCKET_GUID);
+    base64_encode(&sha1(input.as_bytes()))
+}
+
+fn upgrade_response(key: &str) -> Vec<u8>{
+    format!("HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n", accept_key(key)).into_bytes()
+}
+
+/// The server side of a WebSocket connection.
+///
+/// Bytes read from the socket are passed to `receive`, and whatever is left in `output`
+/// afterwards (the handshake response, pongs, close replies) should be written back.
+/// Each binary message from the client carries one MessageFrame.
+pub struct WebSocketStream{
+    handshake_complete: bool,
+    closed: bool,
+
+    /// Bytes received but not yet parsed
+    input: Vec<u8>,
+
+    /// Payload of a fragmented message received so far
+    fragments: Vec<u8>,
+    fragmented_opcode: Option<Opcode>,
+
+    /// Complete binary messages waiting to be decoded
+    messages: VecDeque<Vec<u8>>,
+
+    /// MessageFrames waiting for the handshake to complete before they're sent
+    pub outgoing: VecDeque<Vec<u8>>,
+
+    /// Bytes to be written to the socket
+    pub output: Vec<u8>,
+
+    /// Messages with longer MessageFrame payloads are rejected
+    pub max_payload_length: u32
+}
+
+impl WebSocketStream{
+    pub fn new() -> WebSocketStream{
+        WebSocketStream{
+            handshake_complete: false,
+            closed: false,
+            input: Vec::new(),
+            fragments: Vec::new(),
+            fragmented_opcode: None,
+            messages: VecDeque::new(),
+            outgoing: VecDeque::new(),
+            output: Vec::new(),
+            max_payload_length: DEFAULT_MAX_PAYLOAD_LENGTH
+        }
+    }
+
+    /// Return TRUE once the upgrade is complete and until the connection is closed
+    pub fn is_open(&self) -> bool{
+        self.handshake_complete && !self.closed
+    }
+
+    pub fn is_closed(&self) -> bool{
+        self.closed
+    }
+
+    /// Process bytes read from the socket
+    pub fn receive(&mut self, bytes: &[u8]) -> Result<()>{
+        self.input.extend_from_slice(bytes);
+
+        if !self.handshake_complete{
+            match read_upgrade_request(&self.input){
+                Ok(Some((key, length))) => {
+                    self.input.drain(..length);
+                    self.output.extend_from_slice(&upgrade_response(&key));
+                    self.handshake_complete = true;
+                    debug!("Completed WebSocket handshake");
+                },
+                Ok(None) => { return Ok(()); },
+                Err(e) => {
+                    self.output.extend_from_slice(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n");
+                    self.closed = true;
+                    return Err(e);
+                }
+            }
+        }
+
+        while !self.closed{
+            let (frame, length) = match try!(read_frame(&self.input)){
+                Some(frame) => frame,
+                None => { break; }
+            };
+            self.input.drain(..length);
+            try!(self.on_frame(frame));
+        }
+
+        return Ok(());
+    }
+
+    fn on_frame(&mut self, frame: WebSocketFrame) -> Result<()>{
+        match frame.opcode{
+            Opcode::Ping => {
+                self.output.extend_from_slice(&encode_frame(Opcode::Pong, &frame.payload));
+            },
+            Opcode::Pong => {},
+            Opcode::Close => {
+                // Echo the status code back, as the closing handshake requires
+                let status = if frame.payload.len() >= 2 { &frame.payload[..2] } else { &[] };
+                self.output.extend_from_slice(&encode_frame(Opcode::Close, status));
+                self.closed = true;
+            },
+            Opcode::Text | Opcode::Binary => {
+                if self.fragmented_opcode.is_some(){
+                    return Err(protocol_error(String::from("Received a new message before the last one was finished")));
+                }
+                if frame.fin{
+                    self.on_message(frame.opcode, frame.payload);
+                }
+                else{
+                    self.fragmented_opcode = Some(frame.opcode);
+                    self.fragments = frame.payload;
+                }
+            },
+            Opcode::Continuation => {
+                let opcode = try!(self.fragmented_opcode.ok_or(protocol_error(String::from("Received a continuation with no message to continue"))));
+                if self.fragments.len() + frame.payload.len() > MAX_MESSAGE_LENGTH{
+                    return Err(protocol_error(String::from("Fragmented message is too large")));
+                }
+
+                self.fragments.extend_from_slice(&frame.payload);
+                if frame.fin{
+                    let payload = ::std::mem::replace(&mut self.fragments, Vec::new());
+                    self.fragmented_opcode = None;
+                    self.on_message(opcode, payload);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, opcode: Opcode, payload: Vec<u8>){
+        match opcode{
+            Opcode::Binary => { self.messages.push_back(payload); },
+            _ => { warn!("Ignoring {:?} WebSocket message, messages must be binary", opcode); }
+        }
+    }
+
+    /// Decode the next message received, along with the number of bytes it took up
+    pub fn next_message(&mut self) -> Option<ProtocolResult<(Message, usize)>>{
+        self.messages.pop_front().map(|payload|{
+            let message = try!(Message::read_limited(&mut payload.as_slice(), self.max_payload_length));
+            Ok((message, payload.len()))
+        })
+    }
+}
+
+/// A client connected over WebSocket, such as a browser
+pub struct WebSocketConnection{
+    socket: TcpStream,
+    stream: WebSocketStream
+}
+
+impl WebSocketConnection{
+    /// Wrap a socket which has yet to send its upgrade request
+    pub fn new(socket: TcpStream) -> WebSocketConnection{
+        WebSocketConnection{ socket: socket, stream: WebSocketStream::new() }
+    }
+}
+
+impl Connection for WebSocketConnection{
+    fn evented(&self) -> Option<&Evented>{
+        Some(&self.socket)
+    }
+
+    fn send(&mut self, frame: Vec<u8>, _: Delivery) -> Result<()>{
+        self.stream.outgoing.push_back(frame);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()>{
+        // Nothing but the handshake response may be sent until the upgrade is complete
+        if self.stream.is_open(){
+            while let Some(frame) = self.stream.outgoing.pop_front(){
+                self.stream.output.extend_from_slice(&encode_frame(Opcode::Binary, &frame));
+            }
+        }
+
+        while !self.stream.output.is_empty(){
+            match try!(self.socket.try_write(&self.stream.output)){
+                Some(bytes_written) => { self.stream.output.drain(..bytes_written); },
+                None => { break; }
+            }
+        }
+        Ok(())
+    }
+
+    fn has_pending_output(&self) -> bool{
+        !self.stream.output.is_empty() || (self.stream.is_open() && !self.stream.outgoing.is_empty())
+    }
+
+    /// Reads everything the socket has available
+    fn receive(&mut self) -> Vec<ProtocolResult<(Message, usize)>>{
+        let mut results = Vec::new();
+        let mut buffer = [0u8; 4096];
+        let mut closed = false;
+        loop{
+            match self.socket.try_read(&mut buffer){
+                Ok(Some(0)) => { closed = true; break; },
+                Ok(Some(bytes_read)) => {
+                    if let Err(e) = self.stream.receive(&buffer[..bytes_read]){
+                        results.push(Err(e.into()));
+                        break;
+                    }
+                },
+                Ok(None) => { break; },
+                Err(e) => { results.push(Err(e.into())); break; }
+            }
+        }
+
+        // Send the handshake response, or any pongs, straight away
+        if let Err(e) = self.flush(){
+            results.push(Err(e.into()));
+        }
+
+        while let Some(message) = self.stream.next_message(){
+            results.push(message);
+        }
+
+        if closed || self.stream.is_closed(){
+            results.push(Err(Error::new(ErrorKind::UnexpectedEof, String::from("Connection closed by peer")).into()));
+        }
+
+        return results;
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr>{
+        self.socket.peer_addr().ok()
+    }
+
+    fn shutdown(&mut self){
+        if self.stream.is_open(){
+            self.flush().ok();
+            self.stream.output.extend_from_slice(&encode_frame(Opcode::Close, &[0x03, 0xE8]));
+        }
+        self.flush().ok();
+        self.socket.shutdown(Shutdown::Both).ok();
+    }
+
+    fn set_max_payload_length(&mut self, max_payload_length: u32){
+        self.stream.max_payload_length = max_payload_length;
+    }
+}
+
+/// Accepts browser clients, which upgrade to WebSocket before playing
+pub struct WebSocketTransport{
+    listener: TcpListener
+}
+
+impl WebSocketTransport{
+    pub fn bind(address: &SocketAddr) -> Result<WebSocketTransport>{
+        Ok(WebSocketTransport{ listener: try!(TcpListener::bind(address)) })
+    }
+}
+
+impl Transport for WebSocketTransport{
+    fn evented(&self) -> Option<&Evented>{
+        Some(&self.listener)
+    }
+
+    fn accept(&mut self) -> Result<Option<(Box<Connection>, SocketAddr)>>{
+        match try!(self.listener.accept()){
+            Some((socket, address)) => Ok(Some((Box::new(WebSocketConnection::new(socket)), address))),
+            None => Ok(None)
+        }
+    }
+}
+
+/// SHA-1 digest of @input, needed only for the handshake
+pub fn sha1(input: &[u8]) -> [u8; 20]{
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = input.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56{
+        message.push(0);
+    }
+    let mut bit_length = [0u8; 8];
+    BigEndian::write_u64(&mut bit_length, (input.len() as u64).wrapping_mul(8));
+    message.extend_from_slice(&bit_length);
+
+    for block in message.chunks(64){
+        let mut words = [0u32; 80];
+        for i in 0..16{
+            words[i] = BigEndian::read_u32(&block[i * 4..i * 4 + 4]);
+        }
+        for i in 16..80{
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (state[0], state[1], state[2], state[3], state[4]);
+        for i in 0..80{
+            let (f, k) = match i{
+                0..=19  => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _       => (b ^ c ^ d, 0xCA62C1D6)
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(words[i]);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        state[0] = state[0].wrapping_add(a);
+        state[1] = state[1].wrapping_add(b);
+        state[2] = state[2].wrapping_add(c);
+        state[3] = state[3].wrapping_add(d);
+        state[4] = state[4].wrapping_add(e);
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, word) in state.iter().enumerate(){
+        BigEndian::write_u32(&mut digest[i * 4..i * 4 + 4], *word);
+    }
+    return digest;
+}
+
+/// Standard base64, with padding
+pub fn base64_encode(input: &[u8]) -> String{
+    const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output = String::with_capacity((input.len() + 2) / 3 * 4);
+    for chunk in input.chunks(3){
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let group = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for i in 0..4{
+            if i <= chunk.len(){
+                output.push(ALPHABET[(group >> (18 - i * 6)) as usize & 0x3F] as char);
+            }
+            else{
+                output.push('=');
+            }
+        }
+    }
+    return output;
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use authoritative::AuthoritativeServer;
+    use config::ServerConfig;
+    use frame::{Message, ToFrame};
+    use mio::EventLoop;
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Encode a masked frame, as a client would
+    fn client_frame(opcode: Opcode, payload: &[u8]) -> Vec<u8>{
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut output = encode_frame(opcode, payload);
+        let header_length = output.len() - payload.len();
+        output[1] |= 0x80;
+
+        let masked: Vec<u8> = payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect();
+        output.truncate(header_length);
+        output.extend_from_slice(&mask);
+        output.extend_from_slice(&masked);
+        return output;
+    }
+
+    #[test]
+    fn test_sha1(){
+        assert_eq!(base64_encode(&sha1(b"abc")), "qZk+NkcGgWq6PiVxeFDCbJzQ2J0=");
+        assert_eq!(base64_encode(&sha1(b"")), "2jmj7l5rSw0yVb/vlWAYkK/YBwk=");
+    }
+
+    #[test]
+    fn test_base64_padding(){
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+    }
+
+    #[test]
+    fn test_accept_key(){
+        // The example from RFC 6455 section 1.3
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn test_fragmented_message(){
+        let mut stream = WebSocketStream::new();
+        stream.receive(b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+        assert!(stream.is_open());
+        stream.output.clear();
+
+        let bytes = Message::new_text_message(String::from("Hello")).to_frame().to_bytes();
+        let mut first = client_frame(Opcode::Binary, &bytes[..4]);
+        first[0] &= 0x7F;
+        let second = client_frame(Opcode::Continuation, &bytes[4..]);
+
+        stream.receive(&first).unwrap();
+        stream.receive(&client_frame(Opcode::Ping, b"hi")).unwrap();
+        assert!(stream.next_message().is_none());
+        assert_eq!(stream.output, encode_frame(Opcode::Pong, b"hi"));
+
+        // Deliver the last fragment a byte at a time
+        for byte in second.iter(){
+            stream.receive(&[*byte]).unwrap();
+        }
+        match stream.next_message(){
+            Some(Ok((Message::Text{ message }, _))) => { assert_eq!(message, "Hello"); },
+            other => { panic!("Expected a text message, got {:?}", other); }
+        }
+    }
+
+    #[test]
+    fn test_local_client(){
+        let mut config = ServerConfig::new();
+        config.address = "127.0.0.1:0".parse().unwrap();
+        config.websocket_address = Some("127.0.0.1:47123".parse().unwrap());
+        config.ban_list_path = String::from("/nonexistent/lag-test-bans.txt");
+
+        thread::spawn(move ||{
+            let mut event_loop = EventLoop::new().unwrap();
+            let mut server = AuthoritativeServer::new(config);
+            server.run(&mut event_loop);
+        });
+        thread::sleep(Duration::from_millis(200));
+
+        let mut socket = TcpStream::connect("127.0.0.1:47123").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket.write_all(b"GET /lag HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                           Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 1024];
+        while !received.windows(4).any(|window| window == b"\r\n\r\n"){
+            let length = socket.read(&mut buffer).unwrap();
+            assert!(length > 0, "Server closed the connection during the handshake");
+            received.extend_from_slice(&buffer[..length]);
+        }
+        let response_end = received.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+        let response = String::from_utf8_lossy(&received[..response_end]).into_owned();
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        // The server assigns the new client its entity in a binary frame, ahead of the world snapshot
+        received.drain(..response_end);
+        let frame_length = |received: &Vec<u8>| match received.get(1).cloned(){
+            Some(126) if received.len() >= 4 => Some((4, BigEndian::read_u16(&received[2..4]) as usize)),
+            Some(126) | None => None,
+            Some(length) => Some((2, length as usize))
+        };
+        while frame_length(&received).map_or(true, |(start, length)| received.len() < start + length){
+            let length = socket.read(&mut buffer).unwrap();
+            assert!(length > 0, "Server closed the connection");
+            received.extend_from_slice(&buffer[..length]);
+        }
+        assert_eq!(received[0], 0x80 | Opcode::Binary as u8);
+        let (start, length) = frame_length(&received).unwrap();
+        let payload = &received[start..start + length];
+        match Message::read(&mut &payload[..]).unwrap().into_messages().remove(0){
+            Message::ClientUpdate(_) => {},
+            other => { panic!("Expected a client update, got {:?}", other); }
+        }
+
+        socket.write_all(&client_frame(Opcode::Close, &[0x03, 0xE8])).unwrap();
+    }
+}
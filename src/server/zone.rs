@@ -0,0 +1,836 @@
+extern crate log;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Result;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use config::{load_data, parse_sections, ServerConfig, MovementMode};
+use entity::{Component, Entity, EntityKind, EntityDelta};
+use frame::Message;
+use history::StateHistory;
+use input::{InputCommand, BUTTON_PRIMARY, MAX_INPUT_DURATION_MS};
+use math::Scalar;
+use navigation::{NavGrid, Pathfinder};
+use npc::{Npc, NpcDefinition, parse_position};
+use physics;
+use physics::{Aabb, CollisionWorld, CORRECTION_TOLERANCE};
+use state::{ClientState, GameState, Position};
+
+/// The zone players join when they connect
+pub const DEFAULT_ZONE: &'static str = "world";
+
+/// IDs of entities which aren't players are assigned from here up, clear of client tokens
+pub const FIRST_NON_PLAYER_ENTITY_ID: u32 = 0x01000000;
+
+/// Zones are simulated in fixed steps of this many milliseconds
+pub const SIMULATION_STEP_MS: u64 = 50;
+
+/// At most this many simulation steps are run to catch up after a slow tick
+const MAX_CATCH_UP_STEPS: u32 = 5;
+
+/// Players can bank at most this many milliseconds of movement while their input commands are delayed
+const MAX_INPUT_CREDIT_MS: u32 = 500;
+
+/// Input commands beyond this many waiting for a player are dropped, oldest first
+const MAX_QUEUED_INPUTS: usize = 32;
+
+/// How far a player's primary action reaches
+const HIT_SCAN_RANGE: f32 = 50.0;
+
+/// Height above a player's feet that its shots are fired from
+const EYE_HEIGHT: f32 = 1.5;
+
+/// Health taken by a hit from a player without a Damage component
+const DEFAULT_HIT_DAMAGE: u16 = 1;
+
+/// The input commands a player has sent which the zone hasn't yet applied
+#[derive(Clone)]
+struct PlayerInputs{
+    commands: VecDeque<InputCommand>,
+
+    /// The sequence number of the latest command accepted
+    last_sequence: Option<u32>,
+
+    /// Milliseconds of movement the player may still make, earned as simulation time passes
+    credit_ms: u32,
+
+    /// How long the world took to reach the player when it sent its latest command
+    latency: Duration
+}
+
+impl PlayerInputs{
+    fn new() -> PlayerInputs{
+        PlayerInputs{
+            commands: VecDeque::new(),
+            last_sequence: None,
+            credit_ms: MAX_INPUT_CREDIT_MS,
+            latency: Duration::from_millis(0)
+        }
+    }
+}
+
+/// What a zone is built from, as described in a zone data file
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZoneTemplate{
+    pub name: String,
+
+    /// Where players entering the zone appear
+    pub spawn: Position,
+
+    /// Data file describing the NPCs spawned when the zone is created, if any
+    pub npc_path: Option<String>,
+
+    /// Data file holding the navigation grid; without one, NPCs walk in straight lines
+    pub navigation_path: Option<String>,
+
+    /// Data file holding the level geometry characters collide with, if any
+    pub collision_path: Option<String>,
+
+    /// Instanced zones are created afresh for each group sent to them, and torn down once everyone has left
+    pub instanced: bool
+}
+
+impl ZoneTemplate{
+    pub fn new(name: &str) -> ZoneTemplate{
+        ZoneTemplate{
+            name: String::from(name),
+            spawn: Position::zero(),
+            npc_path: None,
+            navigation_path: None,
+            collision_path: None,
+            instanced: false
+        }
+    }
+
+    /// The zone players join when they connect, built from the data files named in @config
+    pub fn default_zone(config: &ServerConfig) -> ZoneTemplate{
+        let mut template = ZoneTemplate::new(DEFAULT_ZONE);
+        template.npc_path = config.npc_path.clone();
+        template.navigation_path = config.navigation_path.clone();
+        template.collision_path = config.collision_path.clone();
+        return template;
+    }
+
+    /// Load every zone template in the data file at @path
+    pub fn load_all(path: &str) -> Result<Vec<ZoneTemplate>>{
+        load_data(path, ZoneTemplate::parse_all)
+    }
+
+    /// Parse every zone template in @contents.
+    /// Each template starts with a `[name]` line, followed by `key = value` settings.
+    pub fn parse_all(contents: &str) -> Result<Vec<ZoneTemplate>>{
+        parse_sections(contents, |name|{
+            // Instances are named `<template>#<n>`
+            if name.is_empty() || name.contains('#'){
+                return Err(format!("Invalid zone name `{}`", name));
+            }
+            Ok(ZoneTemplate::new(name))
+        }, ZoneTemplate::set)
+    }
+
+    /// Apply a single setting.
+    fn set(&mut self, key: &str, value: &str) -> ::std::result::Result<(), String>{
+        match key{
+            "spawn"      => { self.spawn = try!(parse_position(value)); },
+            "npcs"       => { self.npc_path = Some(String::from(value)); },
+            "navigation" => { self.navigation_path = Some(String::from(value)); },
+            "collision"  => { self.collision_path = Some(String::from(value)); },
+            "instanced"  => { self.instanced = try!(value.parse::<bool>().map_err(|_| format!("Invalid value `{}` for `{}`", value, key))); },
+            _ => { return Err(format!("Unknown setting `{}`", key)); }
+        }
+        Ok(())
+    }
+}
+
+/// Something sent to a zone from the event loop or another zone, applied in the order it was sent
+pub enum ZoneInput{
+    /// The latest state a player has sent, in `MovementMode::Client`
+    Move(ClientState),
+
+    /// An input command sent by the player with the given ID, and the player's latency when it was sent
+    Input(u32, InputCommand, Duration),
+
+    /// A message for every player in the zone
+    Broadcast(Message),
+
+    /// A player arriving, which is sent where it spawned and everything it can see.
+    /// A player coming from another zone brings its entity, and the despawns of what it could see there.
+    Enter{ id: u32, entity: Option<Entity>, forgotten: Vec<EntityDelta> },
+
+    /// The ID of a player who has disconnected
+    Leave(u32),
+
+    /// A player to send on to the zone whose inbox is given, once it's taken out of this one
+    Transfer{ id: u32, to: Sender<ZoneInput> },
+
+    /// A non-player entity to add, with an ID already handed out
+    Spawn{ id: u32, kind: EntityKind, owner: Option<u32>, position: Position, components: Vec<Component> },
+
+    /// The ID of a non-player entity to remove, if it's in this zone
+    Despawn(u32)
+}
+
+/// A part of the world with its own game state, simulation and broadcast scope.
+/// Players in one zone see nothing of the others.
+pub struct Zone{
+    /// Unique among the server's zones; instances are named after their template with a `#<number>` suffix
+    pub name: String,
+
+    pub template: ZoneTemplate,
+
+    game_state: GameState,
+
+    /// The players in the zone, by entity ID
+    players: HashSet<u32>,
+
+    /// The behavior driving each NPC, by entity ID
+    npcs: HashMap<u32, Npc>,
+
+    /// The latest state each player has sent, applied at the next simulation step
+    pending_moves: HashMap<u32, ClientState>,
+
+    /// Input commands waiting to be applied, by player entity ID
+    inputs: HashMap<u32, PlayerInputs>,
+
+    /// Messages for every player in the zone, sent at the end of the tick
+    broadcasts: Vec<Message>,
+
+    /// Messages for single players, sent at the end of the tick
+    outbox: Vec<(u32, Message)>,
+
+    /// When the zone was last simulated
+    last_step: Instant,
+
+    /// The number of simulation steps run so far
+    tick: u64,
+
+    /// Entity transforms over recent simulation steps, for lag compensation
+    history: StateHistory,
+
+    /// How far behind the latest state players draw other entities
+    interpolation_delay: Duration,
+
+    /// Answers path queries on the navigation grid, if one was loaded
+    navigation: Option<Pathfinder>,
+
+    /// Level geometry characters collide with
+    collision: CollisionWorld,
+
+    movement_mode: MovementMode,
+    player_speed: f32,
+
+    /// Hands out non-player entity IDs, shared by every zone so IDs stay unique across the server
+    entity_ids: Arc<AtomicUsize>,
+
+    /// What the event loop has sent the zone, which may arrive while it's being simulated elsewhere
+    inbox: Receiver<ZoneInput>,
+    inbox_sender: Sender<ZoneInput>,
+
+    /// Players sent on elsewhere before they arrived here, and where to send them once they do
+    forwards: HashMap<u32, Sender<ZoneInput>>
+}
+
+impl Zone{
+    /// Build the zone @name from @template, loading its data files
+    pub fn new(name: &str, template: &ZoneTemplate, config: &ServerConfig, entity_ids: Arc<AtomicUsize>) -> Result<Zone>{
+        let navigation = match template.navigation_path{
+            Some(ref path) => {
+                let mut pathfinder = Pathfinder::new(try!(NavGrid::load(path)));
+                pathfinder.queries_per_step = config.path_queries_per_step;
+                Some(pathfinder)
+            },
+            None => None
+        };
+
+        let collision = match template.collision_path{
+            Some(ref path) => try!(CollisionWorld::load(path)),
+            None => CollisionWorld::new()
+        };
+
+        let npc_definitions = match template.npc_path{
+            Some(ref path) => try!(NpcDefinition::load_all(path)),
+            None => Vec::new()
+        };
+
+        let (inbox_sender, inbox) = channel();
+        let mut zone = Zone{
+            name: String::from(name),
+            template: template.clone(),
+            game_state: GameState::new(),
+            players: HashSet::new(),
+            npcs: HashMap::new(),
+            pending_moves: HashMap::new(),
+            inputs: HashMap::new(),
+            broadcasts: Vec::new(),
+            outbox: Vec::new(),
+            last_step: Instant::now(),
+            tick: 0,
+            history: StateHistory::new(Duration::from_millis(SIMULATION_STEP_MS), Duration::from_millis(config.max_rewind_ms)),
+            interpolation_delay: Duration::from_millis(config.interpolation_delay_ms),
+            navigation: navigation,
+            collision: collision,
+            movement_mode: config.movement_mode,
+            player_speed: config.player_speed,
+            entity_ids: entity_ids,
+            inbox: inbox,
+            inbox_sender: inbox_sender,
+            forwards: HashMap::new()
+        };
+        for definition in npc_definitions.iter(){
+            zone.spawn_npc(definition);
+        }
+
+        info!("Created zone `{}`", name);
+        return Ok(zone);
+    }
+
+    /// Return TRUE if there are no players in the zone
+    pub fn is_empty(&self) -> bool{
+        self.players.is_empty()
+    }
+
+    /// The players in the zone
+    pub fn players(&self) -> &HashSet<u32>{
+        &self.players
+    }
+
+    /// Return TRUE if the entity @id is in this zone
+    pub fn contains(&self, id: u32) -> bool{
+        self.game_state.entities.contains_key(&id)
+    }
+
+    /// The number of simulation steps run so far
+    pub fn tick(&self) -> u64{
+        self.tick
+    }
+
+    /// When the latest simulation step was due
+    pub fn last_step(&self) -> Instant{
+        self.last_step
+    }
+
+    /// Whether a simulation step is due by @now
+    pub fn step_due(&self, now: Instant) -> bool{
+        now.duration_since(self.last_step) >= Duration::from_millis(SIMULATION_STEP_MS)
+    }
+
+    /// Somewhere to send the zone input, which it takes in before its next step wherever it's running
+    pub fn inbox(&self) -> Sender<ZoneInput>{
+        self.inbox_sender.clone()
+    }
+
+    /// Take in everything sent to the inbox so far, in the order it was sent
+    pub fn receive_inputs(&mut self){
+        while let Ok(input) = self.inbox.try_recv(){
+            match input{
+                // Anything a player sends before it has arrived is dropped
+                ZoneInput::Move(client_state) => {
+                    if self.players.contains(&client_state.id){
+                        self.queue_move(client_state);
+                    }
+                },
+                ZoneInput::Input(id, command, latency) => {
+                    if self.players.contains(&id){
+                        self.queue_input(id, command, latency);
+                    }
+                },
+                ZoneInput::Broadcast(message) => { self.broadcast(message); },
+                ZoneInput::Enter{ id, entity, forgotten } => {
+                    if let Some(to) = self.forwards.remove(&id){
+                        to.send(ZoneInput::Enter{ id: id, entity: entity, forgotten: forgotten }).ok();
+                        continue;
+                    }
+                    if !forgotten.is_empty(){
+                        self.outbox.push((id, Message::EntityUpdate(forgotten)));
+                    }
+                    let state = self.place_player(id, entity);
+
+                    // Everything already in the zone; changes from here on arrive with each tick
+                    let snapshot = self.snapshot_for(id);
+                    self.outbox.push((id, Message::new_client_update_message(&state)));
+                    self.outbox.push((id, Message::EntityUpdate(snapshot)));
+                },
+                ZoneInput::Leave(id) => {
+                    self.forwards.remove(&id);
+                    self.remove_player(id);
+                },
+                ZoneInput::Transfer{ id, to } => {
+                    // A player sent on again before it got here is forwarded when it arrives
+                    if !self.players.contains(&id){
+                        self.forwards.insert(id, to);
+                        continue;
+                    }
+
+                    // The player forgets everything it could see here
+                    let forgotten = self.snapshot_for(id).iter()
+                        .filter(|delta| delta.id != id)
+                        .map(|delta| EntityDelta::despawn(delta.id))
+                        .collect();
+                    let entity = self.remove_player(id);
+                    to.send(ZoneInput::Enter{ id: id, entity: entity, forgotten: forgotten }).ok();
+                },
+                ZoneInput::Spawn{ id, kind, owner, position, components } => { self.insert_entity(id, kind, owner, position, components); },
+                ZoneInput::Despawn(id) => {
+                    if self.game_state.entities.contains_key(&id){
+                        self.despawn_entity(id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Bring the player @id into the zone at its spawn point, with the components of @entity if it's arriving from elsewhere.
+    /// Returns where the player now is.
+    pub fn enter(&mut self, id: u32, entity: Option<Entity>) -> ClientState{
+        self.receive_inputs();
+        return self.place_player(id, entity);
+    }
+
+    fn place_player(&mut self, id: u32, entity: Option<Entity>) -> ClientState{
+        let mut state = ClientState::new(id);
+        state.position = self.collision.resolve(self.template.spawn, &self.character_positions(id));
+
+        let mut player = Entity::player(state);
+        if let Some(entity) = entity{
+            for component in entity.components(){
+                player.set_component(component.clone());
+            }
+        }
+
+        debug!("Player {} entered zone `{}`", id, self.name);
+        self.game_state.entities.insert(id, player);
+        self.players.insert(id);
+        return state;
+    }
+
+    /// Take the player @id out of the zone, returning its entity
+    pub fn leave(&mut self, id: u32) -> Option<Entity>{
+        // Whatever the player sent before leaving is dropped along with it
+        self.receive_inputs();
+        return self.remove_player(id);
+    }
+
+    fn remove_player(&mut self, id: u32) -> Option<Entity>{
+        self.players.remove(&id);
+        self.pending_moves.remove(&id);
+        self.inputs.remove(&id);
+        debug!("Player {} left zone `{}`", id, self.name);
+        return self.game_state.remove(id);
+    }
+
+    /// Apply @client_state, sent by its player, at the next simulation step
+    pub fn queue_move(&mut self, client_state: ClientState){
+        self.pending_moves.insert(client_state.id, client_state);
+    }
+
+    /// Apply @command, sent by the player @id with @latency, once the player has earned the time for it
+    pub fn queue_input(&mut self, id: u32, command: InputCommand, latency: Duration){
+        let inputs = self.inputs.entry(id).or_insert_with(PlayerInputs::new);
+        if inputs.last_sequence.map_or(false, |last| command.sequence <= last){
+            trace!("Ignoring stale input command {}", command.sequence);
+            return;
+        }
+        inputs.last_sequence = Some(command.sequence);
+        inputs.latency = latency;
+        inputs.commands.push_back(command);
+        if inputs.commands.len() > MAX_QUEUED_INPUTS{
+            debug!("Too many input commands queued, dropping the oldest");
+            inputs.commands.pop_front();
+        }
+    }
+
+    /// Send @message to every player in the zone
+    pub fn broadcast(&mut self, message: Message){
+        self.broadcasts.push(message);
+    }
+
+    /// The messages for every player in the zone this tick
+    pub fn broadcasts(&self) -> &[Message]{
+        &self.broadcasts
+    }
+
+    /// Take the messages for single players queued this tick
+    pub fn take_outbox(&mut self) -> Vec<(u32, Message)>{
+        ::std::mem::replace(&mut self.outbox, Vec::new())
+    }
+
+    /// Everything in the zone that @recipient may see
+    pub fn snapshot_for(&self, recipient: u32) -> Vec<EntityDelta>{
+        self.game_state.snapshot_for(recipient)
+    }
+
+    /// Whatever changed in the zone this tick that @recipient may see
+    pub fn deltas_for(&self, recipient: u32) -> Vec<EntityDelta>{
+        self.game_state.deltas_for(recipient)
+    }
+
+    /// Forget this tick's changes and broadcasts, once every player has been sent them
+    pub fn end_tick(&mut self){
+        self.game_state.clear_dirty();
+        self.broadcasts.clear();
+    }
+
+    /// Add an entity of @kind at @position to the zone, returning its ID
+    pub fn spawn_entity(&mut self, kind: EntityKind, owner: Option<u32>, position: Position, components: Vec<Component>) -> u32{
+        let id = self.entity_ids.fetch_add(1, Ordering::SeqCst) as u32;
+        self.insert_entity(id, kind, owner, position, components);
+        return id;
+    }
+
+    /// Add an entity of @kind at @position to the zone, under the ID @id
+    fn insert_entity(&mut self, id: u32, kind: EntityKind, owner: Option<u32>, position: Position, components: Vec<Component>){
+        let mut state = ClientState::new(id);
+        state.position = position;
+
+        let mut entity = Entity::new(state, kind);
+        entity.set_owner(owner);
+        for component in components{
+            entity.set_component(component);
+        }
+
+        info!("Spawned {:?} {} at {:?} in zone `{}`", kind, id, position, self.name);
+        self.game_state.entities.insert(id, entity);
+    }
+
+    /// Spawn the NPC described by @definition, returning its entity ID
+    pub fn spawn_npc(&mut self, definition: &NpcDefinition) -> u32{
+        let id = self.spawn_entity(EntityKind::Npc, None, definition.position, definition.components.clone());
+        debug!("NPC {} is `{}`", id, definition.name);
+        let mut npc = Npc::new(id, definition);
+        npc.navigating = self.navigation.is_some();
+        self.npcs.insert(id, npc);
+        return id;
+    }
+
+    /// Remove the non-player entity @id from the zone.
+    /// Returns FALSE if there is no such entity; players are removed by disconnecting them.
+    pub fn despawn_entity(&mut self, id: u32) -> bool{
+        match self.game_state.entities.get(&id).map(|entity| entity.kind()){
+            Some(EntityKind::Player) => {
+                warn!("Entity {} is a player, and can't be despawned", id);
+                return false;
+            },
+            Some(_) => {},
+            None => { return false; }
+        }
+
+        info!("Despawned entity {}", id);
+        self.game_state.remove(id);
+        self.npcs.remove(&id);
+        if let Some(ref mut pathfinder) = self.navigation{
+            pathfinder.cancel(id);
+        }
+        return true;
+    }
+
+    /// Run every simulation step due since the last one
+    pub fn simulate(&mut self, now: Instant){
+        self.receive_inputs();
+        let step = Duration::from_millis(SIMULATION_STEP_MS);
+        let mut steps = 0;
+        while now.duration_since(self.last_step) >= step{
+            if steps == MAX_CATCH_UP_STEPS{
+                warn!("Zone `{}` is running behind, skipping {:?}", self.name, now.duration_since(self.last_step));
+                self.last_step = now;
+                break;
+            }
+            self.last_step += step;
+            self.step_players();
+            self.step_npcs(SIMULATION_STEP_MS as f32 / 1000.0);
+            self.tick += 1;
+            self.history.record(self.tick, &self.game_state);
+            steps += 1;
+        }
+    }
+
+    /// Every entity's transform as a client saw it when it acted at simulation step @tick,
+    /// the world having taken @latency to reach it and being drawn @interpolation_delay behind
+    pub fn rewind(&self, tick: u64, latency: Duration, interpolation_delay: Duration) -> Option<HashMap<u32, ClientState>>{
+        self.history.rewind(tick, latency, interpolation_delay)
+    }
+
+    /// The player or NPC hit by a shot @shooter fired at simulation step @tick,
+    /// from @origin along @direction for up to @range, checked against where things were when the shooter fired.
+    /// Level geometry stops the shot.
+    pub fn hit_scan(&self, shooter: u32, tick: u64, latency: Duration, interpolation_delay: Duration, origin: Position, direction: Position, range: f32) -> Option<u32>{
+        let length = direction.length().to_f32();
+        if length == 0.0{
+            return None;
+        }
+        let origin = physics::to_array(origin);
+        let direction = physics::to_array(direction);
+        let direction = [direction[0] / length, direction[1] / length, direction[2] / length];
+        let range = self.collision.ray_distance(origin, direction).map_or(range, |blocked| blocked.min(range));
+
+        let states = match self.rewind(tick, latency, interpolation_delay){
+            Some(states) => states,
+            None => { return None; }
+        };
+
+        let mut hit: Option<(u32, f32)> = None;
+        for (id, state) in states.iter(){
+            let is_character = self.game_state.entities.get(id)
+                .map_or(false, |entity| entity.kind() == EntityKind::Player || entity.kind() == EntityKind::Npc);
+            if *id == shooter || !is_character{
+                continue;
+            }
+            if let Some(distance) = Aabb::character(physics::to_array(state.position)).ray_distance(origin, direction){
+                if distance <= range && hit.map_or(true, |(_, nearest)| distance < nearest){
+                    hit = Some((*id, distance));
+                }
+            }
+        }
+        return hit.map(|(id, _)| id);
+    }
+
+    /// The positions of every player and NPC except @id
+    fn character_positions(&self, id: u32) -> Vec<Position>{
+        self.game_state.entities.values()
+            .filter(|entity| entity.id() != id && (entity.kind() == EntityKind::Player || entity.kind() == EntityKind::Npc))
+            .map(|entity| entity.state().position)
+            .collect()
+    }
+
+    /// Apply the moves players have sent since the last step, pushed out of anything they collide with.
+    /// Players whose moves had to be corrected are sent where they ended up.
+    fn step_players(&mut self){
+        self.apply_inputs(SIMULATION_STEP_MS as u32);
+
+        let mut moves: Vec<ClientState> = self.pending_moves.drain().map(|(_, client_state)| client_state).collect();
+        moves.sort_by_key(|client_state| client_state.id);
+
+        for mut client_state in moves{
+            let resolved = self.collision.resolve(client_state.position, &self.character_positions(client_state.id));
+            if resolved.distance(client_state.position).to_f32() > CORRECTION_TOLERANCE{
+                debug!("Corrected the position of {} from {:?} to {:?}", client_state.id, client_state.position, resolved);
+                client_state.position = resolved;
+
+                // Players moved by input commands see where they are through entity updates
+                if self.movement_mode == MovementMode::Client{
+                    self.outbox.push((client_state.id, Message::new_client_update_message(&client_state)));
+                }
+            }
+            if let Some(entity) = self.game_state.entities.get_mut(&client_state.id){
+                entity.set_state(client_state);
+            }
+        }
+    }
+
+    /// Move players by the input commands they've sent, as far as @step_ms more milliseconds of credit allows,
+    /// and fire the shots of those holding the primary button.
+    /// Commands a player hasn't earned the time for wait for a later step.
+    fn apply_inputs(&mut self, step_ms: u32){
+        let speed = self.player_speed;
+        let mut shots = Vec::new();
+        for (id, inputs) in self.inputs.iter_mut(){
+            inputs.credit_ms = (inputs.credit_ms + step_ms).min(MAX_INPUT_CREDIT_MS);
+
+            let mut state = match self.game_state.entities.get(id){
+                Some(entity) => *entity.state(),
+                None => { continue; }
+            };
+
+            let mut moved = false;
+            while let Some(command) = inputs.commands.front().cloned(){
+                let duration = command.duration.min(MAX_INPUT_DURATION_MS) as u32;
+                if duration > inputs.credit_ms{
+                    break;
+                }
+                inputs.credit_ms -= duration;
+                inputs.commands.pop_front();
+                state = command.apply(&state, speed);
+                moved = true;
+
+                if command.is_pressed(BUTTON_PRIMARY){
+                    shots.push((*id, state.position, command.facing(), inputs.latency));
+                }
+            }
+
+            if moved{
+                self.pending_moves.insert(*id, state);
+            }
+        }
+
+        shots.sort_by_key(|&(id, _, _, _)| id);
+        for (shooter, position, direction, latency) in shots{
+            self.fire(shooter, position, direction, latency);
+        }
+    }
+
+    /// Fire a shot for the player @shooter standing at @position and facing @direction,
+    /// checked against where things were when the player saw them @latency ago.
+    /// Whatever is hit loses the shooter's Damage in health.
+    fn fire(&mut self, shooter: u32, position: Position, direction: Position, latency: Duration){
+        let origin = position + Position::new(0.0, EYE_HEIGHT, 0.0);
+        let target = match self.hit_scan(shooter, self.tick, latency, self.interpolation_delay, origin, direction, HIT_SCAN_RANGE){
+            Some(target) => target,
+            None => { return; }
+        };
+
+        let damage = self.game_state.entities.get(&shooter)
+            .and_then(|entity| entity.components().iter().filter_map(|component| match *component{
+                Component::Damage(damage) => Some(damage),
+                _ => None
+            }).next())
+            .unwrap_or(DEFAULT_HIT_DAMAGE);
+        debug!("Player {} hit {} for {}", shooter, target, damage);
+
+        if let Some(entity) = self.game_state.entities.get_mut(&target){
+            let health = entity.components().iter().filter_map(|component| match *component{
+                Component::Health{ current, maximum } => Some((current, maximum)),
+                _ => None
+            }).next();
+            if let Some((current, maximum)) = health{
+                entity.set_component(Component::Health{ current: current.saturating_sub(damage), maximum: maximum });
+            }
+        }
+    }
+
+    /// Advance every NPC by @dt seconds
+    fn step_npcs(&mut self, dt: f32){
+        let game_state = &mut self.game_state;
+        let players: Vec<(u32, Position)> = game_state.entities.values()
+            .filter(|entity| entity.kind() == EntityKind::Player)
+            .map(|entity| (entity.id(), entity.state().position))
+            .collect();
+        let player_positions: Vec<Position> = players.iter().map(|&(_, position)| position).collect();
+
+        for npc in self.npcs.values_mut(){
+            let entity = match game_state.entities.get_mut(&npc.entity_id){
+                Some(entity) => entity,
+                None => { continue; }
+            };
+
+            if let Some((goal, path)) = self.navigation.as_mut().and_then(|pathfinder| pathfinder.take_result(npc.entity_id)){
+                npc.set_route(goal, path);
+            }
+
+            let mut next = npc.update(entity.state(), &players, dt);
+            next.position = self.collision.resolve(next.position, &player_positions);
+            if next.position != entity.state().position || next.velocity != entity.state().velocity{
+                entity.set_state(next);
+            }
+
+            if let (Some(goal), Some(pathfinder)) = (npc.take_path_request(), self.navigation.as_mut()){
+                pathfinder.request(npc.entity_id, next.position, goal);
+            }
+        }
+
+        if let Some(ref mut pathfinder) = self.navigation{
+            pathfinder.run();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use config::ServerConfig;
+
+    fn zone(template: &ZoneTemplate) -> Zone{
+        Zone::new(&template.name, template, &ServerConfig::new(), Arc::new(AtomicUsize::new(FIRST_NON_PLAYER_ENTITY_ID as usize))).unwrap()
+    }
+
+    #[test]
+    fn test_load_templates(){
+        let templates = ZoneTemplate::parse_all("# Somewhere to fight\n[dungeon]\ninstanced = true\nspawn = 1 2 3\ncollision = dungeon.txt\n\n[town]\n").unwrap();
+        assert_eq!(templates.len(), 2);
+        assert_eq!((templates[0].name.as_str(), templates[0].instanced, templates[0].spawn), ("dungeon", true, Position::new(1.0, 2.0, 3.0)));
+        assert_eq!(templates[0].collision_path, Some(String::from("dungeon.txt")));
+        assert_eq!((templates[1].name.as_str(), templates[1].instanced), ("town", false));
+
+        assert!(ZoneTemplate::parse_all("instanced = true\n").is_err());
+        assert!(ZoneTemplate::parse_all("[dungeon#2]\n").is_err());
+    }
+
+    #[test]
+    fn test_players_enter_and_leave(){
+        let mut template = ZoneTemplate::new("dungeon");
+        template.spawn = Position::new(5.0, 0.0, 0.0);
+        let mut zone = zone(&template);
+        assert!(zone.is_empty());
+
+        // Players arrive at the spawn point, keeping their components, and are pushed apart
+        let state = zone.enter(2, None);
+        assert_eq!(state.position, Position::new(5.0, 0.0, 0.0));
+        let mut arriving = Entity::player(ClientState::new(3));
+        arriving.set_component(Component::Health{ current: 4, maximum: 10 });
+        let state = zone.enter(3, Some(arriving));
+        assert!(state.position != Position::new(5.0, 0.0, 0.0));
+        assert_eq!(zone.players().len(), 2);
+
+        let entity = zone.leave(3).unwrap();
+        assert_eq!(entity.components().len(), 1);
+        assert!(!zone.contains(3) && zone.contains(2));
+        zone.leave(2);
+        assert!(zone.is_empty());
+    }
+
+    #[test]
+    fn test_lag_compensation(){
+        let mut zone = zone(&ZoneTemplate::new(DEFAULT_ZONE));
+        let delay = Duration::from_millis(100);
+        let latency = Duration::from_millis(0);
+
+        // A target runs across the shooter's line of fire, one unit each step
+        let mut target = ClientState::new(40);
+        let start = zone.last_step();
+        for step in 0..6{
+            target.position = Position::new(step as f32, 0.0, 10.0);
+            zone.game_state.entities.insert(40, Entity::player(target));
+            zone.simulate(start + Duration::from_millis(SIMULATION_STEP_MS * (step + 1)));
+        }
+        assert_eq!(zone.tick(), 6);
+
+        // The target has left the line of fire, but was in it when the shooter saw it 100ms earlier
+        let direction = Position::new(0.0, 0.0, 1.0);
+        let origin = Position::new(4.0, 1.0, 0.0);
+        assert_eq!(zone.rewind(6, latency, delay).unwrap()[&40].position, Position::new(3.0, 0.0, 10.0));
+        assert_eq!(zone.hit_scan(41, 6, latency, delay, Position::new(3.0, 1.0, 0.0), direction, 20.0), Some(40));
+        assert_eq!(zone.hit_scan(41, 6, latency, delay, origin, direction, 20.0), None);
+        assert_eq!(zone.hit_scan(41, 8, latency, delay, origin, direction, 20.0), None);
+        assert_eq!(zone.hit_scan(41, 7, latency, delay, origin, direction, 20.0), Some(40));
+
+        // Out of range, and the target can't shoot itself
+        assert_eq!(zone.hit_scan(41, 7, latency, delay, origin, direction, 5.0), None);
+        assert_eq!(zone.hit_scan(40, 7, latency, delay, origin, direction, 20.0), None);
+    }
+
+    #[test]
+    fn test_shots_fired_by_input_commands(){
+        let mut zone = zone(&ZoneTemplate::new(DEFAULT_ZONE));
+        zone.interpolation_delay = Duration::from_millis(100);
+        zone.enter(41, None);
+        let mut shooter = ClientState::new(41);
+        shooter.position = Position::new(2.0, 0.0, 0.0);
+        zone.game_state.entities.get_mut(&41).unwrap().set_state(shooter);
+
+        // A target runs across the shooter's line of fire, one unit each step
+        let mut target = Entity::player(ClientState::new(40));
+        target.set_component(Component::Health{ current: 10, maximum: 10 });
+        zone.game_state.entities.insert(40, target);
+        let start = zone.last_step();
+        for step in 0..6{
+            let mut state = ClientState::new(40);
+            state.position = Position::new(step as f32, 0.0, 10.0);
+            zone.game_state.entities.get_mut(&40).unwrap().set_state(state);
+            zone.simulate(start + Duration::from_millis(SIMULATION_STEP_MS * (step + 1)));
+        }
+
+        // The target has left the line of fire, but was in it when the shot was fired 50ms before reaching the server
+        let mut command = InputCommand::new(1, 0, 16);
+        command.buttons = BUTTON_PRIMARY;
+        zone.inbox().send(ZoneInput::Input(41, command, Duration::from_millis(50))).unwrap();
+        zone.simulate(start + Duration::from_millis(SIMULATION_STEP_MS * 7));
+        assert!(zone.game_state.entities[&40].components().contains(&Component::Health{ current: 9, maximum: 10 }));
+
+        // Without the latency the shot is checked against where the target was later, and misses
+        command.sequence = 2;
+        zone.inbox().send(ZoneInput::Input(41, command, Duration::from_millis(0))).unwrap();
+        zone.simulate(start + Duration::from_millis(SIMULATION_STEP_MS * 8));
+        assert!(zone.game_state.entities[&40].components().contains(&Component::Health{ current: 9, maximum: 10 }));
+    }
+}
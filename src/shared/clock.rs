@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+use std::io::Read;
+use std::time::Duration;
+use byteorder::{ByteOrder, BigEndian};
+
+use frame::{read_exact_into, ProtocolResult};
+
+/// Length of a clock sample on the wire
+pub const CLOCK_SAMPLE_LENGTH: usize = 8 * 5 + 4;
+
+/// The clock offset is estimated from at most this many of the latest samples
+const CLOCK_SAMPLE_WINDOW: usize = 8;
+
+/// The timestamps of one clock synchronization exchange, carried by a Ping.
+/// Times are in microseconds, each on the clock of the side which took it.
+/// The server also pings clients to measure the round trip, in which case the client answers and the roles are swapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClockSample{
+    /// When the client sent the ping
+    pub client_sent: u64,
+
+    /// When the server received the ping; zero until the server replies
+    pub server_received: u64,
+
+    /// When the server sent its reply; zero until the server replies
+    pub server_sent: u64,
+
+    /// The server's latest simulation tick when it replied
+    pub tick: u64,
+
+    /// When that tick was simulated
+    pub tick_time: u64,
+
+    /// Length of a simulation step on the server, in microseconds
+    pub step: u32
+}
+
+impl ClockSample{
+    /// A ping the client sends at @client_sent
+    pub fn new(client_sent: u64) -> ClockSample{
+        ClockSample{
+            client_sent: client_sent,
+            server_received: 0,
+            server_sent: 0,
+            tick: 0,
+            tick_time: 0,
+            step: 0
+        }
+    }
+
+    /// Fill in when the ping was @received and the reply @sent, on the answering side's clock
+    pub fn answer(&mut self, received: u64, sent: u64){
+        // Zero marks a ping which hasn't been answered
+        self.server_received = received.max(1);
+        self.server_sent = sent.max(1);
+    }
+
+    /// Return TRUE if this is a reply, rather than a ping to be answered
+    pub fn is_answered(&self) -> bool{
+        self.server_sent != 0
+    }
+
+    /// How far the server clock is ahead of the client's, if the reply arrived at @client_received
+    pub fn offset(&self, client_received: u64) -> i64{
+        let outbound = self.server_received as i64 - self.client_sent as i64;
+        let inbound = self.server_sent as i64 - client_received as i64;
+        return (outbound + inbound) / 2;
+    }
+
+    /// Time spent on the network, leaving out how long the server held the ping, if the reply arrived at @client_received
+    pub fn round_trip(&self, client_received: u64) -> u64{
+        let total = client_received.saturating_sub(self.client_sent);
+        return total.saturating_sub(self.server_sent.saturating_sub(self.server_received));
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = vec![0u8; CLOCK_SAMPLE_LENGTH];
+        BigEndian::write_u64(&mut buf[0..8], self.client_sent);
+        BigEndian::write_u64(&mut buf[8..16], self.server_received);
+        BigEndian::write_u64(&mut buf[16..24], self.server_sent);
+        BigEndian::write_u64(&mut buf[24..32], self.tick);
+        BigEndian::write_u64(&mut buf[32..40], self.tick_time);
+        BigEndian::write_u32(&mut buf[40..44], self.step);
+        return buf;
+    }
+
+    pub fn read<R: Read>(input: &mut R) -> ProtocolResult<ClockSample>{
+        let mut buf = [0u8; CLOCK_SAMPLE_LENGTH];
+        try!(read_exact_into(input, &mut buf));
+
+        Ok(ClockSample{
+            client_sent: BigEndian::read_u64(&buf[0..8]),
+            server_received: BigEndian::read_u64(&buf[8..16]),
+            server_sent: BigEndian::read_u64(&buf[16..24]),
+            tick: BigEndian::read_u64(&buf[24..32]),
+            tick_time: BigEndian::read_u64(&buf[32..40]),
+            step: BigEndian::read_u32(&buf[40..44])
+        })
+    }
+}
+
+/// @micros microseconds as a Duration
+pub fn micros_to_duration(micros: u64) -> Duration{
+    Duration::new(micros / 1000000, (micros % 1000000) as u32 * 1000)
+}
+
+/// Estimates the server's clock from the replies to clock sync pings.
+/// Like NTP, the offset is taken from the recent sample with the shortest round trip,
+/// as it had the least room for queueing delays to skew it.
+pub struct ClockSync{
+    /// The latest samples, with the offset and round trip each measured
+    samples: VecDeque<(ClockSample, i64, u64)>
+}
+
+impl ClockSync{
+    pub fn new() -> ClockSync{
+        ClockSync{
+            samples: VecDeque::with_capacity(CLOCK_SAMPLE_WINDOW)
+        }
+    }
+
+    /// Add the server's reply @sample, which arrived at @client_received
+    pub fn add_sample(&mut self, sample: ClockSample, client_received: u64){
+        if self.samples.len() == CLOCK_SAMPLE_WINDOW{
+            self.samples.pop_front();
+        }
+        self.samples.push_back((sample, sample.offset(client_received), sample.round_trip(client_received)));
+    }
+
+    /// The number of samples the estimate is made from
+    pub fn sample_count(&self) -> usize{
+        self.samples.len()
+    }
+
+    /// The sample with the shortest round trip
+    fn best(&self) -> Option<&(ClockSample, i64, u64)>{
+        self.samples.iter().min_by_key(|&&(_, _, round_trip)| round_trip)
+    }
+
+    /// How far the server clock is ahead of the client's, in microseconds
+    pub fn offset(&self) -> Option<i64>{
+        self.best().map(|&(_, offset, _)| offset)
+    }
+
+    /// The shortest recent round trip to the server
+    pub fn round_trip(&self) -> Option<Duration>{
+        self.best().map(|&(_, _, round_trip)| micros_to_duration(round_trip))
+    }
+
+    /// The server's clock when the client's reads @client_time
+    pub fn server_time(&self, client_time: u64) -> Option<u64>{
+        self.offset().map(|offset| (client_time as i64 + offset).max(0) as u64)
+    }
+
+    /// The simulation tick the server is running when the client's clock reads @client_time
+    pub fn server_tick(&self, client_time: u64) -> Option<u64>{
+        // Players moved between zones take up the tick of the zone they're in, so only the latest sample counts
+        let latest = match self.samples.back(){
+            Some(&(sample, _, _)) => sample,
+            None => { return None; }
+        };
+        if latest.step == 0{
+            return Some(latest.tick);
+        }
+        let server_time = match self.server_time(client_time){
+            Some(server_time) => server_time,
+            None => { return None; }
+        };
+        return Some(latest.tick + server_time.saturating_sub(latest.tick_time) / latest.step as u64);
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+
+    /// The reply to a ping sent at @sent, taking @outbound and @inbound microseconds each way,
+    /// from a server whose clock is @offset ahead and whose tick 10 ran at 1s
+    fn exchange(sent: u64, outbound: u64, inbound: u64, offset: u64) -> (ClockSample, u64){
+        let mut sample = ClockSample::new(sent);
+        sample.server_received = sent + outbound + offset;
+        sample.server_sent = sample.server_received + 500;
+        sample.tick = 10;
+        sample.tick_time = 1000000;
+        sample.step = 50000;
+        return (sample, sent + outbound + 500 + inbound);
+    }
+
+    #[test]
+    fn test_sample_round_trip(){
+        let (sample, _) = exchange(123, 10, 20, 5000000);
+        let bytes = sample.to_bytes();
+        assert_eq!(bytes.len(), CLOCK_SAMPLE_LENGTH);
+        assert_eq!(ClockSample::read(&mut bytes.as_slice()).unwrap(), sample);
+        assert!(ClockSample::read(&mut &bytes[..20]).is_err());
+    }
+
+    #[test]
+    fn test_clock_sync(){
+        let mut clock = ClockSync::new();
+        assert_eq!(clock.server_time(0), None);
+
+        // A symmetric exchange measures the offset exactly
+        let (sample, received) = exchange(0, 20000, 20000, 1000000);
+        clock.add_sample(sample, received);
+        assert_eq!(clock.offset(), Some(1000000));
+        assert_eq!(clock.round_trip(), Some(Duration::from_millis(40)));
+
+        // A sample delayed in a queue on the way back skews the offset, so is passed over
+        let (sample, received) = exchange(100000, 20000, 200000, 1000000);
+        clock.add_sample(sample, received);
+        assert_eq!(clock.offset(), Some(1000000));
+
+        // Server time 1.125s is two and a half steps after tick 10
+        assert_eq!(clock.server_time(125000), Some(1125000));
+        assert_eq!(clock.server_tick(125000), Some(12));
+    }
+}
@@ -0,0 +1,685 @@
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use byteorder::{ByteOrder, BigEndian};
+
+use frame::{read_exact_into, ProtocolError, ProtocolResult};
+use state::{ClientState, CLIENT_STATE_LENGTH};
+
+/// Sent in place of an owner for entities nobody owns
+const NO_OWNER: u32 = 0xFFFFFFFF;
+
+/// Length of an entity on the wire, before its components: state, kind, owner and component count
+pub const ENTITY_HEADER_LENGTH: usize = CLIENT_STATE_LENGTH + 1 + 4 + 1;
+
+/// Length of the type and payload length preceding each component
+const COMPONENT_HEADER_LENGTH: usize = 1 + 2;
+
+/// Dirty bit for an entity's position, orientation and velocity
+const DIRTY_STATE: u32 = 1 << 0;
+
+/// Dirty bit for an entity's kind and owner
+const DIRTY_META: u32 = 1 << 1;
+
+/// Everything about an entity is dirty until it has been replicated once
+const DIRTY_ALL: u32 = !0;
+
+/// EntityDelta flag: the delta carries the entity's state
+const DELTA_STATE: u8 = 0x01;
+
+/// EntityDelta flag: the delta carries the entity's kind and owner
+const DELTA_META: u8 = 0x02;
+
+/// EntityDelta flag: the entity has been removed from the world
+const DELTA_DESPAWNED: u8 = 0x04;
+
+/// Who a component is replicated to
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Replication{
+    Everyone,
+
+    /// Only the player owning the entity
+    OwnerOnly,
+
+    /// Nobody; the component only exists on the server
+    ServerOnly
+}
+
+/// Who each type of component is replicated to, by type ID
+const REPLICATION: [(u8, Replication); 6] = [
+    (0x01, Replication::Everyone),      // Health
+    (0x02, Replication::Everyone),      // Item
+    (0x03, Replication::Everyone),      // Door
+    (0x04, Replication::Everyone),      // Damage
+    (0x05, Replication::OwnerOnly),     // Ammo
+    (0x06, Replication::ServerOnly)     // Loot
+];
+
+/// What sort of thing an entity is
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum EntityKind{
+    Player      = 0x00,
+    Npc         = 0x01,
+    Item        = 0x02,
+    Projectile  = 0x03,
+    Door        = 0x04
+}
+
+impl EntityKind{
+    pub fn from_u8(byte: u8) -> Option<EntityKind>{
+        match byte{
+            0x00 => { Some(EntityKind::Player) },
+            0x01 => { Some(EntityKind::Npc) },
+            0x02 => { Some(EntityKind::Item) },
+            0x03 => { Some(EntityKind::Projectile) },
+            0x04 => { Some(EntityKind::Door) },
+            _ => None
+        }
+    }
+}
+
+/// Replicated data attached to an entity, at most one of each type
+#[derive(Debug, PartialEq, Clone)]
+pub enum Component{
+    Health{ current: u16, maximum: u16 },
+
+    /// A stack of @quantity of the item @item_id
+    Item{ item_id: u32, quantity: u16 },
+
+    Door{ open: bool },
+
+    /// Damage dealt on hit, by projectiles
+    Damage(u16),
+
+    /// Rounds left, only shown to the owner
+    Ammo(u16),
+
+    /// The loot table rolled when the entity dies
+    Loot{ table_id: u32 }
+}
+
+impl Component{
+    /// The type byte identifying this component on the wire
+    pub fn type_id(&self) -> u8{
+        match *self{
+            Component::Health{ .. } => 0x01,
+            Component::Item{ .. } => 0x02,
+            Component::Door{ .. } => 0x03,
+            Component::Damage(_) => 0x04,
+            Component::Ammo(_) => 0x05,
+            Component::Loot{ .. } => 0x06
+        }
+    }
+
+    /// Who components of type @type_id are replicated to
+    pub fn replication_for(type_id: u8) -> Replication{
+        REPLICATION.iter()
+            .find(|&&(id, _)| id == type_id)
+            .map(|&(_, replication)| replication)
+            .unwrap_or(Replication::ServerOnly)
+    }
+
+    pub fn replication(&self) -> Replication{
+        Self::replication_for(self.type_id())
+    }
+
+    /// Return TRUE if components of type @type_id on an entity owned by @owner are sent to @recipient
+    pub fn is_visible_to(type_id: u8, owner: Option<u32>, recipient: u32) -> bool{
+        match Self::replication_for(type_id){
+            Replication::Everyone => true,
+            Replication::OwnerOnly => owner == Some(recipient),
+            Replication::ServerOnly => false
+        }
+    }
+
+    fn dirty_bit(type_id: u8) -> u32{
+        1 << (8 + (type_id as u32 % 24))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>{
+        match *self{
+            Component::Health{ current, maximum } => {
+                let mut buf = [0u8; 4];
+                BigEndian::write_u16(&mut buf[0..2], current);
+                BigEndian::write_u16(&mut buf[2..4], maximum);
+                return buf.to_vec();
+            },
+            Component::Item{ item_id, quantity } => {
+                let mut buf = [0u8; 6];
+                BigEndian::write_u32(&mut buf[0..4], item_id);
+                BigEndian::write_u16(&mut buf[4..6], quantity);
+                return buf.to_vec();
+            },
+            Component::Door{ open } => {
+                return vec![open as u8];
+            },
+            Component::Damage(value) | Component::Ammo(value) => {
+                let mut buf = [0u8; 2];
+                BigEndian::write_u16(&mut buf, value);
+                return buf.to_vec();
+            },
+            Component::Loot{ table_id } => {
+                let mut buf = [0u8; 4];
+                BigEndian::write_u32(&mut buf, table_id);
+                return buf.to_vec();
+            }
+        }
+    }
+
+    /// Parse the @payload of a component of type @type_id.
+    /// Types this build doesn't know are skipped, returning None.
+    pub fn read(type_id: u8, payload: &[u8]) -> ProtocolResult<Option<Component>>{
+        let expected = match type_id{
+            0x01 => 4,
+            0x02 => 6,
+            0x03 => 1,
+            0x04 => 2,
+            0x05 => 2,
+            0x06 => 4,
+            _ => { return Ok(None); }
+        };
+        if payload.len() != expected{
+            return Err(ProtocolError::Truncated{ expected: expected, got: payload.len() });
+        }
+
+        let component = match type_id{
+            0x01 => Component::Health{ current: BigEndian::read_u16(&payload[0..2]), maximum: BigEndian::read_u16(&payload[2..4]) },
+            0x02 => Component::Item{ item_id: BigEndian::read_u32(&payload[0..4]), quantity: BigEndian::read_u16(&payload[4..6]) },
+            0x03 => Component::Door{ open: payload[0] != 0 },
+            0x04 => Component::Damage(BigEndian::read_u16(payload)),
+            0x05 => Component::Ammo(BigEndian::read_u16(payload)),
+            _ => Component::Loot{ table_id: BigEndian::read_u32(payload) }
+        };
+        return Ok(Some(component));
+    }
+
+    fn write_components(buf: &mut Vec<u8>, components: &[Component]){
+        buf.push(components.len() as u8);
+        for component in components{
+            let payload = component.to_bytes();
+            let mut header = [0u8; COMPONENT_HEADER_LENGTH];
+            header[0] = component.type_id();
+            BigEndian::write_u16(&mut header[1..3], payload.len() as u16);
+            buf.extend_from_slice(&header);
+            buf.extend_from_slice(&payload);
+        }
+    }
+
+    fn read_components<R: Read>(input: &mut R) -> ProtocolResult<Vec<Component>>{
+        let mut count = [0u8; 1];
+        try!(read_exact_into(input, &mut count));
+
+        let mut components = Vec::with_capacity(count[0] as usize);
+        for _ in 0..count[0]{
+            let mut component_header = [0u8; COMPONENT_HEADER_LENGTH];
+            try!(read_exact_into(input, &mut component_header));
+
+            let mut payload = vec![0u8; BigEndian::read_u16(&component_header[1..3]) as usize];
+            try!(read_exact_into(input, &mut payload));
+
+            if let Some(component) = try!(Component::read(component_header[0], &payload)){
+                components.push(component);
+            }
+        }
+        return Ok(components);
+    }
+}
+
+/// Anything in the replicated world: players, NPCs, dropped items, projectiles, doors.
+/// Changes are made through setters, which mark what has changed since the last replication.
+#[derive(Debug, Clone)]
+pub struct Entity{
+    /// The entity's ID, position, orientation and velocity
+    state: ClientState,
+
+    kind: EntityKind,
+
+    /// The ID of the player entity this belongs to, if any
+    owner: Option<u32>,
+
+    components: Vec<Component>,
+
+    /// What has changed since the entity was last replicated
+    dirty: u32,
+
+    /// Types of the components removed since the entity was last replicated
+    removed: Vec<u8>,
+
+    /// Whoever owned the entity when it was last replicated, if ownership has changed since
+    previous_owner: Option<u32>
+}
+
+impl Entity{
+    pub fn new(state: ClientState, kind: EntityKind) -> Entity{
+        Entity{
+            state: state,
+            kind: kind,
+            owner: None,
+            components: Vec::new(),
+            dirty: DIRTY_ALL,
+            removed: Vec::new(),
+            previous_owner: None
+        }
+    }
+
+    /// The entity controlled by the player given by @state, which the player owns
+    pub fn player(state: ClientState) -> Entity{
+        let mut entity = Self::new(state, EntityKind::Player);
+        entity.owner = Some(state.id);
+        return entity;
+    }
+
+    pub fn id(&self) -> u32{
+        self.state.id
+    }
+
+    pub fn state(&self) -> &ClientState{
+        &self.state
+    }
+
+    pub fn kind(&self) -> EntityKind{
+        self.kind
+    }
+
+    pub fn owner(&self) -> Option<u32>{
+        self.owner
+    }
+
+    pub fn components(&self) -> &[Component]{
+        &self.components
+    }
+
+    /// Update the entity's position, orientation and velocity. The ID is left unchanged.
+    pub fn set_state(&mut self, state: ClientState){
+        let id = self.state.id;
+        self.state = state;
+        self.state.id = id;
+        self.dirty |= DIRTY_STATE;
+    }
+
+    /// Hand the entity to @owner. The new owner is sent the components only owners see,
+    /// and the previous owner is told to forget them.
+    pub fn set_owner(&mut self, owner: Option<u32>){
+        if owner != self.owner{
+            if self.previous_owner.is_none(){
+                self.previous_owner = self.owner;
+            }
+            for type_id in self.owner_only_components(){
+                self.dirty |= Component::dirty_bit(type_id);
+            }
+        }
+        self.owner = owner;
+        self.dirty |= DIRTY_META;
+    }
+
+    /// Types of the components on the entity which only its owner sees
+    fn owner_only_components(&self) -> Vec<u8>{
+        self.components.iter()
+            .map(|component| component.type_id())
+            .filter(|type_id| Component::replication_for(*type_id) == Replication::OwnerOnly)
+            .collect()
+    }
+
+    /// The entity's component of type @type_id, if it has one
+    pub fn component(&self, type_id: u8) -> Option<&Component>{
+        self.components.iter().find(|component| component.type_id() == type_id)
+    }
+
+    /// Add @component, replacing any existing component of the same type
+    pub fn set_component(&mut self, component: Component){
+        let type_id = component.type_id();
+        self.components.retain(|existing| existing.type_id() != type_id);
+        self.components.push(component);
+        self.removed.retain(|removed| *removed != type_id);
+        self.dirty |= Component::dirty_bit(type_id);
+    }
+
+    /// Remove the component of type @type_id, returning it if the entity had one
+    pub fn remove_component(&mut self, type_id: u8) -> Option<Component>{
+        let index = match self.components.iter().position(|component| component.type_id() == type_id){
+            Some(index) => index,
+            None => { return None; }
+        };
+        self.removed.push(type_id);
+        self.dirty &= !Component::dirty_bit(type_id);
+        return Some(self.components.remove(index));
+    }
+
+    /// Return TRUE if anything has changed since the entity was last replicated
+    pub fn is_dirty(&self) -> bool{
+        self.dirty != 0 || !self.removed.is_empty()
+    }
+
+    /// Mark the entity as replicated
+    pub fn clear_dirty(&mut self){
+        self.dirty = 0;
+        self.removed.clear();
+        self.previous_owner = None;
+    }
+
+    /// The changes @recipient should be sent, or everything visible to them if @full.
+    /// Returns None if none of the changes are visible to them.
+    pub fn delta_for(&self, recipient: u32, full: bool) -> Option<EntityDelta>{
+        let dirty = if full { DIRTY_ALL } else { self.dirty };
+        let owner = self.owner;
+        let visible = |type_id: u8| Component::is_visible_to(type_id, owner, recipient);
+
+        let mut removed_components: Vec<u8> = if full { Vec::new() } else { self.removed.iter().cloned().filter(|type_id| visible(*type_id)).collect() };
+
+        // A player which lost the entity no longer sees what only owners do
+        if !full && self.previous_owner == Some(recipient) && owner != Some(recipient){
+            removed_components.extend(self.owner_only_components());
+        }
+
+        let delta = EntityDelta{
+            id: self.id(),
+            state: if dirty & DIRTY_STATE != 0 { Some(self.state) } else { None },
+            meta: if dirty & DIRTY_META != 0 { Some((self.kind, self.owner)) } else { None },
+            components: self.components.iter()
+                .filter(|component| dirty & Component::dirty_bit(component.type_id()) != 0 && visible(component.type_id()))
+                .cloned()
+                .collect(),
+            removed_components: removed_components,
+            despawned: false
+        };
+
+        if delta.is_empty(){
+            return None;
+        }
+        return Some(delta);
+    }
+
+    /// Apply changes replicated from the server
+    pub fn apply(&mut self, delta: &EntityDelta){
+        if let Some(state) = delta.state{
+            self.state = state;
+        }
+        if let Some((kind, owner)) = delta.meta{
+            self.kind = kind;
+            self.owner = owner;
+        }
+        for component in delta.components.iter(){
+            self.components.retain(|existing| existing.type_id() != component.type_id());
+            self.components.push(component.clone());
+        }
+        for type_id in delta.removed_components.iter(){
+            self.components.retain(|existing| existing.type_id() != *type_id);
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = self.state.to_bytes();
+        buf.push(self.kind as u8);
+
+        let mut owner = [0u8; 4];
+        BigEndian::write_u32(&mut owner, self.owner.unwrap_or(NO_OWNER));
+        buf.extend_from_slice(&owner);
+
+        Component::write_components(&mut buf, &self.components);
+        return buf;
+    }
+
+    pub fn read<R: Read>(input: &mut R) -> ProtocolResult<Entity>{
+        let state = try!(ClientState::read(input));
+
+        let mut header = [0u8; ENTITY_HEADER_LENGTH - CLIENT_STATE_LENGTH - 1];
+        try!(read_exact_into(input, &mut header));
+
+        let kind = try!(EntityKind::from_u8(header[0]).ok_or(ProtocolError::UnknownCode(header[0])));
+        let owner = match BigEndian::read_u32(&header[1..5]){
+            NO_OWNER => None,
+            owner => Some(owner)
+        };
+
+        let mut entity = Entity::new(state, kind);
+        entity.owner = owner;
+        entity.components = try!(Component::read_components(input));
+        return Ok(entity);
+    }
+}
+
+/// What has changed about an entity since it was last replicated
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityDelta{
+    pub id: u32,
+
+    /// The entity's new position, orientation and velocity
+    pub state: Option<ClientState>,
+
+    /// The entity's new kind and owner
+    pub meta: Option<(EntityKind, Option<u32>)>,
+
+    /// Components added or changed
+    pub components: Vec<Component>,
+
+    /// Types of the components removed
+    pub removed_components: Vec<u8>,
+
+    /// Set when the entity has been removed from the world
+    pub despawned: bool
+}
+
+impl EntityDelta{
+    /// A delta removing the entity @id from the world
+    pub fn despawn(id: u32) -> EntityDelta{
+        EntityDelta{
+            id: id,
+            state: None,
+            meta: None,
+            components: Vec::new(),
+            removed_components: Vec::new(),
+            despawned: true
+        }
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.state.is_none() && self.meta.is_none() && self.components.is_empty() && self.removed_components.is_empty() && !self.despawned
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = vec![0u8; 5];
+        BigEndian::write_u32(&mut buf[0..4], self.id);
+
+        let mut flags = 0;
+        if let Some(ref state) = self.state{
+            flags |= DELTA_STATE;
+            buf.extend_from_slice(&state.to_bytes());
+        }
+        if let Some((kind, owner)) = self.meta{
+            flags |= DELTA_META;
+            let mut meta = [0u8; 5];
+            meta[0] = kind as u8;
+            BigEndian::write_u32(&mut meta[1..5], owner.unwrap_or(NO_OWNER));
+            buf.extend_from_slice(&meta);
+        }
+        if self.despawned{
+            flags |= DELTA_DESPAWNED;
+        }
+        buf[4] = flags;
+
+        Component::write_components(&mut buf, &self.components);
+        buf.push(self.removed_components.len() as u8);
+        buf.extend_from_slice(&self.removed_components);
+        return buf;
+    }
+
+    pub fn read<R: Read>(input: &mut R) -> ProtocolResult<EntityDelta>{
+        let mut header = [0u8; 5];
+        try!(read_exact_into(input, &mut header));
+        let flags = header[4];
+
+        let mut delta = EntityDelta::despawn(BigEndian::read_u32(&header[0..4]));
+        delta.despawned = flags & DELTA_DESPAWNED != 0;
+
+        if flags & DELTA_STATE != 0{
+            let state = try!(ClientState::read(input));
+            delta.state = Some(state);
+        }
+        if flags & DELTA_META != 0{
+            let mut meta = [0u8; 5];
+            try!(read_exact_into(input, &mut meta));
+            let kind = try!(EntityKind::from_u8(meta[0]).ok_or(ProtocolError::UnknownCode(meta[0])));
+            let owner = match BigEndian::read_u32(&meta[1..5]){
+                NO_OWNER => None,
+                owner => Some(owner)
+            };
+            delta.meta = Some((kind, owner));
+        }
+
+        delta.components = try!(Component::read_components(input));
+
+        let mut removed_count = [0u8; 1];
+        try!(read_exact_into(input, &mut removed_count));
+        delta.removed_components = vec![0u8; removed_count[0] as usize];
+        try!(read_exact_into(input, &mut delta.removed_components));
+
+        return Ok(delta);
+    }
+}
+
+impl Hash for Entity{
+    fn hash<H: Hasher>(&self, state: &mut H){
+        self.state.hash(state);
+    }
+}
+
+impl Hash for EntityDelta{
+    fn hash<H: Hasher>(&self, state: &mut H){
+        state.write_u32(self.id);
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use state::{ClientState, Position};
+
+    #[test]
+    fn test_entity_round_trip(){
+        let mut state = ClientState::new(0x01000000);
+        state.position = Position::new(4.0, 0.0, -2.5);
+
+        let mut entity = Entity::new(state, EntityKind::Item);
+        entity.set_owner(Some(7));
+        entity.set_component(Component::Item{ item_id: 12, quantity: 3 });
+        entity.set_component(Component::Health{ current: 5, maximum: 10 });
+        entity.set_component(Component::Item{ item_id: 12, quantity: 4 });
+
+        let bytes = entity.to_bytes();
+        let read = Entity::read(&mut bytes.as_slice()).unwrap();
+
+        assert_eq!(read.id(), 0x01000000);
+        assert_eq!(read.kind, EntityKind::Item);
+        assert_eq!(read.owner, Some(7));
+        assert_eq!(read.state.position, state.position);
+        assert_eq!(read.components.len(), 2);
+        assert_eq!(read.component(0x02), Some(&Component::Item{ item_id: 12, quantity: 4 }));
+    }
+
+    #[test]
+    fn test_unknown_component_skipped(){
+        let mut bytes = Entity::player(ClientState::new(3)).to_bytes();
+
+        // One component of a type from a newer build, followed by a door
+        bytes[ENTITY_HEADER_LENGTH - 1] = 2;
+        bytes.extend_from_slice(&[0x7F, 0x00, 0x02, 0xAB, 0xCD]);
+        bytes.extend_from_slice(&[0x03, 0x00, 0x01, 0x01]);
+
+        let read = Entity::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read.owner, Some(3));
+        assert_eq!(read.components, vec![Component::Door{ open: true }]);
+    }
+
+    #[test]
+    fn test_dirty_components_replicated(){
+        let mut entity = Entity::new(ClientState::new(0x01000000), EntityKind::Npc);
+        entity.set_owner(Some(7));
+        entity.set_component(Component::Health{ current: 10, maximum: 10 });
+        entity.set_component(Component::Ammo(30));
+        entity.set_component(Component::Loot{ table_id: 3 });
+
+        // Until it has been replicated once, everything the recipient may see is sent
+        let first = entity.delta_for(8, false).unwrap();
+        assert!(first.state.is_some());
+        assert_eq!(first.meta, Some((EntityKind::Npc, Some(7))));
+        assert_eq!(first.components, vec![Component::Health{ current: 10, maximum: 10 }]);
+        assert_eq!(entity.delta_for(7, false).unwrap().components.len(), 2);
+
+        // Afterwards only what changed, and nothing at all if others can't see the change
+        entity.clear_dirty();
+        assert!(entity.delta_for(7, false).is_none());
+        entity.set_component(Component::Ammo(29));
+        assert!(entity.delta_for(8, false).is_none());
+        assert_eq!(entity.delta_for(7, false), Some(EntityDelta{
+            id: 0x01000000,
+            state: None,
+            meta: None,
+            components: vec![Component::Ammo(29)],
+            removed_components: Vec::new(),
+            despawned: false
+        }));
+
+        entity.clear_dirty();
+        entity.remove_component(0x01);
+        let removed = entity.delta_for(8, false).unwrap();
+        assert_eq!(removed.removed_components, vec![0x01]);
+
+        // A full snapshot ignores what has already been replicated
+        assert_eq!(entity.delta_for(7, true).unwrap().components, vec![Component::Ammo(29)]);
+    }
+
+    #[test]
+    fn test_owner_change_replicated(){
+        let mut entity = Entity::new(ClientState::new(0x01000000), EntityKind::Item);
+        entity.set_owner(Some(7));
+        entity.set_component(Component::Item{ item_id: 4, quantity: 1 });
+        entity.set_component(Component::Ammo(30));
+        entity.clear_dirty();
+
+        // The new owner is sent what only owners see, and the previous one told to forget it
+        entity.set_owner(Some(8));
+        let gained = entity.delta_for(8, false).unwrap();
+        assert_eq!(gained.meta, Some((EntityKind::Item, Some(8))));
+        assert_eq!(gained.components, vec![Component::Ammo(30)]);
+        let lost = entity.delta_for(7, false).unwrap();
+        assert!(lost.components.is_empty());
+        assert_eq!(lost.removed_components, vec![0x05]);
+
+        // Bystanders only see the owner change
+        let seen = entity.delta_for(9, false).unwrap();
+        assert!(seen.components.is_empty() && seen.removed_components.is_empty());
+
+        // Handing it back before it's replicated leaves the original owner with nothing to forget
+        entity.set_owner(Some(7));
+        assert!(entity.delta_for(7, false).unwrap().removed_components.is_empty());
+        assert_eq!(entity.delta_for(8, false).unwrap().removed_components, Vec::<u8>::new());
+
+        entity.clear_dirty();
+        assert!(entity.delta_for(7, false).is_none());
+    }
+
+    #[test]
+    fn test_delta_round_trip(){
+        let mut state = ClientState::new(5);
+        state.position = Position::new(1.0, 2.0, 3.0);
+
+        let mut player = Entity::player(state);
+        player.set_component(Component::Health{ current: 3, maximum: 5 });
+        let deltas = vec![player.delta_for(5, false).unwrap(), EntityDelta::despawn(0x01000001)];
+
+        let bytes: Vec<u8> = deltas.iter().flat_map(|delta| delta.to_bytes()).collect();
+        let mut remaining = bytes.as_slice();
+        let first = EntityDelta::read(&mut remaining).unwrap();
+        let second = EntityDelta::read(&mut remaining).unwrap();
+        assert!(remaining.is_empty());
+        assert_eq!(first, deltas[0]);
+        assert_eq!(second, deltas[1]);
+
+        // Applying the delta to a blank entity reproduces the original
+        let mut replica = Entity::new(ClientState::new(5), EntityKind::Npc);
+        replica.apply(&first);
+        assert_eq!(replica.kind(), EntityKind::Player);
+        assert_eq!(replica.owner(), Some(5));
+        assert_eq!(replica.state().position, state.position);
+        assert_eq!(replica.components(), player.components());
+    }
+}
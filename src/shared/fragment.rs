@@ -0,0 +1,319 @@
+extern crate log;
+
+use std::collections::HashMap;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use frame::{Message, ProtocolError, ProtocolResult};
+
+/// Frames longer than this are split into fragments, each small enough for one UDP message
+pub const DEFAULT_FRAGMENT_SIZE: usize = 1024;
+
+/// Reassembled frames longer than this are rejected
+pub const DEFAULT_MAX_MESSAGE_LENGTH: usize = 1024 * 1024;
+
+/// At most this many bytes of incomplete messages are buffered per connection
+pub const DEFAULT_MAX_BUFFERED: usize = 4 * 1024 * 1024;
+
+/// At most this many incomplete messages are buffered per connection
+pub const DEFAULT_MAX_PARTIAL_MESSAGES: usize = 64;
+
+/// Incomplete messages are dropped if they aren't completed within this many seconds
+pub const DEFAULT_REASSEMBLY_TIMEOUT_SECS: u64 = 10;
+
+/// Splits frames too large to send in one piece into Fragment messages
+pub struct Fragmenter{
+    next_message_id: u32,
+    pub fragment_size: usize
+}
+
+impl Fragmenter{
+    pub fn new() -> Fragmenter{
+        Fragmenter{
+            next_message_id: 0,
+            fragment_size: DEFAULT_FRAGMENT_SIZE
+        }
+    }
+
+    /// Return TRUE if a frame of @length bytes must be sent as fragments
+    pub fn needs_split(&self, length: usize) -> bool{
+        length > self.fragment_size
+    }
+
+    /// Split the encoded frame @frame_bytes into Fragment messages, under a new message ID
+    pub fn split(&mut self, frame_bytes: &[u8]) -> Vec<Message>{
+        let message_id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+
+        let count = (frame_bytes.len() + self.fragment_size - 1) / self.fragment_size;
+        return frame_bytes.chunks(self.fragment_size).enumerate().map(|(index, data)|{
+            Message::Fragment{ message_id: message_id, index: index as u16, count: count as u16, data: data.to_vec() }
+        }).collect();
+    }
+}
+
+/// The fragments of a message received so far
+struct PartialMessage{
+    fragments: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    length: usize,
+
+    /// Bytes taken up by the fragment slots themselves, which count toward the buffer cap
+    slots: usize,
+    started: Instant
+}
+
+/// Collects fragments until every piece of a message has arrived
+pub struct Reassembler{
+    partial: HashMap<u32, PartialMessage>,
+    buffered: usize,
+
+    /// The size the sender splits frames into; only the last fragment of a message may be shorter
+    pub fragment_size: usize,
+    pub max_message_length: usize,
+    pub max_buffered: usize,
+    pub max_partial_messages: usize,
+    pub timeout: Duration
+}
+
+impl Reassembler{
+    pub fn new() -> Reassembler{
+        Reassembler{
+            partial: HashMap::new(),
+            buffered: 0,
+            fragment_size: DEFAULT_FRAGMENT_SIZE,
+            max_message_length: DEFAULT_MAX_MESSAGE_LENGTH,
+            max_buffered: DEFAULT_MAX_BUFFERED,
+            max_partial_messages: DEFAULT_MAX_PARTIAL_MESSAGES,
+            timeout: Duration::from_secs(DEFAULT_REASSEMBLY_TIMEOUT_SECS)
+        }
+    }
+
+    /// Add fragment @index of @count of the message @message_id.
+    /// Returns the message once all of its fragments have arrived.
+    pub fn insert(&mut self, message_id: u32, index: u16, count: u16, data: Vec<u8>, now: Instant) -> ProtocolResult<Option<Message>>{
+        if index >= count || data.len() > self.fragment_size{
+            return Err(ProtocolError::BadFragment{ message_id: message_id });
+        }
+
+        if !self.partial.contains_key(&message_id){
+            // Every fragment but the last is full, so a message's fragment count says how long it is at least
+            let shortest = (count as usize - 1) * self.fragment_size + 1;
+            if shortest > self.max_message_length{
+                return Err(ProtocolError::TooLarge{ length: shortest as u32, max: self.max_message_length as u32 });
+            }
+
+            if self.partial.len() >= self.max_partial_messages{
+                warn!("Too many incomplete messages, dropping the oldest");
+                self.remove_oldest();
+            }
+
+            let slots = count as usize * mem::size_of::<Option<Vec<u8>>>();
+            self.buffered += slots;
+            self.partial.insert(message_id, PartialMessage{
+                fragments: vec![None; count as usize],
+                missing: count as usize,
+                length: 0,
+                slots: slots,
+                started: now
+            });
+        }
+
+        let length = {
+            let partial = self.partial.get_mut(&message_id).unwrap();
+
+            if partial.fragments.len() != count as usize{
+                return Err(ProtocolError::BadFragment{ message_id: message_id });
+            }
+            if partial.fragments[index as usize].is_some(){
+                trace!("Ignoring duplicate fragment {} of message {}", index, message_id);
+                return Ok(None);
+            }
+
+            partial.length + data.len()
+        };
+
+        if length > self.max_message_length{
+            self.remove(message_id);
+            return Err(ProtocolError::TooLarge{ length: length as u32, max: self.max_message_length as u32 });
+        }
+
+        self.buffered += data.len();
+        let complete = {
+            let partial = self.partial.get_mut(&message_id).unwrap();
+            partial.length = length;
+            partial.fragments[index as usize] = Some(data);
+            partial.missing -= 1;
+            partial.missing == 0
+        };
+
+        if complete{
+            let frame_bytes = self.remove(message_id).map(|partial| partial.fragments.into_iter().flat_map(|fragment| fragment.unwrap()).collect::<Vec<u8>>()).unwrap_or(Vec::new());
+            let message = try!(Message::read_limited(&mut frame_bytes.as_slice(), self.max_message_length as u32));
+
+            // Fragments can't be nested
+            if let Message::Fragment{ .. } = message{
+                return Err(ProtocolError::BadFragment{ message_id: message_id });
+            }
+            return Ok(Some(message));
+        }
+
+        self.evict();
+        return Ok(None);
+    }
+
+    /// Drop incomplete messages which have been waiting longer than the timeout.
+    /// Returns how many were dropped.
+    pub fn expire(&mut self, now: Instant) -> usize{
+        let timeout = self.timeout;
+        let expired: Vec<u32> = self.partial.iter()
+            .filter(|&(_, partial)| now.duration_since(partial.started) > timeout)
+            .map(|(message_id, _)| *message_id)
+            .collect();
+
+        for message_id in expired.iter(){
+            debug!("Dropping incomplete message {}", message_id);
+            self.remove(*message_id);
+        }
+        return expired.len();
+    }
+
+    /// Drop the oldest incomplete messages until the buffer is back under its cap
+    fn evict(&mut self){
+        while self.buffered > self.max_buffered{
+            warn!("Reassembly buffer full, dropping the oldest incomplete message");
+            if !self.remove_oldest(){
+                break;
+            }
+        }
+    }
+
+    /// Drop the incomplete message which started first. Returns FALSE if there are none.
+    fn remove_oldest(&mut self) -> bool{
+        let oldest = self.partial.iter().min_by_key(|&(_, partial)| partial.started).map(|(message_id, _)| *message_id);
+        match oldest{
+            Some(message_id) => {
+                debug!("Dropping incomplete message {}", message_id);
+                self.remove(message_id);
+                true
+            },
+            None => false
+        }
+    }
+
+    fn remove(&mut self, message_id: u32) -> Option<PartialMessage>{
+        let partial = self.partial.remove(&message_id);
+        if let Some(ref partial) = partial{
+            self.buffered -= partial.length + partial.slots;
+        }
+        return partial;
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use frame::{Message, ProtocolError, ToFrame};
+    use std::time::{Duration, Instant};
+
+    fn long_text(length: usize) -> String{
+        (0..length).map(|i| (b'a' + (i % 26) as u8) as char).collect()
+    }
+
+    fn insert(reassembler: &mut Reassembler, fragment: Message, now: Instant) -> ProtocolResult<Option<Message>>{
+        match fragment{
+            Message::Fragment{ message_id, index, count, data } => reassembler.insert(message_id, index, count, data, now),
+            _ => panic!("Expected a fragment")
+        }
+    }
+
+    #[test]
+    fn test_split_and_reassemble(){
+        let text = long_text(5000);
+        let frame_bytes = Message::new_text_message(text.clone()).to_frame().to_bytes();
+
+        let mut fragmenter = Fragmenter::new();
+        assert!(fragmenter.needs_split(frame_bytes.len()));
+        let mut fragments = fragmenter.split(&frame_bytes);
+        assert_eq!(fragments.len(), 5);
+
+        // Fragments may arrive in any order, and more than once
+        fragments.reverse();
+        let duplicate = fragments[0].clone();
+        fragments.insert(1, duplicate);
+
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        let last = fragments.pop().unwrap();
+        for fragment in fragments{
+            assert!(insert(&mut reassembler, fragment, now).unwrap().is_none());
+        }
+        match insert(&mut reassembler, last, now).unwrap(){
+            Some(Message::Text{ message }) => { assert_eq!(message, text); },
+            other => { panic!("Expected the reassembled text, got {:?}", other); }
+        }
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn test_reassembly_limits(){
+        let frame_bytes = Message::new_text_message(long_text(5000)).to_frame().to_bytes();
+        let mut fragmenter = Fragmenter::new();
+        let now = Instant::now();
+
+        // Incomplete messages time out
+        let mut reassembler = Reassembler::new();
+        insert(&mut reassembler, fragmenter.split(&frame_bytes).remove(0), now).unwrap();
+        assert_eq!(reassembler.expire(now), 0);
+        assert_eq!(reassembler.expire(now + reassembler.timeout + Duration::from_secs(1)), 1);
+        assert_eq!(reassembler.buffered, 0);
+
+        // The oldest incomplete message is dropped when the buffer is full
+        reassembler.max_buffered = 1500;
+        insert(&mut reassembler, fragmenter.split(&frame_bytes).remove(0), now).unwrap();
+        insert(&mut reassembler, fragmenter.split(&frame_bytes).remove(0), now + Duration::from_millis(1)).unwrap();
+        assert_eq!(reassembler.buffered, 1024 + 5 * mem::size_of::<Option<Vec<u8>>>());
+
+        // Messages over the length limit are rejected, as soon as the fragment count gives them away
+        reassembler.max_buffered = DEFAULT_MAX_BUFFERED;
+        reassembler.max_message_length = 2000;
+        match insert(&mut reassembler, fragmenter.split(&frame_bytes).remove(0), now){
+            Err(ProtocolError::TooLarge{ length: 4097, max: 2000 }) => {},
+            other => { panic!("Expected the message to be too large, got {:?}", other); }
+        }
+        reassembler.insert(50, 0, 2, vec![0; 1024], now).unwrap();
+        match reassembler.insert(50, 1, 2, vec![0; 1024], now){
+            Err(ProtocolError::TooLarge{ length: 2048, max: 2000 }) => {},
+            other => { panic!("Expected the message to be too large, got {:?}", other); }
+        }
+
+        match reassembler.insert(99, 3, 3, Vec::new(), now){
+            Err(ProtocolError::BadFragment{ message_id: 99 }) => {},
+            other => { panic!("Expected a bad fragment, got {:?}", other); }
+        }
+        match reassembler.insert(99, 0, 3, vec![0; 1025], now){
+            Err(ProtocolError::BadFragment{ message_id: 99 }) => {},
+            other => { panic!("Expected a bad fragment, got {:?}", other); }
+        }
+    }
+
+    #[test]
+    fn test_empty_fragments_capped(){
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+
+        // A fragment count no message under the length limit could need is rejected outright
+        match reassembler.insert(1, 0, 65535, Vec::new(), now){
+            Err(ProtocolError::TooLarge{ .. }) => {},
+            other => { panic!("Expected the message to be too large, got {:?}", other); }
+        }
+
+        // Empty fragments of many messages still fill the buffer, and only so many messages are kept
+        for message_id in 0..1000{
+            reassembler.insert(message_id, 0, 1000, Vec::new(), now + Duration::from_millis(message_id as u64)).unwrap();
+        }
+        assert_eq!(reassembler.partial.len(), DEFAULT_MAX_PARTIAL_MESSAGES);
+        assert!(reassembler.buffered <= reassembler.max_buffered);
+        assert_eq!(reassembler.buffered, DEFAULT_MAX_PARTIAL_MESSAGES * 1000 * mem::size_of::<Option<Vec<u8>>>());
+    }
+}
@@ -1,22 +1,66 @@
 extern crate byteorder;
 extern crate log;
 
-use std::io::{Read, ErrorKind, Result, Error};
+use std::io::{self, Read, ErrorKind};
+use std::error;
+use std::fmt;
 use byteorder::{ByteOrder, BigEndian};
-use std::collections::VecDeque;
-use std::mem;
+use lz4_flex::block::{compress_prepend_size, decompress_size_prepended};
+use std::collections::BTreeMap;
 
-#[path="../shared/state.rs"]
-mod state;
-use state::{ClientState, GameState};
+use state::ClientState;
+use entity::{Entity, EntityDelta};
+use input::{InputCommand, INPUT_COMMAND_LENGTH};
+use clock::{ClockSample, CLOCK_SAMPLE_LENGTH};
 
 const MAGIC_BYTES: u32 = 0x4C414721; // b'LAG!'
 
-#[derive(Debug, PartialEq, Clone)]
+/// Length of a frame header: magic, code, flags and payload length
+pub const HEADER_LENGTH: usize = 4 + 1 + 1 + 4;
+
+/// Header flag set when the payload is LZ4 compressed
+pub const FLAG_COMPRESSED: u8 = 0x01;
+
+/// Header flag set when the payload is followed by a CRC32 of the header and payload
+pub const FLAG_CHECKSUM: u8 = 0x02;
+
+/// Length of the CRC32 trailer
+pub const CHECKSUM_LENGTH: usize = 4;
+
+/// Frames with longer payloads are rejected, unless configured otherwise
+pub const DEFAULT_MAX_PAYLOAD_LENGTH: u32 = 64 * 1024;
+
+/// Hello capability bit: the sender accepts compressed frames
+pub const CAPABILITY_COMPRESSION: u8 = 0x01;
+
+/// Hello capability bit: the sender can move players with input commands rather than client updates.
+/// The server only replies with it when players must move that way.
+pub const CAPABILITY_INPUT_COMMANDS: u8 = 0x02;
+
+/// Every capability this build supports
+pub const SUPPORTED_CAPABILITIES: u8 = CAPABILITY_COMPRESSION | CAPABILITY_INPUT_COMMANDS;
+
+/// Length of the code and payload length preceding each message in a Batch
+pub const BATCH_ENTRY_HEADER_LENGTH: usize = 1 + 4;
+
+/// Length of the message ID, fragment index and fragment count preceding a Fragment's data
+pub const FRAGMENT_HEADER_LENGTH: usize = 4 + 2 + 2;
+
+/// Payloads larger than this are compressed, unless configured otherwise
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 512;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum MessageCode{
     Text            = 0x01,
     ClientUpdate    = 0x02,
     GameStateUpdate = 0x03,
+    Login           = 0x04,
+    Disconnect      = 0x05,
+    Hello           = 0x06,
+    Batch           = 0x07,
+    Fragment        = 0x08,
+    EntityUpdate    = 0x09,
+    Input           = 0x0A,
     Ping            = 0xFF
 }
 
@@ -26,6 +70,13 @@ impl MessageCode{
             0x01 => { Some(MessageCode::Text) },
             0x02 => { Some(MessageCode::ClientUpdate) },
             0x03 => { Some(MessageCode::GameStateUpdate) },
+            0x04 => { Some(MessageCode::Login) },
+            0x05 => { Some(MessageCode::Disconnect) },
+            0x06 => { Some(MessageCode::Hello) },
+            0x07 => { Some(MessageCode::Batch) },
+            0x08 => { Some(MessageCode::Fragment) },
+            0x09 => { Some(MessageCode::EntityUpdate) },
+            0x0A => { Some(MessageCode::Input) },
             0xFF => { Some(MessageCode::Ping) },
             _    => { None }
         }
@@ -39,7 +90,10 @@ pub struct MessageHeader{
     /// The type of message contained in this frame
     pub code: MessageCode,
 
-    /// The length of the payload
+    /// Bit flags describing the payload, such as `FLAG_COMPRESSED`
+    pub flags: u8,
+
+    /// The length of the payload, as sent
     pub length: u32
 }
 
@@ -49,41 +103,46 @@ impl MessageHeader{
         MessageHeader{
             magic: MAGIC_BYTES,
             code: code,
+            flags: 0,
             length: data_len
         }
     }
 
-    pub fn read<R: Read>(input: &mut R) -> Result<MessageHeader>{
-        info!("begin messageheader::read");
-        let mut header_buf = [0u8; 9];
-        let header_buf_length = match input.read(&mut header_buf){
-            Ok(n) => { n },
-            Err(e) => { return Err(e); }
-        };
+    pub fn read<R: Read>(input: &mut R) -> ProtocolResult<MessageHeader>{
+        trace!("Reading message header");
+        let mut header_buf = [0u8; HEADER_LENGTH];
+        let header_buf_length = try!(input.read(&mut header_buf));
 
-        if header_buf_length < 9{
-            return Err(Error::new(ErrorKind::Other, format!("Message header Input length ({}) is not long enough!", header_buf_length)));
+        // A zero length read means the other end has closed the connection
+        if header_buf_length == 0{
+            return Err(ProtocolError::Io(io::Error::new(ErrorKind::UnexpectedEof, String::from("Connection closed by peer"))));
+        }
+
+        if header_buf_length < HEADER_LENGTH{
+            return Err(ProtocolError::Truncated{ expected: HEADER_LENGTH, got: header_buf_length });
         }
 
         return Self::read_slice(header_buf.as_ref());
     }
 
-    pub fn read_slice(input: &[u8]) -> Result<MessageHeader>{
-        if input.len() < 8{
-            return Err(Error::new(ErrorKind::Other, format!("Message header slice Input length ({}) is not long enough!", input.len())));
+    pub fn read_slice(input: &[u8]) -> ProtocolResult<MessageHeader>{
+        if input.len() < HEADER_LENGTH{
+            return Err(ProtocolError::Truncated{ expected: HEADER_LENGTH, got: input.len() });
         }
 
-        let message_code_byte = input[4..5].first().unwrap();
-        let message_code = MessageCode::from_u8(*message_code_byte);
-        if message_code.is_none(){
-            return Err(Error::new(ErrorKind::Other, format!("Received unknown message code {:x}!", message_code_byte)));
+        let magic = BigEndian::read_u32(input[0..4].as_ref());
+        if magic != MAGIC_BYTES{
+            return Err(ProtocolError::BadMagic(magic));
         }
 
-        let payload_length = BigEndian::read_u32(input[5..9].as_ref());
+        let message_code = try!(MessageCode::from_u8(input[4]).ok_or(ProtocolError::UnknownCode(input[4])));
+
+        let payload_length = BigEndian::read_u32(input[6..10].as_ref());
 
         Ok(MessageHeader{
-            magic: BigEndian::read_u32(input[0..4].as_ref()),
-            code: message_code.unwrap(),
+            magic: magic,
+            code: message_code,
+            flags: input[5],
             length: payload_length,
         })
     }
@@ -92,26 +151,218 @@ impl MessageHeader{
         self.magic == MAGIC_BYTES
     }
 
+    pub fn is_compressed(&self) -> bool{
+        self.flags & FLAG_COMPRESSED != 0
+    }
+
+    pub fn has_checksum(&self) -> bool{
+        self.flags & FLAG_CHECKSUM != 0
+    }
+
+    /// Length of the whole frame this header starts, including any checksum trailer
+    pub fn frame_length(&self) -> usize{
+        let trailer_length = if self.has_checksum() { CHECKSUM_LENGTH } else { 0 };
+        HEADER_LENGTH + self.length as usize + trailer_length
+    }
+
+    /// Return an error if the payload is longer than @max_payload_length.
+    /// Checked before any of the payload is read.
+    pub fn check_length(&self, max_payload_length: u32) -> ProtocolResult<()>{
+        if self.length > max_payload_length{
+            return Err(ProtocolError::TooLarge{ length: self.length, max: max_payload_length });
+        }
+        Ok(())
+    }
+
 
     pub fn to_bytes(&self) -> Vec<u8>{
-        let mut buffer = [0u8; 4 + 1 + 4];
+        let mut buffer = [0u8; HEADER_LENGTH];
 
         BigEndian::write_u32(&mut buffer[0..4], self.magic);
         buffer[4] = self.code.clone() as u8;
-        BigEndian::write_u32(&mut buffer[5..9], self.length);
+        buffer[5] = self.flags;
+        BigEndian::write_u32(&mut buffer[6..10], self.length);
 
         return buffer.to_vec();
     }
 }
 
 
+/// Why a frame couldn't be read
+#[derive(Debug)]
+pub enum ProtocolError{
+    /// The frame doesn't start with the magic number
+    BadMagic(u32),
+
+    /// The header names a message code this build doesn't know
+    UnknownCode(u8),
+
+    /// The frame, or a field within it, ended early
+    Truncated{ expected: usize, got: usize },
+
+    /// The payload is longer than the receiver accepts
+    TooLarge{ length: u32, max: u32 },
+
+    /// The frame doesn't match its CRC32 trailer
+    ChecksumMismatch{ expected: u32, actual: u32 },
+
+    /// A compressed payload couldn't be decompressed
+    BadCompression,
+
+    /// A string field is not valid UTF-8
+    InvalidUtf8,
+
+    /// A fragment doesn't fit the message it claims to be part of
+    BadFragment{ message_id: u32 },
+
+    /// A batch contains another batch
+    NestedBatch,
+
+    /// The underlying connection failed, or has nothing to read yet
+    Io(io::Error)
+}
+
+pub type ProtocolResult<T> = ::std::result::Result<T, ProtocolError>;
+
+impl ProtocolError{
+    /// The kind of the underlying IO error, if this is one
+    pub fn io_kind(&self) -> Option<ErrorKind>{
+        match *self{
+            ProtocolError::Io(ref e) => Some(e.kind()),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        match *self{
+            ProtocolError::BadMagic(magic) => write!(f, "Frame starts with {:08x} rather than the magic number", magic),
+            ProtocolError::UnknownCode(code) => write!(f, "Unknown message code {:02x}", code),
+            ProtocolError::Truncated{ expected, got } => write!(f, "Expected {} bytes, received {} bytes", expected, got),
+            ProtocolError::TooLarge{ length, max } => write!(f, "Frame payload of {} bytes exceeds the maximum of {} bytes", length, max),
+            ProtocolError::ChecksumMismatch{ expected, actual } => write!(f, "Frame checksum {:08x} does not match {:08x}", actual, expected),
+            ProtocolError::BadCompression => write!(f, "Failed to decompress payload"),
+            ProtocolError::InvalidUtf8 => write!(f, "String is not valid UTF-8"),
+            ProtocolError::BadFragment{ message_id } => write!(f, "Invalid fragment of message {}", message_id),
+            ProtocolError::NestedBatch => write!(f, "Batch contains another batch"),
+            ProtocolError::Io(ref e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl error::Error for ProtocolError{
+    fn description(&self) -> &str{
+        match *self{
+            ProtocolError::BadMagic(_) => "bad magic number",
+            ProtocolError::UnknownCode(_) => "unknown message code",
+            ProtocolError::Truncated{ .. } => "truncated frame",
+            ProtocolError::TooLarge{ .. } => "frame too large",
+            ProtocolError::ChecksumMismatch{ .. } => "frame checksum mismatch",
+            ProtocolError::BadCompression => "bad compressed payload",
+            ProtocolError::InvalidUtf8 => "invalid UTF-8",
+            ProtocolError::BadFragment{ .. } => "invalid fragment",
+            ProtocolError::NestedBatch => "nested batch",
+            ProtocolError::Io(ref e) => error::Error::description(e)
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError{
+    fn from(error: io::Error) -> ProtocolError{
+        ProtocolError::Io(error)
+    }
+}
+
+impl From<ProtocolError> for io::Error{
+    fn from(error: ProtocolError) -> io::Error{
+        match error{
+            ProtocolError::Io(e) => e,
+            e => io::Error::new(ErrorKind::InvalidData, e)
+        }
+    }
+}
+
+/// CRC-32 (IEEE 802.3) of @data, as used by zlib
+pub fn crc32(data: &[u8]) -> u32{
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data{
+        crc ^= *byte as u32;
+        for _ in 0..8{
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    return !crc;
+}
+
+/// Read exactly enough bytes from @input to fill @buffer
+pub(crate) fn read_exact_into<R: Read>(input: &mut R, buffer: &mut [u8]) -> ProtocolResult<()>{
+    let mut bytes_read = 0;
+    while bytes_read < buffer.len(){
+        match try!(input.read(&mut buffer[bytes_read..])){
+            0 => { return Err(ProtocolError::Truncated{ expected: buffer.len(), got: bytes_read }); },
+            read => { bytes_read += read; }
+        }
+    }
+    return Ok(());
+}
+
+
+/// Why the server is closing a connection
+#[derive(Hash, Debug, PartialEq, Clone, Copy)]
+pub enum DisconnectReason{
+    Unknown     = 0x00,
+    Banned      = 0x01,
+    RateLimited = 0x02,
+    ServerFull  = 0x03,
+    Kicked      = 0x04,
+    ProtocolError = 0x05,
+    TooSlow     = 0x06
+}
+
+impl DisconnectReason{
+    pub fn from_u8(byte: u8) -> DisconnectReason{
+        match byte{
+            0x01 => { DisconnectReason::Banned },
+            0x02 => { DisconnectReason::RateLimited },
+            0x03 => { DisconnectReason::ServerFull },
+            0x04 => { DisconnectReason::Kicked },
+            0x05 => { DisconnectReason::ProtocolError },
+            0x06 => { DisconnectReason::TooSlow },
+            _    => { DisconnectReason::Unknown }
+        }
+    }
+}
 
 #[derive(Hash, Debug, Clone)]
 pub enum Message{
-    Ping,
+    /// Keeps the connection alive. Pings carrying a clock sample are answered by the server
+    /// with its timestamps filled in, so the client can estimate the server's clock.
+    /// The server pings clients the same way to measure the round trip, and clients answer in kind.
+    Ping(Option<ClockSample>),
+
     Text{ message: String },
     ClientUpdate (ClientState),
-    GameStateUpdate (Vec<ClientState>)
+    GameStateUpdate (Vec<Entity>),
+    Login{ account_id: u32 },
+    Disconnect{ reason: DisconnectReason, message: String },
+
+    /// Sent by each side when a connection opens, listing the `CAPABILITY_*` bits it supports.
+    /// The server replies with the capabilities both sides share.
+    Hello{ capabilities: u8 },
+
+    /// Several messages packed into one frame, each prefixed with its code and length
+    Batch(Vec<Message>),
+
+    /// Part @index of @count of the frame of a message too large to send in one piece
+    Fragment{ message_id: u32, index: u16, count: u16, data: Vec<u8> },
+
+    /// The changes to entities since the last update, limited to what the recipient may see
+    EntityUpdate(Vec<EntityDelta>),
+
+    /// What the player did, sent in place of client updates when the server moves players itself
+    Input(InputCommand)
 }
 
 impl Message{
@@ -123,89 +374,265 @@ impl Message{
         Message::ClientUpdate( *client_state )
     }
 
+    pub fn new_login_message(account_id: u32) -> Message{
+        Message::Login{ account_id: account_id }
+    }
+
+    pub fn new_disconnect_message(reason: DisconnectReason, message: String) -> Message{
+        Message::Disconnect{ reason: reason, message: message }
+    }
+
+    pub fn new_hello_message(capabilities: u8) -> Message{
+        Message::Hello{ capabilities: capabilities }
+    }
+
+    pub fn new_batch_message(messages: Vec<Message>) -> Message{
+        Message::Batch(messages)
+    }
+
+    /// The messages packed in a Batch, or just this message if it isn't one
+    pub fn into_messages(self) -> Vec<Message>{
+        match self{
+            Message::Batch(messages) => messages,
+            message => vec![message]
+        }
+    }
+
     /// Read bytes from the input parameter, and return a parsed Message.
-    pub fn read<R: Read>(mut input: &mut R) -> Result<Message>{
-        info!("Begin message::read");
-        let header = MessageHeader::read(&mut input);
-        if header.is_err(){
-            return Err(header.err().unwrap());
+    /// Payloads longer than `DEFAULT_MAX_PAYLOAD_LENGTH` are rejected.
+    pub fn read<R: Read>(input: &mut R) -> ProtocolResult<Message>{
+        Self::read_limited(input, DEFAULT_MAX_PAYLOAD_LENGTH)
+    }
+
+    /// Read a Message, rejecting payloads longer than @max_payload_length before reading them.
+    /// The whole frame is read before its payload is parsed, so a bad payload can't leave @input mid-frame,
+    /// but a rejected header or a frame cut short can. Stream transports buffer their input and hand
+    /// this only whole frames, through `transport::next_frame`.
+    pub fn read_limited<R: Read>(mut input: &mut R, max_payload_length: u32) -> ProtocolResult<Message>{
+        trace!("Reading message");
+        let header = try!(MessageHeader::read(&mut input));
+        try!(header.check_length(max_payload_length));
+
+        let mut payload = try!(Self::read_exact(&mut input, header.length as usize));
+
+        if header.has_checksum(){
+            let trailer = try!(Self::read_exact(&mut input, CHECKSUM_LENGTH));
+            let expected = BigEndian::read_u32(&trailer);
+            let mut checked_bytes = header.to_bytes();
+            checked_bytes.extend_from_slice(&payload);
+            let actual = crc32(&checked_bytes);
+            if actual != expected{
+                return Err(ProtocolError::ChecksumMismatch{ expected: expected, actual: actual });
+            }
+        }
+
+        if header.is_compressed(){
+            payload = try!(Self::decompress_payload(&payload, max_payload_length));
         }
 
-        let header = header.unwrap();
-        if !header.is_valid(){
-            return Err(Error::new(ErrorKind::InvalidInput, String::from("Received an invalid message header!")));
+        let header = MessageHeader::new(header.code, payload.len() as u32);
+        return Self::read_payload(&mut payload.as_slice(), &header);
+    }
+
+    /// Read exactly @length bytes from @input
+    fn read_exact<R: Read>(input: &mut R, length: usize) -> ProtocolResult<Vec<u8>>{
+        let mut buffer = Vec::with_capacity(length);
+        try!(input.take(length as u64).read_to_end(&mut buffer));
+        if buffer.len() != length{
+            return Err(ProtocolError::Truncated{ expected: length, got: buffer.len() });
         }
+        return Ok(buffer);
+    }
 
+    /// Decompress a compressed payload, which may expand to at most @max_payload_length bytes
+    fn decompress_payload(compressed: &[u8], max_payload_length: u32) -> ProtocolResult<Vec<u8>>{
+        if compressed.len() < 4{
+            return Err(ProtocolError::BadCompression);
+        }
+        // The decompressed length is prepended, little endian, by the encoder
+        let decompressed_length = compressed[0] as u32 | (compressed[1] as u32) << 8 | (compressed[2] as u32) << 16 | (compressed[3] as u32) << 24;
+        if decompressed_length > max_payload_length{
+            return Err(ProtocolError::TooLarge{ length: decompressed_length, max: max_payload_length });
+        }
+
+        decompress_size_prepended(compressed).map_err(|_| ProtocolError::BadCompression)
+    }
+
+    /// Parse the uncompressed payload described by @header
+    fn read_payload<R: Read>(mut input: &mut R, header: &MessageHeader) -> ProtocolResult<Message>{
         let message = match header.code{
             MessageCode::Text => {
                 Self::read_text_message(&mut input, &header)
                 //return Ok(Message::Text{message: String::from("Hello!")});
             },
             MessageCode::Ping => {
-                Ok(Message::Ping)
+                // Plain pings may carry anything, so only a payload the length of a clock sample is read
+                if header.length as usize == CLOCK_SAMPLE_LENGTH{
+                    Ok(Message::Ping(Some(try!(ClockSample::read(&mut input)))))
+                }
+                else{
+                    Ok(Message::Ping(None))
+                }
             },
             MessageCode::ClientUpdate => {
-                info!("Reading client update data!");
+                trace!("Reading client update");
                 Self::read_client_update_message(&mut input, &header)
             },
             MessageCode::GameStateUpdate => {
-                info!("Received game state update");
+                trace!("Reading game state update");
                 Self::read_game_state_update_message(&mut input, &header)
+            },
+            MessageCode::Login => {
+                Self::read_login_message(&mut input, &header)
+            },
+            MessageCode::Disconnect => {
+                Self::read_disconnect_message(&mut input, &header)
+            },
+            MessageCode::Hello => {
+                Self::read_hello_message(&mut input, &header)
+            },
+            MessageCode::Batch => {
+                Self::read_batch_message(&mut input, &header)
+            },
+            MessageCode::Fragment => {
+                Self::read_fragment_message(&mut input, &header)
+            },
+            MessageCode::EntityUpdate => {
+                trace!("Reading entity update");
+                Self::read_entity_update_message(&mut input, &header)
+            },
+            MessageCode::Input => {
+                Self::read_input_message(&mut input, &header)
             }
-            //_ => { return Err(Error::new(ErrorKind::InvalidInput, format!("Received an unhandled message type, {:?}!", header.code))); }
         };
 
         return message;
     }
 
 
-    fn read_text_message<R: Read>(input: &mut R, header: &MessageHeader) -> Result<Message>{
-        let mut message_buf = [0u8; 1024];
-        let bytes_read = input.read(&mut message_buf);
-        // Error checking; Make sure we read bytes of the message,
-        // and ensure it's the length the client claimed it would be.
-        match bytes_read{
-            Ok(bytes_read) => {
-                if bytes_read != header.length as usize{
-                    return Err(Error::new(ErrorKind::Other, format!("Expected string of {} bytes, received a string of {} bytes!", header.length, bytes_read)));
-                }
-            },
-            Err(e) => {
-                return Err(e);
-            }
-        }
-
-        let message = String::from_utf8(message_buf.iter().take(bytes_read.unwrap()).map(|br| *br).collect::<Vec<u8>>()).unwrap();
+    fn read_text_message<R: Read>(input: &mut R, header: &MessageHeader) -> ProtocolResult<Message>{
+        let message_buf = try!(Self::read_exact(input, header.length as usize));
 
-        // If all checks passed, return the message.
-        Ok(Message::Text{message: message})
+        match String::from_utf8(message_buf){
+            Ok(message) => Ok(Message::Text{ message: message }),
+            Err(_) => Err(ProtocolError::InvalidUtf8)
+        }
     }
 
-    fn read_client_update_message<R: Read>(input: &mut R, _: &MessageHeader) -> Result<Message>{
+    fn read_client_update_message<R: Read>(input: &mut R, _: &MessageHeader) -> ProtocolResult<Message>{
         let client_state = try!(ClientState::read(input));
 
         return Ok(Message::ClientUpdate(client_state));
     }
 
-    fn read_game_state_update_message<R: Read>(input: &mut R, header: &MessageHeader) -> Result<Message>{
-        let expected_quantity = header.length / (mem::size_of::<ClientState>() as u32);
+    fn read_game_state_update_message<R: Read>(input: &mut R, header: &MessageHeader) -> ProtocolResult<Message>{
+        let payload = try!(Self::read_exact(input, header.length as usize));
+
+        let mut entities = Vec::new();
+        let mut remaining = payload.as_slice();
+        while !remaining.is_empty(){
+            entities.push(try!(Entity::read(&mut remaining)));
+        }
+
+        return Ok(Message::GameStateUpdate(entities));
+    }
+
+    fn read_entity_update_message<R: Read>(input: &mut R, header: &MessageHeader) -> ProtocolResult<Message>{
+        let payload = try!(Self::read_exact(input, header.length as usize));
+
+        let mut deltas = Vec::new();
+        let mut remaining = payload.as_slice();
+        while !remaining.is_empty(){
+            deltas.push(try!(EntityDelta::read(&mut remaining)));
+        }
+
+        return Ok(Message::EntityUpdate(deltas));
+    }
+
+    fn read_input_message<R: Read>(input: &mut R, header: &MessageHeader) -> ProtocolResult<Message>{
+        if header.length as usize != INPUT_COMMAND_LENGTH{
+            return Err(ProtocolError::Truncated{ expected: INPUT_COMMAND_LENGTH, got: header.length as usize });
+        }
+        return Ok(Message::Input(try!(InputCommand::read(input))));
+    }
 
-        let mut clients = Vec::new();
+    fn read_login_message<R: Read>(input: &mut R, header: &MessageHeader) -> ProtocolResult<Message>{
+        let mut message_buf = [0u8; 4];
+        let bytes_read = try!(input.read(&mut message_buf));
+
+        if bytes_read != 4 || header.length != 4{
+            return Err(ProtocolError::Truncated{ expected: 4, got: bytes_read });
+        }
 
-        loop{
-            if let Ok(client_state) = ClientState::read(input){
-                clients.push(client_state);
+        return Ok(Message::Login{ account_id: BigEndian::read_u32(&message_buf) });
+    }
+
+    fn read_disconnect_message<R: Read>(input: &mut R, header: &MessageHeader) -> ProtocolResult<Message>{
+        let message_buf = try!(Self::read_exact(input, header.length as usize));
+
+        if message_buf.is_empty(){
+            return Err(ProtocolError::Truncated{ expected: 1, got: 0 });
+        }
+
+        let reason = DisconnectReason::from_u8(message_buf[0]);
+        let message = String::from_utf8_lossy(&message_buf[1..]).into_owned();
+
+        return Ok(Message::Disconnect{ reason: reason, message: message });
+    }
+
+    fn read_batch_message<R: Read>(input: &mut R, header: &MessageHeader) -> ProtocolResult<Message>{
+        let payload = try!(Self::read_exact(input, header.length as usize));
+
+        let mut messages = Vec::new();
+        let mut remaining = payload.as_slice();
+        while !remaining.is_empty(){
+            if remaining.len() < BATCH_ENTRY_HEADER_LENGTH{
+                return Err(ProtocolError::Truncated{ expected: BATCH_ENTRY_HEADER_LENGTH, got: remaining.len() });
             }
-            else{
-                break;
+
+            let code = try!(MessageCode::from_u8(remaining[0]).ok_or(ProtocolError::UnknownCode(remaining[0])));
+            // Batches can't be nested
+            if code == MessageCode::Batch{
+                return Err(ProtocolError::NestedBatch);
+            }
+
+            let length = BigEndian::read_u32(&remaining[1..BATCH_ENTRY_HEADER_LENGTH]) as usize;
+            let entry = &remaining[BATCH_ENTRY_HEADER_LENGTH..];
+            if entry.len() < length{
+                return Err(ProtocolError::Truncated{ expected: length, got: entry.len() });
             }
+
+            let entry_header = MessageHeader::new(code, length as u32);
+            messages.push(try!(Self::read_payload(&mut &entry[..length], &entry_header)));
+            remaining = &entry[length..];
         }
 
-        if clients.len() as u32 != expected_quantity{
-            info!("Error! Received {} clients, expected {}!", clients.len(), expected_quantity);
+        return Ok(Message::Batch(messages));
+    }
+
+    fn read_fragment_message<R: Read>(input: &mut R, header: &MessageHeader) -> ProtocolResult<Message>{
+        let payload = try!(Self::read_exact(input, header.length as usize));
+        if payload.len() < FRAGMENT_HEADER_LENGTH{
+            return Err(ProtocolError::Truncated{ expected: FRAGMENT_HEADER_LENGTH, got: payload.len() });
         }
 
-        return Ok(Message::GameStateUpdate(clients));
+        return Ok(Message::Fragment{
+            message_id: BigEndian::read_u32(&payload[0..4]),
+            index: BigEndian::read_u16(&payload[4..6]),
+            count: BigEndian::read_u16(&payload[6..8]),
+            data: payload[FRAGMENT_HEADER_LENGTH..].to_vec()
+        });
+    }
+
+    fn read_hello_message<R: Read>(input: &mut R, header: &MessageHeader) -> ProtocolResult<Message>{
+        let mut message_buf = [0u8; 1];
+        let bytes_read = try!(input.read(&mut message_buf));
+
+        if bytes_read != 1 || header.length != 1{
+            return Err(ProtocolError::Truncated{ expected: 1, got: bytes_read });
+        }
+
+        return Ok(Message::Hello{ capabilities: message_buf[0] });
     }
 
 
@@ -216,26 +643,77 @@ impl Message{
                 //data_buf.append(&mut message_copy.into_bytes());
                 return message_copy.into_bytes();
             },
-            &Message::Ping => {
-                return MessageHeader::new(MessageCode::Ping, 0u32).to_bytes();
+            &Message::Ping(None) => {
+                return Vec::new();
+            },
+            &Message::Ping(Some(ref sample)) => {
+                return sample.to_bytes();
             },
             &Message::ClientUpdate(ref client_state) =>{
                 return client_state.to_bytes();
             },
             &Message::GameStateUpdate( ref game_state ) => {
                 return game_state.iter()
-                            .map(|client_state| client_state.to_bytes())
+                            .map(|entity| entity.to_bytes())
+                            .fold(Vec::new(), |mut buf, mut mes|{ buf.append(&mut mes); buf });
+            },
+            &Message::Login{ account_id } => {
+                let mut buf = [0u8; 4];
+                BigEndian::write_u32(&mut buf, account_id);
+                return buf.to_vec();
+            },
+            &Message::Disconnect{ reason, ref message } => {
+                let mut buf = vec![reason as u8];
+                buf.extend_from_slice(message.as_bytes());
+                return buf;
+            },
+            &Message::Hello{ capabilities } => {
+                return vec![capabilities];
+            },
+            &Message::Batch(ref messages) => {
+                let mut buf = Vec::new();
+                for message in messages{
+                    let payload = message.to_bytes();
+                    let mut entry_header = [0u8; BATCH_ENTRY_HEADER_LENGTH];
+                    entry_header[0] = message.get_message_code() as u8;
+                    BigEndian::write_u32(&mut entry_header[1..], payload.len() as u32);
+                    buf.extend_from_slice(&entry_header);
+                    buf.extend_from_slice(&payload);
+                }
+                return buf;
+            },
+            &Message::Fragment{ message_id, index, count, ref data } => {
+                let mut buf = vec![0u8; FRAGMENT_HEADER_LENGTH];
+                BigEndian::write_u32(&mut buf[0..4], message_id);
+                BigEndian::write_u16(&mut buf[4..6], index);
+                BigEndian::write_u16(&mut buf[6..8], count);
+                buf.extend_from_slice(data);
+                return buf;
+            },
+            &Message::EntityUpdate(ref deltas) => {
+                return deltas.iter()
+                            .map(|delta| delta.to_bytes())
                             .fold(Vec::new(), |mut buf, mut mes|{ buf.append(&mut mes); buf });
+            },
+            &Message::Input(ref command) => {
+                return command.to_bytes();
             }
         }
     }
 
-    fn get_message_code(&self) -> MessageCode{
+    pub fn get_message_code(&self) -> MessageCode{
         match self{
             &Message::Text{message: _} => { return MessageCode::Text; },
-            &Message::Ping => { return MessageCode::Ping; },
+            &Message::Ping(_) => { return MessageCode::Ping; },
             &Message::ClientUpdate(_) => { return MessageCode::ClientUpdate; },
-            &Message::GameStateUpdate(_) => { return MessageCode::GameStateUpdate; }
+            &Message::GameStateUpdate(_) => { return MessageCode::GameStateUpdate; },
+            &Message::Login{ account_id: _ } => { return MessageCode::Login; },
+            &Message::Disconnect{ reason: _, message: _ } => { return MessageCode::Disconnect; },
+            &Message::Hello{ capabilities: _ } => { return MessageCode::Hello; },
+            &Message::Batch(_) => { return MessageCode::Batch; },
+            &Message::Fragment{ .. } => { return MessageCode::Fragment; },
+            &Message::EntityUpdate(_) => { return MessageCode::EntityUpdate; },
+            &Message::Input(_) => { return MessageCode::Input; }
         }
     }
 }
@@ -264,16 +742,83 @@ pub struct MessageFrame{
 }
 
 impl MessageFrame{
+    /// The type of message contained in this frame
+    pub fn code(&self) -> MessageCode{
+        self.header.code.clone()
+    }
+
+    /// Follow the payload with a CRC32 of the frame, so the receiver can check its integrity
+    pub fn add_checksum(&mut self){
+        self.header.flags |= FLAG_CHECKSUM;
+    }
+
+    /// Compress the payload if it is larger than @threshold bytes and compressing makes it smaller.
+    /// Returns the payload length before and after, if compression was attempted.
+    pub fn compress(&mut self, threshold: usize) -> Option<(usize, usize)>{
+        if self.header.is_compressed() || self.payload.len() <= threshold{
+            return None;
+        }
+
+        let uncompressed_length = self.payload.len();
+        let compressed = compress_prepend_size(&self.payload);
+        if compressed.len() < uncompressed_length{
+            self.header.flags |= FLAG_COMPRESSED;
+            self.header.length = compressed.len() as u32;
+            self.payload = compressed;
+        }
+        return Some((uncompressed_length, self.payload.len()));
+    }
+
     pub fn to_bytes(&self) -> Vec<u8>{
         let mut header_bytes = self.header.to_bytes();
         let mut bytes = Vec::with_capacity(header_bytes.len() + self.header.length as usize);
         let mut payload_bytes = self.payload.clone();
         bytes.append(&mut header_bytes);
         bytes.append(&mut payload_bytes);
+
+        if self.header.has_checksum(){
+            let mut trailer = [0u8; CHECKSUM_LENGTH];
+            BigEndian::write_u32(&mut trailer, crc32(&bytes));
+            bytes.extend_from_slice(&trailer);
+        }
         return bytes;
     }
 }
 
+/// How well payloads of each message type have compressed
+#[derive(Clone, Debug)]
+pub struct CompressionStats{
+    /// Total payload bytes before and after compression, by message code
+    totals: BTreeMap<u8, (u64, u64)>
+}
+
+impl CompressionStats{
+    pub fn new() -> CompressionStats{
+        CompressionStats{ totals: BTreeMap::new() }
+    }
+
+    pub fn record(&mut self, code: MessageCode, uncompressed_length: usize, compressed_length: usize){
+        let totals = self.totals.entry(code as u8).or_insert((0, 0));
+        totals.0 += uncompressed_length as u64;
+        totals.1 += compressed_length as u64;
+    }
+
+    /// Bytes sent per byte of payload for messages of type @code, if any were compressed
+    pub fn ratio(&self, code: MessageCode) -> Option<f64>{
+        match self.totals.get(&(code as u8)){
+            Some(&(uncompressed, compressed)) if uncompressed > 0 => Some(compressed as f64 / uncompressed as f64),
+            _ => None
+        }
+    }
+
+    /// Total bytes before and after compression for every message code, in code order
+    pub fn totals(&self) -> Vec<(MessageCode, u64, u64)>{
+        self.totals.iter()
+            .filter_map(|(code, &(uncompressed, compressed))| MessageCode::from_u8(*code).map(|code| (code, uncompressed, compressed)))
+            .collect()
+    }
+}
+
 
 #[cfg(test)]
 mod test{
@@ -283,8 +828,8 @@ mod test{
 
     #[test]
     fn test_read_header(){
-        // Send the message `LAG!0003`
-        let test_message = vec!['L' as u8, 'A' as u8, 'G' as u8, '!' as u8, 1u8, 0u8, 0u8, 0u8, 3u8];
+        // Send the message `LAG!` Text, no flags, length 3
+        let test_message = vec!['L' as u8, 'A' as u8, 'G' as u8, '!' as u8, 1u8, 0u8, 0u8, 0u8, 0u8, 3u8];
         let message = MessageHeader::read_slice(test_message.as_slice()).unwrap();
 
         assert_eq!(BigEndian::read_u32(b"LAG!"), message.magic);
@@ -293,8 +838,8 @@ mod test{
 
     #[test]
     fn test_is_valid(){
-        // Send the message `LAG!0003`
-        let test_message = vec!['L' as u8, 'A' as u8, 'G' as u8, '!' as u8, 1u8, 0u8, 0u8, 0u8, 3u8];
+        // Send the message `LAG!` Text, no flags, length 3
+        let test_message = vec!['L' as u8, 'A' as u8, 'G' as u8, '!' as u8, 1u8, 0u8, 0u8, 0u8, 0u8, 3u8];
         let message = MessageHeader::read_slice(test_message.as_slice()).unwrap();
 
         assert!(message.is_valid());
@@ -311,7 +856,7 @@ mod test{
 
     #[test]
     fn test_message_to_bytes(){
-        let test_message_output = vec!['L' as u8, 'A' as u8, 'G' as u8, '!' as u8, 1u8, 0u8, 0u8, 0u8, 4u8, 'T' as u8, 'e' as u8, 's' as u8, 't' as u8];
+        let test_message_output = vec!['L' as u8, 'A' as u8, 'G' as u8, '!' as u8, 1u8, 0u8, 0u8, 0u8, 0u8, 4u8, 'T' as u8, 'e' as u8, 's' as u8, 't' as u8];
         let test_message = Message::new_text_message(String::from("Test"));
 
         assert_eq!(test_message.to_frame().to_bytes(), test_message_output);
@@ -320,8 +865,8 @@ mod test{
     #[test]
     fn test_client_update_serialize(){
         let mut test_client = ClientState::new(1337);
-        test_client.position = Position(1,2,3);
-        test_client.rotation = Rotation(90);
+        test_client.position = Position::new(1.0, 2.0, 3.0);
+        test_client.rotation = Rotation::from_degrees(90.0);
 
         let god = Message::new_client_update_message(&test_client);
         let fucking = god.to_frame();
@@ -339,9 +884,191 @@ mod test{
                 assert_eq!(client_state.position.0, test_client.position.0);
                 assert_eq!(client_state.position.1, test_client.position.1);
                 assert_eq!(client_state.position.2, test_client.position.2);
-                assert_eq!(client_state.rotation.0, test_client.rotation.0);
+                assert!((client_state.rotation.yaw() - test_client.rotation.yaw()).abs() < 0.1);
+            },
+            _ => { panic!(); }
+        }
+    }
+
+    #[test]
+    fn test_disconnect_serialize(){
+        let message = Message::new_disconnect_message(DisconnectReason::Banned, String::from("Cheating"));
+        let bytes = message.to_frame().to_bytes();
+
+        match Message::read(&mut bytes.as_slice()).unwrap(){
+            Message::Disconnect{ reason, message } => {
+                assert_eq!(reason, DisconnectReason::Banned);
+                assert_eq!(message, "Cheating");
+            },
+            _ => { panic!(); }
+        }
+    }
+
+    #[test]
+    fn test_hello_serialize(){
+        let bytes = Message::new_hello_message(SUPPORTED_CAPABILITIES).to_frame().to_bytes();
+
+        match Message::read(&mut bytes.as_slice()).unwrap(){
+            Message::Hello{ capabilities } => { assert_eq!(capabilities, SUPPORTED_CAPABILITIES); },
+            _ => { panic!(); }
+        }
+    }
+
+    #[test]
+    fn test_compressed_round_trip(){
+        let entities = (0..100).map(|id| Entity::player(ClientState::new(id))).collect::<Vec<_>>();
+        let mut frame = Message::GameStateUpdate(entities).to_frame();
+
+        let (uncompressed_length, compressed_length) = frame.compress(DEFAULT_COMPRESSION_THRESHOLD).unwrap();
+        assert!(compressed_length < uncompressed_length);
+
+        let bytes = frame.to_bytes();
+        assert_eq!(bytes.len(), HEADER_LENGTH + compressed_length);
+        assert!(MessageHeader::read_slice(&bytes).unwrap().is_compressed());
+
+        match Message::read(&mut bytes.as_slice()).unwrap(){
+            Message::GameStateUpdate(entities) => {
+                assert_eq!(entities.len(), 100);
+                assert_eq!(entities[99].id(), 99);
             },
             _ => { panic!(); }
         }
     }
+
+    #[test]
+    fn test_small_payload_not_compressed(){
+        let mut frame = Message::new_text_message(String::from("Test")).to_frame();
+
+        assert!(frame.compress(DEFAULT_COMPRESSION_THRESHOLD).is_none());
+        assert!(!MessageHeader::read_slice(&frame.to_bytes()).unwrap().is_compressed());
+    }
+
+    #[test]
+    fn test_compression_stats(){
+        let mut stats = CompressionStats::new();
+        stats.record(MessageCode::GameStateUpdate, 1000, 250);
+        stats.record(MessageCode::GameStateUpdate, 1000, 150);
+
+        assert_eq!(stats.ratio(MessageCode::GameStateUpdate), Some(0.2));
+        assert_eq!(stats.ratio(MessageCode::Text), None);
+    }
+
+    #[test]
+    fn test_crc32(){
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn test_long_text_message(){
+        let text = (0..3000).map(|i| if i % 2 == 0 { 'a' } else { 'é' }).collect::<String>();
+        let bytes = Message::new_text_message(text.clone()).to_frame().to_bytes();
+
+        match Message::read(&mut bytes.as_slice()).unwrap(){
+            Message::Text{ message } => { assert_eq!(message, text); },
+            _ => { panic!(); }
+        }
+    }
+
+    #[test]
+    fn test_too_large_rejected_before_payload(){
+        // A header claiming a 4GB payload, with none of it following
+        let mut bytes = MessageHeader::new(MessageCode::Text, u32::max_value()).to_bytes();
+        bytes.truncate(HEADER_LENGTH);
+
+        match Message::read(&mut bytes.as_slice()){
+            Err(ProtocolError::TooLarge{ length, max }) => {
+                assert_eq!(length, u32::max_value());
+                assert_eq!(max, DEFAULT_MAX_PAYLOAD_LENGTH);
+            },
+            other => { panic!("Expected a payload too large error, got {:?}", other); }
+        }
+
+        let bytes = Message::new_text_message(String::from("Test")).to_frame().to_bytes();
+        assert!(Message::read_limited(&mut bytes.as_slice(), 3).is_err());
+        assert!(Message::read_limited(&mut bytes.as_slice(), 4).is_ok());
+    }
+
+    #[test]
+    fn test_checksum(){
+        let mut frame = Message::new_text_message(String::from("Test")).to_frame();
+        frame.add_checksum();
+        let mut bytes = frame.to_bytes();
+        assert_eq!(bytes.len(), MessageHeader::read_slice(&bytes).unwrap().frame_length());
+
+        match Message::read(&mut bytes.as_slice()).unwrap(){
+            Message::Text{ message } => { assert_eq!(message, "Test"); },
+            _ => { panic!(); }
+        }
+
+        bytes[HEADER_LENGTH] = b'B';
+        match Message::read(&mut bytes.as_slice()){
+            Err(ProtocolError::ChecksumMismatch{ .. }) => {},
+            other => { panic!("Expected a checksum mismatch, got {:?}", other); }
+        }
+    }
+
+    #[test]
+    fn test_protocol_errors(){
+        let mut bytes = Message::new_text_message(String::from("Test")).to_frame().to_bytes();
+
+        match Message::read(&mut &bytes[..7]){
+            Err(ProtocolError::Truncated{ expected, got }) => { assert_eq!((expected, got), (HEADER_LENGTH, 7)); },
+            other => { panic!("Expected a truncated header, got {:?}", other); }
+        }
+
+        match Message::read(&mut &bytes[..HEADER_LENGTH + 2]){
+            Err(ProtocolError::Truncated{ expected, got }) => { assert_eq!((expected, got), (4, 2)); },
+            other => { panic!("Expected a truncated payload, got {:?}", other); }
+        }
+
+        bytes[HEADER_LENGTH] = 0xFF;
+        match Message::read(&mut bytes.as_slice()){
+            Err(ProtocolError::InvalidUtf8) => {},
+            other => { panic!("Expected invalid UTF-8, got {:?}", other); }
+        }
+
+        bytes[4] = 0x7F;
+        match Message::read(&mut bytes.as_slice()){
+            Err(ProtocolError::UnknownCode(0x7F)) => {},
+            other => { panic!("Expected an unknown code, got {:?}", other); }
+        }
+
+        bytes[0] = b'X';
+        match Message::read(&mut bytes.as_slice()){
+            Err(ProtocolError::BadMagic(_)) => {},
+            other => { panic!("Expected a bad magic number, got {:?}", other); }
+        }
+    }
+
+    #[test]
+    fn test_batch_serialize(){
+        let batch = Message::new_batch_message(vec![
+            Message::new_text_message(String::from("One")),
+            Message::new_client_update_message(&ClientState::new(7)),
+            Message::new_text_message(String::from("Two"))
+        ]);
+        let bytes = batch.to_frame().to_bytes();
+
+        let messages = Message::read(&mut bytes.as_slice()).unwrap().into_messages();
+        assert_eq!(messages.len(), 3);
+        match messages[1]{
+            Message::ClientUpdate(ref state) => { assert_eq!(state.id, 7); },
+            _ => { panic!(); }
+        }
+        match messages[2]{
+            Message::Text{ ref message } => { assert_eq!(message, "Two"); },
+            _ => { panic!(); }
+        }
+    }
+
+    #[test]
+    fn test_nested_batch_rejected(){
+        let inner = Message::new_batch_message(vec![Message::new_text_message(String::from("Inner"))]);
+        let bytes = Message::new_batch_message(vec![inner]).to_frame().to_bytes();
+
+        match Message::read(&mut bytes.as_slice()){
+            Err(ProtocolError::NestedBatch) => {},
+            other => { panic!("Expected a nested batch to be rejected, got {:?}", other); }
+        }
+    }
 }
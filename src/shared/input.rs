@@ -0,0 +1,179 @@
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use byteorder::{ByteOrder, BigEndian};
+
+use frame::{read_exact_into, ProtocolResult};
+use math::{Scalar, Unit};
+use state::{ClientState, Position, Rotation, Velocity};
+
+/// Length of an input command on the wire: sequence, timestamp, duration, movement, facing and buttons
+pub const INPUT_COMMAND_LENGTH: usize = 4 + 4 + 2 + 2 + 2 + 1;
+
+/// Input commands held for longer than this many milliseconds are cut short
+pub const MAX_INPUT_DURATION_MS: u16 = 250;
+
+/// Button bit: jump
+pub const BUTTON_JUMP: u8 = 0x01;
+
+/// Button bit: primary action, such as attacking
+pub const BUTTON_PRIMARY: u8 = 0x02;
+
+/// Button bit: secondary action
+pub const BUTTON_SECONDARY: u8 = 0x04;
+
+/// Button bit: interact with whatever is in front of the player
+pub const BUTTON_USE: u8 = 0x08;
+
+/// What a player did over a short span of time, sent in place of where they ended up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputCommand{
+    /// Increases by one with each command a client sends
+    pub sequence: u32,
+
+    /// When the command was sampled, in milliseconds since the client connected
+    pub timestamp: u32,
+
+    /// How long the input was held, in milliseconds
+    pub duration: u16,
+
+    /// Sideways and forward movement relative to the facing, each between -1 and 1
+    pub movement: (f32, f32),
+
+    /// The direction the player faces, as degrees of yaw
+    pub yaw: f32,
+
+    /// `BUTTON_*` bits held down
+    pub buttons: u8
+}
+
+impl InputCommand{
+    pub fn new(sequence: u32, timestamp: u32, duration: u16) -> InputCommand{
+        InputCommand{
+            sequence: sequence,
+            timestamp: timestamp,
+            duration: duration,
+            movement: (0.0, 0.0),
+            yaw: 0.0,
+            buttons: 0
+        }
+    }
+
+    /// Return TRUE if @button is held down
+    pub fn is_pressed(&self, button: u8) -> bool{
+        self.buttons & button != 0
+    }
+
+    /// The world direction of the movement, no longer than 1
+    pub fn direction(&self) -> Position{
+        let (strafe, forward) = self.movement;
+        let (sin, cos) = self.yaw.to_radians().sin_cos();
+        let direction = (forward * sin + strafe * cos, forward * cos - strafe * sin);
+
+        let length = (direction.0 * direction.0 + direction.1 * direction.1).sqrt();
+        let scale = if length > 1.0 { 1.0 / length } else { 1.0 };
+        return Position::new(direction.0 * scale, 0.0, direction.1 * scale);
+    }
+
+    /// The world direction the player faces, along the ground
+    pub fn facing(&self) -> Position{
+        let (sin, cos) = self.yaw.to_radians().sin_cos();
+        return Position::new(sin, 0.0, cos);
+    }
+
+    /// Where a player at @state ends up after this command, moving at @speed units per second
+    pub fn apply(&self, state: &ClientState, speed: f32) -> ClientState{
+        let seconds = self.duration.min(MAX_INPUT_DURATION_MS) as f32 / 1000.0;
+        let velocity: Velocity = self.direction() * Unit::from_f32(speed);
+
+        let mut next = *state;
+        next.rotation = Rotation::from_degrees(self.yaw);
+        next.velocity = velocity;
+        next.position = state.position + velocity * Unit::from_f32(seconds);
+        return next;
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buf = vec![0u8; INPUT_COMMAND_LENGTH];
+        BigEndian::write_u32(&mut buf[0..4], self.sequence);
+        BigEndian::write_u32(&mut buf[4..8], self.timestamp);
+        BigEndian::write_u16(&mut buf[8..10], self.duration);
+        buf[10] = quantize_axis(self.movement.0) as u8;
+        buf[11] = quantize_axis(self.movement.1) as u8;
+
+        let yaw = self.yaw % 360.0;
+        let yaw = if yaw < 0.0 { yaw + 360.0 } else { yaw };
+        BigEndian::write_u16(&mut buf[12..14], (yaw / 360.0 * 65536.0).round() as u32 as u16);
+        buf[14] = self.buttons;
+        return buf;
+    }
+
+    pub fn read<R: Read>(input: &mut R) -> ProtocolResult<InputCommand>{
+        let mut buf = [0u8; INPUT_COMMAND_LENGTH];
+        try!(read_exact_into(input, &mut buf));
+
+        Ok(InputCommand{
+            sequence: BigEndian::read_u32(&buf[0..4]),
+            timestamp: BigEndian::read_u32(&buf[4..8]),
+            duration: BigEndian::read_u16(&buf[8..10]),
+            movement: (buf[10] as i8 as f32 / 127.0, buf[11] as i8 as f32 / 127.0),
+            yaw: BigEndian::read_u16(&buf[12..14]) as f32 / 65536.0 * 360.0,
+            buttons: buf[14]
+        })
+    }
+}
+
+impl Hash for InputCommand{
+    fn hash<H: Hasher>(&self, state: &mut H){
+        state.write_u32(self.sequence);
+    }
+}
+
+/// A movement axis between -1 and 1, as a signed byte
+fn quantize_axis(value: f32) -> i8{
+    (value.max(-1.0).min(1.0) * 127.0).round() as i8
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use math::Scalar;
+    use state::{ClientState, Position};
+
+    #[test]
+    fn test_input_round_trip(){
+        let mut command = InputCommand::new(42, 1500, 16);
+        command.movement = (-0.5, 1.0);
+        command.yaw = 270.0;
+        command.buttons = BUTTON_JUMP | BUTTON_USE;
+
+        let bytes = command.to_bytes();
+        assert_eq!(bytes.len(), INPUT_COMMAND_LENGTH);
+        let read = InputCommand::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!((read.sequence, read.timestamp, read.duration, read.buttons), (42, 1500, 16, BUTTON_JUMP | BUTTON_USE));
+        assert!((read.movement.0 + 0.5).abs() < 0.01 && read.movement.1 == 1.0);
+        assert!((read.yaw - 270.0).abs() < 0.01);
+        assert!(read.is_pressed(BUTTON_USE) && !read.is_pressed(BUTTON_PRIMARY));
+
+        assert!(InputCommand::read(&mut &bytes[..10]).is_err());
+    }
+
+    #[test]
+    fn test_apply(){
+        let state = ClientState::new(3);
+
+        // Forward at a yaw of 90 degrees is along X
+        let mut command = InputCommand::new(0, 0, 125);
+        command.movement = (0.0, 1.0);
+        command.yaw = 90.0;
+        let moved = command.apply(&state, 4.0);
+        assert!(moved.position.distance(Position::new(0.5, 0.0, 0.0)).to_f32() < 0.01);
+        assert!((moved.rotation.yaw() - 90.0).abs() < 0.01);
+
+        // Moving diagonally is no faster, and long commands are cut short
+        command.movement = (1.0, 1.0);
+        command.duration = 60000;
+        let moved = command.apply(&state, 4.0);
+        assert!((moved.velocity.length().to_f32() - 4.0).abs() < 0.01);
+        assert!((moved.position.length().to_f32() - 1.0).abs() < 0.01);
+    }
+}
@@ -0,0 +1,169 @@
+extern crate log;
+
+use log::LogRecord;
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Fields identifying the connection an event belongs to.
+/// While a context is entered, every event logged on that thread carries its fields.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LogContext{
+    /// The event loop token of the connection
+    pub token: Option<usize>,
+
+    /// The ID of the entity controlled by the connection
+    pub entity: Option<u32>,
+
+    /// The remote address of the connection
+    pub peer: Option<SocketAddr>
+}
+
+impl LogContext{
+    pub fn new() -> LogContext{
+        LogContext{ token: None, entity: None, peer: None }
+    }
+
+    pub fn for_connection(token: usize, entity: Option<u32>, peer: Option<SocketAddr>) -> LogContext{
+        LogContext{ token: Some(token), entity: entity, peer: peer }
+    }
+}
+
+thread_local!(static CURRENT_CONTEXT: RefCell<LogContext> = RefCell::new(LogContext::new()));
+
+/// Restores the previous context when dropped
+pub struct ContextGuard{
+    previous: LogContext
+}
+
+impl Drop for ContextGuard{
+    fn drop(&mut self){
+        let previous = ::std::mem::replace(&mut self.previous, LogContext::new());
+        CURRENT_CONTEXT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Attach @context to everything logged on this thread until the returned guard is dropped
+pub fn enter(context: LogContext) -> ContextGuard{
+    let previous = CURRENT_CONTEXT.with(|current| ::std::mem::replace(&mut *current.borrow_mut(), context));
+    ContextGuard{ previous: previous }
+}
+
+/// The context of the calling thread
+pub fn current() -> LogContext{
+    CURRENT_CONTEXT.with(|current| current.borrow().clone())
+}
+
+/// How log events are written out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat{
+    /// `<time> <LEVEL> <target>: <message> key=value...`
+    Text,
+
+    /// One JSON object per line, for log shipping
+    Json
+}
+
+impl LogFormat{
+    pub fn from_str(format: &str) -> Option<LogFormat>{
+        match format{
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None
+        }
+    }
+
+    /// Format @record, along with the context of the calling thread
+    pub fn format(&self, record: &LogRecord) -> String{
+        let context = current();
+        match *self{
+            LogFormat::Text => format_text(record, &context),
+            LogFormat::Json => format_json(record, &context)
+        }
+    }
+}
+
+fn timestamp() -> f64{
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs() as f64 + (now.subsec_nanos() / 1_000_000) as f64 / 1000.0
+}
+
+fn format_text(record: &LogRecord, context: &LogContext) -> String{
+    let mut output = format!("{:.3} {:<5} {}: {}", timestamp(), record.level(), record.target(), record.args());
+
+    if let Some(token) = context.token{
+        let _ = write!(output, " token={}", token);
+    }
+    if let Some(entity) = context.entity{
+        let _ = write!(output, " entity={}", entity);
+    }
+    if let Some(peer) = context.peer{
+        let _ = write!(output, " peer={}", peer);
+    }
+
+    return output;
+}
+
+fn format_json(record: &LogRecord, context: &LogContext) -> String{
+    let mut output = format!("{{\"ts\":{:.3},\"level\":\"{}\",\"target\":", timestamp(), record.level());
+    write_json_string(&mut output, record.target());
+    output.push_str(",\"msg\":");
+    write_json_string(&mut output, &record.args().to_string());
+
+    if let Some(token) = context.token{
+        let _ = write!(output, ",\"token\":{}", token);
+    }
+    if let Some(entity) = context.entity{
+        let _ = write!(output, ",\"entity\":{}", entity);
+    }
+    if let Some(peer) = context.peer{
+        output.push_str(",\"peer\":");
+        write_json_string(&mut output, &peer.to_string());
+    }
+
+    output.push('}');
+    return output;
+}
+
+fn write_json_string(output: &mut String, value: &str){
+    output.push('"');
+    for c in value.chars(){
+        match c{
+            '"'  => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(output, "\\u{:04x}", c as u32); },
+            c => output.push(c)
+        }
+    }
+    output.push('"');
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+
+    #[test]
+    fn test_enter_restores_previous(){
+        let outer = LogContext::for_connection(2, Some(2), None);
+        let inner = LogContext::for_connection(3, None, Some("127.0.0.1:1234".parse().unwrap()));
+
+        let _outer_guard = enter(outer.clone());
+        {
+            let _inner_guard = enter(inner.clone());
+            assert_eq!(current(), inner);
+        }
+        assert_eq!(current(), outer);
+    }
+
+    #[test]
+    fn test_json_escape(){
+        let mut output = String::new();
+        write_json_string(&mut output, "say \"hi\"\n");
+
+        assert_eq!(output, "\"say \\\"hi\\\"\\n\"");
+    }
+}
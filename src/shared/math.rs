@@ -0,0 +1,171 @@
+use std::fmt;
+use std::ops::{Add, Sub, Mul, Div, Neg};
+
+/// The numeric type of world coordinates and angles.
+/// f32 by default, or Fixed when built with the `fixed-point` feature.
+#[cfg(not(feature = "fixed-point"))]
+pub type Unit = f32;
+#[cfg(feature = "fixed-point")]
+pub type Unit = Fixed;
+
+/// Arithmetic shared by the types world coordinates can be stored as
+pub trait Scalar: Copy + PartialEq + PartialOrd + fmt::Debug
+    + Add<Output=Self> + Sub<Output=Self> + Mul<Output=Self> + Div<Output=Self> + Neg<Output=Self>{
+    fn from_f32(value: f32) -> Self;
+    fn to_f32(self) -> f32;
+
+    fn zero() -> Self{ Self::from_f32(0.0) }
+
+    fn sqrt(self) -> Self{ Self::from_f32(self.to_f32().sqrt()) }
+
+    /// The dot product of the vectors @a and @b
+    fn dot(a: [Self; 3], b: [Self; 3]) -> Self{
+        a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+    }
+
+    /// The length of the vector @v
+    fn length(v: [Self; 3]) -> Self{
+        Self::dot(v, v).sqrt()
+    }
+}
+
+impl Scalar for f32{
+    fn from_f32(value: f32) -> f32{ value }
+    fn to_f32(self) -> f32{ self }
+    fn sqrt(self) -> f32{ f32::sqrt(self) }
+}
+
+/// Number of fractional bits in a Fixed value
+pub const FIXED_FRACTION_BITS: u32 = 8;
+
+/// A Fixed value of 1 is this fraction of a world unit
+pub const FIXED_SCALE: f32 = 1.0 / (1 << FIXED_FRACTION_BITS) as f32;
+
+/// A signed fixed-point number with FIXED_FRACTION_BITS fractional bits
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Fixed(pub i32);
+
+impl Scalar for Fixed{
+    fn from_f32(value: f32) -> Fixed{
+        Fixed((value / FIXED_SCALE).round() as i32)
+    }
+
+    fn to_f32(self) -> f32{
+        self.0 as f32 * FIXED_SCALE
+    }
+
+    // Products are summed before scaling back down, so only a result out of range saturates
+    fn dot(a: [Fixed; 3], b: [Fixed; 3]) -> Fixed{
+        let sum = (0..3).fold(0i64, |sum, i| sum.saturating_add(a[i].0 as i64 * b[i].0 as i64));
+        Fixed(saturate(sum >> FIXED_FRACTION_BITS))
+    }
+
+    // The squared length of anything more than a few thousand units away doesn't fit in a Fixed,
+    // so the length is worked out from the raw values
+    fn length(v: [Fixed; 3]) -> Fixed{
+        let squared = v.iter().fold(0.0f64, |sum, component| sum + component.0 as f64 * component.0 as f64);
+        Fixed(saturate(squared.sqrt().round() as i64))
+    }
+}
+
+/// Clamp @value into the range of a Fixed's raw value
+fn saturate(value: i64) -> i32{
+    value.max(::std::i32::MIN as i64).min(::std::i32::MAX as i64) as i32
+}
+
+impl fmt::Debug for Fixed{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "{}", self.to_f32())
+    }
+}
+
+impl Add for Fixed{
+    type Output = Fixed;
+    fn add(self, other: Fixed) -> Fixed{ Fixed(saturate(self.0 as i64 + other.0 as i64)) }
+}
+
+impl Sub for Fixed{
+    type Output = Fixed;
+    fn sub(self, other: Fixed) -> Fixed{ Fixed(saturate(self.0 as i64 - other.0 as i64)) }
+}
+
+impl Mul for Fixed{
+    type Output = Fixed;
+    fn mul(self, other: Fixed) -> Fixed{
+        Fixed(saturate((self.0 as i64 * other.0 as i64) >> FIXED_FRACTION_BITS))
+    }
+}
+
+impl Div for Fixed{
+    type Output = Fixed;
+    // Dividing by zero gives the furthest value in the direction of the dividend, rather than panicking
+    fn div(self, other: Fixed) -> Fixed{
+        if other.0 == 0{
+            return Fixed(saturate(self.0.signum() as i64 * ::std::i64::MAX));
+        }
+        Fixed(saturate(((self.0 as i64) << FIXED_FRACTION_BITS) / other.0 as i64))
+    }
+}
+
+impl Neg for Fixed{
+    type Output = Fixed;
+    fn neg(self) -> Fixed{ Fixed(saturate(-(self.0 as i64))) }
+}
+
+/// Linear interpolation from @from to @to, @t being the fraction of the way there
+pub fn lerp<T: Scalar>(from: T, to: T, t: T) -> T{
+    from + (to - from) * t
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+
+    #[test]
+    fn test_fixed_arithmetic(){
+        let a = Fixed::from_f32(2.5);
+        let b = Fixed::from_f32(-1.25);
+
+        assert_eq!(a.0, 640);
+        assert_eq!((a + b).to_f32(), 1.25);
+        assert_eq!((a - b).to_f32(), 3.75);
+        assert_eq!((a * b).to_f32(), -3.125);
+        assert_eq!((a / b).to_f32(), -2.0);
+        assert_eq!((-a).to_f32(), -2.5);
+        assert_eq!(Fixed::from_f32(16.0).sqrt().to_f32(), 4.0);
+        assert_eq!(lerp(b, a, Fixed::from_f32(0.5)).to_f32(), 0.625);
+
+        // Values are rounded to the nearest representable step
+        assert_eq!(Fixed::from_f32(0.001).0, 0);
+        assert_eq!(Fixed::from_f32(FIXED_SCALE * 0.6).0, 1);
+
+        // Products out of range saturate instead of wrapping around
+        let far = Fixed::from_f32(100000.0);
+        assert_eq!((far * far).0, ::std::i32::MAX);
+        assert_eq!((far * -far).0, ::std::i32::MIN);
+
+        // So do sums, differences, negations and quotients
+        let max = Fixed(::std::i32::MAX);
+        let min = Fixed(::std::i32::MIN);
+        assert_eq!(max + a, max);
+        assert_eq!(min + b, min);
+        assert_eq!(max - b, max);
+        assert_eq!(min - a, min);
+        assert_eq!(-min, max);
+        assert_eq!(max / Fixed::from_f32(0.5), max);
+        assert_eq!(min / Fixed::from_f32(0.5), min);
+
+        // Dividing by zero saturates by the sign of the dividend
+        let zero = Fixed::from_f32(0.0);
+        assert_eq!(a / zero, max);
+        assert_eq!(b / zero, min);
+        assert_eq!(zero / zero, zero);
+    }
+
+    #[test]
+    fn test_fixed_length_far_away(){
+        let v = [Fixed::from_f32(6000.0), Fixed::from_f32(0.0), Fixed::from_f32(8000.0)];
+        assert_eq!(Fixed::length(v).to_f32(), 10000.0);
+        assert_eq!(Fixed::dot(v, v).0, ::std::i32::MAX);
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::VecDeque;
+
+use frame::{Message, MessageCode, ToFrame};
+use fragment::DEFAULT_FRAGMENT_SIZE;
+
+/// Bulk messages are dropped once this many messages are waiting to be sent
+pub const DEFAULT_MAX_BACKLOG: usize = 256;
+
+/// At most this many messages are taken from a send queue each time the connection is written
+pub const MESSAGES_PER_WRITE: usize = 64;
+
+/// How urgently a message needs to be sent, most urgent first
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Priority{
+    /// Connection setup and teardown, always sent before anything else
+    Control = 0,
+    State   = 1,
+    Chat    = 2,
+
+    /// Dropped when the connection falls behind
+    Bulk    = 3
+}
+
+/// Share of the sends each class gets while several are waiting. Control is never made to wait.
+const WEIGHTS: [u32; 4] = [0, 8, 4, 1];
+
+impl Priority{
+    pub fn for_code(code: &MessageCode) -> Priority{
+        match *code{
+            MessageCode::Hello           => { Priority::Control },
+            MessageCode::Login           => { Priority::Control },
+            MessageCode::Disconnect      => { Priority::Control },
+            MessageCode::Ping            => { Priority::Control },
+            MessageCode::ClientUpdate    => { Priority::State },
+            MessageCode::GameStateUpdate => { Priority::State },
+            MessageCode::EntityUpdate    => { Priority::State },
+            MessageCode::Input           => { Priority::State },
+            MessageCode::Text            => { Priority::Chat },
+            MessageCode::Batch           => { Priority::Bulk },
+            MessageCode::Fragment        => { Priority::Bulk }
+        }
+    }
+
+    /// The class of a message of type @code whose frame is @length bytes.
+    /// Chat too long for one frame is sent as fragments, which is what a connection falls behind on, so it goes as bulk.
+    pub fn for_frame(code: &MessageCode, length: usize) -> Priority{
+        match Priority::for_code(code){
+            Priority::Chat if length > DEFAULT_FRAGMENT_SIZE => Priority::Bulk,
+            priority => priority
+        }
+    }
+
+    /// The class @message is sent with, as `for_frame`; only chat is encoded to find its length
+    pub fn for_message(message: &Message) -> Priority{
+        let code = message.get_message_code();
+        match Priority::for_code(&code){
+            Priority::Chat => Priority::for_frame(&code, message.to_frame().to_bytes().len()),
+            priority => priority
+        }
+    }
+}
+
+/// A send queue with a FIFO queue per Priority, drained by weight
+pub struct PriorityQueue<T>{
+    queues: [VecDeque<T>; 4],
+    credits: [u32; 4],
+
+    /// Bulk items pushed while this many items are queued are dropped
+    pub max_backlog: usize
+}
+
+impl<T> PriorityQueue<T>{
+    pub fn new() -> PriorityQueue<T>{
+        PriorityQueue{
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            credits: WEIGHTS,
+            max_backlog: DEFAULT_MAX_BACKLOG
+        }
+    }
+
+    /// Queue @item to be sent with @priority.
+    /// Returns FALSE if it was dropped because the queue has fallen behind.
+    pub fn push_back(&mut self, priority: Priority, item: T) -> bool{
+        if priority == Priority::Bulk && self.len() >= self.max_backlog{
+            return false;
+        }
+        self.queues[priority as usize].push_back(item);
+        return true;
+    }
+
+    /// The item pop_front would return next
+    pub fn front(&self) -> Option<&T>{
+        self.next_class().and_then(|class| self.queues[class].front())
+    }
+
+    pub fn pop_front(&mut self) -> Option<T>{
+        let class = match self.next_class(){
+            Some(class) => class,
+            None => { return None; }
+        };
+
+        if class != Priority::Control as usize{
+            if self.credits[class] == 0{
+                self.credits = WEIGHTS;
+            }
+            self.credits[class] -= 1;
+        }
+        return self.queues[class].pop_front();
+    }
+
+    pub fn len(&self) -> usize{
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+
+    pub fn clear(&mut self){
+        for queue in self.queues.iter_mut(){
+            queue.clear();
+        }
+        self.credits = WEIGHTS;
+    }
+
+    /// Control first, then the waiting class with the most credit left.
+    /// Once every waiting class has used its credit, all credits are refilled.
+    fn next_class(&self) -> Option<usize>{
+        if !self.queues[Priority::Control as usize].is_empty(){
+            return Some(Priority::Control as usize);
+        }
+
+        let waiting = (1..self.queues.len()).filter(|class| !self.queues[*class].is_empty());
+        let with_credit = waiting.clone().filter(|class| self.credits[*class] > 0).max_by_key(|class| (self.credits[*class], -(*class as i32)));
+        return with_credit.or_else(|| waiting.max_by_key(|class| (WEIGHTS[*class], -(*class as i32))));
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+
+    #[test]
+    fn test_weighted_draining(){
+        let mut queue = PriorityQueue::new();
+        for i in 0..20{
+            queue.push_back(Priority::Chat, ("chat", i));
+        }
+        for i in 0..20{
+            queue.push_back(Priority::State, ("state", i));
+        }
+        queue.push_back(Priority::Control, ("control", 0));
+
+        // Control jumps the queue, and a burst of chat doesn't hold up state
+        assert_eq!(queue.pop_front(), Some(("control", 0)));
+        let drained: Vec<&str> = (0..12).map(|_| queue.pop_front().unwrap().0).collect();
+        assert_eq!(drained.iter().filter(|class| **class == "state").count(), 8);
+        assert_eq!(drained.iter().filter(|class| **class == "chat").count(), 4);
+        assert_eq!(queue.front(), Some(&("state", 8)));
+
+        // Each class stays in order
+        let mut last_chat = None;
+        while let Some((class, i)) = queue.pop_front(){
+            if class == "chat"{
+                assert!(last_chat.map_or(true, |last| i > last));
+                last_chat = Some(i);
+            }
+        }
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_long_chat_is_bulk(){
+        assert_eq!(Priority::for_message(&Message::new_text_message(String::from("Hello"))), Priority::Chat);
+        let long = Message::new_text_message((0..DEFAULT_FRAGMENT_SIZE).map(|_| 'x').collect());
+        assert_eq!(Priority::for_message(&long), Priority::Bulk);
+        assert_eq!(Priority::for_frame(&MessageCode::EntityUpdate, DEFAULT_FRAGMENT_SIZE * 4), Priority::State);
+    }
+
+    #[test]
+    fn test_bulk_dropped_when_behind(){
+        let mut queue = PriorityQueue::new();
+        queue.max_backlog = 4;
+        for i in 0..4{
+            assert!(queue.push_back(Priority::State, i));
+        }
+        assert!(!queue.push_back(Priority::Bulk, 4));
+        assert!(queue.push_back(Priority::Chat, 5));
+        assert_eq!(queue.len(), 5);
+    }
+}
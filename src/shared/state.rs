@@ -4,20 +4,85 @@ extern crate core;
 use std::cmp::PartialEq;
 use std::hash::{Hash, Hasher};
 use std::collections::HashMap;
-use std::io::{Read, ErrorKind, Result, Error};
+use std::io::Read;
 use byteorder::{ByteOrder, BigEndian};
-use std::mem;
-use std::ops::Add;
+use std::ops::{Add, Sub, Mul};
 
-#[derive(Copy, Debug, Clone)]
-pub struct Position(pub i32, pub i32, pub i32);
-#[derive(Copy, Debug, Clone)]
-pub struct Rotation(pub i32);
+use frame::{ProtocolError, ProtocolResult};
+use math;
+use math::{Scalar, Unit};
+use entity::{Entity, EntityDelta, EntityKind};
+
+/// Positions are sent over the wire as multiples of this many world units
+pub const POSITION_QUANTUM: f32 = 1.0 / 64.0;
+
+/// Velocities are sent over the wire as multiples of this many world units per second
+pub const VELOCITY_QUANTUM: f32 = 1.0 / 64.0;
+
+/// Bits per component of a quaternion encoded as its smallest three components
+const QUATERNION_COMPONENT_BITS: u32 = 10;
+
+/// Length of a ClientState on the wire: ID, quantized position, smallest-three rotation and quantized velocity
+pub const CLIENT_STATE_LENGTH: usize = 4 + 3 * 4 + 4 + 3 * 2;
+
+#[derive(Copy, Debug, Clone, PartialEq)]
+pub struct Position(pub Unit, pub Unit, pub Unit);
+
+/// Linear velocity, in world units per second
+pub type Velocity = Position;
+
+/// An orientation, as a unit quaternion.
+/// Yaw turns about the Y axis, pitch about the X axis and roll about the Z axis.
+#[derive(Copy, Debug, Clone, PartialEq)]
+pub struct Rotation{ pub w: f32, pub x: f32, pub y: f32, pub z: f32 }
 #[derive(Copy, Debug, Clone)]
 pub struct Transform{pub position: Position, pub rotation: Rotation}
 
 impl Position{
-    pub fn zero() -> Position{ Position(0,0,0) }
+    pub fn new(x: f32, y: f32, z: f32) -> Position{
+        Position(Unit::from_f32(x), Unit::from_f32(y), Unit::from_f32(z))
+    }
+
+    pub fn zero() -> Position{ Position(Unit::zero(), Unit::zero(), Unit::zero()) }
+
+    pub fn dot(&self, other: Position) -> Unit{
+        Unit::dot([self.0, self.1, self.2], [other.0, other.1, other.2])
+    }
+
+    pub fn length(&self) -> Unit{
+        Unit::length([self.0, self.1, self.2])
+    }
+
+    pub fn distance(&self, other: Position) -> Unit{
+        (*self - other).length()
+    }
+
+    /// This direction with a length of one, or zero if this has no length
+    pub fn normalize(&self) -> Position{
+        let length = self.length();
+        if length == Unit::zero(){
+            return Position::zero();
+        }
+        return Position(self.0 / length, self.1 / length, self.2 / length);
+    }
+
+    /// The position @t of the way from this one to @other
+    pub fn lerp(&self, other: Position, t: Unit) -> Position{
+        Position(math::lerp(self.0, other.0, t), math::lerp(self.1, other.1, t), math::lerp(self.2, other.2, t))
+    }
+
+    /// Reduce to the precision sent over the wire
+    pub fn quantize(&self) -> [i32; 3]{
+        [Self::quantize_component(self.0), Self::quantize_component(self.1), Self::quantize_component(self.2)]
+    }
+
+    pub fn dequantize(quantized: [i32; 3]) -> Position{
+        Position::new(quantized[0] as f32 * POSITION_QUANTUM, quantized[1] as f32 * POSITION_QUANTUM, quantized[2] as f32 * POSITION_QUANTUM)
+    }
+
+    fn quantize_component(value: Unit) -> i32{
+        (value.to_f32() / POSITION_QUANTUM).round() as i32
+    }
 }
 
 impl Add for Position{
@@ -28,8 +93,138 @@ impl Add for Position{
     }
 }
 
+impl Sub for Position{
+    type Output = Position;
+
+    fn sub(self, other: Position) -> Position {
+        Position(self.0 - other.0, self.1 - other.1, self.2 - other.2)
+    }
+}
+
+impl Mul<Unit> for Position{
+    type Output = Position;
+
+    fn mul(self, scale: Unit) -> Position {
+        Position(self.0 * scale, self.1 * scale, self.2 * scale)
+    }
+}
+
 impl Rotation{
-    pub fn zero() -> Rotation{ Rotation(0) }
+    /// No rotation
+    pub fn zero() -> Rotation{ Rotation{ w: 1.0, x: 0.0, y: 0.0, z: 0.0 } }
+
+    /// A rotation of @degrees of yaw
+    pub fn from_degrees(degrees: f32) -> Rotation{
+        Self::from_yaw_pitch_roll(degrees, 0.0, 0.0)
+    }
+
+    /// Yaw, then pitch, then roll, each in degrees
+    pub fn from_yaw_pitch_roll(yaw: f32, pitch: f32, roll: f32) -> Rotation{
+        let (sy, cy) = (yaw.to_radians() / 2.0).sin_cos();
+        let (sp, cp) = (pitch.to_radians() / 2.0).sin_cos();
+        let (sr, cr) = (roll.to_radians() / 2.0).sin_cos();
+
+        Rotation{
+            w: cy * cp * cr + sy * sp * sr,
+            x: cy * sp * cr + sy * cp * sr,
+            y: sy * cp * cr - cy * sp * sr,
+            z: cy * cp * sr - sy * sp * cr
+        }
+    }
+
+    /// Yaw, pitch and roll in degrees
+    pub fn to_yaw_pitch_roll(&self) -> (f32, f32, f32){
+        let Rotation{ w, x, y, z } = *self;
+        let yaw = (2.0 * (x * z + w * y)).atan2(1.0 - 2.0 * (x * x + y * y));
+        let pitch = (2.0 * (w * x - y * z)).max(-1.0).min(1.0).asin();
+        let roll = (2.0 * (x * y + w * z)).atan2(1.0 - 2.0 * (x * x + z * z));
+        return (yaw.to_degrees(), pitch.to_degrees(), roll.to_degrees());
+    }
+
+    /// The yaw in degrees, between 0 and 360
+    pub fn yaw(&self) -> f32{
+        let (yaw, _, _) = self.to_yaw_pitch_roll();
+        if yaw < 0.0 { yaw + 360.0 } else { yaw }
+    }
+
+    pub fn dot(&self, other: Rotation) -> f32{
+        self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    /// The same rotation as a unit quaternion
+    pub fn normalize(&self) -> Rotation{
+        let length = self.dot(*self).sqrt();
+        if length == 0.0{
+            return Rotation::zero();
+        }
+        return Rotation{ w: self.w / length, x: self.x / length, y: self.y / length, z: self.z / length };
+    }
+
+    /// Spherical interpolation @t of the way from this rotation to @other, turning the shorter way round
+    pub fn slerp(&self, other: Rotation, t: f32) -> Rotation{
+        let mut other = other;
+        let mut cos_theta = self.dot(other);
+        if cos_theta < 0.0{
+            other = Rotation{ w: -other.w, x: -other.x, y: -other.y, z: -other.z };
+            cos_theta = -cos_theta;
+        }
+
+        // Nearly identical rotations are interpolated linearly, to avoid dividing by almost zero
+        let (from_weight, to_weight) = if cos_theta > 0.9995{
+            (1.0 - t, t)
+        }
+        else{
+            let theta = cos_theta.acos();
+            let sin_theta = theta.sin();
+            (((1.0 - t) * theta).sin() / sin_theta, (t * theta).sin() / sin_theta)
+        };
+
+        return Rotation{
+            w: self.w * from_weight + other.w * to_weight,
+            x: self.x * from_weight + other.x * to_weight,
+            y: self.y * from_weight + other.y * to_weight,
+            z: self.z * from_weight + other.z * to_weight
+        }.normalize();
+    }
+
+    /// Encode as the smallest three components, from which the largest can be rebuilt.
+    /// The top two bits give the index of the largest component.
+    pub fn quantize(&self) -> u32{
+        let rotation = self.normalize();
+        let components = [rotation.w, rotation.x, rotation.y, rotation.z];
+        let largest = (0..4).fold(0, |largest, i| if components[i].abs() > components[largest].abs() { i } else { largest });
+
+        // q and -q are the same rotation, so the largest component can always be made positive
+        let sign = if components[largest] < 0.0 { -1.0 } else { 1.0 };
+        let max_value = (1 << QUATERNION_COMPONENT_BITS) - 1;
+
+        let mut packed = (largest as u32) << 30;
+        let mut shift = 2 * QUATERNION_COMPONENT_BITS;
+        for i in (0..4).filter(|i| *i != largest){
+            let normalized = (components[i] * sign / ::std::f32::consts::FRAC_1_SQRT_2 + 1.0) / 2.0;
+            let value = (normalized * max_value as f32).round().max(0.0).min(max_value as f32) as u32;
+            packed |= value << shift;
+            shift = shift.saturating_sub(QUATERNION_COMPONENT_BITS);
+        }
+        return packed;
+    }
+
+    pub fn dequantize(packed: u32) -> Rotation{
+        let largest = (packed >> 30) as usize;
+        let max_value = (1 << QUATERNION_COMPONENT_BITS) - 1;
+
+        let mut components = [0.0f32; 4];
+        let mut shift = 2 * QUATERNION_COMPONENT_BITS;
+        for i in (0..4).filter(|i| *i != largest){
+            let value = (packed >> shift) & max_value;
+            components[i] = (value as f32 / max_value as f32 * 2.0 - 1.0) * ::std::f32::consts::FRAC_1_SQRT_2;
+            shift = shift.saturating_sub(QUATERNION_COMPONENT_BITS);
+        }
+        let sum_of_squares: f32 = components.iter().map(|c| c * c).sum();
+        components[largest] = (1.0 - sum_of_squares).max(0.0).sqrt();
+
+        return Rotation{ w: components[0], x: components[1], y: components[2], z: components[3] }.normalize();
+    }
 }
 
 impl Transform{
@@ -47,8 +242,11 @@ pub struct ClientState{
     /// The world location of this client
     pub position: Position,
 
-    /// The rotational yaw of this client
-    pub rotation: Rotation
+    /// The orientation of this client
+    pub rotation: Rotation,
+
+    /// How fast this client is moving, used to extrapolate its position
+    pub velocity: Velocity
 }
 
 impl ClientState{
@@ -56,15 +254,32 @@ impl ClientState{
         ClientState{
             id: id,
             position: Position::zero(),
-            rotation: Rotation::zero()
+            rotation: Rotation::zero(),
+            velocity: Velocity::zero()
         }
     }
 
-    pub fn read<R: Read>(input: &mut R) -> Result<ClientState>{
-        // The number of bytes we're expecting to read
-        const BUFFER_LENGTH : usize = 20;
+    /// The state @t of the way from this one to @other
+    pub fn interpolate(&self, other: &ClientState, t: f32) -> ClientState{
+        let t_unit = Unit::from_f32(t);
+        ClientState{
+            id: self.id,
+            position: self.position.lerp(other.position, t_unit),
+            rotation: self.rotation.slerp(other.rotation, t),
+            velocity: self.velocity.lerp(other.velocity, t_unit)
+        }
+    }
+
+    /// Where this client would be after @seconds more at its current velocity
+    pub fn extrapolate(&self, seconds: f32) -> ClientState{
+        let mut state = *self;
+        state.position = self.position + self.velocity * Unit::from_f32(seconds);
+        return state;
+    }
 
-        assert_eq!(BUFFER_LENGTH, mem::size_of::<Self>());
+    pub fn read<R: Read>(input: &mut R) -> ProtocolResult<ClientState>{
+        // The number of bytes we're expecting to read
+        const BUFFER_LENGTH : usize = CLIENT_STATE_LENGTH;
 
         let mut message_buf = [0u8; BUFFER_LENGTH];
         let bytes_read = try!(input.read(&mut message_buf));
@@ -72,33 +287,55 @@ impl ClientState{
         // Error checking; Make sure we read bytes of the message,
         // and ensure it's the length the client claimed it would be.
         if bytes_read != BUFFER_LENGTH{
-            return Err(Error::new(ErrorKind::Other, format!("Expected string of {} bytes, received a string of {} bytes!", BUFFER_LENGTH, bytes_read)));
+            return Err(ProtocolError::Truncated{ expected: BUFFER_LENGTH, got: bytes_read });
         }
 
         let mut client_state = ClientState::new(0);
 
         client_state.id         = BigEndian::read_u32(&message_buf[00..04]);
-        client_state.position.0 = BigEndian::read_i32(&message_buf[04..08]);
-        client_state.position.1 = BigEndian::read_i32(&message_buf[08..12]);
-        client_state.position.2 = BigEndian::read_i32(&message_buf[12..16]);
-        client_state.rotation.0 = BigEndian::read_i32(&message_buf[16..20]);
+        client_state.position   = Position::dequantize([
+            BigEndian::read_i32(&message_buf[04..08]),
+            BigEndian::read_i32(&message_buf[08..12]),
+            BigEndian::read_i32(&message_buf[12..16])
+        ]);
+        client_state.rotation   = Rotation::dequantize(BigEndian::read_u32(&message_buf[16..20]));
+        client_state.velocity   = dequantize_velocity([
+            BigEndian::read_i16(&message_buf[20..22]),
+            BigEndian::read_i16(&message_buf[22..24]),
+            BigEndian::read_i16(&message_buf[24..26])
+        ]);
 
         return Ok(client_state);
     }
 
     pub fn to_bytes(&self) -> Vec<u8>{
-        let mut buf = [0u8; 20];
+        let mut buf = [0u8; CLIENT_STATE_LENGTH];
+        let position = self.position.quantize();
+        let velocity = quantize_velocity(self.velocity);
 
         BigEndian::write_u32(&mut buf[00..04], self.id);
-        BigEndian::write_i32(&mut buf[04..08], self.position.0);
-        BigEndian::write_i32(&mut buf[08..12], self.position.1);
-        BigEndian::write_i32(&mut buf[12..16], self.position.2);
-        BigEndian::write_i32(&mut buf[16..20], self.rotation.0);
+        BigEndian::write_i32(&mut buf[04..08], position[0]);
+        BigEndian::write_i32(&mut buf[08..12], position[1]);
+        BigEndian::write_i32(&mut buf[12..16], position[2]);
+        BigEndian::write_u32(&mut buf[16..20], self.rotation.quantize());
+        BigEndian::write_i16(&mut buf[20..22], velocity[0]);
+        BigEndian::write_i16(&mut buf[22..24], velocity[1]);
+        BigEndian::write_i16(&mut buf[24..26], velocity[2]);
 
         return buf.to_vec();
     }
 }
 
+/// Reduce @velocity to the precision sent over the wire, clamping it to the range that can be sent
+fn quantize_velocity(velocity: Velocity) -> [i16; 3]{
+    let quantize = |value: Unit| (value.to_f32() / VELOCITY_QUANTUM).round().max(i16::min_value() as f32).min(i16::max_value() as f32) as i16;
+    [quantize(velocity.0), quantize(velocity.1), quantize(velocity.2)]
+}
+
+fn dequantize_velocity(quantized: [i16; 3]) -> Velocity{
+    Velocity::new(quantized[0] as f32 * VELOCITY_QUANTUM, quantized[1] as f32 * VELOCITY_QUANTUM, quantized[2] as f32 * VELOCITY_QUANTUM)
+}
+
 impl PartialEq for ClientState{
     fn eq(&self, other: &ClientState) -> bool{
         self.id == other.id
@@ -114,20 +351,150 @@ impl Hash for ClientState{
 
 #[derive(Clone, Debug)]
 pub struct GameState{
-    pub clients: HashMap<u32, ClientState>
+    /// Every entity in the world, players included, by ID
+    pub entities: HashMap<u32, Entity>,
+
+    /// IDs of the entities removed since the last replication
+    despawned: Vec<u32>
 }
 
 impl GameState{
     pub fn new() -> GameState{
         GameState{
-            clients: HashMap::with_capacity(32)
+            entities: HashMap::with_capacity(32),
+            despawned: Vec::new()
         }
     }
 
-    pub fn update_from_vec(&mut self, update_vec: &Vec<ClientState>){
-        self.clients.clear();
-        for client in update_vec{
-            self.clients.insert(client.id, *client);
+    /// Remove the entity @id from the world, replicating its removal
+    pub fn remove(&mut self, id: u32) -> Option<Entity>{
+        let entity = self.entities.remove(&id);
+        if entity.is_some(){
+            self.despawned.push(id);
         }
+        return entity;
+    }
+
+    /// Everything which has changed since the last replication that @recipient may see
+    pub fn deltas_for(&self, recipient: u32) -> Vec<EntityDelta>{
+        let changed = self.entities.values()
+            .filter(|entity| entity.is_dirty())
+            .filter_map(|entity| entity.delta_for(recipient, false));
+        let despawned = self.despawned.iter().map(|id| EntityDelta::despawn(*id));
+        return changed.chain(despawned).collect();
+    }
+
+    /// Every entity, with everything about it that @recipient may see, for a newly joined client
+    pub fn snapshot_for(&self, recipient: u32) -> Vec<EntityDelta>{
+        self.entities.values().filter_map(|entity| entity.delta_for(recipient, true)).collect()
+    }
+
+    /// Mark every entity as replicated
+    pub fn clear_dirty(&mut self){
+        for entity in self.entities.values_mut(){
+            entity.clear_dirty();
+        }
+        self.despawned.clear();
+    }
+
+    /// Apply changes replicated from the server
+    pub fn apply(&mut self, deltas: &[EntityDelta]){
+        for delta in deltas{
+            if delta.despawned{
+                self.entities.remove(&delta.id);
+                continue;
+            }
+
+            let entity = self.entities.entry(delta.id).or_insert_with(|| Entity::new(ClientState::new(delta.id), EntityKind::Npc));
+            entity.apply(delta);
+            entity.clear_dirty();
+        }
+    }
+
+    pub fn update_from_vec(&mut self, update_vec: &Vec<Entity>){
+        self.entities.clear();
+        for entity in update_vec{
+            self.entities.insert(entity.id(), entity.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use math::{Scalar, Unit};
+
+    #[test]
+    fn test_position_math(){
+        let a = Position::new(3.0, 4.0, 0.0);
+        let b = Position::new(1.0, 1.0, 1.0);
+
+        assert_eq!(a - b, Position::new(2.0, 3.0, -1.0));
+        assert_eq!(a * Unit::from_f32(0.5), Position::new(1.5, 2.0, 0.0));
+        assert_eq!(a.length().to_f32(), 5.0);
+        // Fixed-point division loses a little precision
+        assert!(a.normalize().distance(Position::new(0.6, 0.8, 0.0)).to_f32() < 0.01);
+        assert_eq!(Position::zero().normalize(), Position::zero());
+        assert_eq!(b.lerp(a, Unit::from_f32(0.5)), Position::new(2.0, 2.5, 0.5));
+    }
+
+    #[test]
+    #[cfg(feature = "fixed-point")]
+    fn test_far_apart_distance(){
+        // Squared distances this large don't fit in a Fixed
+        let a = Position::new(-3000.0, 10.0, 2000.0);
+        let b = Position::new(3000.0, 10.0, -6000.0);
+        assert_eq!(a.distance(b).to_f32(), 10000.0);
+        assert!((a.normalize().length().to_f32() - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_rotation(){
+        let rotation = Rotation::from_yaw_pitch_roll(30.0, 20.0, 10.0);
+        let (yaw, pitch, roll) = rotation.to_yaw_pitch_roll();
+        assert!((yaw - 30.0).abs() < 0.01 && (pitch - 20.0).abs() < 0.01 && (roll - 10.0).abs() < 0.01);
+        assert!((Rotation::from_degrees(-90.0).yaw() - 270.0).abs() < 0.01);
+
+        // Interpolation turns the short way round
+        let from = Rotation::from_degrees(350.0);
+        let to = Rotation::from_degrees(10.0);
+        let halfway = from.slerp(to, 0.5).yaw();
+        assert!(halfway < 0.01 || halfway > 359.99);
+    }
+
+    #[test]
+    fn test_extrapolate(){
+        let mut state = ClientState::new(1);
+        state.position = Position::new(1.0, 0.0, 0.0);
+        state.velocity = Velocity::new(2.0, 0.0, -4.0);
+
+        assert_eq!(state.extrapolate(0.5).position, Position::new(2.0, 0.0, -2.0));
+
+        let mut later = state;
+        later.position = Position::new(3.0, 0.0, 0.0);
+        later.rotation = Rotation::from_degrees(90.0);
+        let between = state.interpolate(&later, 0.5);
+        assert_eq!(between.position, Position::new(2.0, 0.0, 0.0));
+        assert!((between.rotation.yaw() - 45.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_quantized_round_trip(){
+        let mut state = ClientState::new(42);
+        state.position = Position::new(12.34, -0.5, 1000.0);
+        state.rotation = Rotation::from_yaw_pitch_roll(123.4, -45.0, 170.0);
+        state.velocity = Velocity::new(5.5, -0.25, 1000.0);
+
+        let bytes = state.to_bytes();
+        assert_eq!(bytes.len(), CLIENT_STATE_LENGTH);
+
+        let read = ClientState::read(&mut bytes.as_slice()).unwrap();
+        assert!(read.position.distance(state.position).to_f32() <= POSITION_QUANTUM);
+        assert!(read.rotation.dot(state.rotation).abs() > 0.9999);
+        assert_eq!(read.velocity.0, state.velocity.0);
+        assert_eq!(read.velocity.1, state.velocity.1);
+
+        // Velocities beyond what can be sent are clamped
+        assert_eq!(read.velocity.2.to_f32(), i16::max_value() as f32 * VELOCITY_QUANTUM);
     }
 }
@@ -0,0 +1,380 @@
+extern crate log;
+
+use std::io::{Error, ErrorKind, Result, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::convert::TryFrom;
+use mio::Evented;
+use mio::tcp::{TcpListener, TcpStream, Shutdown};
+
+use rustls;
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, ServerConfig, ServerConnection, SignatureScheme};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::pki_types::pem::PemObject;
+
+use frame::{Message, ProtocolResult, DEFAULT_MAX_PAYLOAD_LENGTH};
+use transport::{Connection, Transport, next_frame};
+use udp::Delivery;
+
+/// How a client decides whether to trust the server it connects to
+#[derive(Clone, Debug)]
+pub enum ServerTrust{
+    /// Accept any certificate issued by the CA certificates in this PEM file
+    CertificateAuthority(String),
+
+    /// Accept only the certificate in this PEM file, whoever issued it
+    PinnedCertificate(String)
+}
+
+/// Load every certificate in the PEM file at @path
+fn load_certificates(path: &str) -> Result<Vec<CertificateDer<'static>>>{
+    let certificates: Vec<CertificateDer<'static>> = try!(
+        CertificateDer::pem_file_iter(path)
+            .and_then(|certificates| certificates.collect())
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Failed to read certificates from {}: {}", path, e)))
+    );
+
+    if certificates.is_empty(){
+        return Err(Error::new(ErrorKind::InvalidData, format!("No certificates found in {}", path)));
+    }
+    return Ok(certificates);
+}
+
+/// Load the private key in the PEM file at @path
+fn load_private_key(path: &str) -> Result<PrivateKeyDer<'static>>{
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Failed to read private key from {}: {}", path, e)))
+}
+
+fn tls_error(e: rustls::Error) -> Error{
+    Error::new(ErrorKind::InvalidData, format!("TLS error: {}", e))
+}
+
+/// Build the server's TLS settings from the certificate chain and private key at the given paths
+pub fn server_config(certificate_path: &str, key_path: &str) -> Result<Arc<ServerConfig>>{
+    let certificates = try!(load_certificates(certificate_path));
+    let key = try!(load_private_key(key_path));
+
+    let config = try!(ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(tls_error));
+    return Ok(Arc::new(config));
+}
+
+/// Build a client's TLS settings, trusting servers as described by @trust
+pub fn client_config(trust: &ServerTrust) -> Result<Arc<ClientConfig>>{
+    let config = match *trust{
+        ServerTrust::CertificateAuthority(ref path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in try!(load_certificates(path)){
+                try!(roots.add(certificate).map_err(tls_error));
+            }
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth()
+        },
+        ServerTrust::PinnedCertificate(ref path) => {
+            let certificate = try!(load_certificates(path)).remove(0);
+            let verifier = PinnedCertificate{
+                certificate: certificate,
+                provider: Arc::new(rustls::crypto::ring::default_provider())
+            };
+            ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth()
+        }
+    };
+    return Ok(Arc::new(config));
+}
+
+/// Accepts exactly one server certificate, ignoring its issuer, name and expiry.
+/// The handshake signatures are still checked, so the server must hold the matching key.
+#[derive(Debug)]
+struct PinnedCertificate{
+    certificate: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>
+}
+
+impl ServerCertVerifier for PinnedCertificate{
+    fn verify_server_cert(&self, end_entity: &CertificateDer, _: &[CertificateDer], _: &ServerName, _: &[u8], _: UnixTime)
+        -> ::std::result::Result<ServerCertVerified, rustls::Error>{
+        if end_entity.as_ref() == self.certificate.as_ref(){
+            return Ok(ServerCertVerified::assertion());
+        }
+        return Err(rustls::Error::InvalidCertificate(rustls::CertificateError::ApplicationVerificationFailure));
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], certificate: &CertificateDer, signature: &DigitallySignedStruct)
+        -> ::std::result::Result<HandshakeSignatureValid, rustls::Error>{
+        verify_tls12_signature(message, certificate, signature, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], certificate: &CertificateDer, signature: &DigitallySignedStruct)
+        -> ::std::result::Result<HandshakeSignatureValid, rustls::Error>{
+        verify_tls13_signature(message, certificate, signature, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme>{
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// MessageFrames sent back to back over a TLS session on a TCP stream
+pub struct TlsConnection{
+    socket: TcpStream,
+    session: rustls::Connection,
+
+    /// Decrypted bytes not yet making up a whole MessageFrame
+    input: Vec<u8>,
+
+    max_payload_length: u32
+}
+
+impl TlsConnection{
+    /// Start the client side of a session with the server named @server_name
+    pub fn connect(socket: TcpStream, server_name: &str, config: Arc<ClientConfig>) -> Result<TlsConnection>{
+        let name = try!(ServerName::try_from(String::from(server_name))
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("Invalid server name `{}`", server_name))));
+        let session = try!(ClientConnection::new(config, name).map_err(tls_error));
+
+        Ok(TlsConnection::new(socket, rustls::Connection::Client(session)))
+    }
+
+    /// Start the server side of a session with a newly accepted client
+    pub fn accept(socket: TcpStream, config: Arc<ServerConfig>) -> Result<TlsConnection>{
+        let session = try!(ServerConnection::new(config).map_err(tls_error));
+
+        Ok(TlsConnection::new(socket, rustls::Connection::Server(session)))
+    }
+
+    fn new(socket: TcpStream, session: rustls::Connection) -> TlsConnection{
+        TlsConnection{ socket: socket, session: session, input: Vec::new(), max_payload_length: DEFAULT_MAX_PAYLOAD_LENGTH }
+    }
+
+    /// Move whatever the session has decrypted into the input buffer.
+    /// Returns TRUE if the peer has closed the session.
+    fn read_plaintext(&mut self) -> Result<bool>{
+        let mut buffer = [0u8; 4096];
+        loop{
+            match self.session.reader().read(&mut buffer){
+                Ok(0) => { return Ok(true); },
+                Ok(bytes_read) => { self.input.extend_from_slice(&buffer[..bytes_read]); },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => { return Ok(false); },
+                Err(e) => { return Err(e); }
+            }
+        }
+    }
+}
+
+impl Connection for TlsConnection{
+    fn evented(&self) -> Option<&Evented>{
+        Some(&self.socket)
+    }
+
+    fn send(&mut self, frame: Vec<u8>, _: Delivery) -> Result<()>{
+        self.session.writer().write_all(&frame)
+    }
+
+    fn flush(&mut self) -> Result<()>{
+        while self.session.wants_write(){
+            match self.session.write_tls(&mut self.socket){
+                Ok(_) => {},
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => { break; },
+                Err(e) => { return Err(e); }
+            }
+        }
+        Ok(())
+    }
+
+    fn has_pending_output(&self) -> bool{
+        self.session.wants_write()
+    }
+
+    fn receive(&mut self) -> Vec<ProtocolResult<(Message, usize)>>{
+        let mut results = Vec::new();
+        let mut closed = false;
+        loop{
+            match self.session.read_tls(&mut self.socket){
+                Ok(0) => { closed = true; break; },
+                Ok(_) => {},
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => { break; },
+                Err(e) => { results.push(Err(e.into())); break; }
+            }
+
+            if let Err(e) = self.session.process_new_packets(){
+                // Let the peer know why before giving up on the session
+                self.flush().ok();
+                results.push(Err(Error::new(ErrorKind::ConnectionAborted, format!("TLS error: {}", e)).into()));
+                return results;
+            }
+
+            match self.read_plaintext(){
+                Ok(true) => { closed = true; break; },
+                Ok(false) => {},
+                Err(e) => { results.push(Err(e.into())); break; }
+            }
+        }
+
+        // Send any handshake messages straight away
+        if let Err(e) = self.flush(){
+            results.push(Err(e.into()));
+        }
+
+        while let Some(message) = next_frame(&mut self.input, self.max_payload_length){
+            results.push(message);
+        }
+
+        if closed{
+            results.push(Err(Error::new(ErrorKind::UnexpectedEof, String::from("Connection closed by peer")).into()));
+        }
+
+        return results;
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr>{
+        self.socket.peer_addr().ok()
+    }
+
+    fn shutdown(&mut self){
+        self.session.send_close_notify();
+        self.flush().ok();
+        self.socket.shutdown(Shutdown::Both).ok();
+    }
+
+    fn set_max_payload_length(&mut self, max_payload_length: u32){
+        self.max_payload_length = max_payload_length;
+    }
+}
+
+/// Accepts game clients over TLS
+pub struct TlsTransport{
+    listener: TcpListener,
+    config: Arc<ServerConfig>
+}
+
+impl TlsTransport{
+    pub fn bind(address: &SocketAddr, config: Arc<ServerConfig>) -> Result<TlsTransport>{
+        Ok(TlsTransport{ listener: try!(TcpListener::bind(address)), config: config })
+    }
+}
+
+impl Transport for TlsTransport{
+    fn evented(&self) -> Option<&Evented>{
+        Some(&self.listener)
+    }
+
+    fn accept(&mut self) -> Result<Option<(Box<Connection>, SocketAddr)>>{
+        match try!(self.listener.accept()){
+            Some((socket, address)) => {
+                let connection = try!(TlsConnection::accept(socket, self.config.clone()));
+                Ok(Some((Box::new(connection), address)))
+            },
+            None => Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::env;
+    use std::fs::File;
+    use std::io::{ErrorKind, Write};
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+    use mio::tcp::TcpStream;
+    use rcgen;
+
+    use frame::{Message, ProtocolResult, ToFrame};
+    use transport::{Connection, Transport};
+    use udp::Delivery;
+
+    /// Write a new self-signed certificate for localhost, and its key, to the temp directory
+    fn generate_certificate(name: &str) -> (String, String){
+        let generated = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let directory = env::temp_dir();
+        let certificate_path = directory.join(format!("lag-test-{}.crt", name)).to_string_lossy().into_owned();
+        let key_path = directory.join(format!("lag-test-{}.key", name)).to_string_lossy().into_owned();
+
+        File::create(&certificate_path).unwrap().write_all(generated.cert.pem().as_bytes()).unwrap();
+        File::create(&key_path).unwrap().write_all(generated.key_pair.serialize_pem().as_bytes()).unwrap();
+        (certificate_path, key_path)
+    }
+
+    /// Connect a client to a TlsTransport on @port, then pump both ends until the server receives a message
+    fn exchange_message(port: u16, server_certificate: &(String, String), trust: ServerTrust) -> ProtocolResult<Message>{
+        let address: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let config = server_config(&server_certificate.0, &server_certificate.1).unwrap();
+        let mut transport = TlsTransport::bind(&address, config).unwrap();
+
+        let socket = TcpStream::connect(&address).unwrap();
+        let mut client = TlsConnection::connect(socket, "localhost", client_config(&trust).unwrap()).unwrap();
+        client.send(Message::new_text_message(String::from("Secret")).to_frame().to_bytes(), Delivery::ReliableOrdered).unwrap();
+
+        let mut server: Option<Box<Connection>> = None;
+        for _ in 0..200{
+            try!(client.flush());
+            for received in client.receive(){
+                if let Err(e) = received{
+                    return Err(e);
+                }
+            }
+
+            if server.is_none(){
+                server = transport.accept().unwrap().map(|(connection, _)| connection);
+            }
+            if let Some(ref mut server) = server{
+                for received in server.receive(){
+                    match received{
+                        Ok((message, _)) => { return Ok(message); },
+                        Err(ref e) if e.io_kind() == Some(ErrorKind::WouldBlock) => {},
+                        Err(e) => { return Err(e); }
+                    }
+                }
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("Timed out waiting for the TLS handshake");
+    }
+
+    #[test]
+    fn test_certificate_authority(){
+        let certificate = generate_certificate("ca");
+        let trust = ServerTrust::CertificateAuthority(certificate.0.clone());
+
+        match exchange_message(47131, &certificate, trust){
+            Ok(Message::Text{ message }) => { assert_eq!(message, "Secret"); },
+            other => { panic!("Expected a text message, got {:?}", other); }
+        }
+    }
+
+    #[test]
+    fn test_pinned_certificate(){
+        let certificate = generate_certificate("pinned");
+        let trust = ServerTrust::PinnedCertificate(certificate.0.clone());
+
+        match exchange_message(47132, &certificate, trust){
+            Ok(Message::Text{ message }) => { assert_eq!(message, "Secret"); },
+            other => { panic!("Expected a text message, got {:?}", other); }
+        }
+    }
+
+    #[test]
+    fn test_pinned_certificate_mismatch(){
+        let certificate = generate_certificate("served");
+        let other_certificate = generate_certificate("other");
+        let trust = ServerTrust::PinnedCertificate(other_certificate.0.clone());
+
+        assert!(exchange_message(47133, &certificate, trust).is_err());
+    }
+
+    #[test]
+    fn test_missing_certificate(){
+        assert!(server_config("/nonexistent/lag.crt", "/nonexistent/lag.key").is_err());
+    }
+}
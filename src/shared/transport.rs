@@ -0,0 +1,411 @@
+extern crate log;
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result, Read};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+use std::time::Duration;
+use mio::{Evented, TryWrite};
+use mio::tcp::{TcpListener, TcpStream, Shutdown};
+
+use frame::{Message, MessageHeader, ProtocolResult, HEADER_LENGTH, DEFAULT_MAX_PAYLOAD_LENGTH};
+use udp::Delivery;
+
+/// A connection to a single peer, over any transport.
+///
+/// Messages are passed in and out as encoded MessageFrames.
+/// A connection with no socket to register is polled every tick instead.
+pub trait Connection: Send{
+    /// The socket to register with the event loop, or None if the connection is polled every tick
+    fn evented(&self) -> Option<&Evented>;
+
+    /// Queue an encoded MessageFrame to be sent.
+    /// @delivery is only honoured by transports which aren't already reliable and ordered.
+    fn send(&mut self, frame: Vec<u8>, delivery: Delivery) -> Result<()>;
+
+    /// Write as much queued output as the transport will take
+    fn flush(&mut self) -> Result<()>;
+
+    /// Return TRUE if queued output is waiting on the socket becoming writable
+    fn has_pending_output(&self) -> bool;
+
+    /// Read the messages available, along with the number of bytes each took up.
+    /// An UnexpectedEof IO error means the peer has gone, and TimedOut that it stopped responding.
+    fn receive(&mut self) -> Vec<ProtocolResult<(Message, usize)>>;
+
+    /// The remote address, if the transport has one
+    fn peer_addr(&self) -> Option<SocketAddr>;
+
+    /// Close the connection, sending whatever is queued first where possible
+    fn shutdown(&mut self);
+
+    /// Reject incoming frames with payloads longer than @max_payload_length
+    fn set_max_payload_length(&mut self, max_payload_length: u32);
+
+    /// The round trip time to the peer, for transports which measure it
+    fn rtt(&self) -> Option<Duration>{
+        None
+    }
+}
+
+/// A source of new connections for the server
+pub trait Transport{
+    /// The listener to register with the event loop, or None if it is polled every tick
+    fn evented(&self) -> Option<&Evented>;
+
+    /// Accept the next pending connection, or None once there are no more
+    fn accept(&mut self) -> Result<Option<(Box<Connection>, SocketAddr)>>;
+}
+
+/// Take the next whole MessageFrame off the front of @input, if one has arrived,
+/// rejecting payloads longer than @max_payload_length as soon as the header is in
+pub fn next_frame(input: &mut Vec<u8>, max_payload_length: u32) -> Option<ProtocolResult<(Message, usize)>>{
+    if input.len() < HEADER_LENGTH{
+        return None;
+    }
+
+    // Check the length before waiting on the rest of the frame, so a bad one can't make us buffer forever
+    let frame_length = match MessageHeader::read_slice(input).and_then(|header| header.check_length(max_payload_length).map(|_| header)){
+        Ok(header) => header.frame_length(),
+        Err(e) => {
+            // Nothing after a bad header can be trusted to line up
+            input.clear();
+            return Some(Err(e));
+        }
+    };
+    if input.len() < frame_length{
+        return None;
+    }
+
+    let message = Message::read_limited(&mut &input[..frame_length], max_payload_length);
+    input.drain(..frame_length);
+    return Some(message.map(|message| (message, frame_length)));
+}
+
+/// MessageFrames sent back to back over a TCP stream
+pub struct TcpConnection{
+    socket: TcpStream,
+
+    /// Bytes read but not yet making up a whole MessageFrame
+    input: Vec<u8>,
+
+    /// Bytes queued but not yet accepted by the socket
+    output: Vec<u8>,
+
+    max_payload_length: u32
+}
+
+impl TcpConnection{
+    pub fn new(socket: TcpStream) -> TcpConnection{
+        TcpConnection{ socket: socket, input: Vec::new(), output: Vec::new(), max_payload_length: DEFAULT_MAX_PAYLOAD_LENGTH }
+    }
+}
+
+impl Connection for TcpConnection{
+    fn evented(&self) -> Option<&Evented>{
+        Some(&self.socket)
+    }
+
+    fn send(&mut self, frame: Vec<u8>, _: Delivery) -> Result<()>{
+        self.output.extend_from_slice(&frame);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()>{
+        while !self.output.is_empty(){
+            match try!(self.socket.try_write(&self.output)){
+                Some(bytes_written) => { self.output.drain(..bytes_written); },
+                None => { break; }
+            }
+        }
+        Ok(())
+    }
+
+    fn has_pending_output(&self) -> bool{
+        !self.output.is_empty()
+    }
+
+    /// Reads everything the socket has, returning the whole frames among it.
+    /// A frame split across reads waits in the input buffer for the rest.
+    fn receive(&mut self) -> Vec<ProtocolResult<(Message, usize)>>{
+        let mut results = Vec::new();
+        let mut closed = false;
+        let mut buffer = [0u8; 4096];
+        loop{
+            match self.socket.read(&mut buffer){
+                Ok(0) => { closed = true; break; },
+                Ok(bytes_read) => { self.input.extend_from_slice(&buffer[..bytes_read]); },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => { break; },
+                Err(e) => { results.push(Err(e.into())); break; }
+            }
+        }
+
+        while let Some(message) = next_frame(&mut self.input, self.max_payload_length){
+            results.push(message);
+        }
+
+        if closed{
+            results.push(Err(Error::new(ErrorKind::UnexpectedEof, String::from("Connection closed by peer")).into()));
+        }
+
+        return results;
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr>{
+        self.socket.peer_addr().ok()
+    }
+
+    fn shutdown(&mut self){
+        self.flush().ok();
+        self.socket.shutdown(Shutdown::Both).ok();
+    }
+
+    fn set_max_payload_length(&mut self, max_payload_length: u32){
+        self.max_payload_length = max_payload_length;
+    }
+}
+
+/// Accepts game clients over plain TCP
+pub struct TcpTransport{
+    listener: TcpListener
+}
+
+impl TcpTransport{
+    pub fn bind(address: &SocketAddr) -> Result<TcpTransport>{
+        Ok(TcpTransport{ listener: try!(TcpListener::bind(address)) })
+    }
+}
+
+impl Transport for TcpTransport{
+    fn evented(&self) -> Option<&Evented>{
+        Some(&self.listener)
+    }
+
+    fn accept(&mut self) -> Result<Option<(Box<Connection>, SocketAddr)>>{
+        match try!(self.listener.accept()){
+            Some((socket, address)) => Ok(Some((Box::new(TcpConnection::new(socket)), address))),
+            None => Ok(None)
+        }
+    }
+}
+
+/// One direction of a loopback connection
+struct MemoryPipe{
+    frames: VecDeque<Vec<u8>>,
+    closed: bool
+}
+
+impl MemoryPipe{
+    fn new() -> Arc<Mutex<MemoryPipe>>{
+        Arc::new(Mutex::new(MemoryPipe{ frames: VecDeque::new(), closed: false }))
+    }
+}
+
+/// An in-process connection with no socket, for running a server and its clients in one process.
+/// Frames are handed across whole, and are never lost or reordered.
+pub struct MemoryConnection{
+    incoming: Arc<Mutex<MemoryPipe>>,
+    outgoing: Arc<Mutex<MemoryPipe>>,
+    peer: SocketAddr,
+    max_payload_length: u32
+}
+
+impl MemoryConnection{
+    /// Create both ends of a connection; each end reports the other's address as its peer
+    pub fn pair(address: SocketAddr, peer: SocketAddr) -> (MemoryConnection, MemoryConnection){
+        let forward = MemoryPipe::new();
+        let backward = MemoryPipe::new();
+
+        let local = MemoryConnection{ incoming: backward.clone(), outgoing: forward.clone(), peer: peer, max_payload_length: DEFAULT_MAX_PAYLOAD_LENGTH };
+        let remote = MemoryConnection{ incoming: forward, outgoing: backward, peer: address, max_payload_length: DEFAULT_MAX_PAYLOAD_LENGTH };
+        (local, remote)
+    }
+}
+
+impl Connection for MemoryConnection{
+    fn evented(&self) -> Option<&Evented>{
+        None
+    }
+
+    fn send(&mut self, frame: Vec<u8>, _: Delivery) -> Result<()>{
+        match self.outgoing.lock(){
+            Ok(ref mut pipe) if !pipe.closed => {
+                pipe.frames.push_back(frame);
+                Ok(())
+            },
+            _ => Err(Error::new(ErrorKind::BrokenPipe, String::from("Connection closed")))
+        }
+    }
+
+    fn flush(&mut self) -> Result<()>{
+        Ok(())
+    }
+
+    fn has_pending_output(&self) -> bool{
+        false
+    }
+
+    fn receive(&mut self) -> Vec<ProtocolResult<(Message, usize)>>{
+        let mut results = Vec::new();
+        if let Ok(mut pipe) = self.incoming.lock(){
+            while let Some(frame) = pipe.frames.pop_front(){
+                results.push(Message::read_limited(&mut frame.as_slice(), self.max_payload_length).map(|message| (message, frame.len())));
+            }
+            if pipe.closed{
+                results.push(Err(Error::new(ErrorKind::UnexpectedEof, String::from("Connection closed by peer")).into()));
+            }
+        }
+        return results;
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr>{
+        Some(self.peer)
+    }
+
+    fn shutdown(&mut self){
+        if let Ok(mut pipe) = self.outgoing.lock(){
+            pipe.closed = true;
+        }
+    }
+
+    fn set_max_payload_length(&mut self, max_payload_length: u32){
+        self.max_payload_length = max_payload_length;
+    }
+}
+
+impl Drop for MemoryConnection{
+    fn drop(&mut self){
+        self.shutdown();
+    }
+}
+
+/// Accepts loopback connections opened through its `MemoryConnector`s
+pub struct MemoryListener{
+    pending: Receiver<(MemoryConnection, SocketAddr)>,
+    connector: MemoryConnector
+}
+
+/// Opens loopback connections to a `MemoryListener`, from any thread
+#[derive(Clone)]
+pub struct MemoryConnector{
+    sender: Sender<(MemoryConnection, SocketAddr)>,
+
+    /// Each connection is given its own port on 127.0.0.1 so it has a distinct peer address
+    next_port: Arc<AtomicUsize>
+}
+
+impl MemoryListener{
+    pub fn new() -> MemoryListener{
+        let (sender, receiver) = channel();
+        MemoryListener{
+            pending: receiver,
+            connector: MemoryConnector{ sender: sender, next_port: Arc::new(AtomicUsize::new(1)) }
+        }
+    }
+
+    pub fn connector(&self) -> MemoryConnector{
+        self.connector.clone()
+    }
+}
+
+impl MemoryConnector{
+    /// Open a connection, returning the client's end
+    pub fn connect(&self) -> Result<MemoryConnection>{
+        let port = (self.next_port.fetch_add(1, Ordering::SeqCst) % 65535 + 1) as u16;
+        let client_address = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+        let server_address = SocketAddr::new("127.0.0.1".parse().unwrap(), 0);
+
+        let (client_end, server_end) = MemoryConnection::pair(client_address, server_address);
+        try!(self.sender.send((server_end, client_address)).map_err(|_| Error::new(ErrorKind::ConnectionRefused, String::from("The listener has been dropped"))));
+        Ok(client_end)
+    }
+}
+
+impl Transport for MemoryListener{
+    fn evented(&self) -> Option<&Evented>{
+        None
+    }
+
+    fn accept(&mut self) -> Result<Option<(Box<Connection>, SocketAddr)>>{
+        match self.pending.try_recv(){
+            Ok((connection, address)) => Ok(Some((Box::new(connection), address))),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use frame::{Message, ToFrame};
+    use udp::Delivery;
+
+    #[test]
+    fn test_memory_pair(){
+        let mut listener = MemoryListener::new();
+        let mut client = listener.connector().connect().unwrap();
+        let (mut server, address) = listener.accept().unwrap().unwrap();
+        assert_eq!(server.peer_addr(), Some(address));
+
+        let frame = Message::new_text_message(String::from("Hello")).to_frame().to_bytes();
+        client.send(frame.clone(), Delivery::ReliableOrdered).unwrap();
+
+        let received = server.receive();
+        assert_eq!(received.len(), 1);
+        match received[0]{
+            Ok((Message::Text{ ref message }, length)) => {
+                assert_eq!(message, "Hello");
+                assert_eq!(length, frame.len());
+            },
+            ref other => { panic!("Expected a text message, got {:?}", other); }
+        }
+
+        drop(client);
+        match server.receive().pop(){
+            Some(Err(ref e)) if e.io_kind() == Some(ErrorKind::UnexpectedEof) => {},
+            other => { panic!("Expected the connection to be closed, got {:?}", other); }
+        }
+    }
+
+    #[test]
+    fn test_tcp_frame_split_across_reads(){
+        use std::io::Write;
+        use std::net::TcpStream as StdTcpStream;
+        use std::thread;
+        use std::time::Duration;
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut client = StdTcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut server = loop{
+            if let Some((socket, _)) = listener.accept().unwrap(){
+                break TcpConnection::new(socket);
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+
+        let frame = Message::new_text_message(String::from("Hello")).to_frame().to_bytes();
+        let frames: Vec<u8> = frame.iter().chain(frame.iter()).cloned().collect();
+
+        // The first write ends partway into the header
+        client.write_all(&frames[..14]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(server.receive().is_empty());
+
+        // The rest of the first frame arrives along with all of the second
+        client.write_all(&frames[14..]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let received = server.receive();
+        assert_eq!(received.len(), 2);
+        for message in received{
+            match message{
+                Ok((Message::Text{ ref message }, length)) => {
+                    assert_eq!(message, "Hello");
+                    assert_eq!(length, frame.len());
+                },
+                other => { panic!("Expected a text message, got {:?}", other); }
+            }
+        }
+    }
+}
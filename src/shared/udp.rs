@@ -0,0 +1,992 @@
+extern crate byteorder;
+extern crate log;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{ErrorKind, Result, Error};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use byteorder::{ByteOrder, BigEndian};
+use mio::Evented;
+use mio::udp::UdpSocket;
+
+use frame::{Message, MessageCode, ProtocolResult, DEFAULT_MAX_PAYLOAD_LENGTH};
+use transport::{Connection, Transport};
+
+const PACKET_MAGIC: u32 = 0x4C414755; // b'LAGU'
+
+/// Set in the kind byte of a packet whose ack fields are meaningful
+const PACKET_FLAG_HAS_ACK: u8 = 0x80;
+
+/// magic + kind + connection ID + sequence + ack + ack bits
+pub const PACKET_HEADER_LENGTH: usize = 4 + 1 + 4 + 2 + 2 + 4;
+
+/// delivery + sequence + length
+const MESSAGE_HEADER_LENGTH: usize = 1 + 2 + 2;
+
+/// Largest datagram we send; small enough to avoid IP fragmentation on typical links
+pub const MAX_PACKET_LENGTH: usize = 1200;
+
+/// Largest single message that fits in one packet
+pub const MAX_MESSAGE_LENGTH: usize = MAX_PACKET_LENGTH - PACKET_HEADER_LENGTH - MESSAGE_HEADER_LENGTH;
+
+/// Number of sent packets remembered while waiting for their acks
+const SENT_PACKET_HISTORY: usize = 256;
+
+/// Maximum number of out-of-order reliable messages buffered on the receiving side
+const MAX_REORDER_BUFFER: usize = 1024;
+
+/// Send an empty packet at least this often so the other side knows we're alive
+const HEARTBEAT_INTERVAL_MS: u64 = 250;
+
+/// How often a client resends Connect while waiting for the server to accept
+const CONNECT_RETRY_MS: u64 = 250;
+
+/// A connection which hasn't heard from the other side in this long is dropped
+const CONNECTION_TIMEOUT_SECS: u64 = 10;
+
+/// How a message is delivered over the UDP transport
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Delivery{
+    /// Sent once, may be lost, duplicated or arrive out of order
+    Unreliable          = 0x00,
+
+    /// Resent until acknowledged, and delivered in the order sent
+    ReliableOrdered     = 0x01,
+
+    /// May be lost, but a message older than one already delivered is dropped
+    UnreliableSequenced = 0x02
+}
+
+impl Delivery{
+    pub fn from_u8(byte: u8) -> Option<Delivery>{
+        match byte{
+            0x00 => { Some(Delivery::Unreliable) },
+            0x01 => { Some(Delivery::ReliableOrdered) },
+            0x02 => { Some(Delivery::UnreliableSequenced) },
+            _    => { None }
+        }
+    }
+
+    /// The default delivery for messages with the given code.
+    /// State updates are superseded by the next one, so only the newest matters.
+    /// Entity updates only carry what changed, so every one of them must arrive.
+    pub fn for_code(code: &MessageCode) -> Delivery{
+        match *code{
+            MessageCode::ClientUpdate    => { Delivery::UnreliableSequenced },
+            MessageCode::GameStateUpdate => { Delivery::UnreliableSequenced },
+            MessageCode::EntityUpdate    => { Delivery::ReliableOrdered },
+            MessageCode::Ping            => { Delivery::Unreliable },
+            _                            => { Delivery::ReliableOrdered }
+        }
+    }
+
+    pub fn for_message(message: &Message) -> Delivery{
+        Self::for_code(&message.get_message_code())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PacketKind{
+    /// Sent by a client to open a connection
+    Connect    = 0x01,
+
+    /// Sent by the server in reply to Connect, carrying the new connection ID
+    Accept     = 0x02,
+
+    /// Messages and acks
+    Data       = 0x03,
+
+    /// Either side closing the connection
+    Disconnect = 0x04
+}
+
+impl PacketKind{
+    pub fn from_u8(byte: u8) -> Option<PacketKind>{
+        match byte{
+            0x01 => { Some(PacketKind::Connect) },
+            0x02 => { Some(PacketKind::Accept) },
+            0x03 => { Some(PacketKind::Data) },
+            0x04 => { Some(PacketKind::Disconnect) },
+            _    => { None }
+        }
+    }
+}
+
+/// A single message carried inside a packet
+#[derive(Debug, Clone)]
+pub struct PacketMessage{
+    pub delivery: Delivery,
+
+    /// Reliable or sequenced message number, unused for unreliable messages
+    pub sequence: u16,
+
+    /// The encoded MessageFrame
+    pub payload: Vec<u8>
+}
+
+/// A datagram of the UDP transport
+#[derive(Debug, Clone)]
+pub struct Packet{
+    pub kind: PacketKind,
+    pub connection_id: u32,
+
+    /// Sequence number of this packet
+    pub sequence: u16,
+
+    /// Whether `ack` and `ack_bits` say anything; FALSE until the sender has received a packet
+    pub has_ack: bool,
+
+    /// Most recent packet sequence received from the other side
+    pub ack: u16,
+
+    /// Bit N set means packet `ack - 1 - N` was also received
+    pub ack_bits: u32,
+
+    pub messages: Vec<PacketMessage>
+}
+
+impl Packet{
+    /// A packet with no sequencing information, used during the handshake and on disconnect
+    pub fn control(kind: PacketKind, connection_id: u32) -> Packet{
+        Packet{
+            kind: kind,
+            connection_id: connection_id,
+            sequence: 0,
+            has_ack: false,
+            ack: 0,
+            ack_bits: 0,
+            messages: Vec::new()
+        }
+    }
+
+    pub fn read(input: &[u8]) -> Result<Packet>{
+        if input.len() < PACKET_HEADER_LENGTH{
+            return Err(Error::new(ErrorKind::InvalidData, format!("Packet of {} bytes is shorter than the packet header", input.len())));
+        }
+
+        if BigEndian::read_u32(&input[0..4]) != PACKET_MAGIC{
+            return Err(Error::new(ErrorKind::InvalidData, String::from("Received a packet with an invalid magic number")));
+        }
+
+        let kind_byte = input[4] & !PACKET_FLAG_HAS_ACK;
+        let kind = try!(PacketKind::from_u8(kind_byte).ok_or(Error::new(ErrorKind::InvalidData, format!("Received unknown packet kind {:x}", kind_byte))));
+
+        let mut packet = Packet{
+            kind: kind,
+            connection_id: BigEndian::read_u32(&input[5..9]),
+            sequence: BigEndian::read_u16(&input[9..11]),
+            has_ack: input[4] & PACKET_FLAG_HAS_ACK != 0,
+            ack: BigEndian::read_u16(&input[11..13]),
+            ack_bits: BigEndian::read_u32(&input[13..17]),
+            messages: Vec::new()
+        };
+
+        let mut remaining = &input[PACKET_HEADER_LENGTH..];
+        while !remaining.is_empty(){
+            if remaining.len() < MESSAGE_HEADER_LENGTH{
+                return Err(Error::new(ErrorKind::InvalidData, String::from("Packet ends in a truncated message header")));
+            }
+
+            let delivery = try!(Delivery::from_u8(remaining[0]).ok_or(Error::new(ErrorKind::InvalidData, format!("Received unknown delivery {:x}", remaining[0]))));
+            let sequence = BigEndian::read_u16(&remaining[1..3]);
+            let length = BigEndian::read_u16(&remaining[3..5]) as usize;
+
+            if remaining.len() < MESSAGE_HEADER_LENGTH + length{
+                return Err(Error::new(ErrorKind::InvalidData, format!("Packet message of {} bytes is truncated", length)));
+            }
+
+            packet.messages.push(PacketMessage{
+                delivery: delivery,
+                sequence: sequence,
+                payload: remaining[MESSAGE_HEADER_LENGTH..MESSAGE_HEADER_LENGTH + length].to_vec()
+            });
+            remaining = &remaining[MESSAGE_HEADER_LENGTH + length..];
+        }
+
+        return Ok(packet);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut buffer = vec![0u8; PACKET_HEADER_LENGTH];
+
+        BigEndian::write_u32(&mut buffer[0..4], PACKET_MAGIC);
+        buffer[4] = match self.has_ack{
+            true  => self.kind as u8 | PACKET_FLAG_HAS_ACK,
+            false => self.kind as u8
+        };
+        BigEndian::write_u32(&mut buffer[5..9], self.connection_id);
+        BigEndian::write_u16(&mut buffer[9..11], self.sequence);
+        BigEndian::write_u16(&mut buffer[11..13], self.ack);
+        BigEndian::write_u32(&mut buffer[13..17], self.ack_bits);
+
+        for message in self.messages.iter(){
+            let mut message_header = [0u8; MESSAGE_HEADER_LENGTH];
+            message_header[0] = message.delivery as u8;
+            BigEndian::write_u16(&mut message_header[1..3], message.sequence);
+            BigEndian::write_u16(&mut message_header[3..5], message.payload.len() as u16);
+
+            buffer.extend_from_slice(&message_header);
+            buffer.extend_from_slice(&message.payload);
+        }
+
+        return buffer;
+    }
+}
+
+/// Return TRUE if sequence @a is more recent than @b, accounting for wrap around
+pub fn sequence_greater_than(a: u16, b: u16) -> bool{
+    ((a > b) && (a - b <= 32768)) || ((a < b) && (b - a > 32768))
+}
+
+/// A reliable message waiting to be acknowledged
+struct PendingMessage{
+    payload: Vec<u8>,
+    last_sent: Option<Instant>
+}
+
+/// A packet we sent, remembered until it is acked or falls out of the history
+struct SentPacket{
+    sent_at: Instant,
+    reliable_sequences: Vec<u16>
+}
+
+/// Sequencing, acks and resends for one side of a UDP connection.
+///
+/// Messages are queued with `send`, turned into packets with `flush`,
+/// and incoming packets are unpacked into their delivered messages by `receive`.
+pub struct ReliableEndpoint{
+    connection_id: u32,
+
+    /// Sequence of the next packet we send
+    local_sequence: u16,
+
+    /// Most recent packet sequence received, and which of the 32 before it were received
+    remote_sequence: u16,
+    received_bits: u32,
+    has_received: bool,
+
+    /// Set when we've received a packet and haven't acked it yet
+    ack_pending: bool,
+    last_sent: Option<Instant>,
+
+    sent_packets: HashMap<u16, SentPacket>,
+    sent_order: VecDeque<u16>,
+
+    /// Outgoing reliable messages, by reliable sequence
+    send_reliable_sequence: u16,
+    unacked: BTreeMap<u16, PendingMessage>,
+
+    /// Incoming reliable messages which arrived ahead of a missing one
+    receive_reliable_sequence: u16,
+    reorder_buffer: BTreeMap<u16, Vec<u8>>,
+
+    send_sequenced: u16,
+    last_received_sequenced: Option<u16>,
+
+    /// Outgoing unreliable and sequenced messages, sent on the next flush
+    unreliable_queue: VecDeque<PacketMessage>,
+
+    /// Smoothed round trip time
+    rtt: Option<Duration>
+}
+
+impl ReliableEndpoint{
+    pub fn new(connection_id: u32) -> ReliableEndpoint{
+        ReliableEndpoint{
+            connection_id: connection_id,
+            local_sequence: 0,
+            remote_sequence: 0,
+            received_bits: 0,
+            has_received: false,
+            ack_pending: false,
+            last_sent: None,
+            sent_packets: HashMap::new(),
+            sent_order: VecDeque::with_capacity(SENT_PACKET_HISTORY),
+            send_reliable_sequence: 0,
+            unacked: BTreeMap::new(),
+            receive_reliable_sequence: 0,
+            reorder_buffer: BTreeMap::new(),
+            send_sequenced: 0,
+            last_received_sequenced: None,
+            unreliable_queue: VecDeque::new(),
+            rtt: None
+        }
+    }
+
+    pub fn connection_id(&self) -> u32{
+        self.connection_id
+    }
+
+    /// The smoothed round trip time, once at least one packet has been acked
+    pub fn rtt(&self) -> Option<Duration>{
+        self.rtt
+    }
+
+    /// Number of reliable messages sent but not yet acknowledged
+    pub fn unacked_count(&self) -> usize{
+        self.unacked.len()
+    }
+
+    /// Queue an encoded MessageFrame for sending
+    pub fn send(&mut self, delivery: Delivery, payload: Vec<u8>) -> Result<()>{
+        if payload.len() > MAX_MESSAGE_LENGTH{
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Message of {} bytes is too large for a packet", payload.len())));
+        }
+
+        match delivery{
+            Delivery::ReliableOrdered => {
+                let sequence = self.send_reliable_sequence;
+                self.send_reliable_sequence = sequence.wrapping_add(1);
+                self.unacked.insert(sequence, PendingMessage{ payload: payload, last_sent: None });
+            },
+            Delivery::UnreliableSequenced => {
+                let sequence = self.send_sequenced;
+                self.send_sequenced = sequence.wrapping_add(1);
+                self.unreliable_queue.push_back(PacketMessage{ delivery: delivery, sequence: sequence, payload: payload });
+            },
+            Delivery::Unreliable => {
+                self.unreliable_queue.push_back(PacketMessage{ delivery: delivery, sequence: 0, payload: payload });
+            }
+        }
+        Ok(())
+    }
+
+    /// How long to wait for an ack before resending a reliable message
+    fn resend_interval(&self) -> Duration{
+        match self.rtt{
+            Some(rtt) => ::std::cmp::min(::std::cmp::max(rtt * 2, Duration::from_millis(30)), Duration::from_secs(1)),
+            None => Duration::from_millis(200)
+        }
+    }
+
+    /// Build the packets to send now: new and due-for-resend reliable messages,
+    /// queued unreliable messages, and an empty packet if an ack or heartbeat is due.
+    pub fn flush(&mut self, now: Instant) -> Vec<Packet>{
+        let resend_interval = self.resend_interval();
+        let mut packets = Vec::new();
+        let mut current: Option<(Packet, usize, Vec<u16>)> = None;
+
+        let mut outgoing = Vec::new();
+        for (sequence, pending) in self.unacked.iter_mut(){
+            let due = pending.last_sent.map(|sent| now.duration_since(sent) >= resend_interval).unwrap_or(true);
+            if due{
+                pending.last_sent = Some(now);
+                outgoing.push(PacketMessage{ delivery: Delivery::ReliableOrdered, sequence: *sequence, payload: pending.payload.clone() });
+            }
+        }
+        outgoing.extend(self.unreliable_queue.drain(..));
+
+        for message in outgoing{
+            let message_length = MESSAGE_HEADER_LENGTH + message.payload.len();
+            let full = current.as_ref().map(|&(_, length, _)| length + message_length > MAX_PACKET_LENGTH).unwrap_or(false);
+            if full{
+                let (packet, _, reliable) = current.take().unwrap();
+                packets.push(self.finish_packet(packet, reliable, now));
+            }
+
+            if current.is_none(){
+                current = Some((self.new_packet(), PACKET_HEADER_LENGTH, Vec::new()));
+            }
+
+            if let Some((ref mut packet, ref mut length, ref mut reliable)) = current{
+                if message.delivery == Delivery::ReliableOrdered{
+                    reliable.push(message.sequence);
+                }
+                *length += message_length;
+                packet.messages.push(message);
+            }
+        }
+
+        if let Some((packet, _, reliable)) = current.take(){
+            packets.push(self.finish_packet(packet, reliable, now));
+        }
+
+        let heartbeat_due = self.last_sent.map(|sent| now.duration_since(sent) >= Duration::from_millis(HEARTBEAT_INTERVAL_MS)).unwrap_or(true);
+        if packets.is_empty() && (self.ack_pending || heartbeat_due){
+            let packet = self.new_packet();
+            packets.push(self.finish_packet(packet, Vec::new(), now));
+        }
+
+        return packets;
+    }
+
+    fn new_packet(&self) -> Packet{
+        let mut packet = Packet::control(PacketKind::Data, self.connection_id);
+        // Until something has arrived there's nothing to ack, and acking 0 would ack our own first packet
+        packet.has_ack = self.has_received;
+        packet.ack = self.remote_sequence;
+        packet.ack_bits = self.received_bits;
+        return packet;
+    }
+
+    /// Assign a sequence number to @packet and remember it until it's acked
+    fn finish_packet(&mut self, mut packet: Packet, reliable_sequences: Vec<u16>, now: Instant) -> Packet{
+        packet.sequence = self.local_sequence;
+        self.local_sequence = self.local_sequence.wrapping_add(1);
+
+        if self.sent_order.len() >= SENT_PACKET_HISTORY{
+            if let Some(oldest) = self.sent_order.pop_front(){
+                self.sent_packets.remove(&oldest);
+            }
+        }
+        self.sent_order.push_back(packet.sequence);
+        self.sent_packets.insert(packet.sequence, SentPacket{ sent_at: now, reliable_sequences: reliable_sequences });
+
+        self.ack_pending = false;
+        self.last_sent = Some(now);
+        return packet;
+    }
+
+    /// Process a Data packet from the other side, returning the messages ready for delivery
+    pub fn receive(&mut self, packet: Packet, now: Instant) -> Vec<Vec<u8>>{
+        let mut delivered = Vec::new();
+
+        if !self.record_received(packet.sequence){
+            trace!("Dropping duplicate packet {}", packet.sequence);
+            return delivered;
+        }
+        if packet.has_ack{
+            self.process_acks(packet.ack, packet.ack_bits, now);
+        }
+
+        for message in packet.messages{
+            match message.delivery{
+                Delivery::Unreliable => {
+                    delivered.push(message.payload);
+                },
+                Delivery::UnreliableSequenced => {
+                    let is_newest = self.last_received_sequenced.map(|last| sequence_greater_than(message.sequence, last)).unwrap_or(true);
+                    if is_newest{
+                        self.last_received_sequenced = Some(message.sequence);
+                        delivered.push(message.payload);
+                    }
+                },
+                Delivery::ReliableOrdered => {
+                    if message.sequence == self.receive_reliable_sequence{
+                        self.receive_reliable_sequence = self.receive_reliable_sequence.wrapping_add(1);
+                        delivered.push(message.payload);
+
+                        // Anything buffered directly after this message can now be delivered too
+                        while let Some(payload) = self.reorder_buffer.remove(&self.receive_reliable_sequence){
+                            self.receive_reliable_sequence = self.receive_reliable_sequence.wrapping_add(1);
+                            delivered.push(payload);
+                        }
+                    }
+                    else if sequence_greater_than(message.sequence, self.receive_reliable_sequence){
+                        if self.reorder_buffer.len() < MAX_REORDER_BUFFER{
+                            self.reorder_buffer.insert(message.sequence, message.payload);
+                        }
+                    }
+                }
+            }
+        }
+
+        return delivered;
+    }
+
+    /// Update the received packet window. Returns FALSE if the packet was already received.
+    fn record_received(&mut self, sequence: u16) -> bool{
+        self.ack_pending = true;
+
+        if !self.has_received{
+            self.has_received = true;
+            self.remote_sequence = sequence;
+            self.received_bits = 0;
+            return true;
+        }
+
+        if sequence_greater_than(sequence, self.remote_sequence){
+            let shift = sequence.wrapping_sub(self.remote_sequence) as u32;
+            self.received_bits = if shift > 32 { 0 } else { ((self.received_bits as u64) << shift | 1u64 << (shift - 1)) as u32 };
+            self.remote_sequence = sequence;
+            return true;
+        }
+
+        if sequence == self.remote_sequence{
+            return false;
+        }
+
+        let distance = self.remote_sequence.wrapping_sub(sequence) as u32;
+        if distance > 32{
+            // Too old to track, treat it as a duplicate
+            return false;
+        }
+
+        let bit = 1u32 << (distance - 1);
+        if self.received_bits & bit != 0{
+            return false;
+        }
+        self.received_bits |= bit;
+        return true;
+    }
+
+    fn process_acks(&mut self, ack: u16, ack_bits: u32, now: Instant){
+        self.ack_packet(ack, now);
+        for bit in 0..32{
+            if ack_bits & (1 << bit) != 0{
+                self.ack_packet(ack.wrapping_sub(bit + 1), now);
+            }
+        }
+    }
+
+    fn ack_packet(&mut self, sequence: u16, now: Instant){
+        if let Some(sent) = self.sent_packets.remove(&sequence){
+            for reliable_sequence in sent.reliable_sequences{
+                self.unacked.remove(&reliable_sequence);
+            }
+
+            let sample = now.duration_since(sent.sent_at);
+            self.rtt = Some(match self.rtt{
+                Some(rtt) => (rtt * 7 + sample) / 8,
+                None => sample
+            });
+        }
+    }
+}
+
+/// Packets routed to an accepted connection by the server's `UdpTransport`
+struct UdpInbox{
+    packets: VecDeque<Packet>,
+    closed: bool
+}
+
+/// One side of a connection over the UDP transport.
+///
+/// A client's connection reads its own socket and sends Connect until the server accepts,
+/// while the connections accepted by a server share its socket, and are fed by its `UdpTransport`.
+pub struct UdpConnection{
+    socket: UdpSocket,
+    peer: SocketAddr,
+
+    /// Created once the connection has been accepted
+    endpoint: Option<ReliableEndpoint>,
+
+    /// Messages sent before the connection was accepted
+    pending: Vec<(Vec<u8>, Delivery)>,
+
+    /// Set on connections accepted by a server, which has the socket to itself
+    inbox: Option<Arc<Mutex<UdpInbox>>>,
+
+    last_connect_sent: Option<Instant>,
+
+    /// When we last heard from the other side, or started connecting
+    last_received: Instant,
+
+    closed: bool,
+
+    max_payload_length: u32
+}
+
+impl UdpConnection{
+    /// Open a connection to the server at @address
+    pub fn connect(address: &SocketAddr) -> Result<UdpConnection>{
+        let bind_address = match *address{
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0"
+        };
+        let socket = try!(UdpSocket::bound(&bind_address.parse().unwrap()));
+
+        Ok(UdpConnection{
+            socket: socket,
+            peer: *address,
+            endpoint: None,
+            pending: Vec::new(),
+            inbox: None,
+            last_connect_sent: None,
+            last_received: Instant::now(),
+            closed: false,
+            max_payload_length: DEFAULT_MAX_PAYLOAD_LENGTH
+        })
+    }
+
+    fn accepted(socket: UdpSocket, peer: SocketAddr, connection_id: u32, inbox: Arc<Mutex<UdpInbox>>) -> UdpConnection{
+        UdpConnection{
+            socket: socket,
+            peer: peer,
+            endpoint: Some(ReliableEndpoint::new(connection_id)),
+            pending: Vec::new(),
+            inbox: Some(inbox),
+            last_connect_sent: None,
+            last_received: Instant::now(),
+            closed: false,
+            max_payload_length: DEFAULT_MAX_PAYLOAD_LENGTH
+        }
+    }
+
+    /// The smoothed round trip time, once at least one packet has been acked
+    pub fn rtt(&self) -> Option<Duration>{
+        self.endpoint.as_ref().and_then(|endpoint| endpoint.rtt())
+    }
+
+    fn send_packet(&self, packet: &Packet) -> Result<()>{
+        try!(self.socket.send_to(&packet.to_bytes(), &self.peer));
+        Ok(())
+    }
+
+    fn on_packet(&mut self, packet: Packet, results: &mut Vec<ProtocolResult<(Message, usize)>>){
+        let now = Instant::now();
+        self.last_received = now;
+
+        match packet.kind{
+            PacketKind::Accept => {
+                if self.endpoint.is_none() && self.inbox.is_none(){
+                    info!("Connected over UDP as connection {}", packet.connection_id);
+                    let mut endpoint = ReliableEndpoint::new(packet.connection_id);
+                    for (payload, delivery) in self.pending.drain(..){
+                        if let Err(e) = endpoint.send(delivery, payload){
+                            results.push(Err(e.into()));
+                        }
+                    }
+                    self.endpoint = Some(endpoint);
+                }
+            },
+            PacketKind::Data => {
+                if let Some(ref mut endpoint) = self.endpoint{
+                    if packet.connection_id == endpoint.connection_id(){
+                        for payload in endpoint.receive(packet, now){
+                            results.push(Message::read_limited(&mut payload.as_slice(), self.max_payload_length).map(|message| (message, payload.len())));
+                        }
+                    }
+                }
+            },
+            PacketKind::Disconnect => {
+                self.closed = true;
+                results.push(Err(Error::new(ErrorKind::UnexpectedEof, String::from("Connection closed by peer")).into()));
+            },
+            PacketKind::Connect => {
+                trace!("Ignoring Connect packet on an open connection");
+            }
+        }
+    }
+}
+
+impl Connection for UdpConnection{
+    fn evented(&self) -> Option<&Evented>{
+        match self.inbox{
+            Some(_) => None,
+            None => Some(&self.socket)
+        }
+    }
+
+    fn send(&mut self, frame: Vec<u8>, delivery: Delivery) -> Result<()>{
+        match self.endpoint{
+            Some(ref mut endpoint) => endpoint.send(delivery, frame),
+            None => {
+                self.pending.push((frame, delivery));
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends everything queued, along with any acks, resends and heartbeats due
+    fn flush(&mut self) -> Result<()>{
+        if self.closed{
+            return Ok(());
+        }
+        let now = Instant::now();
+
+        let packets = match self.endpoint{
+            Some(ref mut endpoint) => endpoint.flush(now),
+            None => {
+                let retry_due = self.last_connect_sent.map(|sent| now.duration_since(sent) >= Duration::from_millis(CONNECT_RETRY_MS)).unwrap_or(true);
+                if !retry_due{
+                    return Ok(());
+                }
+                self.last_connect_sent = Some(now);
+                vec![Packet::control(PacketKind::Connect, 0)]
+            }
+        };
+
+        for packet in packets{
+            try!(self.send_packet(&packet));
+        }
+        Ok(())
+    }
+
+    fn has_pending_output(&self) -> bool{
+        false
+    }
+
+    fn receive(&mut self) -> Vec<ProtocolResult<(Message, usize)>>{
+        let mut results = Vec::new();
+
+        match self.inbox.clone(){
+            Some(inbox) => {
+                let packets = match inbox.lock(){
+                    Ok(mut inbox) => inbox.packets.drain(..).collect(),
+                    Err(_) => Vec::new()
+                };
+                for packet in packets{
+                    self.on_packet(packet, &mut results);
+                }
+            },
+            None => {
+                let mut buffer = [0u8; MAX_PACKET_LENGTH];
+                while !self.closed{
+                    let (length, address) = match self.socket.recv_from(&mut buffer){
+                        Ok(Some(received)) => received,
+                        Ok(None) => { break; },
+                        Err(e) => {
+                            results.push(Err(e.into()));
+                            break;
+                        }
+                    };
+
+                    if address != self.peer{
+                        trace!("Dropping datagram from {}", address);
+                        continue;
+                    }
+
+                    match Packet::read(&buffer[..length]){
+                        Ok(packet) => { self.on_packet(packet, &mut results); },
+                        Err(e) => { results.push(Err(e.into())); }
+                    }
+                }
+            }
+        }
+
+        if !self.closed && Instant::now().duration_since(self.last_received) >= Duration::from_secs(CONNECTION_TIMEOUT_SECS){
+            self.closed = true;
+            results.push(Err(Error::new(ErrorKind::TimedOut, String::from("Connection timed out")).into()));
+        }
+
+        return results;
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr>{
+        Some(self.peer)
+    }
+
+    fn shutdown(&mut self){
+        if !self.closed{
+            self.flush().ok();
+            if let Some(ref endpoint) = self.endpoint{
+                self.send_packet(&Packet::control(PacketKind::Disconnect, endpoint.connection_id())).ok();
+            }
+            self.closed = true;
+        }
+
+        if let Some(ref inbox) = self.inbox{
+            if let Ok(mut inbox) = inbox.lock(){
+                inbox.closed = true;
+            }
+        }
+    }
+
+    fn set_max_payload_length(&mut self, max_payload_length: u32){
+        self.max_payload_length = max_payload_length;
+    }
+
+    fn rtt(&self) -> Option<Duration>{
+        UdpConnection::rtt(self)
+    }
+}
+
+/// Accepts UDP clients on a single socket, and routes each datagram to its connection
+pub struct UdpTransport{
+    socket: UdpSocket,
+
+    /// Inboxes of the open connections by connection ID, and the connection ID of each remote address
+    connections: HashMap<u32, Arc<Mutex<UdpInbox>>>,
+    addresses: HashMap<SocketAddr, u32>,
+
+    /// State of the generator for connection IDs
+    connection_id_seed: u64
+}
+
+impl UdpTransport{
+    pub fn bind(address: &SocketAddr) -> Result<UdpTransport>{
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        Ok(UdpTransport{
+            socket: try!(UdpSocket::bound(address)),
+            connections: HashMap::new(),
+            addresses: HashMap::new(),
+            connection_id_seed: (now.as_secs() << 32) ^ (now.subsec_nanos() as u64) | 1
+        })
+    }
+
+    /// Generate a nonzero connection ID not in use by any other connection
+    fn next_connection_id(&mut self) -> u32{
+        loop{
+            // xorshift64*
+            self.connection_id_seed ^= self.connection_id_seed >> 12;
+            self.connection_id_seed ^= self.connection_id_seed << 25;
+            self.connection_id_seed ^= self.connection_id_seed >> 27;
+            let connection_id = (self.connection_id_seed.wrapping_mul(0x2545F4914F6CDD1D) >> 32) as u32;
+
+            if connection_id != 0 && !self.connections.contains_key(&connection_id){
+                return connection_id;
+            }
+        }
+    }
+
+    /// Forget connections which have been shut down
+    fn remove_closed(&mut self){
+        let closed: Vec<u32> = self.connections.iter()
+            .filter(|&(_, inbox)| inbox.lock().map(|inbox| inbox.closed).unwrap_or(true))
+            .map(|(connection_id, _)| *connection_id)
+            .collect();
+
+        for connection_id in closed{
+            self.connections.remove(&connection_id);
+        }
+        let connections = &self.connections;
+        self.addresses.retain(|_, connection_id| connections.contains_key(connection_id));
+    }
+
+    fn send_packet(&self, packet: &Packet, address: &SocketAddr){
+        if let Err(e) = self.socket.send_to(&packet.to_bytes(), address){
+            warn!("Failed to send {:?} packet: {:?}", packet.kind, e);
+        }
+    }
+}
+
+impl Transport for UdpTransport{
+    fn evented(&self) -> Option<&Evented>{
+        Some(&self.socket)
+    }
+
+    /// Reads every datagram waiting, passing data to open connections, until a new client connects
+    fn accept(&mut self) -> Result<Option<(Box<Connection>, SocketAddr)>>{
+        self.remove_closed();
+        let mut buffer = [0u8; MAX_PACKET_LENGTH];
+
+        loop{
+            let (length, address) = match try!(self.socket.recv_from(&mut buffer)){
+                Some(received) => received,
+                None => { return Ok(None); }
+            };
+
+            let packet = match Packet::read(&buffer[..length]){
+                Ok(packet) => packet,
+                Err(e) => {
+                    debug!("Dropping malformed packet from {}: {:?}", address, e);
+                    continue;
+                }
+            };
+
+            if packet.kind == PacketKind::Connect{
+                // The Accept was lost, so send it again
+                if let Some(connection_id) = self.addresses.get(&address).cloned(){
+                    self.send_packet(&Packet::control(PacketKind::Accept, connection_id), &address);
+                    continue;
+                }
+
+                let connection_id = self.next_connection_id();
+                let inbox = Arc::new(Mutex::new(UdpInbox{ packets: VecDeque::new(), closed: false }));
+                let connection = UdpConnection::accepted(try!(self.socket.try_clone()), address, connection_id, inbox.clone());
+
+                self.connections.insert(connection_id, inbox);
+                self.addresses.insert(address, connection_id);
+                self.send_packet(&Packet::control(PacketKind::Accept, connection_id), &address);
+
+                return Ok(Some((Box::new(connection), address)));
+            }
+
+            match (self.addresses.get(&address), self.connections.get(&packet.connection_id)){
+                (Some(connection_id), Some(inbox)) if *connection_id == packet.connection_id => {
+                    if let Ok(mut inbox) = inbox.lock(){
+                        inbox.packets.push_back(packet);
+                    }
+                },
+                _ => {
+                    trace!("Dropping packet from {} for unknown connection {}", address, packet.connection_id);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    /// Deliver every packet in @packets from one endpoint to the other, returning delivered payloads
+    fn deliver(receiver: &mut ReliableEndpoint, packets: Vec<Packet>, now: Instant) -> Vec<Vec<u8>>{
+        packets.into_iter()
+            .flat_map(|packet| receiver.receive(Packet::read(&packet.to_bytes()).unwrap(), now))
+            .collect()
+    }
+
+    #[test]
+    fn test_sequence_wraps(){
+        assert!(sequence_greater_than(1, 0));
+        assert!(sequence_greater_than(0, 65535));
+        assert!(!sequence_greater_than(65535, 0));
+    }
+
+    #[test]
+    fn test_reliable_resent_until_acked(){
+        let now = Instant::now();
+        let mut client = ReliableEndpoint::new(7);
+        let mut server = ReliableEndpoint::new(7);
+
+        client.send(Delivery::ReliableOrdered, vec![1]).unwrap();
+        client.send(Delivery::ReliableOrdered, vec![2]).unwrap();
+
+        // The first flush is lost
+        let _ = client.flush(now);
+        assert!(deliver(&mut server, client.flush(now), now).is_empty());
+
+        // After the resend interval both messages are sent again, in order
+        let later = now + Duration::from_millis(250);
+        assert_eq!(deliver(&mut server, client.flush(later), later), vec![vec![1], vec![2]]);
+
+        // Once the server's ack arrives the messages are no longer resent
+        deliver(&mut client, server.flush(later), later);
+        assert_eq!(client.unacked_count(), 0);
+    }
+
+    #[test]
+    fn test_first_packet_lost(){
+        let now = Instant::now();
+        let mut client = ReliableEndpoint::new(7);
+        let mut server = ReliableEndpoint::new(7);
+
+        // The client's first packet is lost, and the server has nothing of the client's to ack
+        client.send(Delivery::ReliableOrdered, vec![1]).unwrap();
+        let _ = client.flush(now);
+        server.send(Delivery::Unreliable, vec![9]).unwrap();
+        assert_eq!(deliver(&mut client, server.flush(now), now), vec![vec![9]]);
+        assert_eq!(client.unacked_count(), 1);
+
+        // So the message is still resent
+        let later = now + Duration::from_millis(250);
+        assert_eq!(deliver(&mut server, client.flush(later), later), vec![vec![1]]);
+        deliver(&mut client, server.flush(later), later);
+        assert_eq!(client.unacked_count(), 0);
+    }
+
+    #[test]
+    fn test_reliable_reordered(){
+        let now = Instant::now();
+        let mut client = ReliableEndpoint::new(7);
+        let mut server = ReliableEndpoint::new(7);
+
+        client.send(Delivery::ReliableOrdered, vec![1]).unwrap();
+        let first = client.flush(now);
+        client.send(Delivery::ReliableOrdered, vec![2]).unwrap();
+        let second = client.flush(now);
+
+        assert!(deliver(&mut server, second, now).is_empty());
+        assert_eq!(deliver(&mut server, first, now), vec![vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn test_sequenced_drops_stale(){
+        let now = Instant::now();
+        let mut client = ReliableEndpoint::new(7);
+        let mut server = ReliableEndpoint::new(7);
+
+        client.send(Delivery::UnreliableSequenced, vec![1]).unwrap();
+        let first = client.flush(now);
+        client.send(Delivery::UnreliableSequenced, vec![2]).unwrap();
+        let second = client.flush(now);
+
+        assert_eq!(deliver(&mut server, second, now), vec![vec![2]]);
+        assert!(deliver(&mut server, first, now).is_empty());
+    }
+}